@@ -86,6 +86,26 @@ pub struct NoopIoEventBackend {}
 
 impl IoEventBackend for NoopIoEventBackend {}
 
+/// The ShellEscapeBackend trait allows the program driving the TeX engines to
+/// execute "shell escape" commands (`\write18`) on their behalf.
+///
+/// Implementations are responsible for providing the command with a sensible
+/// working environment, and for making any files that it creates available to
+/// subsequent I/O operations.
+pub trait ShellEscapeBackend {
+    /// Execute a shell command.
+    ///
+    /// On success, the return value lists the names of the files that were
+    /// created or modified by the command, along with the digests of their
+    /// new contents. The engine reports these to the `IoEventBackend` as if
+    /// they had been written by the engine itself.
+    fn execute_shell_command(
+        &mut self,
+        command: &str,
+        status: &mut dyn StatusBackend,
+    ) -> Result<Vec<(String, DigestData)>>;
+}
+
 // Function defined in the C support code:
 extern "C" {
     fn _ttbc_get_error_message() -> *const libc::c_char;
@@ -136,6 +156,7 @@ pub struct CoreBridgeLauncher<'a> {
     io: &'a mut dyn IoProvider,
     events: &'a mut dyn IoEventBackend,
     status: &'a mut dyn StatusBackend,
+    shell_escape: Option<&'a mut dyn ShellEscapeBackend>,
 }
 
 impl<'a> CoreBridgeLauncher<'a> {
@@ -145,7 +166,22 @@ impl<'a> CoreBridgeLauncher<'a> {
         events: &'a mut dyn IoEventBackend,
         status: &'a mut dyn StatusBackend,
     ) -> Self {
-        CoreBridgeLauncher { io, events, status }
+        CoreBridgeLauncher {
+            io,
+            events,
+            status,
+            shell_escape: None,
+        }
+    }
+
+    /// Provide a backend for executing shell-escape commands.
+    ///
+    /// If no backend is provided, all shell-escape requests made by the
+    /// engine will fail. Note that engines generally have their own settings
+    /// that control whether they will try to use shell-escape at all.
+    pub fn with_shell_escape(mut self, shell_escape: &'a mut dyn ShellEscapeBackend) -> Self {
+        self.shell_escape = Some(shell_escape);
+        self
     }
 
     /// Invoke a function to launch a bridged FFI engine with a global mutex
@@ -166,7 +202,11 @@ impl<'a> CoreBridgeLauncher<'a> {
         F: FnOnce(&mut CoreBridgeState<'_>) -> Result<T>,
     {
         let _guard = ENGINE_LOCK.lock().unwrap();
-        let mut state = CoreBridgeState::new(self.io, self.events, self.status);
+        let shell_escape = self
+            .shell_escape
+            .as_mut()
+            .map(|se| &mut **se as &mut dyn ShellEscapeBackend);
+        let mut state = CoreBridgeState::new(self.io, self.events, self.status, shell_escape);
         let result = callback(&mut state);
 
         if let Err(ref e) = result {
@@ -189,6 +229,7 @@ pub struct CoreBridgeState<'a> {
     io: &'a mut dyn IoProvider,
     events: &'a mut dyn IoEventBackend,
     status: &'a mut dyn StatusBackend,
+    shell_escape: Option<&'a mut dyn ShellEscapeBackend>,
     #[allow(clippy::vec_box)]
     input_handles: Vec<Box<InputHandle>>,
     #[allow(clippy::vec_box)]
@@ -200,11 +241,13 @@ impl<'a> CoreBridgeState<'a> {
         io: &'a mut dyn IoProvider,
         events: &'a mut dyn IoEventBackend,
         status: &'a mut dyn StatusBackend,
+        shell_escape: Option<&'a mut dyn ShellEscapeBackend>,
     ) -> CoreBridgeState<'a> {
         CoreBridgeState {
             io,
             events,
             status,
+            shell_escape,
             output_handles: Vec::new(),
             input_handles: Vec::new(),
         }
@@ -535,6 +578,35 @@ impl<'a> CoreBridgeState<'a> {
 
         true
     }
+
+    fn shell_escape(&mut self, command: &str) -> bool {
+        let backend = match self.shell_escape {
+            Some(ref mut b) => b,
+            None => {
+                tt_error!(
+                    self.status,
+                    "cannot execute shell-escape command \"{}\": no backend available",
+                    command
+                );
+                return true;
+            }
+        };
+
+        match backend.execute_shell_command(command, self.status) {
+            Ok(changed) => {
+                for (name, digest) in changed {
+                    self.events.output_opened(&name);
+                    self.events.output_closed(name, digest);
+                }
+
+                false
+            }
+            Err(e) => {
+                tt_error!(self.status, "failed to execute shell-escape command \"{}\"", command; e);
+                true
+            }
+        }
+    }
 }
 
 // The entry points.
@@ -858,6 +930,30 @@ pub extern "C" fn ttbc_input_close(
     }
 }
 
+/// Execute a shell-escape command.
+///
+/// The command text is passed as UTF-16 code units, since that is how the
+/// XeTeX engine stores its strings.
+///
+/// # Safety
+///
+/// This function is unsafe because it dereferences raw C pointers.
+#[no_mangle]
+pub unsafe extern "C" fn ttbc_shell_escape(
+    es: &mut CoreBridgeState,
+    cmd: *const u16,
+    len: libc::size_t,
+) -> libc::c_int {
+    let rcmd = slice::from_raw_parts(cmd, len);
+    let rcmd = String::from_utf16_lossy(rcmd);
+
+    if es.shell_escape(&rcmd) {
+        1
+    } else {
+        0
+    }
+}
+
 /// A buffer for diagnostic messages. Rust code does not need to use this type.
 ///
 /// This type has to be public so that it can be exposed in the C/C++ headers,
@@ -306,3 +306,10 @@ ttstub_get_file_md5(char const *path, char *digest)
 {
     return ttbc_get_file_md5(tectonic_global_bridge_core, path, (uint8_t *) digest);
 }
+
+
+int
+ttstub_shell_escape(const uint16_t *cmd, size_t len)
+{
+    return ttbc_shell_escape(tectonic_global_bridge_core, cmd, len);
+}
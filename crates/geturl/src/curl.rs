@@ -3,20 +3,115 @@
 
 //! A URL-get backend based on the `curl` crate.
 
-use curl::easy::Easy;
-use std::io::Cursor;
-use tectonic_errors::{anyhow::bail, Result};
-use tectonic_status_base::StatusBackend;
+use curl::easy::{Easy, List};
+use std::{
+    fs,
+    io::{Cursor, Write},
+    path::Path,
+};
+use tectonic_errors::{anyhow::bail, atry, Result};
+use tectonic_status_base::{tt_warning, StatusBackend};
+use tempfile::{NamedTempFile, TempPath};
 
-use crate::{GetUrlBackend, RangeReader};
+use crate::{Failover, GetUrlBackend, GetUrlConfig, RangeReader};
 
 const MAX_HTTP_REDIRECTS_ALLOWED: u32 = 10;
 
+/// The certificate authorities for curl to trust when extra ones are
+/// configured.
+///
+/// Curl reads a single file of authorities, which replaces its default one,
+/// so we give it a copy of the system's file with the extra authorities
+/// appended. That way they are trusted in addition to the system's ones, as
+/// with the `reqwest` backend. The system's file is found in the same way as
+/// the `curl` crate finds it. Where there is no such file, as on Windows,
+/// curl can only be given the extra authorities.
+#[derive(Debug, Default)]
+struct CaBundle {
+    /// The combined file, once it has been written. It is deleted when this
+    /// is dropped.
+    file: Option<TempPath>,
+}
+
+impl CaBundle {
+    /// Get the file to give to curl, writing it if that hasn't been done
+    /// yet, or `None` if no extra authorities are configured.
+    fn path(&mut self, config: &GetUrlConfig) -> Result<Option<&Path>> {
+        let extra = match &config.ca_certificates {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+
+        if self.file.is_none() {
+            let mut file = atry!(
+                NamedTempFile::new();
+                ["failed to create a temporary file for certificate authorities"]
+            );
+
+            if let Some(system) = openssl_probe::probe().cert_file {
+                let data = atry!(
+                    fs::read(&system);
+                    ["failed to read certificate file `{}`", system.display()]
+                );
+                file.write_all(&data)?;
+                file.write_all(b"\n")?;
+            }
+
+            let data = atry!(
+                fs::read(extra);
+                ["failed to read certificate file `{}`", extra.display()]
+            );
+            file.write_all(&data)?;
+            self.file = Some(file.into_temp_path());
+        }
+
+        Ok(self.file.as_deref())
+    }
+}
+
+/// Apply the network settings to a handle before a request for a URL.
+fn configure_handle(
+    handle: &mut Easy,
+    config: &GetUrlConfig,
+    ca_bundle: &mut CaBundle,
+    url: &str,
+) -> Result<()> {
+    if let Some(proxy) = &config.proxy {
+        handle.proxy(proxy)?;
+    }
+
+    if let Some(path) = ca_bundle.path(config)? {
+        handle.cainfo(path)?;
+    }
+
+    if let Some(t) = config.connect_timeout {
+        handle.connect_timeout(t)?;
+    }
+
+    if let Some(t) = config.timeout {
+        handle.timeout(t)?;
+    }
+
+    // The header list is always set, so that headers for one URL aren't
+    // left over for a request to another.
+    let mut headers = List::new();
+
+    for (name, value) in config.headers_for(url) {
+        headers.append(&format!("{}: {}", name, value))?;
+    }
+
+    handle.http_headers(headers)?;
+    Ok(())
+}
+
 fn get_url_generic(
     handle: &mut Easy,
+    config: &GetUrlConfig,
+    ca_bundle: &mut CaBundle,
     url: &str,
     range: Option<(u64, usize)>,
 ) -> Result<Cursor<Vec<u8>>> {
+    configure_handle(handle, config, ca_bundle, url)?;
     handle.url(url)?;
     handle.follow_location(true)?;
     handle.max_redirections(MAX_HTTP_REDIRECTS_ALLOWED)?;
@@ -46,6 +141,15 @@ fn get_url_generic(
         );
     }
 
+    // A server that doesn't support ranges sends the whole resource instead.
+    if range.is_some() && code != 206 {
+        bail!(
+            "server did not honor a byte-range request (status code {}) for url `{}`",
+            code,
+            url
+        );
+    }
+
     Ok(Cursor::new(buf))
 }
 
@@ -53,13 +157,13 @@ fn get_url_generic(
 #[derive(Debug)]
 pub struct CurlBackend {
     handle: Easy,
+    config: GetUrlConfig,
+    ca_bundle: CaBundle,
 }
 
 impl Default for CurlBackend {
     fn default() -> Self {
-        CurlBackend {
-            handle: Easy::new(),
-        }
+        Self::with_config(GetUrlConfig::default())
     }
 }
 
@@ -67,8 +171,18 @@ impl GetUrlBackend for CurlBackend {
     type Response = Cursor<Vec<u8>>;
     type RangeReader = CurlRangeReader;
 
-    fn get_url(&mut self, url: &str, _status: &mut dyn StatusBackend) -> Result<Self::Response> {
-        get_url_generic(&mut self.handle, url, None)
+    fn get_url(&mut self, url: &str, status: &mut dyn StatusBackend) -> Result<Self::Response> {
+        let handle = &mut self.handle;
+        let config = &self.config;
+        let ca_bundle = &mut self.ca_bundle;
+
+        Failover::new(config, url).attempt(|candidate| {
+            if candidate != url {
+                tt_warning!(status, "trying mirror {}", candidate);
+            }
+
+            get_url_generic(handle, config, ca_bundle, candidate, None)
+        })
     }
 
     fn resolve_url(&mut self, url: &str, _status: &mut dyn StatusBackend) -> Result<String> {
@@ -76,22 +190,34 @@ impl GetUrlBackend for CurlBackend {
     }
 
     fn open_range_reader(&self, url: &str) -> Self::RangeReader {
-        CurlRangeReader::new(url)
+        CurlRangeReader::new(url, &self.config)
+    }
+
+    fn with_config(config: GetUrlConfig) -> Self {
+        CurlBackend {
+            handle: Easy::new(),
+            config,
+            ca_bundle: CaBundle::default(),
+        }
     }
 }
 
 /// Curl-based byte-range reader.
 #[derive(Debug)]
 pub struct CurlRangeReader {
-    url: String,
+    urls: Failover,
+    config: GetUrlConfig,
     handle: Easy,
+    ca_bundle: CaBundle,
 }
 
 impl CurlRangeReader {
-    fn new(url: &str) -> CurlRangeReader {
+    fn new(url: &str, config: &GetUrlConfig) -> CurlRangeReader {
         CurlRangeReader {
-            url: url.to_owned(),
+            urls: Failover::new(config, url),
+            config: config.clone(),
             handle: Easy::new(),
+            ca_bundle: CaBundle::default(),
         }
     }
 }
@@ -100,6 +226,42 @@ impl RangeReader for CurlRangeReader {
     type Response = Cursor<Vec<u8>>;
 
     fn read_range(&mut self, offset: u64, length: usize) -> Result<Self::Response> {
-        get_url_generic(&mut self.handle, &self.url, Some((offset, length)))
+        let handle = &mut self.handle;
+        let config = &self.config;
+        let ca_bundle = &mut self.ca_bundle;
+
+        self.urls
+            .attempt(|url| get_url_generic(handle, config, ca_bundle, url, Some((offset, length))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ca_bundle_adds_to_system_authorities() {
+        let mut extra = NamedTempFile::new().unwrap();
+        extra.write_all(b"extra authorities\n").unwrap();
+
+        let mut ca_bundle = CaBundle::default();
+        assert!(ca_bundle.path(&GetUrlConfig::default()).unwrap().is_none());
+
+        let config = GetUrlConfig {
+            ca_certificates: Some(extra.path().to_owned()),
+            ..GetUrlConfig::default()
+        };
+        let path = ca_bundle.path(&config).unwrap().unwrap().to_owned();
+        let combined = fs::read(&path).unwrap();
+        assert!(combined.ends_with(b"extra authorities\n"));
+
+        if let Some(system) = openssl_probe::probe().cert_file {
+            assert!(combined.starts_with(&fs::read(system).unwrap()));
+        }
+
+        // The file is only written once, and removed with the bundle.
+        assert_eq!(ca_bundle.path(&config).unwrap(), Some(path.as_path()));
+        drop(ca_bundle);
+        assert!(!path.exists());
     }
 }
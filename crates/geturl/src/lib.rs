@@ -11,10 +11,141 @@
 //! one.
 
 use cfg_if::cfg_if;
-use std::io::Read;
+use std::{io::Read, path::PathBuf, time::Duration};
 use tectonic_errors::Result;
 use tectonic_status_base::StatusBackend;
 
+/// Network settings for the URL-get backends.
+///
+/// The default settings make plain requests with the backend's own defaults
+/// for proxies, certificate authorities, and timeouts.
+#[derive(Clone, Debug, Default)]
+pub struct GetUrlConfig {
+    /// A proxy to send all requests through, such as
+    /// `http://proxy.example.com:3128`. If unset, backends use the proxy
+    /// settings given by the usual environment variables.
+    pub proxy: Option<String>,
+
+    /// A PEM file containing extra certificate authorities to trust, in
+    /// addition to the system ones.
+    pub ca_certificates: Option<PathBuf>,
+
+    /// Extra headers to send with requests.
+    pub headers: Vec<UrlHeader>,
+
+    /// Alternative locations for resources.
+    pub mirrors: Vec<Mirror>,
+
+    /// How long to wait for a connection to be established.
+    pub connect_timeout: Option<Duration>,
+
+    /// How long to wait for a whole request to complete.
+    pub timeout: Option<Duration>,
+}
+
+/// An extra HTTP header to send with requests for some URLs.
+#[derive(Clone, Debug)]
+pub struct UrlHeader {
+    /// The header is sent with requests for URLs that start with this
+    /// prefix. An empty prefix matches every URL.
+    pub url_prefix: String,
+
+    /// The name of the header, such as `Authorization`.
+    pub name: String,
+
+    /// The value of the header.
+    pub value: String,
+}
+
+/// Alternative locations for the URLs that start with a prefix.
+///
+/// If a request for such a URL fails, it is retried with the prefix
+/// replaced by each of the mirror prefixes in turn.
+#[derive(Clone, Debug)]
+pub struct Mirror {
+    /// The prefix of the URLs that are mirrored.
+    pub url_prefix: String,
+
+    /// The prefixes to substitute for `url_prefix`, in order of preference.
+    pub mirror_prefixes: Vec<String>,
+}
+
+impl GetUrlConfig {
+    /// Get the locations from which a URL can be fetched, in the order in
+    /// which they should be tried: the URL itself followed by its mirrors.
+    pub fn candidate_urls(&self, url: &str) -> Vec<String> {
+        let mut urls = vec![url.to_owned()];
+
+        for mirror in &self.mirrors {
+            if let Some(rest) = url.strip_prefix(mirror.url_prefix.as_str()) {
+                for prefix in &mirror.mirror_prefixes {
+                    let candidate = format!("{}{}", prefix, rest);
+
+                    if !urls.contains(&candidate) {
+                        urls.push(candidate);
+                    }
+                }
+            }
+        }
+
+        urls
+    }
+
+    /// Get the extra headers to send with a request for a URL, as pairs of
+    /// names and values.
+    pub fn headers_for<'a>(&'a self, url: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.headers
+            .iter()
+            .filter(move |h| url.starts_with(h.url_prefix.as_str()))
+            .map(|h| (h.name.as_str(), h.value.as_str()))
+    }
+}
+
+/// The locations from which a resource can be fetched, remembering which one
+/// last worked.
+#[derive(Debug)]
+#[cfg_attr(not(any(feature = "curl", feature = "reqwest")), allow(dead_code))]
+struct Failover {
+    urls: Vec<String>,
+    current: usize,
+}
+
+#[cfg_attr(not(any(feature = "curl", feature = "reqwest")), allow(dead_code))]
+impl Failover {
+    fn new(config: &GetUrlConfig, url: &str) -> Failover {
+        Failover {
+            urls: config.candidate_urls(url),
+            current: 0,
+        }
+    }
+
+    /// Attempt an operation on each location in turn, starting with the one
+    /// that last worked, until it succeeds. If every attempt fails, the error
+    /// from the first one is returned.
+    fn attempt<T, F>(&mut self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&str) -> Result<T>,
+    {
+        let mut first_error = None;
+
+        for i in 0..self.urls.len() {
+            let index = (self.current + i) % self.urls.len();
+
+            match f(&self.urls[index]) {
+                Ok(result) => {
+                    self.current = index;
+                    return Ok(result);
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        Err(first_error.unwrap())
+    }
+}
+
 /// A trait for reading byte ranges from an HTTP resource.
 pub trait RangeReader {
     /// The readable type returned by the range request.
@@ -43,6 +174,10 @@ pub trait GetUrlBackend: Default {
 
     /// Open a range reader that can perform byte-range reads on the specified URL.
     fn open_range_reader(&self, url: &str) -> Self::RangeReader;
+
+    /// Create a backend that uses the specified network settings. The
+    /// `Default` implementation uses the default settings.
+    fn with_config(config: GetUrlConfig) -> Self;
 }
 
 pub mod null;
@@ -53,6 +188,9 @@ pub mod curl;
 #[cfg(feature = "reqwest")]
 pub mod reqwest;
 
+#[cfg(any(test, feature = "test-server"))]
+pub mod test_server;
+
 cfg_if! {
     if #[cfg(feature = "reqwest")] {
         pub use crate::reqwest::ReqwestBackend as DefaultBackend;
@@ -65,3 +203,234 @@ cfg_if! {
 
 /// The range-reader type exposed by the default URL-get backend (for convenience).
 pub type DefaultRangeReader = <DefaultBackend as GetUrlBackend>::RangeReader;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tectonic_errors::anyhow::bail;
+
+    fn config() -> GetUrlConfig {
+        GetUrlConfig {
+            headers: vec![UrlHeader {
+                url_prefix: "https://b.example.com/".to_owned(),
+                name: "Authorization".to_owned(),
+                value: "Bearer xyz".to_owned(),
+            }],
+            mirrors: vec![Mirror {
+                url_prefix: "https://a.example.com/bundles/".to_owned(),
+                mirror_prefixes: vec![
+                    "https://b.example.com/".to_owned(),
+                    "https://c.example.com/x/".to_owned(),
+                ],
+            }],
+            ..GetUrlConfig::default()
+        }
+    }
+
+    #[test]
+    fn candidates_and_headers() {
+        let config = config();
+
+        assert_eq!(
+            config.candidate_urls("https://a.example.com/bundles/b.tar"),
+            &[
+                "https://a.example.com/bundles/b.tar",
+                "https://b.example.com/b.tar",
+                "https://c.example.com/x/b.tar",
+            ]
+        );
+        assert_eq!(
+            config.candidate_urls("https://a.example.com/other.tar"),
+            &["https://a.example.com/other.tar"]
+        );
+
+        assert_eq!(
+            config
+                .headers_for("https://b.example.com/b.tar")
+                .collect::<Vec<_>>(),
+            &[("Authorization", "Bearer xyz")]
+        );
+        assert_eq!(config.headers_for("https://a.example.com/").count(), 0);
+    }
+
+    #[test]
+    fn failover() {
+        let mut urls = Failover::new(&config(), "https://a.example.com/bundles/b.tar");
+        let mut tried = Vec::new();
+
+        let result = urls.attempt(|url| {
+            tried.push(url.to_owned());
+
+            if url.starts_with("https://c.") {
+                Ok(url.to_owned())
+            } else {
+                bail!("no good")
+            }
+        });
+
+        assert_eq!(result.unwrap(), "https://c.example.com/x/b.tar");
+        assert_eq!(tried.len(), 3);
+
+        // The working mirror is tried first next time.
+        tried.clear();
+        urls.attempt(|url| -> Result<()> {
+            tried.push(url.to_owned());
+            bail!("all down")
+        })
+        .unwrap_err();
+        assert_eq!(tried[0], "https://c.example.com/x/b.tar");
+        assert_eq!(tried.len(), 3);
+    }
+
+    /// Tests of the HTTP backends, run against a local test server.
+    #[cfg(any(feature = "reqwest", feature = "curl"))]
+    mod backends {
+        use super::super::*;
+        use crate::test_server::{Fault, TestServer};
+        use tectonic_status_base::NoopStatusBackend;
+
+        fn get<B: GetUrlBackend>(backend: &mut B, url: &str) -> Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            backend
+                .get_url(url, &mut NoopStatusBackend::default())?
+                .read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+
+        fn read_range<B: GetUrlBackend>(
+            backend: &B,
+            url: &str,
+            offset: u64,
+            length: usize,
+        ) -> Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            backend
+                .open_range_reader(url)
+                .read_range(offset, length)?
+                .read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+
+        fn basics<B: GetUrlBackend>() {
+            let server = TestServer::start();
+            server.add_file("/a.txt", b"hello world".to_vec());
+            let url = server.url("/a.txt");
+            let mut backend = B::default();
+
+            assert_eq!(get(&mut backend, &url).unwrap(), b"hello world");
+            assert_eq!(read_range(&backend, &url, 6, 5).unwrap(), b"world");
+            assert_eq!(server.requests()[1].range, Some((6, 10)));
+            assert!(get(&mut backend, &server.url("/missing.txt")).is_err());
+        }
+
+        fn faults<B: GetUrlBackend>() {
+            let server = TestServer::start();
+            server.add_file("/a.txt", b"hello world".to_vec());
+            let url = server.url("/a.txt");
+            let mut backend = B::default();
+
+            for fault in &[Fault::DropConnection, Fault::ServerError] {
+                server.add_faults("/a.txt", &[*fault]);
+                assert!(get(&mut backend, &url).is_err(), "{:?}", fault);
+                assert!(get(&mut backend, &url).is_ok(), "{:?}", fault);
+            }
+
+            server.add_faults("/a.txt", &[Fault::TruncateBody]);
+            assert!(read_range(&backend, &url, 0, 11).is_err());
+
+            server.add_faults("/a.txt", &[Fault::IgnoreRange]);
+            assert!(read_range(&backend, &url, 6, 5).is_err());
+            assert_eq!(read_range(&backend, &url, 6, 5).unwrap(), b"world");
+        }
+
+        fn headers_and_mirrors<B: GetUrlBackend>() {
+            let server = TestServer::start();
+            server.add_file("/mirror/a.txt", b"mirrored".to_vec());
+            server.add_faults("/mirror/a.txt", &[Fault::ServerError]);
+
+            let mut backend = B::with_config(GetUrlConfig {
+                headers: vec![UrlHeader {
+                    url_prefix: server.url("/mirror/"),
+                    name: "Authorization".to_owned(),
+                    value: "Bearer xyz".to_owned(),
+                }],
+                mirrors: vec![Mirror {
+                    url_prefix: server.url("/"),
+                    mirror_prefixes: vec![server.url("/mirror/"), server.url("/other/")],
+                }],
+                ..GetUrlConfig::default()
+            });
+
+            // The first request to the mirror fails, so the other one is
+            // tried before wrapping around to the mirror again.
+            let url = server.url("/a.txt");
+            assert!(get(&mut backend, &url).is_err());
+            assert_eq!(get(&mut backend, &url).unwrap(), b"mirrored");
+            assert_eq!(read_range(&backend, &url, 0, 6).unwrap(), b"mirror");
+
+            for req in server.requests() {
+                let expected = if req.path.starts_with("/mirror/") {
+                    Some("Bearer xyz")
+                } else {
+                    None
+                };
+                assert_eq!(req.header("authorization"), expected, "{}", req.path);
+            }
+        }
+
+        #[cfg(feature = "reqwest")]
+        mod reqwest {
+            use super::*;
+            use crate::reqwest::ReqwestBackend;
+
+            #[test]
+            fn basics() {
+                super::basics::<ReqwestBackend>();
+            }
+
+            #[test]
+            fn faults() {
+                super::faults::<ReqwestBackend>();
+            }
+
+            #[test]
+            fn headers_and_mirrors() {
+                super::headers_and_mirrors::<ReqwestBackend>();
+            }
+
+            #[test]
+            fn resolve_stops_at_cdn() {
+                let server = TestServer::start();
+                server.add_redirect("/default", "/bundles/b.tar");
+                server.add_redirect("/bundles/b.tar", "/cdn/0123abcd");
+                server.add_file("/cdn/0123abcd", b"data".to_vec());
+
+                let resolved = ReqwestBackend::default()
+                    .resolve_url(&server.url("/default"), &mut NoopStatusBackend::default())
+                    .unwrap();
+                assert_eq!(resolved, server.url("/bundles/b.tar"));
+                assert_eq!(server.count_requests("HEAD", "/cdn/0123abcd"), 0);
+            }
+        }
+
+        #[cfg(feature = "curl")]
+        mod curl {
+            use crate::curl::CurlBackend;
+
+            #[test]
+            fn basics() {
+                super::basics::<CurlBackend>();
+            }
+
+            #[test]
+            fn faults() {
+                super::faults::<CurlBackend>();
+            }
+
+            #[test]
+            fn headers_and_mirrors() {
+                super::headers_and_mirrors::<CurlBackend>();
+            }
+        }
+    }
+}
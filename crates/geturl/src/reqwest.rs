@@ -4,36 +4,120 @@
 //! A URL-get backend based on the `reqwest` crate.
 
 use reqwest::{
-    blocking::{Client, Response},
-    header::{HeaderMap, RANGE},
+    blocking::{Client, ClientBuilder, Response},
+    header::{HeaderMap, HeaderName, HeaderValue, RANGE},
     redirect::Policy,
-    StatusCode,
+    Certificate, Proxy, StatusCode,
 };
-use tectonic_errors::{anyhow::bail, Result};
-use tectonic_status_base::{tt_note, StatusBackend};
+use std::{fs, path::Path};
+use tectonic_errors::{anyhow::bail, atry, Result};
+use tectonic_status_base::{tt_note, tt_warning, StatusBackend};
 
-use crate::{GetUrlBackend, RangeReader};
+use crate::{Failover, GetUrlBackend, GetUrlConfig, RangeReader};
 
 const MAX_HTTP_REDIRECTS_ALLOWED: usize = 10;
 
+/// Start building an HTTP client that follows the network settings.
+fn client_builder(config: &GetUrlConfig) -> Result<ClientBuilder> {
+    let mut builder = Client::builder();
+
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(atry!(Proxy::all(proxy); ["invalid proxy URL `{}`", proxy]));
+    }
+
+    if let Some(path) = &config.ca_certificates {
+        for cert in load_certificates(path)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if let Some(t) = config.connect_timeout {
+        builder = builder.connect_timeout(t);
+    }
+
+    if let Some(t) = config.timeout {
+        builder = builder.timeout(t);
+    }
+
+    Ok(builder)
+}
+
+/// Load all of the certificates in a PEM file. `Certificate::from_pem` only
+/// reads the first one, so we split them up ourselves.
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    const END_MARKER: &str = "-----END CERTIFICATE-----";
+
+    let text = atry!(
+        fs::read_to_string(path);
+        ["failed to read certificate file `{}`", path.display()]
+    );
+
+    let mut certs = Vec::new();
+    let mut rest = text.as_str();
+
+    while let Some(i) = rest.find(END_MARKER) {
+        let end = i + END_MARKER.len();
+        certs.push(atry!(
+            Certificate::from_pem(&rest.as_bytes()[..end]);
+            ["invalid certificate in `{}`", path.display()]
+        ));
+        rest = &rest[end..];
+    }
+
+    if certs.is_empty() {
+        bail!("no certificates found in `{}`", path.display());
+    }
+
+    Ok(certs)
+}
+
+/// Get the extra headers to send with a request for a URL.
+fn headers_for(config: &GetUrlConfig, url: &str) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in config.headers_for(url) {
+        headers.insert(
+            atry!(HeaderName::from_bytes(name.as_bytes()); ["invalid HTTP header name `{}`", name]),
+            atry!(HeaderValue::from_str(value); ["invalid value for HTTP header `{}`", name]),
+        );
+    }
+
+    Ok(headers)
+}
+
 /// URL-get backend implemented using the `reqwest` crate.
 #[derive(Debug, Default)]
-pub struct ReqwestBackend {}
+pub struct ReqwestBackend {
+    config: GetUrlConfig,
+}
 
 impl GetUrlBackend for ReqwestBackend {
     type Response = Response;
     type RangeReader = ReqwestRangeReader;
 
-    fn get_url(&mut self, url: &str, _status: &mut dyn StatusBackend) -> Result<Response> {
-        let res = Client::new().get(url).send()?;
-        if !res.status().is_success() {
-            bail!(
-                "unexpected HTTP response code {} for URL {}",
-                res.status(),
-                url
-            );
-        }
-        Ok(res)
+    fn get_url(&mut self, url: &str, status: &mut dyn StatusBackend) -> Result<Response> {
+        let client = client_builder(&self.config)?.build()?;
+
+        Failover::new(&self.config, url).attempt(|candidate| {
+            if candidate != url {
+                tt_warning!(status, "trying mirror {}", candidate);
+            }
+
+            let res = client
+                .get(candidate)
+                .headers(headers_for(&self.config, candidate)?)
+                .send()?;
+
+            if !res.status().is_success() {
+                bail!(
+                    "unexpected HTTP response code {} for URL {}",
+                    res.status(),
+                    candidate
+                );
+            }
+
+            Ok(res)
+        })
     }
 
     fn resolve_url(&mut self, url: &str, status: &mut dyn StatusBackend) -> Result<String> {
@@ -66,21 +150,39 @@ impl GetUrlBackend for ReqwestBackend {
             }
         });
 
-        let res = Client::builder()
+        let client = client_builder(&self.config)?
             .redirect(redirect_policy)
-            .build()?
-            .head(url)
-            .send()?;
-
-        if !(res.status().is_success() || res.status() == StatusCode::FOUND) {
-            bail!(
-                "unexpected HTTP response code {} for URL {}",
-                res.status(),
-                url
-            );
-        }
+            .build()?;
+
+        let final_url = Failover::new(&self.config, url).attempt(|candidate| {
+            if candidate != url {
+                tt_warning!(status, "trying mirror {}", candidate);
+            }
+
+            let res = client
+                .head(candidate)
+                .headers(headers_for(&self.config, candidate)?)
+                .send()?;
+
+            if !(res.status().is_success() || res.status() == StatusCode::FOUND) {
+                bail!(
+                    "unexpected HTTP response code {} for URL {}",
+                    res.status(),
+                    candidate
+                );
+            }
+
+            // If a mirror answered without redirecting, stick with the
+            // original URL, so that later requests can fail over too.
+            let final_url = res.url().clone().into_string();
+
+            if final_url == candidate {
+                Ok(url.to_owned())
+            } else {
+                Ok(final_url)
+            }
+        })?;
 
-        let final_url = res.url().clone().into_string();
         if final_url != url {
             tt_note!(status, "resolved to {}", final_url);
         }
@@ -89,7 +191,11 @@ impl GetUrlBackend for ReqwestBackend {
     }
 
     fn open_range_reader(&self, url: &str) -> Self::RangeReader {
-        ReqwestRangeReader::new(url)
+        ReqwestRangeReader::new(url, &self.config)
+    }
+
+    fn with_config(config: GetUrlConfig) -> Self {
+        ReqwestBackend { config }
     }
 }
 
@@ -98,15 +204,17 @@ impl GetUrlBackend for ReqwestBackend {
 /// needed.
 #[derive(Debug)]
 pub struct ReqwestRangeReader {
-    url: String,
-    client: Client,
+    urls: Failover,
+    config: GetUrlConfig,
+    client: Option<Client>,
 }
 
 impl ReqwestRangeReader {
-    fn new(url: &str) -> ReqwestRangeReader {
+    fn new(url: &str, config: &GetUrlConfig) -> ReqwestRangeReader {
         ReqwestRangeReader {
-            url: url.to_owned(),
-            client: Client::new(),
+            urls: Failover::new(config, url),
+            config: config.clone(),
+            client: None,
         }
     }
 }
@@ -116,21 +224,32 @@ impl RangeReader for ReqwestRangeReader {
 
     fn read_range(&mut self, offset: u64, length: usize) -> Result<Response> {
         let end_inclusive = offset + length as u64 - 1;
-        let header_val = format!("bytes={}-{}", offset, end_inclusive).parse()?;
+        let range_val: HeaderValue = format!("bytes={}-{}", offset, end_inclusive).parse()?;
 
-        let mut headers = HeaderMap::new();
-        headers.insert(RANGE, header_val);
+        // The client is created lazily, since `open_range_reader` can't
+        // report errors in the network settings.
+        if self.client.is_none() {
+            self.client = Some(client_builder(&self.config)?.build()?);
+        }
 
-        let res = self.client.get(&self.url).headers(headers).send()?;
+        let client = self.client.as_ref().unwrap();
+        let config = &self.config;
 
-        if res.status() != StatusCode::PARTIAL_CONTENT {
-            bail!(
-                "unexpected HTTP response code {} for URL {}",
-                res.status(),
-                self.url
-            );
-        }
+        self.urls.attempt(|url| {
+            let mut headers = headers_for(config, url)?;
+            headers.insert(RANGE, range_val.clone());
+
+            let res = client.get(url).headers(headers).send()?;
+
+            if res.status() != StatusCode::PARTIAL_CONTENT {
+                bail!(
+                    "unexpected HTTP response code {} for URL {}",
+                    res.status(),
+                    url
+                );
+            }
 
-        Ok(res)
+            Ok(res)
+        })
     }
 }
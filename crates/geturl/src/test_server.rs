@@ -0,0 +1,331 @@
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! A local HTTP server for testing code that fetches URLs.
+//!
+//! [`TestServer`] serves files from memory on a local port, honoring byte
+//! range requests and following a scripted set of redirects. Failures such
+//! as server errors and dropped connections can be injected with
+//! [`TestServer::add_faults`], so that error handling can be tested without
+//! a real network. Every request is recorded and can be inspected afterwards.
+//!
+//! The server speaks just enough HTTP/1.1 to satisfy the backends in this
+//! crate. It closes each connection after responding, and it is not meant
+//! for anything but tests.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// A failure to inject into the response to a request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// Close the connection without sending a response.
+    DropConnection,
+
+    /// Send the response headers and half of the body, then close the
+    /// connection.
+    TruncateBody,
+
+    /// Respond with a 500 Internal Server Error.
+    ServerError,
+
+    /// Ignore any `Range` header and send the whole file with a 200 OK
+    /// status, as some servers do.
+    IgnoreRange,
+}
+
+/// A request received by a [`TestServer`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RequestInfo {
+    /// The HTTP method, such as `GET` or `HEAD`.
+    pub method: String,
+
+    /// The path of the request.
+    pub path: String,
+
+    /// The requested byte range, if any, as a start offset and an inclusive
+    /// end offset.
+    pub range: Option<(u64, u64)>,
+
+    /// The request headers, with their names in lower case.
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestInfo {
+    /// Get the value of a request header, given its name in lower case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    files: HashMap<String, Arc<Vec<u8>>>,
+    redirects: HashMap<String, String>,
+    faults: HashMap<String, VecDeque<Fault>>,
+    requests: Vec<RequestInfo>,
+}
+
+/// An HTTP server for tests, running on a local port until it is dropped.
+#[derive(Debug)]
+pub struct TestServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Start a server with no files on a free local port.
+    pub fn start() -> TestServer {
+        let listener =
+            TcpListener::bind(("127.0.0.1", 0)).expect("failed to bind test server socket");
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let state = state.clone();
+            let shutdown = shutdown.clone();
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    if let Ok(stream) = stream {
+                        let state = state.clone();
+                        thread::spawn(move || handle_connection(stream, &state));
+                    }
+                }
+            })
+        };
+
+        TestServer {
+            addr,
+            state,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    /// Get the full URL of a path on this server. The path should start
+    /// with a slash.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Serve a file at a path, replacing any file that was there before.
+    pub fn add_file(&self, path: &str, data: Vec<u8>) {
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .insert(path.to_owned(), Arc::new(data));
+    }
+
+    /// Redirect requests for a path with a 302 Found response. The location
+    /// may be a full URL or a path on this server.
+    pub fn add_redirect(&self, path: &str, location: &str) {
+        let location = if location.starts_with('/') {
+            self.url(location)
+        } else {
+            location.to_owned()
+        };
+
+        self.state
+            .lock()
+            .unwrap()
+            .redirects
+            .insert(path.to_owned(), location);
+    }
+
+    /// Inject failures into the responses to the next `GET` requests for a
+    /// path, one per request, in order. Once they have been used up,
+    /// requests succeed again. `HEAD` requests are never affected.
+    pub fn add_faults(&self, path: &str, faults: &[Fault]) {
+        self.state
+            .lock()
+            .unwrap()
+            .faults
+            .entry(path.to_owned())
+            .or_default()
+            .extend(faults);
+    }
+
+    /// Get all of the requests received so far, in order.
+    pub fn requests(&self) -> Vec<RequestInfo> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Count the requests received so far with a method and path.
+    pub fn count_requests(&self, method: &str, path: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|r| r.method == method && r.path == path)
+            .count()
+    }
+
+    /// Forget the requests received so far.
+    pub fn clear_requests(&self) {
+        self.state.lock().unwrap().requests.clear();
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+
+        // Wake up the listening thread so that it notices.
+        let _ = TcpStream::connect(self.addr);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Read an HTTP request, returning `None` if it is malformed.
+fn read_request(stream: &TcpStream) -> Option<RequestInfo> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+
+    let mut bits = line.split_whitespace();
+    let method = bits.next()?.to_owned();
+    let path = bits.next()?.to_owned();
+    let mut headers = Vec::new();
+
+    loop {
+        line.clear();
+
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+
+        let line = line.trim_end();
+
+        if line.is_empty() {
+            break;
+        }
+
+        let (name, value) = line.split_at(line.find(':')?);
+        headers.push((name.to_lowercase(), value[1..].trim().to_owned()));
+    }
+
+    let range = headers
+        .iter()
+        .find(|(n, _)| n == "range")
+        .and_then(|(_, v)| {
+            let spec = v.strip_prefix("bytes=")?;
+            let dash = spec.find('-')?;
+            Some((spec[..dash].parse().ok()?, spec[dash + 1..].parse().ok()?))
+        });
+
+    Some(RequestInfo {
+        method,
+        path,
+        range,
+        headers,
+    })
+}
+
+fn handle_connection(mut stream: TcpStream, state: &Mutex<State>) {
+    let request = match read_request(&stream) {
+        Some(r) => r,
+        None => return,
+    };
+
+    let (file, redirect, fault) = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(request.clone());
+
+        let fault = if request.method == "GET" {
+            state
+                .faults
+                .get_mut(&request.path)
+                .and_then(|f| f.pop_front())
+        } else {
+            None
+        };
+
+        (
+            state.files.get(&request.path).cloned(),
+            state.redirects.get(&request.path).cloned(),
+            fault,
+        )
+    };
+
+    let mut headers = vec!["Connection: close".to_owned()];
+    let mut body: &[u8] = &[];
+
+    let status = if fault == Some(Fault::DropConnection) {
+        return;
+    } else if fault == Some(Fault::ServerError) {
+        "500 Internal Server Error"
+    } else if let Some(location) = redirect {
+        headers.push(format!("Location: {}", location));
+        "302 Found"
+    } else if let Some(file) = file.as_ref() {
+        match request.range {
+            Some((start, end)) if fault != Some(Fault::IgnoreRange) => {
+                if start > end || end >= file.len() as u64 {
+                    headers.push(format!("Content-Range: bytes */{}", file.len()));
+                    "416 Range Not Satisfiable"
+                } else {
+                    headers.push(format!(
+                        "Content-Range: bytes {}-{}/{}",
+                        start,
+                        end,
+                        file.len()
+                    ));
+                    body = &file[start as usize..=end as usize];
+                    "206 Partial Content"
+                }
+            }
+            _ => {
+                body = &file[..];
+                "200 OK"
+            }
+        }
+    } else {
+        "404 Not Found"
+    };
+
+    headers.push(format!("Content-Length: {}", body.len()));
+
+    if fault == Some(Fault::TruncateBody) {
+        body = &body[..body.len() / 2];
+    }
+
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+
+    for h in &headers {
+        response.push_str(h);
+        response.push_str("\r\n");
+    }
+
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+
+    if request.method != "HEAD" {
+        response.extend_from_slice(body);
+    }
+
+    let _ = stream.write_all(&response);
+}
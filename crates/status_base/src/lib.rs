@@ -9,7 +9,12 @@
 //! frameworks, but we do have some extra hooks to help support a nice user
 //! experience for the Tectonic CLI tool.
 
-use std::{cmp::Ordering, fmt::Arguments, result::Result as StdResult, str::FromStr};
+use std::{
+    cmp::Ordering,
+    fmt::{self, Arguments, Display},
+    result::Result as StdResult,
+    str::FromStr,
+};
 use tectonic_errors::Error;
 
 pub mod plain;
@@ -29,6 +34,35 @@ pub enum MessageKind {
     Error,
 }
 
+/// A problem with one of the files being processed, such as an error
+/// reported by the TeX engine, located as precisely as possible.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// How serious the problem is.
+    pub kind: MessageKind,
+
+    /// The file where the problem is, if known.
+    pub file: Option<String>,
+
+    /// The line of the file where the problem is, if known. Lines are
+    /// numbered from 1.
+    pub line: Option<u32>,
+
+    /// A description of the problem.
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: {}", file, line, self.message),
+            (Some(file), None) => write!(f, "{}: {}", file, self.message),
+            (None, Some(line)) => write!(f, "line {}: {}", line, self.message),
+            (None, None) => write!(f, "{}", self.message),
+        }
+    }
+}
+
 /// A setting regarding which messages to display.
 #[repr(usize)]
 #[non_exhaustive]
@@ -130,6 +164,14 @@ pub trait StatusBackend {
     /// should print the provided output, which may span many lines, with some
     /// clear delineation.
     fn dump_error_logs(&mut self, output: &[u8]);
+
+    /// Report a problem with one of the files being processed.
+    ///
+    /// The default implementation delegates to `report()`, prefixing the
+    /// message with the location of the problem.
+    fn report_diagnostic(&mut self, diag: &Diagnostic) {
+        self.report(diag.kind, format_args!("{}", diag), None)
+    }
 }
 
 /// Report a formatted informational message to the user.
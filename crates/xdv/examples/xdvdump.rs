@@ -9,7 +9,7 @@ use std::fs::File;
 use std::io;
 use std::process;
 use std::str;
-use tectonic_xdv::{FileType, XdvError};
+use tectonic_xdv::{FileType, FontDefinition, GlyphRun, NativeFontDefinition, Position, XdvError};
 
 /// We'd like to use String as our error type, but we also would like to
 /// use the `XdvParser::process()` function, which when imposes the requirement
@@ -40,6 +40,13 @@ impl From<XdvError> for Error {
     }
 }
 
+/// Format a position for printing. Inexact horizontal positions are marked
+/// with a tilde.
+fn describe(pos: Position) -> String {
+    let approx = if pos.h_exact { "" } else { "~" };
+    format!("({}{}, {})", approx, pos.h, pos.v)
+}
+
 struct Stats {}
 
 impl Stats {
@@ -88,10 +95,28 @@ impl tectonic_xdv::XdvEvents for Stats {
         Ok(())
     }
 
-    fn handle_special(&mut self, contents: &[u8]) -> Result<(), Self::Error> {
+    fn handle_end_page(&mut self) -> Result<(), Self::Error> {
+        println!("end of page");
+        Ok(())
+    }
+
+    fn handle_postamble(
+        &mut self,
+        max_height: i32,
+        max_width: i32,
+        n_pages: u16,
+    ) -> Result<(), Self::Error> {
+        println!(
+            "postamble: {} pages, max height {}, max width {}",
+            n_pages, max_height, max_width
+        );
+        Ok(())
+    }
+
+    fn handle_special(&mut self, pos: Position, contents: &[u8]) -> Result<(), Self::Error> {
         match str::from_utf8(contents) {
             Ok(s) => {
-                println!("special: {}", s);
+                println!("special at {}: {}", describe(pos), s);
             }
             Err(e) => {
                 println!("cannot UTF8-parse special: {}", e);
@@ -101,11 +126,70 @@ impl tectonic_xdv::XdvEvents for Stats {
         Ok(())
     }
 
-    fn handle_char_run(&mut self, chars: &[i32]) -> Result<(), Self::Error> {
+    fn handle_select_font(&mut self, font_num: i32) -> Result<(), Self::Error> {
+        println!("select font {}", font_num);
+        Ok(())
+    }
+
+    fn handle_char_run(&mut self, pos: Position, chars: &[i32]) -> Result<(), Self::Error> {
         let all_ascii_printable = chars.iter().all(|c| *c > 0x20 && *c < 0x7F);
         println!(
-            "chars: {:?} all_ascii_printable={:?}",
-            chars, all_ascii_printable
+            "chars at {}: {:?} all_ascii_printable={:?}",
+            describe(pos),
+            chars,
+            all_ascii_printable
+        );
+        Ok(())
+    }
+
+    fn handle_rule(&mut self, pos: Position, height: i32, width: i32) -> Result<(), Self::Error> {
+        println!("rule at {}: {}x{}", describe(pos), width, height);
+        Ok(())
+    }
+
+    fn handle_define_font(&mut self, font: &FontDefinition) -> Result<(), Self::Error> {
+        println!(
+            "font {}: {}{} at {}",
+            font.font_num, font.area, font.name, font.scale_factor
+        );
+        Ok(())
+    }
+
+    fn handle_define_native_font(
+        &mut self,
+        font: &NativeFontDefinition,
+    ) -> Result<(), Self::Error> {
+        println!(
+            "native font {}: {} (face {}) at {}",
+            font.font_num, font.name, font.face_index, font.size
+        );
+        Ok(())
+    }
+
+    fn handle_glyph_run(&mut self, pos: Position, run: &GlyphRun) -> Result<(), Self::Error> {
+        println!(
+            "glyphs at {}: font={} width={} ids={:?}",
+            describe(pos),
+            run.font_num,
+            run.width,
+            run.glyphs.iter().map(|g| g.id).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    fn handle_text_and_glyphs(
+        &mut self,
+        pos: Position,
+        text: &str,
+        run: &GlyphRun,
+    ) -> Result<(), Self::Error> {
+        println!(
+            "text and glyphs at {}: font={} width={} text={:?} n_glyphs={}",
+            describe(pos),
+            run.font_num,
+            run.width,
+            text,
+            run.glyphs.len()
         );
         Ok(())
     }
@@ -3,7 +3,7 @@
 
 #![deny(missing_docs)]
 
-//! A decoder for the XDV and SPX file formats used by Tectonic and XeTeX.
+//! A decoder and encoder for the XDV and SPX file formats used by Tectonic and XeTeX.
 //!
 //! Both of these file formats are derived from the venerable “device
 //! independent” (DVI) format used by TeX. The XDV format (name presumably
@@ -16,10 +16,14 @@
 use byteorder::{BigEndian, ByteOrder};
 use std::error;
 use std::fmt::{Debug, Display, Error as FmtError, Formatter};
-use std::io::{Error as IoError, Read};
+use std::io::{Error as IoError, ErrorKind, Read};
 use std::marker::PhantomData;
 use std::mem;
 
+mod writer;
+
+pub use writer::XdvWriter;
+
 /// Errors that can occur when parsing XDV/SPX files.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum XdvError {
@@ -70,6 +74,14 @@ impl From<XdvError> for String {
     }
 }
 
+/// This lets `io::Error` serve as an event error type, which is what
+/// `XdvWriter` uses.
+impl From<XdvError> for IoError {
+    fn from(e: XdvError) -> Self {
+        IoError::new(ErrorKind::InvalidData, e)
+    }
+}
+
 impl XdvError {
     /// We can't implement this as a From trait on InternalError because it
     /// conflicts with the generic From: XdvError satisfies `Debug +
@@ -120,17 +132,232 @@ pub trait XdvEvents {
         Ok(())
     }
 
+    /// End the current page.
+    #[allow(unused)]
+    fn handle_end_page(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle the postamble, which comes after the last page.
+    ///
+    /// The arguments give the largest height-plus-depth and the largest width
+    /// of any page in the file, in DVI units, and the number of pages. Note
+    /// that TeX computes the maximum dimensions, and nothing checks them,
+    /// so they aren't necessarily accurate.
+    #[allow(unused)]
+    fn handle_postamble(
+        &mut self,
+        max_height: i32,
+        max_width: i32,
+        n_pages: u16,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Handle a `\special`.
+    ///
+    /// The argument *pos* gives the current position on the page.
+    #[allow(unused)]
+    fn handle_special(&mut self, pos: Position, contents: &[u8]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Select the font used for subsequent characters and glyphs.
     #[allow(unused)]
-    fn handle_special(&mut self, contents: &[u8]) -> Result<(), Self::Error> {
+    fn handle_select_font(&mut self, font_num: i32) -> Result<(), Self::Error> {
         Ok(())
     }
 
     /// Handle a sequence of characters without intervening commands
+    ///
+    /// The argument *pos* gives the position of the first character on the
+    /// page.
+    #[allow(unused)]
+    fn handle_char_run(&mut self, pos: Position, chars: &[i32]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a rule (a filled rectangle).
+    ///
+    /// The argument *pos* gives the position of the bottom left corner of the
+    /// rule, while *height* and *width* give its size in DVI units. Following
+    /// the DVI specification, rules whose height or width is not positive are
+    /// invisible, but they are reported nonetheless.
+    #[allow(unused)]
+    fn handle_rule(&mut self, pos: Position, height: i32, width: i32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Get the horizontal advance of a character set in a traditional TeX
+    /// font, in DVI units.
+    ///
+    /// The parser does not have access to TFM font metrics, so it relies on
+    /// this method to keep track of the horizontal position as characters are
+    /// set. If the advance is not known, return `None`, which is what the
+    /// default implementation does. The parser will then mark subsequent
+    /// positions as inexact; see [`Position::h_exact`]. Native fonts do not
+    /// have this problem, since XDV records the widths of their glyph runs
+    /// explicitly.
+    #[allow(unused)]
+    fn char_advance(&mut self, font_num: i32, c: i32) -> Option<i32> {
+        None
+    }
+
+    /// Handle the definition of a traditional TeX font.
+    ///
+    /// Font definitions appear both before their first use inside the pages
+    /// and in the postamble, so this may be called more than once for the
+    /// same font number.
+    #[allow(unused)]
+    fn handle_define_font(&mut self, font: &FontDefinition) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle the definition of a XeTeX “native” (OpenType, TrueType, etc.)
+    /// font.
+    ///
+    /// As with `handle_define_font`, this may be called more than once for
+    /// the same font number.
     #[allow(unused)]
-    fn handle_char_run(&mut self, chars: &[i32]) -> Result<(), Self::Error> {
+    fn handle_define_native_font(
+        &mut self,
+        font: &NativeFontDefinition,
+    ) -> Result<(), Self::Error> {
         Ok(())
     }
+
+    /// Handle a run of glyphs from a native font.
+    ///
+    /// The argument *pos* gives the position of the start of the run on the
+    /// page. The glyph positions in *run* are relative to this point.
+    #[allow(unused)]
+    fn handle_glyph_run(&mut self, pos: Position, run: &GlyphRun) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a run of glyphs from a native font that comes along with the
+    /// text that it represents.
+    ///
+    /// The argument *pos* is as in `handle_glyph_run`.
+    #[allow(unused)]
+    fn handle_text_and_glyphs(
+        &mut self,
+        pos: Position,
+        text: &str,
+        run: &GlyphRun,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// A position on the page, in DVI units, as reported to `XdvEvents`.
+///
+/// The origin is one inch from the top and left edges of the page. The
+/// horizontal position *h* increases to the right, and the vertical position
+/// *v* increases downwards.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Position {
+    /// The horizontal position.
+    pub h: i32,
+
+    /// The vertical position.
+    pub v: i32,
+
+    /// Whether the horizontal position is known exactly.
+    ///
+    /// This is false when the parser has passed over characters from a
+    /// traditional TeX font whose widths `XdvEvents::char_advance` did not
+    /// provide. In that case *h* leaves out the widths of those characters,
+    /// so it is only meaningful relative to the positions of other events
+    /// since the last exact one. The position becomes exact again when a
+    /// `pop` command restores a position that was saved before the
+    /// characters were set, or when a new page begins.
+    pub h_exact: bool,
+}
+
+/// The definition of a traditional TeX font, as given in a `fnt_def`
+/// command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FontDefinition {
+    /// The number used to refer to the font in this file.
+    pub font_num: i32,
+
+    /// The checksum of the font's TFM file.
+    pub checksum: u32,
+
+    /// The size at which the font is used, in DVI units.
+    pub scale_factor: u32,
+
+    /// The design size of the font, in DVI units.
+    pub design_size: u32,
+
+    /// The “area” (directory) of the font. Usually empty.
+    pub area: String,
+
+    /// The name of the font.
+    pub name: String,
+}
+
+/// The definition of a XeTeX native font.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NativeFontDefinition {
+    /// The number used to refer to the font in this file.
+    pub font_num: i32,
+
+    /// The size at which the font is used, in DVI units.
+    pub size: i32,
+
+    /// Whether the font is set vertically.
+    pub vertical: bool,
+
+    /// The name of the font. This is usually a filename.
+    pub name: String,
+
+    /// The index of the face to use in a font file containing several faces.
+    pub face_index: u32,
+
+    /// The font color as RGBA, if one was specified.
+    pub color_rgba: Option<u32>,
+
+    /// The horizontal extension factor, as a 16.16 fixed-point number, if
+    /// one was specified.
+    pub extend: Option<i32>,
+
+    /// The slant factor, as a 16.16 fixed-point number, if one was
+    /// specified.
+    pub slant: Option<i32>,
+
+    /// The emboldening factor, as a 16.16 fixed-point number, if one was
+    /// specified.
+    pub embolden: Option<i32>,
+}
+
+/// A single glyph in a `GlyphRun`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Glyph {
+    /// The glyph ID within its font.
+    pub id: u16,
+
+    /// The horizontal offset of the glyph from the start of the run, in DVI
+    /// units.
+    pub x: i32,
+
+    /// The vertical offset of the glyph from the start of the run, in DVI
+    /// units.
+    pub y: i32,
+}
+
+/// A run of glyphs from a XeTeX native font.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GlyphRun {
+    /// The number of the font in which the glyphs are set.
+    pub font_num: i32,
+
+    /// The total width of the run, in DVI units.
+    pub width: i32,
+
+    /// The glyphs in the run.
+    pub glyphs: Vec<Glyph>,
 }
 
 /// State for parsing an XDV file.
@@ -143,6 +370,7 @@ pub struct XdvParser<T: XdvEvents> {
     cur_font_num: Option<i32>,
     offset: u64,
     cur_char_run: Vec<i32>,
+    cur_char_run_pos: Position,
 }
 
 /// Which type of file is being parsed.
@@ -201,6 +429,7 @@ impl<T: XdvEvents> XdvParser<T> {
             cur_font_num: None,
             offset: 0,
             cur_char_run: Vec::new(),
+            cur_char_run_pos: State::new().position(),
         }
     }
 
@@ -284,23 +513,25 @@ impl<T: XdvEvents> XdvParser<T> {
             }
 
             let opcode = cursor.get_u8().unwrap();
-            let mut char_run_ended = true; // most commands end runs of characters
+
+            // Most commands end runs of characters. We need to report the run
+            // before processing the command so that events arrive in order.
+            if ends_char_run(opcode) && !self.cur_char_run.is_empty() {
+                self.events
+                    .handle_char_run(self.cur_char_run_pos, &self.cur_char_run)?;
+                self.cur_char_run.clear();
+            }
 
             let rv = match opcode {
                 // This is the least ugly way I've found to map the u8 to the
                 // symbolic enum values.
-                oc if oc == Opcode::Noop as u8 => {
-                    char_run_ended = false;
-                    Ok(())
-                }
+                oc if oc == Opcode::Noop as u8 => Ok(()),
 
                 oc if oc >= Opcode::DefineFont1 as u8 && oc <= Opcode::DefineFont4 as u8 => {
-                    char_run_ended = false;
                     self.do_define_font(oc, &mut cursor)
                 }
 
                 oc if oc == Opcode::DefineNativeFont as u8 => {
-                    char_run_ended = false;
                     self.do_define_native_font(oc, &mut cursor)
                 }
 
@@ -310,10 +541,7 @@ impl<T: XdvEvents> XdvParser<T> {
 
                 oc if oc == Opcode::EndOfPage as u8 => self.do_end_of_page(oc, &mut cursor),
 
-                oc if oc == Opcode::PushStack as u8 => {
-                    char_run_ended = false;
-                    self.do_push_stack(oc, &mut cursor)
-                }
+                oc if oc == Opcode::PushStack as u8 => self.do_push_stack(oc, &mut cursor),
 
                 oc if oc == Opcode::PopStack as u8 => self.do_pop_stack(oc, &mut cursor),
 
@@ -360,15 +588,17 @@ impl<T: XdvEvents> XdvParser<T> {
                 oc if oc >= Opcode::SetCharNumber0 as u8
                     && oc <= Opcode::SetCharNumber127 as u8 =>
                 {
-                    char_run_ended = false;
                     self.do_set_char_number(oc, &mut cursor)
                 }
 
                 oc if oc >= Opcode::SetChar1 as u8 && oc <= Opcode::SetChar4 as u8 => {
-                    char_run_ended = false;
                     self.do_set_char(oc, &mut cursor)
                 }
 
+                oc if oc == Opcode::SetRule as u8 || oc == Opcode::PutRule as u8 => {
+                    self.do_rule(oc, &mut cursor)
+                }
+
                 oc if oc == Opcode::SetGlyphs as u8 => self.do_set_glyphs(oc, &mut cursor),
 
                 oc if oc == Opcode::SetTextAndGlyphs as u8 => {
@@ -403,11 +633,6 @@ impl<T: XdvEvents> XdvParser<T> {
                     return Err(e);
                 }
             }
-
-            if char_run_ended && !self.cur_char_run.is_empty() {
-                self.events.handle_char_run(&self.cur_char_run)?;
-                self.cur_char_run.clear();
-            }
         }
 
         self.offset += cursor.checkpoint as u64;
@@ -447,20 +672,23 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        let _font_num = cursor.get_compact_i32_smpos(opcode - Opcode::DefineFont1 as u8)?;
-        let _checksum = cursor.get_u32()?;
-        let _scale_factor = cursor.get_u32()?;
-        let _design_size = cursor.get_u32()?;
+        let font_num = cursor.get_compact_i32_smpos(opcode - Opcode::DefineFont1 as u8)?;
+        let checksum = cursor.get_u32()?;
+        let scale_factor = cursor.get_u32()?;
+        let design_size = cursor.get_u32()?;
         let area_len = cursor.get_u8()?;
         let name_len = cursor.get_u8()?;
-        // XXX TEMP
-        use std::str::from_utf8;
-        let _area_str = from_utf8(cursor.get_slice(area_len as usize)?)
-            .unwrap()
-            .to_owned();
-        let _name_str = from_utf8(cursor.get_slice(name_len as usize)?)
-            .unwrap()
-            .to_owned();
+        let area = String::from_utf8_lossy(cursor.get_slice(area_len as usize)?).into_owned();
+        let name = String::from_utf8_lossy(cursor.get_slice(name_len as usize)?).into_owned();
+
+        self.events.handle_define_font(&FontDefinition {
+            font_num,
+            checksum,
+            scale_factor,
+            design_size,
+            area,
+            name,
+        })?;
         Ok(())
     }
 
@@ -473,41 +701,49 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        let _font_num = cursor.get_i32()?;
-        let _size = cursor.get_i32()?; // fixed-point
+        let font_num = cursor.get_i32()?;
+        let size = cursor.get_i32()?;
         let flags = cursor.get_u16()?;
         let name_len = cursor.get_u8()?;
-        // XXX TEMP
-        use std::str::from_utf8;
-        let _name_str = from_utf8(cursor.get_slice(name_len as usize)?)
-            .unwrap()
-            .to_owned();
-        let _face_index = cursor.get_u32()?;
+        let name = String::from_utf8_lossy(cursor.get_slice(name_len as usize)?).into_owned();
+        let face_index = cursor.get_u32()?;
 
-        let _color_rgba = if flags & NativeFontFlags::Colored as u16 != 0 {
+        let color_rgba = if flags & NativeFontFlags::Colored as u16 != 0 {
             Some(cursor.get_u32()?)
         } else {
             None
         };
 
-        let _extend = if flags & NativeFontFlags::Extend as u16 != 0 {
-            Some(cursor.get_u32()?) // fixed-point
+        let extend = if flags & NativeFontFlags::Extend as u16 != 0 {
+            Some(cursor.get_i32()?) // fixed-point
         } else {
             None
         };
 
-        let _slant = if flags & NativeFontFlags::Slant as u16 != 0 {
-            Some(cursor.get_u32()?) // fixed-point
+        let slant = if flags & NativeFontFlags::Slant as u16 != 0 {
+            Some(cursor.get_i32()?) // fixed-point
         } else {
             None
         };
 
-        let _embolden = if flags & NativeFontFlags::Embolden as u16 != 0 {
-            Some(cursor.get_u32()?) // fixed-point
+        let embolden = if flags & NativeFontFlags::Embolden as u16 != 0 {
+            Some(cursor.get_i32()?) // fixed-point
         } else {
             None
         };
 
+        self.events
+            .handle_define_native_font(&NativeFontDefinition {
+                font_num,
+                size,
+                vertical: flags & NativeFontFlags::Vertical as u16 != 0,
+                name,
+                face_index,
+                color_rgba,
+                extend,
+                slant,
+                embolden,
+            })?;
         Ok(())
     }
 
@@ -549,6 +785,7 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::Malformed(cursor.global_offset()).into_internal());
         }
 
+        self.events.handle_end_page()?;
         self.state = ParserState::BetweenPages;
         Ok(())
     }
@@ -706,7 +943,9 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        self.cur_font_num = Some(i32::from(opcode - Opcode::SetFontNumber0 as u8));
+        let font_num = i32::from(opcode - Opcode::SetFontNumber0 as u8);
+        self.cur_font_num = Some(font_num);
+        self.events.handle_select_font(font_num)?;
         Ok(())
     }
 
@@ -716,7 +955,9 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        self.cur_font_num = Some(cursor.get_compact_i32_smpos(opcode - Opcode::SetFont1 as u8)?);
+        let font_num = cursor.get_compact_i32_smpos(opcode - Opcode::SetFont1 as u8)?;
+        self.cur_font_num = Some(font_num);
+        self.events.handle_select_font(font_num)?;
         Ok(())
     }
 
@@ -731,7 +972,7 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let char_num = opcode - Opcode::SetCharNumber0 as u8;
-        self.cur_char_run.push(i32::from(char_num));
+        self.push_char(i32::from(char_num));
         Ok(())
     }
 
@@ -742,31 +983,64 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let char_num = cursor.get_compact_i32_smpos(opcode - Opcode::SetChar1 as u8)?;
-        self.cur_char_run.push(char_num as i32);
+        self.push_char(char_num);
         Ok(())
     }
 
-    fn do_set_glyphs(
-        &mut self,
-        opcode: u8,
-        cursor: &mut Cursor<T>,
-    ) -> InternalResult<(), T::Error> {
+    /// Add a character to the current run and advance the horizontal
+    /// position past it.
+    fn push_char(&mut self, c: i32) {
+        let advance = match self.cur_font_num {
+            Some(font_num) => self.events.char_advance(font_num, c),
+            None => None,
+        };
+
+        let state = self.stack.last_mut().unwrap();
+
+        if self.cur_char_run.is_empty() {
+            self.cur_char_run_pos = state.position();
+        }
+
+        self.cur_char_run.push(c);
+
+        match advance {
+            Some(advance) => state.h += advance,
+            None => state.h_exact = false,
+        }
+    }
+
+    /// Handles both `set_rule`, which advances the horizontal position, and
+    /// `put_rule`, which doesn't.
+    fn do_rule(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
         if self.state != ParserState::InPage {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        let _width = cursor.get_i32()?;
-        let n_glyphs = cursor.get_u16()?;
+        let height = cursor.get_i32()?;
+        let width = cursor.get_i32()?;
+        let state = self.stack.last_mut().unwrap();
+        self.events.handle_rule(state.position(), height, width)?;
 
-        for _ in 0..n_glyphs {
-            let _x = cursor.get_u32()?;
-            let _y = cursor.get_u32()?;
+        if opcode == Opcode::SetRule as u8 {
+            state.h += width;
         }
 
-        for _ in 0..n_glyphs {
-            let _glyph_id = cursor.get_i16()?;
+        Ok(())
+    }
+
+    fn do_set_glyphs(
+        &mut self,
+        opcode: u8,
+        cursor: &mut Cursor<T>,
+    ) -> InternalResult<(), T::Error> {
+        if self.state != ParserState::InPage {
+            return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
+        let run = self.get_glyph_run(cursor)?;
+        let state = self.stack.last_mut().unwrap();
+        self.events.handle_glyph_run(state.position(), &run)?;
+        state.h += run.width;
         Ok(())
     }
 
@@ -780,25 +1054,50 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let n_chars = cursor.get_u16()?;
-        let mut chars = Vec::new();
+        let mut chars = Vec::with_capacity(n_chars as usize);
 
         for _ in 0..n_chars {
             chars.push(cursor.get_u16()?);
         }
 
-        let _width = cursor.get_i32()?;
+        let text = String::from_utf16_lossy(&chars);
+        let run = self.get_glyph_run(cursor)?;
+        let state = self.stack.last_mut().unwrap();
+        self.events
+            .handle_text_and_glyphs(state.position(), &text, &run)?;
+        state.h += run.width;
+        Ok(())
+    }
+
+    /// Read the glyph data shared by the `set_glyphs` and
+    /// `set_text_and_glyphs` commands.
+    fn get_glyph_run(&self, cursor: &mut Cursor<T>) -> InternalResult<GlyphRun, T::Error> {
+        let font_num = match self.cur_font_num {
+            Some(n) => n,
+            None => {
+                return Err(XdvError::Malformed(cursor.global_offset()).into_internal());
+            }
+        };
+
+        let width = cursor.get_i32()?;
         let n_glyphs = cursor.get_u16()?;
+        let mut glyphs = Vec::with_capacity(n_glyphs as usize);
 
         for _ in 0..n_glyphs {
-            let _x = cursor.get_u32()?;
-            let _y = cursor.get_u32()?;
+            let x = cursor.get_i32()?;
+            let y = cursor.get_i32()?;
+            glyphs.push(Glyph { id: 0, x, y });
         }
 
-        for _ in 0..n_glyphs {
-            let _glyph_id = cursor.get_i16()?;
+        for glyph in &mut glyphs {
+            glyph.id = cursor.get_u16()?;
         }
 
-        Ok(())
+        Ok(GlyphRun {
+            font_num,
+            width,
+            glyphs,
+        })
     }
 
     fn do_special(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
@@ -807,7 +1106,9 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let n = cursor.get_compact_u32(opcode - Opcode::Special1 as u8)?;
-        self.events.handle_special(cursor.get_slice(n as usize)?)?;
+        let state = self.stack.last().unwrap();
+        self.events
+            .handle_special(state.position(), cursor.get_slice(n as usize)?)?;
 
         Ok(())
     }
@@ -821,11 +1122,13 @@ impl<T: XdvEvents> XdvParser<T> {
         cursor.assert_u32(25_400_000)?; // dimensions unit numerator
         cursor.assert_u32(473_628_672)?; // dimensions unit denominator
         cursor.get_u32()?; // 'mag' factor
-        cursor.get_u32()?; // largest height+depth of tallest page
-        cursor.get_u32()?; // largest width of widest page
+        let max_height = cursor.get_i32()?; // largest height+depth of tallest page
+        let max_width = cursor.get_i32()?; // largest width of widest page
         cursor.get_u16()?; // maximum stack depth
-        cursor.get_u16()?; // number of pages
+        let n_pages = cursor.get_u16()?;
 
+        self.events
+            .handle_postamble(max_height, max_width, n_pages)?;
         self.state = ParserState::PostambleFontDefinitions;
         Ok(())
     }
@@ -866,6 +1169,16 @@ impl<T: XdvEvents> XdvParser<T> {
     }
 }
 
+/// Test whether an opcode ends a run of characters.
+fn ends_char_run(opcode: u8) -> bool {
+    !(opcode == Opcode::Noop as u8
+        || (opcode >= Opcode::DefineFont1 as u8 && opcode <= Opcode::DefineFont4 as u8)
+        || opcode == Opcode::DefineNativeFont as u8
+        || opcode == Opcode::PushStack as u8
+        || opcode <= Opcode::SetCharNumber127 as u8
+        || (opcode >= Opcode::SetChar1 as u8 && opcode <= Opcode::SetChar4 as u8))
+}
+
 /// The states that may be stacked while processing the DVI.
 #[derive(Clone, Debug, Eq, PartialEq)]
 struct State {
@@ -875,6 +1188,7 @@ struct State {
     pub x: i32,
     pub y: i32,
     pub z: i32,
+    pub h_exact: bool,
 }
 
 impl State {
@@ -886,6 +1200,15 @@ impl State {
             x: 0,
             y: 0,
             z: 0,
+            h_exact: true,
+        }
+    }
+
+    pub fn position(&self) -> Position {
+        Position {
+            h: self.h,
+            v: self.v,
+            h_exact: self.h_exact,
         }
     }
 }
@@ -1095,6 +1418,8 @@ enum Opcode {
     SetCharNumber127 = 127,
     SetChar1 = 128,
     SetChar4 = 131,
+    SetRule = 132,
+    PutRule = 137,
     Noop = 138,
     BeginningOfPage = 139,
     EndOfPage = 140,
@@ -1151,3 +1476,431 @@ enum NativeFontFlags {
     Slant = 0x2000,
     Embolden = 0x4000,
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Records events as strings, so that they can be compared easily.
+    /// Inexact horizontal positions are marked with a tilde.
+    #[derive(Debug, Default)]
+    pub struct Recorder {
+        pub events: Vec<String>,
+
+        /// The advance to report for every character, if any.
+        pub advance: Option<i32>,
+    }
+
+    fn describe(pos: Position) -> String {
+        let approx = if pos.h_exact { "" } else { "~" };
+        format!("{}{} {}", approx, pos.h, pos.v)
+    }
+
+    impl XdvEvents for Recorder {
+        type Error = IoError;
+
+        fn handle_header(&mut self, filetype: FileType, comment: &[u8]) -> Result<(), IoError> {
+            self.events
+                .push(format!("header {} {:?}", filetype, comment));
+            Ok(())
+        }
+
+        fn handle_begin_page(&mut self, counters: &[i32], _prev: i32) -> Result<(), IoError> {
+            self.events.push(format!("bop {:?}", counters));
+            Ok(())
+        }
+
+        fn handle_end_page(&mut self) -> Result<(), IoError> {
+            self.events.push("eop".to_owned());
+            Ok(())
+        }
+
+        fn handle_postamble(&mut self, height: i32, width: i32, n: u16) -> Result<(), IoError> {
+            self.events.push(format!("post {} {} {}", height, width, n));
+            Ok(())
+        }
+
+        fn handle_special(&mut self, pos: Position, contents: &[u8]) -> Result<(), IoError> {
+            self.events
+                .push(format!("special {} {:?}", describe(pos), contents));
+            Ok(())
+        }
+
+        fn handle_select_font(&mut self, font_num: i32) -> Result<(), IoError> {
+            self.events.push(format!("font {}", font_num));
+            Ok(())
+        }
+
+        fn handle_char_run(&mut self, pos: Position, chars: &[i32]) -> Result<(), IoError> {
+            self.events
+                .push(format!("chars {} {:?}", describe(pos), chars));
+            Ok(())
+        }
+
+        fn handle_rule(&mut self, pos: Position, height: i32, width: i32) -> Result<(), IoError> {
+            self.events
+                .push(format!("rule {} {} {}", describe(pos), height, width));
+            Ok(())
+        }
+
+        fn char_advance(&mut self, _font_num: i32, _c: i32) -> Option<i32> {
+            self.advance
+        }
+
+        fn handle_define_font(&mut self, font: &FontDefinition) -> Result<(), IoError> {
+            self.events.push(format!("fnt_def {:?}", font));
+            Ok(())
+        }
+
+        fn handle_define_native_font(
+            &mut self,
+            font: &NativeFontDefinition,
+        ) -> Result<(), IoError> {
+            self.events.push(format!("native_font_def {:?}", font));
+            Ok(())
+        }
+
+        fn handle_glyph_run(&mut self, pos: Position, run: &GlyphRun) -> Result<(), IoError> {
+            self.events
+                .push(format!("glyphs {} {:?}", describe(pos), run));
+            Ok(())
+        }
+
+        fn handle_text_and_glyphs(
+            &mut self,
+            pos: Position,
+            text: &str,
+            run: &GlyphRun,
+        ) -> Result<(), IoError> {
+            self.events
+                .push(format!("text {} {:?} {:?}", describe(pos), text, run));
+            Ok(())
+        }
+    }
+
+    /// Assemble a minimal XDV file from the contents of its pages.
+    pub fn xdv(pages: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![Opcode::Preamble as u8, IdByte::Xdv as u8];
+        data.extend_from_slice(&25_400_000u32.to_be_bytes());
+        data.extend_from_slice(&473_628_672u32.to_be_bytes());
+        data.extend_from_slice(&1000u32.to_be_bytes());
+        data.push(0); // comment length
+
+        let mut last_bop = -1i32;
+
+        for (i, page) in pages.iter().enumerate() {
+            let bop = data.len() as i32;
+            data.push(Opcode::BeginningOfPage as u8);
+            data.extend_from_slice(&(i as i32 + 1).to_be_bytes());
+            data.extend_from_slice(&[0; 36]);
+            data.extend_from_slice(&last_bop.to_be_bytes());
+            data.extend_from_slice(page);
+            data.push(Opcode::EndOfPage as u8);
+            last_bop = bop;
+        }
+
+        let post = data.len() as u32;
+        data.push(Opcode::Postamble as u8);
+        data.extend_from_slice(&last_bop.to_be_bytes());
+        data.extend_from_slice(&25_400_000u32.to_be_bytes());
+        data.extend_from_slice(&473_628_672u32.to_be_bytes());
+        data.extend_from_slice(&1000u32.to_be_bytes());
+        data.extend_from_slice(&1000i32.to_be_bytes()); // max height
+        data.extend_from_slice(&2000i32.to_be_bytes()); // max width
+        data.extend_from_slice(&4u16.to_be_bytes()); // max stack depth
+        data.extend_from_slice(&(pages.len() as u16).to_be_bytes());
+        data.push(Opcode::DoublePostamble as u8);
+        data.extend_from_slice(&post.to_be_bytes());
+        data.push(IdByte::Xdv as u8);
+        data.extend_from_slice(&[223; 4]);
+        data
+    }
+
+    /// Parse a file and return the events that it generated, leaving out the
+    /// header, page boundaries and postamble.
+    fn page_events(data: &[u8], advance: Option<i32>) -> Vec<String> {
+        let recorder = Recorder {
+            events: Vec::new(),
+            advance,
+        };
+
+        XdvParser::process(data, recorder)
+            .unwrap()
+            .0
+            .events
+            .into_iter()
+            .filter(|e| !(e.starts_with("header") || e.starts_with("bop") || e.starts_with("post")))
+            .collect()
+    }
+
+    #[test]
+    fn movements() {
+        let page = [
+            Opcode::Right1 as u8,
+            10,
+            Opcode::Down1 as u8,
+            20,
+            Opcode::Special1 as u8,
+            1,
+            b'a',
+            Opcode::PushStack as u8,
+            Opcode::SetW1 as u8,
+            3,
+            Opcode::RightByW as u8,
+            Opcode::SetX1 as u8,
+            0xFF, // -1
+            Opcode::RightByX as u8,
+            Opcode::Special1 as u8,
+            1,
+            b'b',
+            Opcode::SetY1 as u8,
+            4,
+            Opcode::DownByY as u8,
+            Opcode::SetZ1 as u8,
+            2,
+            Opcode::DownByZ as u8,
+            Opcode::Special1 as u8,
+            1,
+            b'c',
+            Opcode::PopStack as u8,
+            Opcode::Special1 as u8,
+            1,
+            b'd',
+        ];
+
+        assert_eq!(
+            page_events(&xdv(&[&page]), None),
+            vec![
+                "special 10 20 [97]",
+                "special 14 20 [98]",
+                "special 14 32 [99]",
+                "special 10 20 [100]",
+                "eop",
+            ]
+        );
+    }
+
+    #[test]
+    fn known_char_widths() {
+        let page = [
+            Opcode::SetFontNumber0 as u8,
+            Opcode::Right1 as u8,
+            10,
+            b'A',
+            b'B',
+            Opcode::Right1 as u8,
+            3,
+            Opcode::SetChar1 as u8,
+            200,
+            Opcode::Special1 as u8,
+            1,
+            b'x',
+        ];
+
+        assert_eq!(
+            page_events(&xdv(&[&page]), Some(5)),
+            vec![
+                "font 0",
+                "chars 10 0 [65, 66]",
+                "chars 23 0 [200]",
+                "special 28 0 [120]",
+                "eop",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_char_widths() {
+        let page1 = [
+            Opcode::SetFontNumber0 as u8,
+            Opcode::Right1 as u8,
+            10,
+            Opcode::PushStack as u8,
+            b'A',
+            Opcode::Right1 as u8,
+            5,
+            b'B',
+            Opcode::Special1 as u8,
+            1,
+            b'x',
+            Opcode::PopStack as u8,
+            Opcode::Special1 as u8,
+            1,
+            b'y',
+            b'C',
+        ];
+        let page2 = [Opcode::Special1 as u8, 1, b'z'];
+
+        assert_eq!(
+            page_events(&xdv(&[&page1, &page2]), None),
+            vec![
+                "font 0",
+                "chars 10 0 [65]",
+                "chars ~15 0 [66]",
+                "special ~15 0 [120]",
+                "special 10 0 [121]",
+                "chars 10 0 [67]",
+                "eop",
+                "special 0 0 [122]",
+                "eop",
+            ]
+        );
+    }
+
+    #[test]
+    fn font_definitions() {
+        let mut page = vec![Opcode::DefineFont1 as u8, 5];
+        page.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        page.extend_from_slice(&655_360u32.to_be_bytes());
+        page.extend_from_slice(&589_824u32.to_be_bytes());
+        page.extend_from_slice(&[0, 5]);
+        page.extend_from_slice(b"cmr10");
+
+        page.push(Opcode::DefineNativeFont as u8);
+        page.extend_from_slice(&7i32.to_be_bytes());
+        page.extend_from_slice(&786_432i32.to_be_bytes());
+        page.extend_from_slice(&0x6200u16.to_be_bytes()); // colored, slanted, emboldened
+        page.push(8);
+        page.extend_from_slice(b"font.otf");
+        page.extend_from_slice(&1u32.to_be_bytes());
+        page.extend_from_slice(&0xFF00_00FFu32.to_be_bytes());
+        page.extend_from_slice(&(-13107i32).to_be_bytes());
+        page.extend_from_slice(&65536i32.to_be_bytes());
+
+        let tfm = FontDefinition {
+            font_num: 5,
+            checksum: 0x1234_5678,
+            scale_factor: 655_360,
+            design_size: 589_824,
+            area: String::new(),
+            name: "cmr10".to_owned(),
+        };
+
+        let native = NativeFontDefinition {
+            font_num: 7,
+            size: 786_432,
+            vertical: false,
+            name: "font.otf".to_owned(),
+            face_index: 1,
+            color_rgba: Some(0xFF00_00FF),
+            extend: None,
+            slant: Some(-13107),
+            embolden: Some(65536),
+        };
+
+        assert_eq!(
+            page_events(&xdv(&[&page]), None),
+            vec![
+                format!("fnt_def {:?}", tfm),
+                format!("native_font_def {:?}", native),
+                "eop".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn glyph_runs() {
+        fn put_run(page: &mut Vec<u8>, width: i32, glyphs: &[(i32, i32, u16)]) {
+            page.extend_from_slice(&width.to_be_bytes());
+            page.extend_from_slice(&(glyphs.len() as u16).to_be_bytes());
+
+            for &(x, y, _) in glyphs {
+                page.extend_from_slice(&x.to_be_bytes());
+                page.extend_from_slice(&y.to_be_bytes());
+            }
+
+            for &(_, _, id) in glyphs {
+                page.extend_from_slice(&id.to_be_bytes());
+            }
+        }
+
+        let mut page = vec![
+            Opcode::SetFontNumber0 as u8 + 7,
+            Opcode::Right1 as u8,
+            10,
+            Opcode::SetGlyphs as u8,
+        ];
+        put_run(&mut page, 30, &[(0, 0, 36), (12, -2, 1000)]);
+        page.push(Opcode::SetTextAndGlyphs as u8);
+        page.extend_from_slice(&3u16.to_be_bytes());
+        page.extend_from_slice(&0xE9u16.to_be_bytes()); // é
+        page.extend_from_slice(&0xD83Du16.to_be_bytes()); // 🙂, as a surrogate pair
+        page.extend_from_slice(&0xDE42u16.to_be_bytes());
+        put_run(&mut page, 20, &[(0, 0, 5)]);
+        page.extend_from_slice(&[Opcode::Special1 as u8, 1, b'x']);
+
+        let run1 = GlyphRun {
+            font_num: 7,
+            width: 30,
+            glyphs: vec![
+                Glyph { id: 36, x: 0, y: 0 },
+                Glyph {
+                    id: 1000,
+                    x: 12,
+                    y: -2,
+                },
+            ],
+        };
+
+        let run2 = GlyphRun {
+            font_num: 7,
+            width: 20,
+            glyphs: vec![Glyph { id: 5, x: 0, y: 0 }],
+        };
+
+        assert_eq!(
+            page_events(&xdv(&[&page]), None),
+            vec![
+                "font 7".to_owned(),
+                format!("glyphs 10 0 {:?}", run1),
+                format!("text 40 0 \"é🙂\" {:?}", run2),
+                "special 60 0 [120]".to_owned(),
+                "eop".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn glyphs_need_a_font() {
+        let mut page = vec![Opcode::SetGlyphs as u8];
+        page.extend_from_slice(&[0; 6]);
+        assert!(XdvParser::process(&xdv(&[&page])[..], Recorder::default()).is_err());
+    }
+
+    #[test]
+    fn rules_and_page_ends() {
+        let mut page = vec![Opcode::Right1 as u8, 10, Opcode::PutRule as u8];
+        page.extend_from_slice(&4i32.to_be_bytes());
+        page.extend_from_slice(&6i32.to_be_bytes());
+        page.push(Opcode::SetRule as u8);
+        page.extend_from_slice(&(-1i32).to_be_bytes());
+        page.extend_from_slice(&6i32.to_be_bytes());
+        page.extend_from_slice(&[Opcode::Special1 as u8, 1, b'x']);
+
+        let events = XdvParser::process(&xdv(&[&page, &[]])[..], Recorder::default())
+            .unwrap()
+            .0
+            .events;
+
+        assert_eq!(
+            events,
+            vec![
+                "header XDV []",
+                "bop [1, 0, 0, 0, 0, 0, 0, 0, 0, 0]",
+                "rule 10 0 4 6",
+                "rule 10 0 -1 6",
+                "special 16 0 [120]",
+                "eop",
+                "bop [2, 0, 0, 0, 0, 0, 0, 0, 0, 0]",
+                "eop",
+                "post 1000 2000 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn unbalanced_page_is_malformed() {
+        let page = [Opcode::PushStack as u8];
+        assert!(XdvParser::process(&xdv(&[&page])[..], Recorder::default()).is_err());
+    }
+}
@@ -0,0 +1,875 @@
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Writing XDV and SPX files.
+
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, ErrorKind, Write};
+
+use super::{
+    FileType, FontDefinition, GlyphRun, IdByte, NativeFontDefinition, NativeFontFlags, Opcode,
+    Position, XdvEvents,
+};
+
+/// The DVI unit numerator and denominator. Together they make one DVI unit
+/// equal to 2<sup>-16</sup> TeX points.
+const DVI_NUMERATOR: u32 = 25_400_000;
+const DVI_DENOMINATOR: u32 = 473_628_672;
+
+/// The magnification factor, in thousandths. We don't magnify.
+const DVI_MAGNIFICATION: u32 = 1000;
+
+/// The byte used to pad out the end of the file.
+const PADDING_BYTE: u8 = 223;
+
+/// The current state of the writer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum WriterState {
+    /// The header has not yet been written.
+    Preamble,
+
+    /// Between pages.
+    BetweenPages,
+
+    /// In a page.
+    InPage,
+
+    /// The postamble has been written.
+    Finished,
+}
+
+/// A font definition of either kind, as recorded for the postamble.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum AnyFontDefinition {
+    Tfm(FontDefinition),
+    Native(NativeFontDefinition),
+}
+
+/// State for writing an XDV file.
+///
+/// The writer can be driven directly, through methods that mirror the events
+/// of the `XdvEvents` trait, or it can be handed to an `XdvParser` as an
+/// event handler, in which case it will reproduce the file being parsed. To
+/// modify a file on its way through, wrap the writer in an event handler
+/// that forwards only the events that it wants to keep.
+///
+/// Positions are given in DVI units, just as the parser reports them. The
+/// writer emits whatever movement commands are needed to get to each
+/// position, so the output is valid but will not necessarily be
+/// byte-for-byte identical to a file produced by XeTeX. Likewise, the page
+/// pointers and page count in the output are computed by the writer, not
+/// taken from its input.
+///
+/// Like the parser, the writer does not know the widths of characters from
+/// traditional TeX fonts. It takes them to be zero when keeping track of
+/// the current position, which is the same thing that the parser does when
+/// `XdvEvents::char_advance` does not provide them. The characters still
+/// advance the position when the output is rendered, so positions given
+/// after a run of characters are effectively relative to its end. That is
+/// what makes replaying a parsed file work, but it means that an event
+/// handler that forwards events to a writer should not report character
+/// advances of its own. To put something at a position that doesn't depend
+/// on the characters that come before it, such as a second page's worth of
+/// material on the same page, save the position with `push` beforehand and
+/// restore it with `pop` afterwards.
+#[derive(Debug)]
+pub struct XdvWriter<W: Write> {
+    stream: W,
+    filetype: FileType,
+    state: WriterState,
+    offset: u64,
+    last_bop: Option<u64>,
+    n_pages: u16,
+    max_height: i32,
+    max_width: i32,
+    fonts: BTreeMap<i32, AnyFontDefinition>,
+    cur_font_num: Option<i32>,
+    emitted_font_num: Option<i32>,
+    h: i32,
+    v: i32,
+    stack: Vec<(i32, i32)>,
+    max_stack_depth: u16,
+}
+
+impl<W: Write> XdvWriter<W> {
+    /// Create a new XDV/SPX writer.
+    ///
+    /// Nothing is written until the `header` method is called.
+    pub fn new(stream: W) -> Self {
+        XdvWriter {
+            stream,
+            filetype: FileType::Xdv,
+            state: WriterState::Preamble,
+            offset: 0,
+            last_bop: None,
+            n_pages: 0,
+            max_height: 0,
+            max_width: 0,
+            fonts: BTreeMap::new(),
+            cur_font_num: None,
+            emitted_font_num: None,
+            h: 0,
+            v: 0,
+            stack: Vec::new(),
+            max_stack_depth: 0,
+        }
+    }
+
+    /// Write the file header. This must be called before anything else.
+    pub fn header(&mut self, filetype: FileType, comment: &[u8]) -> Result<(), IoError> {
+        self.check_state(WriterState::Preamble, "the header has already been written")?;
+
+        if comment.len() > 255 {
+            return Err(misuse("the header comment is longer than 255 bytes"));
+        }
+
+        self.filetype = filetype;
+        self.put(&[Opcode::Preamble as u8, id_byte(filetype)])?;
+        self.put(&DVI_NUMERATOR.to_be_bytes())?;
+        self.put(&DVI_DENOMINATOR.to_be_bytes())?;
+        self.put(&DVI_MAGNIFICATION.to_be_bytes())?;
+        self.put(&[comment.len() as u8])?;
+        self.put(comment)?;
+        self.state = WriterState::BetweenPages;
+        Ok(())
+    }
+
+    /// Set the maximum page dimensions recorded in the postamble.
+    ///
+    /// The writer has no way to measure the pages itself, so these default to
+    /// zero. The arguments are the largest height-plus-depth and the largest
+    /// width of any page, in DVI units.
+    pub fn max_page_dimensions(&mut self, max_height: i32, max_width: i32) -> &mut Self {
+        self.max_height = max_height;
+        self.max_width = max_width;
+        self
+    }
+
+    /// Begin a new page.
+    ///
+    /// Up to ten *counters* are recorded with the page, as with TeX's
+    /// `\count0` through `\count9`. Missing counters are set to zero.
+    pub fn begin_page(&mut self, counters: &[i32]) -> Result<(), IoError> {
+        self.check_state(WriterState::BetweenPages, "pages cannot nest")?;
+
+        if counters.len() > 10 {
+            return Err(misuse("a page can have at most ten counters"));
+        }
+
+        let bop = self.offset;
+        let previous_bop = self.last_bop.map(|o| o as i32).unwrap_or(-1);
+
+        self.put(&[Opcode::BeginningOfPage as u8])?;
+
+        for i in 0..10 {
+            self.put(&counters.get(i).copied().unwrap_or(0).to_be_bytes())?;
+        }
+
+        self.put(&previous_bop.to_be_bytes())?;
+
+        self.last_bop = Some(bop);
+        self.n_pages = self.n_pages.saturating_add(1);
+        self.state = WriterState::InPage;
+        self.cur_font_num = None;
+        self.emitted_font_num = None;
+        self.h = 0;
+        self.v = 0;
+        Ok(())
+    }
+
+    /// End the current page.
+    pub fn end_page(&mut self) -> Result<(), IoError> {
+        self.check_state(WriterState::InPage, "no page has been begun")?;
+
+        if !self.stack.is_empty() {
+            return Err(misuse("a saved position has not been restored"));
+        }
+
+        self.put(&[Opcode::EndOfPage as u8])?;
+        self.state = WriterState::BetweenPages;
+        Ok(())
+    }
+
+    /// Save the current position, to be restored by a matching call to
+    /// `pop`.
+    pub fn push(&mut self) -> Result<(), IoError> {
+        self.check_state(WriterState::InPage, "positions can only be saved in a page")?;
+
+        if self.stack.len() >= u16::MAX as usize {
+            return Err(misuse("too many positions have been saved"));
+        }
+
+        self.put(&[Opcode::PushStack as u8])?;
+        self.stack.push((self.h, self.v));
+        self.max_stack_depth = self.max_stack_depth.max(self.stack.len() as u16);
+        Ok(())
+    }
+
+    /// Go back to the position saved by the last call to `push`.
+    ///
+    /// This undoes the advance of any characters set since then, which the
+    /// writer doesn't know about.
+    pub fn pop(&mut self) -> Result<(), IoError> {
+        self.check_state(
+            WriterState::InPage,
+            "positions can only be restored in a page",
+        )?;
+
+        let (h, v) = match self.stack.pop() {
+            Some(pos) => pos,
+            None => return Err(misuse("no position has been saved")),
+        };
+
+        self.put(&[Opcode::PopStack as u8])?;
+        self.h = h;
+        self.v = v;
+        Ok(())
+    }
+
+    /// Define a traditional TeX font.
+    ///
+    /// Every font must be defined before it is used. Definitions are repeated
+    /// in the postamble automatically, and redefining a font number with an
+    /// identical definition has no effect.
+    pub fn define_font(&mut self, font: &FontDefinition) -> Result<(), IoError> {
+        self.define_any_font(AnyFontDefinition::Tfm(font.clone()))
+    }
+
+    /// Define a XeTeX native font.
+    ///
+    /// The same rules apply as for `define_font`.
+    pub fn define_native_font(&mut self, font: &NativeFontDefinition) -> Result<(), IoError> {
+        self.define_any_font(AnyFontDefinition::Native(font.clone()))
+    }
+
+    /// Select the font used by subsequent calls to `char_run`.
+    ///
+    /// Glyph runs carry their own font numbers, so they don't need this.
+    pub fn select_font(&mut self, font_num: i32) -> Result<(), IoError> {
+        self.check_state(WriterState::InPage, "fonts can only be selected in a page")?;
+        self.cur_font_num = Some(font_num);
+        Ok(())
+    }
+
+    /// Set a sequence of characters from the currently selected font.
+    ///
+    /// The arguments *h* and *v* give the position of the first character.
+    /// As explained in the type documentation, the writer does not advance
+    /// its idea of the current position past the characters.
+    pub fn char_run(&mut self, h: i32, v: i32, chars: &[i32]) -> Result<(), IoError> {
+        self.check_state(WriterState::InPage, "characters can only be set in a page")?;
+
+        if chars.is_empty() {
+            return Ok(());
+        }
+
+        self.move_to(h, v)?;
+        self.sync_font()?;
+
+        for &c in chars {
+            if (0..=Opcode::SetCharNumber127 as i32).contains(&c) {
+                self.put(&[c as u8])?;
+            } else {
+                self.put_compact_unsigned(Opcode::SetChar1 as u8, c)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Draw a rule (a filled rectangle) with its bottom left corner at the
+    /// position given by *h* and *v*.
+    pub fn rule(&mut self, h: i32, v: i32, height: i32, width: i32) -> Result<(), IoError> {
+        self.check_state(WriterState::InPage, "rules can only be drawn in a page")?;
+        self.move_to(h, v)?;
+        self.put(&[Opcode::PutRule as u8])?;
+        self.put(&height.to_be_bytes())?;
+        self.put(&width.to_be_bytes())
+    }
+
+    /// Insert a `\special` at the position given by *h* and *v*.
+    pub fn special(&mut self, h: i32, v: i32, contents: &[u8]) -> Result<(), IoError> {
+        self.check_state(WriterState::InPage, "specials can only appear in a page")?;
+        self.move_to(h, v)?;
+        self.put_compact_unsigned(Opcode::Special1 as u8, contents.len() as i32)?;
+        self.put(contents)
+    }
+
+    /// Set a run of glyphs from a native font, starting at the position given
+    /// by *h* and *v*.
+    pub fn glyph_run(&mut self, h: i32, v: i32, run: &GlyphRun) -> Result<(), IoError> {
+        self.check_state(WriterState::InPage, "glyphs can only be set in a page")?;
+        self.move_to(h, v)?;
+        self.cur_font_num = Some(run.font_num);
+        self.sync_font()?;
+        self.put(&[Opcode::SetGlyphs as u8])?;
+        self.put_glyph_run(run)
+    }
+
+    /// Set a run of glyphs from a native font along with the text that it
+    /// represents, starting at the position given by *h* and *v*.
+    pub fn text_and_glyphs(
+        &mut self,
+        h: i32,
+        v: i32,
+        text: &str,
+        run: &GlyphRun,
+    ) -> Result<(), IoError> {
+        self.check_state(WriterState::InPage, "glyphs can only be set in a page")?;
+
+        let chars: Vec<u16> = text.encode_utf16().collect();
+
+        if chars.len() > u16::MAX as usize {
+            return Err(misuse("the text of a glyph run is too long"));
+        }
+
+        self.move_to(h, v)?;
+        self.cur_font_num = Some(run.font_num);
+        self.sync_font()?;
+        self.put(&[Opcode::SetTextAndGlyphs as u8])?;
+        self.put(&(chars.len() as u16).to_be_bytes())?;
+
+        for c in chars {
+            self.put(&c.to_be_bytes())?;
+        }
+
+        self.put_glyph_run(run)
+    }
+
+    /// Finish writing, consume this object, and return the underlying
+    /// stream.
+    ///
+    /// This writes the postamble, if that hasn't happened already.
+    pub fn finish(mut self) -> Result<W, IoError> {
+        if self.state != WriterState::Finished {
+            self.write_postamble()?;
+        }
+
+        self.stream.flush()?;
+        Ok(self.stream)
+    }
+
+    fn write_postamble(&mut self) -> Result<(), IoError> {
+        self.check_state(
+            WriterState::BetweenPages,
+            "the file is not at a page boundary",
+        )?;
+
+        let postamble = self.offset;
+        let last_bop = self.last_bop.map(|o| o as i32).unwrap_or(-1);
+
+        self.put(&[Opcode::Postamble as u8])?;
+        self.put(&last_bop.to_be_bytes())?;
+        self.put(&DVI_NUMERATOR.to_be_bytes())?;
+        self.put(&DVI_DENOMINATOR.to_be_bytes())?;
+        self.put(&DVI_MAGNIFICATION.to_be_bytes())?;
+        self.put(&self.max_height.to_be_bytes())?;
+        self.put(&self.max_width.to_be_bytes())?;
+        self.put(&self.max_stack_depth.to_be_bytes())?;
+        self.put(&self.n_pages.to_be_bytes())?;
+
+        let fonts: Vec<_> = self.fonts.values().cloned().collect();
+
+        for font in &fonts {
+            self.put_font_definition(font)?;
+        }
+
+        self.put(&[Opcode::DoublePostamble as u8])?;
+        self.put(&(postamble as u32).to_be_bytes())?;
+        self.put(&[id_byte(self.filetype)])?;
+
+        // At least four padding bytes, bringing the length to a multiple of
+        // four.
+        let n_padding = 4 + (4 - (self.offset + 4) % 4) % 4;
+        self.put(&vec![PADDING_BYTE; n_padding as usize])?;
+
+        self.state = WriterState::Finished;
+        Ok(())
+    }
+
+    fn define_any_font(&mut self, font: AnyFontDefinition) -> Result<(), IoError> {
+        let font_num = match font {
+            AnyFontDefinition::Tfm(ref f) => f.font_num,
+            AnyFontDefinition::Native(ref f) => f.font_num,
+        };
+
+        if self.fonts.get(&font_num) == Some(&font) {
+            return Ok(());
+        }
+
+        match self.state {
+            WriterState::Preamble => return Err(misuse("the header has not been written")),
+            WriterState::Finished => return Err(misuse("the postamble has been written")),
+            _ => {}
+        }
+
+        self.put_font_definition(&font)?;
+        self.fonts.insert(font_num, font);
+        Ok(())
+    }
+
+    fn put_font_definition(&mut self, font: &AnyFontDefinition) -> Result<(), IoError> {
+        match font {
+            AnyFontDefinition::Tfm(f) => {
+                if f.area.len() > 255 || f.name.len() > 255 {
+                    return Err(misuse("font area and name must be at most 255 bytes"));
+                }
+
+                self.put_compact_unsigned(Opcode::DefineFont1 as u8, f.font_num)?;
+                self.put(&f.checksum.to_be_bytes())?;
+                self.put(&f.scale_factor.to_be_bytes())?;
+                self.put(&f.design_size.to_be_bytes())?;
+                self.put(&[f.area.len() as u8, f.name.len() as u8])?;
+                self.put(f.area.as_bytes())?;
+                self.put(f.name.as_bytes())
+            }
+
+            AnyFontDefinition::Native(f) => {
+                if f.name.len() > 255 {
+                    return Err(misuse("native font names must be at most 255 bytes"));
+                }
+
+                let mut flags = 0;
+
+                if f.vertical {
+                    flags |= NativeFontFlags::Vertical as u16;
+                }
+                if f.color_rgba.is_some() {
+                    flags |= NativeFontFlags::Colored as u16;
+                }
+                if f.extend.is_some() {
+                    flags |= NativeFontFlags::Extend as u16;
+                }
+                if f.slant.is_some() {
+                    flags |= NativeFontFlags::Slant as u16;
+                }
+                if f.embolden.is_some() {
+                    flags |= NativeFontFlags::Embolden as u16;
+                }
+
+                self.put(&[Opcode::DefineNativeFont as u8])?;
+                self.put(&f.font_num.to_be_bytes())?;
+                self.put(&f.size.to_be_bytes())?;
+                self.put(&flags.to_be_bytes())?;
+                self.put(&[f.name.len() as u8])?;
+                self.put(f.name.as_bytes())?;
+                self.put(&f.face_index.to_be_bytes())?;
+
+                if let Some(rgba) = f.color_rgba {
+                    self.put(&rgba.to_be_bytes())?;
+                }
+
+                for x in [f.extend, f.slant, f.embolden].iter().flatten() {
+                    self.put(&x.to_be_bytes())?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the glyph data shared by the `set_glyphs` and
+    /// `set_text_and_glyphs` commands.
+    fn put_glyph_run(&mut self, run: &GlyphRun) -> Result<(), IoError> {
+        if run.glyphs.len() > u16::MAX as usize {
+            return Err(misuse("a glyph run can have at most 65535 glyphs"));
+        }
+
+        self.put(&run.width.to_be_bytes())?;
+        self.put(&(run.glyphs.len() as u16).to_be_bytes())?;
+
+        for glyph in &run.glyphs {
+            self.put(&glyph.x.to_be_bytes())?;
+            self.put(&glyph.y.to_be_bytes())?;
+        }
+
+        for glyph in &run.glyphs {
+            self.put(&glyph.id.to_be_bytes())?;
+        }
+
+        self.h += run.width;
+        Ok(())
+    }
+
+    /// Emit the movement commands needed to get to the specified position.
+    fn move_to(&mut self, h: i32, v: i32) -> Result<(), IoError> {
+        if h != self.h {
+            self.put_compact_signed(Opcode::Right1 as u8, h - self.h)?;
+            self.h = h;
+        }
+
+        if v != self.v {
+            self.put_compact_signed(Opcode::Down1 as u8, v - self.v)?;
+            self.v = v;
+        }
+
+        Ok(())
+    }
+
+    /// Emit a font selection command, if the selected font has changed since
+    /// the last one.
+    fn sync_font(&mut self) -> Result<(), IoError> {
+        let font_num = match self.cur_font_num {
+            Some(n) => n,
+            None => return Err(misuse("no font has been selected")),
+        };
+
+        if !self.fonts.contains_key(&font_num) {
+            return Err(misuse(format!(
+                "font {} is used before being defined",
+                font_num
+            )));
+        }
+
+        if self.emitted_font_num == Some(font_num) {
+            return Ok(());
+        }
+
+        if (0..=63).contains(&font_num) {
+            self.put(&[Opcode::SetFontNumber0 as u8 + font_num as u8])?;
+        } else {
+            self.put_compact_unsigned(Opcode::SetFont1 as u8, font_num)?;
+        }
+
+        self.emitted_font_num = Some(font_num);
+        Ok(())
+    }
+
+    fn check_state(&self, expected: WriterState, message: &str) -> Result<(), IoError> {
+        if self.state == expected {
+            Ok(())
+        } else {
+            Err(misuse(message))
+        }
+    }
+
+    /// This variation lets small values be signed (used by right, down, etc).
+    fn put_compact_signed(&mut self, opcode: u8, n: i32) -> Result<(), IoError> {
+        let bytes = n.to_be_bytes();
+
+        if (-0x80..=0x7F).contains(&n) {
+            self.put(&[opcode, bytes[3]])
+        } else if (-0x8000..=0x7FFF).contains(&n) {
+            self.put(&[opcode + 1, bytes[2], bytes[3]])
+        } else if (-0x80_0000..=0x7F_FFFF).contains(&n) {
+            self.put(&[opcode + 2, bytes[1], bytes[2], bytes[3]])
+        } else {
+            self.put(&[opcode + 3, bytes[0], bytes[1], bytes[2], bytes[3]])
+        }
+    }
+
+    /// This variation has unsigned small values (used by fnt_def, set_char,
+    /// etc.). Negative values always use the four-byte form.
+    fn put_compact_unsigned(&mut self, opcode: u8, n: i32) -> Result<(), IoError> {
+        let bytes = n.to_be_bytes();
+
+        if (0..=0xFF).contains(&n) {
+            self.put(&[opcode, bytes[3]])
+        } else if (0..=0xFFFF).contains(&n) {
+            self.put(&[opcode + 1, bytes[2], bytes[3]])
+        } else if (0..=0xFF_FFFF).contains(&n) {
+            self.put(&[opcode + 2, bytes[1], bytes[2], bytes[3]])
+        } else {
+            self.put(&[opcode + 3, bytes[0], bytes[1], bytes[2], bytes[3]])
+        }
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), IoError> {
+        self.stream.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+impl<W: Write> XdvEvents for XdvWriter<W> {
+    type Error = IoError;
+
+    fn handle_header(&mut self, filetype: FileType, comment: &[u8]) -> Result<(), Self::Error> {
+        self.header(filetype, comment)
+    }
+
+    fn handle_begin_page(
+        &mut self,
+        counters: &[i32],
+        _previous_bop: i32,
+    ) -> Result<(), Self::Error> {
+        self.begin_page(counters)
+    }
+
+    fn handle_end_page(&mut self) -> Result<(), Self::Error> {
+        self.end_page()
+    }
+
+    fn handle_postamble(
+        &mut self,
+        max_height: i32,
+        max_width: i32,
+        _n_pages: u16,
+    ) -> Result<(), Self::Error> {
+        self.max_page_dimensions(max_height, max_width);
+        self.write_postamble()
+    }
+
+    fn handle_special(&mut self, pos: Position, contents: &[u8]) -> Result<(), Self::Error> {
+        self.special(pos.h, pos.v, contents)
+    }
+
+    fn handle_select_font(&mut self, font_num: i32) -> Result<(), Self::Error> {
+        self.select_font(font_num)
+    }
+
+    fn handle_char_run(&mut self, pos: Position, chars: &[i32]) -> Result<(), Self::Error> {
+        self.char_run(pos.h, pos.v, chars)
+    }
+
+    fn handle_rule(&mut self, pos: Position, height: i32, width: i32) -> Result<(), Self::Error> {
+        self.rule(pos.h, pos.v, height, width)
+    }
+
+    fn handle_define_font(&mut self, font: &FontDefinition) -> Result<(), Self::Error> {
+        self.define_font(font)
+    }
+
+    fn handle_define_native_font(
+        &mut self,
+        font: &NativeFontDefinition,
+    ) -> Result<(), Self::Error> {
+        self.define_native_font(font)
+    }
+
+    fn handle_glyph_run(&mut self, pos: Position, run: &GlyphRun) -> Result<(), Self::Error> {
+        self.glyph_run(pos.h, pos.v, run)
+    }
+
+    fn handle_text_and_glyphs(
+        &mut self,
+        pos: Position,
+        text: &str,
+        run: &GlyphRun,
+    ) -> Result<(), Self::Error> {
+        self.text_and_glyphs(pos.h, pos.v, text, run)
+    }
+}
+
+fn id_byte(filetype: FileType) -> u8 {
+    let id = match filetype {
+        FileType::Xdv => IdByte::Xdv,
+        FileType::Spx => IdByte::Spx,
+    };
+
+    id as u8
+}
+
+fn misuse<E: Into<Box<dyn std::error::Error + Send + Sync>>>(message: E) -> IoError {
+    IoError::new(ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{xdv, Recorder},
+        Glyph, XdvParser,
+    };
+
+    fn tfm_font() -> FontDefinition {
+        FontDefinition {
+            font_num: 300,
+            checksum: 0x1234_5678,
+            scale_factor: 10 * 65536,
+            design_size: 10 * 65536,
+            area: String::new(),
+            name: "cmr10".to_owned(),
+        }
+    }
+
+    fn native_font() -> NativeFontDefinition {
+        NativeFontDefinition {
+            font_num: 2,
+            size: 12 * 65536,
+            vertical: false,
+            name: "lmroman12-regular.otf".to_owned(),
+            face_index: 0,
+            color_rgba: Some(0xFF00_00FF),
+            extend: None,
+            slant: Some(-13107),
+            embolden: None,
+        }
+    }
+
+    fn glyph_run() -> GlyphRun {
+        GlyphRun {
+            font_num: 2,
+            width: 20 * 65536,
+            glyphs: vec![
+                Glyph { id: 36, x: 0, y: 0 },
+                Glyph {
+                    id: 1000,
+                    x: 8 * 65536,
+                    y: -65536,
+                },
+            ],
+        }
+    }
+
+    fn build(filetype: FileType) -> Vec<u8> {
+        let mut w = XdvWriter::new(Vec::new());
+        w.header(filetype, b"test").unwrap();
+        w.define_font(&tfm_font()).unwrap();
+
+        w.begin_page(&[1, 2]).unwrap();
+        w.define_native_font(&native_font()).unwrap();
+        w.select_font(300).unwrap();
+        w.char_run(72 * 65536, 100 * 65536, &[65, 200, 70_000])
+            .unwrap();
+        w.glyph_run(72 * 65536, 120 * 65536, &glyph_run()).unwrap();
+        w.text_and_glyphs(-5, 1 << 30, "Añ", &glyph_run()).unwrap();
+        w.end_page().unwrap();
+
+        w.begin_page(&[]).unwrap();
+        w.rule(10, 20, 30, 40).unwrap();
+        w.special(-200, 300_000, b"tdux:asp").unwrap();
+        w.end_page().unwrap();
+
+        w.max_page_dimensions(1000, 2000);
+        w.finish().unwrap()
+    }
+
+    fn parse(data: &[u8]) -> Vec<String> {
+        XdvParser::process(data, Recorder::default())
+            .unwrap()
+            .0
+            .events
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = build(FileType::Spx);
+        assert_eq!(data.len() % 4, 0);
+
+        let tfm = format!("fnt_def {:?}", tfm_font());
+        let native = format!("native_font_def {:?}", native_font());
+        let run = format!("{:?}", glyph_run());
+
+        assert_eq!(
+            parse(&data),
+            vec![
+                "header SPX [116, 101, 115, 116]".to_owned(),
+                tfm.clone(),
+                "bop [1, 2, 0, 0, 0, 0, 0, 0, 0, 0]".to_owned(),
+                native.clone(),
+                "font 300".to_owned(),
+                "chars 4718592 6553600 [65, 200, 70000]".to_owned(),
+                "font 2".to_owned(),
+                format!("glyphs ~4718592 7864320 {}", run),
+                format!("text ~-5 1073741824 \"Añ\" {}", run),
+                "eop".to_owned(),
+                "bop [0, 0, 0, 0, 0, 0, 0, 0, 0, 0]".to_owned(),
+                "rule 10 20 30 40".to_owned(),
+                "special -200 300000 [116, 100, 117, 120, 58, 97, 115, 112]".to_owned(),
+                "eop".to_owned(),
+                "post 1000 2000 2".to_owned(),
+                native,
+                tfm,
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_is_identical() {
+        let data = build(FileType::Xdv);
+        let (writer, _) = XdvParser::process(&data[..], XdvWriter::new(Vec::new())).unwrap();
+        assert_eq!(writer.finish().unwrap(), data);
+    }
+
+    /// Character runs must keep their spacing when a file is replayed, even
+    /// though neither the parser nor the writer knows how wide they are.
+    #[test]
+    fn replay_keeps_char_spacing() {
+        let mut page = vec![Opcode::DefineFont1 as u8, 0];
+        page.extend_from_slice(&[0; 12]);
+        page.extend_from_slice(&[0, 5]);
+        page.extend_from_slice(b"cmr10");
+        page.push(Opcode::SetFontNumber0 as u8);
+        page.extend_from_slice(b"Hello");
+        page.extend_from_slice(&[Opcode::Right1 as u8, 10]);
+        page.extend_from_slice(b"World");
+        page.extend_from_slice(&[Opcode::SetW1 as u8, 20]);
+        page.push(b'!');
+        page.push(Opcode::RightByW as u8);
+        page.push(b'?');
+        let data = xdv(&[&page]);
+
+        let (writer, _) = XdvParser::process(&data[..], XdvWriter::new(Vec::new())).unwrap();
+        let replayed = writer.finish().unwrap();
+
+        // Once the widths are known, the runs should end up in the same
+        // places in both files.
+        let positions = |data: &[u8]| {
+            let recorder = Recorder {
+                events: Vec::new(),
+                advance: Some(7),
+            };
+
+            XdvParser::process(data, recorder)
+                .unwrap()
+                .0
+                .events
+                .into_iter()
+                .filter(|e| e.starts_with("chars"))
+                .collect::<Vec<_>>()
+        };
+
+        let expected = vec![
+            "chars 0 0 [72, 101, 108, 108, 111]",
+            "chars 45 0 [87, 111, 114, 108, 100]",
+            "chars 100 0 [33]",
+            "chars 127 0 [63]",
+        ];
+
+        assert_eq!(positions(&data), expected);
+        assert_eq!(positions(&replayed), expected);
+    }
+
+    /// Popping the stack goes back to where the matching push was, however
+    /// wide the characters set in between.
+    #[test]
+    fn push_and_pop() {
+        let mut w = XdvWriter::new(Vec::new());
+        w.header(FileType::Xdv, b"").unwrap();
+        w.define_font(&tfm_font()).unwrap();
+        w.begin_page(&[]).unwrap();
+        w.select_font(300).unwrap();
+        w.push().unwrap();
+        w.char_run(10, 20, &[72, 105]).unwrap();
+        w.pop().unwrap();
+        w.char_run(30, 20, &[33]).unwrap();
+        assert!(w.pop().is_err());
+        w.push().unwrap();
+        assert!(w.end_page().is_err());
+        w.pop().unwrap();
+        w.end_page().unwrap();
+        let data = w.finish().unwrap();
+
+        let recorder = Recorder {
+            events: Vec::new(),
+            advance: Some(7),
+        };
+        let events = XdvParser::process(&data[..], recorder).unwrap().0.events;
+
+        assert!(events.contains(&"chars 10 20 [72, 105]".to_owned()));
+        assert!(events.contains(&"chars 30 20 [33]".to_owned()));
+    }
+
+    #[test]
+    fn misuse_is_an_error() {
+        let mut w = XdvWriter::new(Vec::new());
+        assert!(w.begin_page(&[]).is_err());
+        w.header(FileType::Xdv, b"").unwrap();
+        w.begin_page(&[]).unwrap();
+        assert!(w.char_run(0, 0, &[65]).is_err());
+        w.select_font(0).unwrap();
+        assert!(w.char_run(0, 0, &[65]).is_err());
+        assert!(w.finish().is_err());
+    }
+}
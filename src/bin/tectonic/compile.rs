@@ -18,8 +18,10 @@ use tectonic::{
     config::PersistentConfig,
     driver::{OutputFormat, PassSetting, ProcessingSessionBuilder},
     errmsg,
-    errors::{ErrorKind, Result},
-    status::StatusBackend,
+    errors::{ErrorKind, Result, SyncError},
+    page_selection::{Imposition, PageNumbering, PageSelection},
+    report::BuildReport,
+    status::{MessageKind, StatusBackend},
     tt_error, tt_note,
     unstable_opts::{UnstableArg, UnstableOptions},
 };
@@ -34,7 +36,7 @@ pub struct CompileOptions {
     #[structopt(long, short, name = "path", default_value = "latex")]
     format: String,
 
-    /// Use this directory or Zip-format bundle file to find resource files instead of the default
+    /// Use this directory, Zip-format or indexed tar bundle file to find resource files instead of the default
     #[structopt(takes_value(true), parse(from_os_str), long, short, name = "file_path")]
     bundle: Option<PathBuf>,
 
@@ -47,6 +49,10 @@ pub struct CompileOptions {
     #[structopt(short = "C", long)]
     only_cached: bool,
 
+    /// Check whether the web bundle has been updated, even if a check isn't due
+    #[structopt(long, conflicts_with = "only-cached")]
+    refresh: bool,
+
     /// The kind of output to generate
     #[structopt(long, name = "format", default_value = "pdf", possible_values(&["pdf", "html", "xdv", "aux", "fmt"]))]
     outfmt: String,
@@ -55,10 +61,26 @@ pub struct CompileOptions {
     #[structopt(long, name = "dest_path")]
     makefile_rules: Option<PathBuf>,
 
+    /// Write a JSON report on what happened during processing to <report_path>
+    #[structopt(long, name = "report_path", parse(from_os_str))]
+    report: Option<PathBuf>,
+
     /// Which engines to run
     #[structopt(long, default_value = "default", possible_values(&["default", "tex", "bibtex_first"]))]
     pass: String,
 
+    /// Only keep these pages of the output, e.g. "1,4-6,10-"
+    #[structopt(long, name = "ranges")]
+    pages: Option<String>,
+
+    /// How the pages given to --pages are numbered: by position, or by TeX's \count0
+    #[structopt(long, default_value = "physical", possible_values(&["physical", "count0"]))]
+    page_numbering: String,
+
+    /// Arrange the output pages on larger sheets, e.g. "2x2" for four pages per sheet
+    #[structopt(long = "n-up", name = "layout")]
+    n_up: Option<String>,
+
     /// Rerun the TeX engine exactly this many times after the first
     #[structopt(name = "count", long = "reruns", short = "r")]
     reruns: Option<usize>,
@@ -94,7 +116,11 @@ pub struct CompileOptions {
 }
 
 impl CompileOptions {
-    pub fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+    pub fn execute(
+        self,
+        mut config: PersistentConfig,
+        status: &mut dyn StatusBackend,
+    ) -> Result<i32> {
         let unstable = UnstableOptions::from_unstable_args(self.unstable.into_iter());
 
         let mut sess_builder = ProcessingSessionBuilder::default();
@@ -116,6 +142,15 @@ impl CompileOptions {
             sess_builder.reruns(s);
         }
 
+        if let Some(ranges) = self.pages {
+            let numbering = PageNumbering::from_str(&self.page_numbering).unwrap();
+            sess_builder.page_selection(PageSelection::new(numbering, &ranges)?);
+        }
+
+        if let Some(layout) = self.n_up {
+            sess_builder.imposition(Imposition::new(&layout)?);
+        }
+
         if let Some(p) = self.makefile_rules {
             sess_builder.makefile_output_path(p);
         }
@@ -175,6 +210,7 @@ impl CompileOptions {
         }
 
         let only_cached = self.only_cached;
+        config.refresh_bundles(self.refresh);
         if only_cached {
             tt_note!(status, "using only cached resource files");
         }
@@ -201,9 +237,35 @@ impl CompileOptions {
         let mut sess = sess_builder.create(status)?;
         let result = sess.run(status);
 
+        // Save the report even if processing failed, since that's when it's
+        // most interesting.
+        if let Some(ref p) = self.report {
+            if let Err(e) = write_report(sess.report(), p) {
+                if result.is_ok() {
+                    return Err(e);
+                }
+
+                status.report_error(&SyncError::new(e).into());
+            }
+        }
+
         if let Err(e) = &result {
             if let ErrorKind::EngineError(engine) = e.kind() {
-                if let Some(output) = sess.io.mem.files.borrow().get(sess.io.mem.stdout_key()) {
+                // If we can make sense of what TeX said, report the problems
+                // that it found rather than its whole output.
+                let diagnostics = if *engine == "TeX" {
+                    sess.tex_diagnostics()
+                } else {
+                    Vec::new()
+                };
+
+                if diagnostics.iter().any(|d| d.kind == MessageKind::Error) {
+                    for diag in &diagnostics {
+                        status.report_diagnostic(diag);
+                    }
+                } else if let Some(output) =
+                    sess.io.mem.files.borrow().get(sess.io.mem.stdout_key())
+                {
                     tt_error!(
                         status,
                         "something bad happened inside {}; its output follows:\n",
@@ -218,3 +280,15 @@ impl CompileOptions {
         result.map(|_| 0)
     }
 }
+
+#[cfg(feature = "serialization")]
+fn write_report(report: &BuildReport, path: &Path) -> Result<()> {
+    report.write_json(path)
+}
+
+#[cfg(not(feature = "serialization"))]
+fn write_report(_report: &BuildReport, _path: &Path) -> Result<()> {
+    Err(errmsg!(
+        "cannot write reports: Tectonic was built without the \"serialization\" feature"
+    ))
+}
@@ -33,6 +33,23 @@ mod v2cli {
     }
 }
 
+#[cfg(feature = "serialization")]
+fn json_status_backend(chatter_level: ChatterLevel) -> Box<dyn StatusBackend> {
+    Box::new(tectonic::status::json::JsonStatusBackend::new(
+        chatter_level,
+    ))
+}
+
+// JSON messages are also unavailable without serialization support.
+#[cfg(not(feature = "serialization"))]
+fn json_status_backend(_chatter_level: ChatterLevel) -> Box<dyn StatusBackend> {
+    eprintln!(
+        "fatal error: JSON messages require the code to have been built \
+        with the \"serialization\" Cargo feature active. This one wasn't."
+    );
+    process::exit(1);
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "Tectonic", about = "Process a (La)TeX document")]
 struct CliOptions {
@@ -44,6 +61,10 @@ struct CliOptions {
     #[structopt(long = "color", name = "when", default_value = "auto", possible_values(&["always", "auto", "never"]))]
     cli_color: String,
 
+    /// How to print messages: for humans, or as JSON objects for other programs
+    #[structopt(long = "message-format", name = "fmt", default_value = "human", possible_values(&["human", "json"]))]
+    message_format: String,
+
     #[structopt(flatten)]
     compile: compile::CompileOptions,
 }
@@ -121,7 +142,9 @@ fn main() {
         _ => unreachable!(),
     };
 
-    let mut status = if use_cli_color {
+    let mut status = if args.message_format == "json" {
+        json_status_backend(chatter_level)
+    } else if use_cli_color {
         Box::new(TermcolorStatusBackend::new(chatter_level)) as Box<dyn StatusBackend>
     } else {
         Box::new(PlainStatusBackend::new(chatter_level)) as Box<dyn StatusBackend>
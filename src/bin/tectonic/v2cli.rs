@@ -4,18 +4,39 @@
 //! The "v2cli" command-line interface -- a "multitool" interface resembling
 //! Cargo, as compared to the classic "rustc-like" CLI.
 
-use std::{ffi::OsString, path::PathBuf, process, str::FromStr};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
 use structopt::{clap::AppSettings, StructOpt};
 use tectonic::{
     self,
-    config::PersistentConfig,
+    config::{self, PersistentConfig},
     ctry,
+    document::{BuildOptions, Document},
+    errmsg,
     errors::{Result, SyncError},
-    status::{termcolor::TermcolorStatusBackend, ChatterLevel, StatusBackend},
-    tt_note,
+    io::{
+        bundle_creator::{BundleCreator, BundleFormat},
+        cached_itarbundle::{self, CachedITarBundle, UpdatePolicy},
+        format_cache, itarbundle, Bundle, IoProvider, OpenResult,
+    },
+    report::BuildReport,
+    status::{
+        json::JsonStatusBackend, termcolor::TermcolorStatusBackend, ChatterLevel, StatusBackend,
+    },
+    tt_note, tt_warning,
+    watch::WatchOptions,
     workspace::{self, Workspace},
 };
 use tectonic_status_base::plain::PlainStatusBackend;
+use url::Url;
 
 /// The main options for the "V2" command-line interface.
 #[derive(Debug, StructOpt)]
@@ -44,6 +65,15 @@ struct V2CliOptions {
     )]
     cli_color: String,
 
+    /// How to print messages: for humans, or as JSON objects for other programs
+    #[structopt(
+        long = "message-format",
+        name = "fmt",
+        default_value = "human",
+        possible_values(&["human", "json"])
+    )]
+    message_format: String,
+
     #[structopt(subcommand)]
     command: Commands,
 }
@@ -81,7 +111,9 @@ pub fn v2_main(effective_args: &[OsString]) {
         _ => unreachable!(),
     };
 
-    let mut status = if use_cli_color {
+    let mut status = if args.message_format == "json" {
+        Box::new(JsonStatusBackend::new(chatter_level)) as Box<dyn StatusBackend>
+    } else if use_cli_color {
         Box::new(TermcolorStatusBackend::new(chatter_level)) as Box<dyn StatusBackend>
     } else {
         Box::new(PlainStatusBackend::new(chatter_level)) as Box<dyn StatusBackend>
@@ -109,6 +141,14 @@ enum Commands {
     /// Build a document
     Build(BuildCommand),
 
+    #[structopt(name = "bundle")]
+    /// Commands relating to TeX file bundles
+    Bundle(BundleCommand),
+
+    #[structopt(name = "cache")]
+    /// Commands relating to the local cache of bundle files
+    Cache(CacheCommand),
+
     #[structopt(name = "compile")]
     /// Run a standalone (La)TeX compilation
     Compile(crate::compile::CompileOptions),
@@ -116,14 +156,21 @@ enum Commands {
     #[structopt(name = "new")]
     /// Create a new document
     New(NewCommand),
+
+    #[structopt(name = "watch")]
+    /// Build a document, then rebuild it whenever its inputs change
+    Watch(WatchCommand),
 }
 
 impl Commands {
     fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
         match self {
             Commands::Build(o) => o.execute(config, status),
+            Commands::Bundle(o) => o.execute(config, status),
+            Commands::Cache(o) => o.execute(config, status),
             Commands::Compile(o) => o.execute(config, status),
             Commands::New(o) => o.execute(config, status),
+            Commands::Watch(o) => o.execute(config, status),
         }
     }
 }
@@ -135,6 +182,10 @@ pub struct BuildCommand {
     #[structopt(short = "C", long)]
     only_cached: bool,
 
+    /// Check whether the bundle has been updated, even if a check isn't due
+    #[structopt(long, conflicts_with = "only-cached")]
+    refresh: bool,
+
     /// Keep the intermediate files generated during processing
     #[structopt(short, long)]
     keep_intermediates: bool,
@@ -143,6 +194,10 @@ pub struct BuildCommand {
     #[structopt(long)]
     keep_logs: bool,
 
+    /// Start from the intermediate files of the previous build, and save them for the next one
+    #[structopt(long)]
+    incremental: bool,
+
     /// Print the engine's chatter during processing
     #[structopt(long = "print", short)]
     print_stdout: bool,
@@ -150,22 +205,733 @@ pub struct BuildCommand {
     /// Open built document using system handler
     #[structopt(long)]
     open: bool,
+
+    /// Build only the document with this name, rather than all of the documents in the workspace
+    #[structopt(long = "doc", name = "name")]
+    doc: Option<String>,
+
+    /// Write a JSON report on each output that was built to <report_path>
+    #[structopt(long, name = "report_path", parse(from_os_str))]
+    report: Option<PathBuf>,
+}
+
+/// The report on building one output of a document, as saved by `build --report`.
+#[derive(Serialize)]
+struct OutputBuildReport<'a> {
+    document: &'a str,
+    output: &'a str,
+    report: BuildReport,
 }
 
 impl BuildCommand {
-    fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+    fn execute(self, mut config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        config.refresh_bundles(self.refresh);
         let ws = Workspace::open_from_environment()?;
-        let doc = ws.first_document();
-
-        for output_name in doc.output_names() {
-            let mut opts = doc.build_options_for(output_name);
-            opts.format_cache_path(config.format_cache_path()?)
-                .only_cached(self.only_cached)
-                .keep_intermediates(self.keep_intermediates)
-                .keep_logs(self.keep_logs)
-                .print_stdout(self.print_stdout)
-                .open(self.open);
-            doc.build(output_name, &opts, status)?;
+        let docs = select_documents(&ws, self.doc.as_deref())?;
+        let mut reports = Vec::new();
+        let result = self.build_documents(&docs, &ws, &config, &mut reports, status);
+
+        // Save the report even if the build failed, since that's when it's
+        // most interesting.
+        if let Some(ref path) = self.report {
+            if let Err(e) = save_build_reports(path, &reports) {
+                if result.is_ok() {
+                    return Err(e);
+                }
+
+                status.report_error(&SyncError::new(e).into());
+            }
+        }
+
+        result.map(|_| 0)
+    }
+
+    fn build_documents<'a>(
+        &self,
+        docs: &[&'a Document],
+        ws: &Workspace,
+        config: &PersistentConfig,
+        reports: &mut Vec<OutputBuildReport<'a>>,
+        status: &mut dyn StatusBackend,
+    ) -> Result<()> {
+        for doc in docs {
+            if ws.documents().len() > 1 {
+                tt_note!(status, "building document `{}`", doc.name());
+            }
+
+            for output_name in doc.output_names() {
+                let mut opts = doc.build_options_for(output_name);
+                opts.format_cache_path(config.format_cache_path()?)
+                    .only_cached(self.only_cached)
+                    .keep_intermediates(self.keep_intermediates)
+                    .keep_logs(self.keep_logs)
+                    .incremental(self.incremental)
+                    .print_stdout(self.print_stdout)
+                    .open(self.open)
+                    .network_config(config.network_config())
+                    .bundle_update_policy(config.bundle_update_policy());
+
+                if self.report.is_some() {
+                    let mut report = BuildReport::default();
+                    let result = doc.build_with_report(output_name, &opts, &mut report, status);
+                    reports.push(OutputBuildReport {
+                        document: doc.name(),
+                        output: output_name,
+                        report,
+                    });
+                    result?;
+                } else {
+                    doc.build(output_name, &opts, status)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `watch`: Build a document, then rebuild it whenever its inputs change
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct WatchCommand {
+    /// Use only resource files cached locally
+    #[structopt(short = "C", long)]
+    only_cached: bool,
+
+    /// Keep the intermediate files generated during processing
+    #[structopt(short, long)]
+    keep_intermediates: bool,
+
+    /// Keep the log files generated during processing
+    #[structopt(long)]
+    keep_logs: bool,
+
+    /// Start from the intermediate files of the previous build, and save them for the next one
+    #[structopt(long)]
+    incremental: bool,
+
+    /// Print the engine's chatter during processing
+    #[structopt(long = "print", short)]
+    print_stdout: bool,
+
+    /// Watch the document with this name; needed if the workspace has several documents
+    #[structopt(long = "doc", name = "name")]
+    doc: Option<String>,
+}
+
+impl WatchCommand {
+    fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        let mut ws = Workspace::open_from_environment()?;
+
+        let doc = match self.doc {
+            Some(ref name) => match ws.document_mut(name) {
+                Some(doc) => doc,
+                None => return Err(errmsg!("no document named `{}` in this workspace", name)),
+            },
+            None => {
+                if ws.documents().len() > 1 {
+                    return Err(errmsg!(
+                        "this workspace has several documents; use `--doc` to choose one to watch"
+                    ));
+                }
+
+                ws.first_document_mut()
+            }
+        };
+
+        let mut opts = BuildOptions::default();
+        opts.format_cache_path(config.format_cache_path()?)
+            .only_cached(self.only_cached)
+            .keep_intermediates(self.keep_intermediates)
+            .keep_logs(self.keep_logs)
+            .incremental(self.incremental)
+            .print_stdout(self.print_stdout)
+            .network_config(config.network_config())
+            .bundle_update_policy(config.bundle_update_policy());
+
+        // We run until the user interrupts us.
+        doc.watch(&opts, &WatchOptions::default(), status, || true)?;
+        Ok(0)
+    }
+}
+
+/// `bundle`: Commands relating to TeX file bundles
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct BundleCommand {
+    #[structopt(subcommand)]
+    command: BundleCommands,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+enum BundleCommands {
+    #[structopt(name = "cat")]
+    /// Print a file from a bundle
+    Cat(BundleCatCommand),
+
+    #[structopt(name = "create")]
+    /// Create a bundle from a tree of TeX support files
+    Create(BundleCreateCommand),
+
+    #[structopt(name = "fetch")]
+    /// Download bundle files into the local cache
+    Fetch(BundleFetchCommand),
+
+    #[structopt(name = "ls")]
+    /// List the files in a bundle
+    Ls(BundleLsCommand),
+
+    #[structopt(name = "pin")]
+    /// Record the digest of the document's bundle in Tectonic.toml
+    Pin(BundlePinCommand),
+
+    #[structopt(name = "search")]
+    /// Find files in a bundle by name
+    Search(BundleSearchCommand),
+}
+
+impl BundleCommand {
+    fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        match self.command {
+            BundleCommands::Cat(o) => o.execute(config, status),
+            BundleCommands::Create(o) => o.execute(config, status),
+            BundleCommands::Fetch(o) => o.execute(config, status),
+            BundleCommands::Ls(o) => o.execute(config, status),
+            BundleCommands::Pin(o) => o.execute(config, status),
+            BundleCommands::Search(o) => o.execute(config, status),
+        }
+    }
+}
+
+/// Select the documents of a workspace that a command acts on: the one
+/// named with `--doc`, if any, or else all of them.
+fn select_documents<'a>(ws: &'a Workspace, name: Option<&str>) -> Result<Vec<&'a Document>> {
+    match name {
+        Some(name) => match ws.document(name) {
+            Some(doc) => Ok(vec![doc]),
+            None => Err(errmsg!("no document named `{}` in this workspace", name)),
+        },
+        None => Ok(ws.documents().iter().collect()),
+    }
+}
+
+/// Save the reports from `build --report` as a JSON array.
+fn save_build_reports(path: &Path, reports: &[OutputBuildReport]) -> Result<()> {
+    let file = ctry!(File::create(path); "failed to create `{}`", path.display());
+    let mut writer = BufWriter::new(file);
+    ctry!(serde_json::to_writer_pretty(&mut writer, reports); "failed to write `{}`", path.display());
+    ctry!(writer.flush(); "failed to write `{}`", path.display());
+    Ok(())
+}
+
+/// Get the locations of the bundles used by the documents of a workspace,
+/// without duplicates.
+fn workspace_bundle_locs(ws: &Workspace) -> Vec<String> {
+    let mut locs: Vec<String> = Vec::new();
+
+    for doc in ws.documents() {
+        if !locs.iter().any(|l| l == doc.bundle_loc()) {
+            locs.push(doc.bundle_loc().to_owned());
+        }
+    }
+
+    locs
+}
+
+/// Get the locations of the bundles that bundle-related commands should act
+/// on: the one given on the command line, or else the ones used by the
+/// current workspace, or else the configured defaults.
+fn bundle_locs(web_bundle: Option<String>, config: &PersistentConfig) -> Vec<String> {
+    if let Some(url) = web_bundle {
+        vec![url]
+    } else if let Ok(ws) = Workspace::open_from_environment() {
+        workspace_bundle_locs(&ws)
+    } else {
+        config.default_bundle_locs().map(|s| s.to_owned()).collect()
+    }
+}
+
+/// Test whether a bundle location refers to a web bundle, as opposed to a
+/// local file or directory.
+fn is_web_bundle(loc: &str) -> bool {
+    Url::parse(loc)
+        .map(|u| u.scheme() != "file")
+        .unwrap_or(false)
+}
+
+/// Options for choosing the bundle that a bundle inspection command acts on.
+#[derive(Debug, PartialEq, StructOpt)]
+struct BundleChoice {
+    /// Use this directory, Zip-format or indexed tar bundle file instead of the default
+    #[structopt(long, short, parse(from_os_str), name = "file_path")]
+    bundle: Option<PathBuf>,
+
+    /// Use this URL to find resource files instead of the default
+    #[structopt(
+        takes_value(true),
+        long,
+        short,
+        name = "url",
+        conflicts_with = "file_path"
+    )]
+    web_bundle: Option<String>,
+}
+
+impl BundleChoice {
+    /// Open the chosen bundle: the one given on the command line, or else
+    /// the one used by the current document, or else the default.
+    fn open(
+        self,
+        config: &PersistentConfig,
+        status: &mut dyn StatusBackend,
+    ) -> Result<Box<dyn Bundle>> {
+        if let Some(path) = self.bundle {
+            config.make_local_file_provider(path, status)
+        } else if let Some(url) = self.web_bundle {
+            config.make_cached_url_provider(&url, false, None, status)
+        } else if let Ok(ws) = Workspace::open_from_environment() {
+            ws.first_document().bundle(
+                &config.network_config(),
+                config.bundle_update_policy(),
+                false,
+                status,
+            )
+        } else {
+            config.default_bundle(false, status)
+        }
+    }
+}
+
+/// Test whether a name matches a glob pattern, in which `*` matches any
+/// sequence of characters and `?` matches any single character.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+
+    // The position just after the most recent `*` in the pattern, and the
+    // position in the name that it has been matched up to.
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    p = bp;
+                    n = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// `bundle cat`: Print a file from a bundle
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct BundleCatCommand {
+    #[structopt(flatten)]
+    choice: BundleChoice,
+
+    /// The name of the file to print
+    #[structopt(name = "name")]
+    name: String,
+}
+
+impl BundleCatCommand {
+    fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        let name = self.name;
+        let mut bundle = self.choice.open(&config, status)?;
+
+        let mut handle = match bundle.input_open_name(&name, status) {
+            OpenResult::Ok(h) => h,
+            OpenResult::NotAvailable => {
+                return Err(errmsg!("no file named `{}` in the bundle", name))
+            }
+            OpenResult::Err(e) => return Err(e.into()),
+        };
+
+        let stdout = io::stdout();
+        ctry!(io::copy(&mut handle, &mut stdout.lock()); "failed to print `{}`", name);
+        Ok(0)
+    }
+}
+
+/// `bundle create`: Create a bundle from a tree of TeX support files
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct BundleCreateCommand {
+    /// The kind of bundle to create [default: based on the output file's extension]
+    #[structopt(long, possible_values(&["itar", "zip"]))]
+    format: Option<BundleFormat>,
+
+    /// The directory containing the support files
+    #[structopt(parse(from_os_str))]
+    source: PathBuf,
+
+    /// The bundle file to create
+    #[structopt(parse(from_os_str))]
+    output: PathBuf,
+}
+
+impl BundleCreateCommand {
+    fn execute(self, _config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        let format = match self.format {
+            Some(f) => f,
+            None if itarbundle::is_itar_path(&self.output) => BundleFormat::ITar,
+            None if self.output.extension().map(|e| e == "zip").unwrap_or(false) => {
+                BundleFormat::Zip
+            }
+            None => {
+                return Err(errmsg!(
+                    "cannot tell what kind of bundle to create from the name `{}`; use --format",
+                    self.output.display()
+                ))
+            }
+        };
+
+        let creator = BundleCreator::from_tree(&self.source, status)?;
+
+        if creator.is_empty() {
+            return Err(errmsg!("no files found in `{}`", self.source.display()));
+        }
+
+        let data =
+            ctry!(File::create(&self.output); "failed to create `{}`", self.output.display());
+
+        let digest = match format {
+            BundleFormat::Zip => creator.write_zip(BufWriter::new(data))?,
+            BundleFormat::ITar => {
+                let mut index_path = self.output.clone().into_os_string();
+                index_path.push(".index.gz");
+                let index = ctry!(File::create(&index_path); "failed to create `{}`", index_path.to_string_lossy());
+                creator.write_itar(BufWriter::new(data), BufWriter::new(index))?
+            }
+        };
+
+        tt_note!(
+            status,
+            "created bundle `{}` with {} files and digest {}",
+            self.output.display(),
+            creator.len() + 1,
+            digest.to_string()
+        );
+        Ok(0)
+    }
+}
+
+/// `bundle fetch`: Download bundle files into the local cache
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct BundleFetchCommand {
+    /// Use this URL to find resource files instead of the default
+    #[structopt(takes_value(true), long, short, name = "url")]
+    web_bundle: Option<String>,
+
+    /// Files or packages to fetch [default: the entire bundle]
+    #[structopt(name = "name")]
+    names: Vec<String>,
+}
+
+impl BundleFetchCommand {
+    fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        let mut unmatched: HashSet<&String> = self.names.iter().collect();
+
+        for loc in bundle_locs(self.web_bundle.clone(), &config) {
+            // Only web bundles have a cache to fill.
+            if !is_web_bundle(&loc) {
+                tt_note!(status, "skipping local bundle `{}`", loc);
+                continue;
+            }
+
+            tt_note!(status, "fetching files from bundle `{}`", loc);
+            let mut bundle = CachedITarBundle::new(
+                &loc,
+                false,
+                config.bundle_update_policy(),
+                None,
+                &config.network_config(),
+                status,
+            )?;
+
+            let files = if self.names.is_empty() {
+                bundle.all_files(status)?
+            } else {
+                let mut files = Vec::new();
+
+                // With several bundles, a name only needs to match in one.
+                for name in &self.names {
+                    if let Ok(mut f) = bundle.select_files(std::slice::from_ref(name)) {
+                        unmatched.remove(name);
+                        files.append(&mut f);
+                    }
+                }
+
+                files
+            };
+
+            bundle.fetch_files(&files, status)?;
+        }
+
+        if let Some(name) = unmatched.into_iter().next() {
+            return Err(errmsg!(
+                "no file or package named \"{}\" in the bundle",
+                name
+            ));
+        }
+
+        Ok(0)
+    }
+}
+
+/// `bundle ls`: List the files in a bundle
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct BundleLsCommand {
+    #[structopt(flatten)]
+    choice: BundleChoice,
+}
+
+impl BundleLsCommand {
+    fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        let mut names = self.choice.open(&config, status)?.all_files(status)?;
+        names.sort_unstable();
+
+        for name in names {
+            println!("{}", name);
+        }
+
+        Ok(0)
+    }
+}
+
+/// `bundle pin`: Record the digest of the document's bundle in Tectonic.toml
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct BundlePinCommand {
+    /// Check whether the bundle has been updated first, even if a check isn't due
+    #[structopt(long)]
+    refresh: bool,
+
+    /// Pin only the document with this name, rather than all of the documents in the workspace
+    #[structopt(long = "doc", name = "name")]
+    doc: Option<String>,
+}
+
+impl BundlePinCommand {
+    fn execute(self, mut config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        config.refresh_bundles(self.refresh);
+        let mut ws = Workspace::open_from_environment()?;
+
+        // Check the name before changing anything.
+        select_documents(&ws, self.doc.as_deref())?;
+
+        for doc in ws.documents_mut() {
+            if let Some(ref name) = self.doc {
+                if doc.name() != name {
+                    continue;
+                }
+            }
+
+            let digest = doc
+                .bundle(
+                    &config.network_config(),
+                    config.bundle_update_policy(),
+                    false,
+                    status,
+                )?
+                .get_digest(status)?;
+
+            doc.pin_bundle_digest(digest)?;
+            tt_note!(
+                status,
+                "pinned bundle `{}` of document `{}` with digest {}",
+                doc.bundle_loc(),
+                doc.name(),
+                digest.to_string()
+            );
+        }
+
+        Ok(0)
+    }
+}
+
+/// `bundle search`: Find files in a bundle by name
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct BundleSearchCommand {
+    #[structopt(flatten)]
+    choice: BundleChoice,
+
+    /// The pattern to match, in which `*` matches any text and `?` any character
+    #[structopt(name = "pattern")]
+    pattern: String,
+}
+
+impl BundleSearchCommand {
+    fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        let pattern = self.pattern;
+        let mut names = self.choice.open(&config, status)?.all_files(status)?;
+        names.retain(|n| glob_matches(&pattern, n));
+        names.sort_unstable();
+
+        if names.is_empty() {
+            return Err(errmsg!("no files in the bundle match `{}`", pattern));
+        }
+
+        for name in names {
+            println!("{}", name);
+        }
+
+        Ok(0)
+    }
+}
+
+/// `cache`: Commands relating to the local cache of bundle files
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct CacheCommand {
+    #[structopt(subcommand)]
+    command: CacheCommands,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+enum CacheCommands {
+    #[structopt(name = "gc")]
+    /// Remove cached data that the configured bundles no longer use
+    Gc(CacheGcCommand),
+
+    #[structopt(name = "verify")]
+    /// Check cached files for corruption and download them again if needed
+    Verify(CacheVerifyCommand),
+}
+
+impl CacheCommand {
+    fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        match self.command {
+            CacheCommands::Gc(o) => o.execute(config, status),
+            CacheCommands::Verify(o) => o.execute(config, status),
+        }
+    }
+}
+
+/// `cache gc`: Remove cached data that the configured bundles no longer use
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct CacheGcCommand {}
+
+impl CacheGcCommand {
+    fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        if config::is_config_test_mode_activated() {
+            return Err(errmsg!("cannot clean up the cache in test mode"));
+        }
+
+        // Format files are keyed by the digest of the complete bundle that
+        // made them, which we can only compute offline if all of its web
+        // bundles are cached. If they aren't, there are no formats to keep.
+
+        let all_cached = |locs: &[String]| -> Result<bool> {
+            for loc in locs {
+                if is_web_bundle(loc)
+                    && cached_itarbundle::cached_bundle_digest(loc, None)?.is_none()
+                {
+                    return Ok(false);
+                }
+            }
+
+            Ok(true)
+        };
+
+        let mut live_locs: Vec<String> =
+            config.default_bundle_locs().map(|s| s.to_owned()).collect();
+        let mut format_digests = Vec::new();
+
+        if all_cached(&live_locs)? {
+            format_digests.push(config.default_bundle(true, status)?.get_digest(status)?);
+        }
+
+        if let Ok(ws) = Workspace::open_from_environment() {
+            for doc in ws.documents() {
+                let loc = doc.bundle_loc().to_owned();
+
+                if all_cached(std::slice::from_ref(&loc))? {
+                    format_digests.push(
+                        doc.bundle(&config.network_config(), UpdatePolicy::Never, true, status)?
+                            .get_digest(status)?,
+                    );
+                }
+
+                live_locs.push(loc);
+            }
+        }
+
+        let live_urls: Vec<&str> = live_locs
+            .iter()
+            .map(|s| s.as_str())
+            .filter(|s| is_web_bundle(s))
+            .collect();
+        cached_itarbundle::gc_cache(&live_urls, None, status)?;
+
+        let (n_formats, n_bytes) =
+            format_cache::remove_unused_formats(&config.format_cache_path()?, &format_digests)?;
+        tt_note!(
+            status,
+            "removed {} unused format files ({:.1} MiB)",
+            n_formats,
+            n_bytes as f64 / 1048576.
+        );
+
+        Ok(0)
+    }
+}
+
+/// `cache verify`: Check cached files for corruption
+#[derive(Debug, PartialEq, StructOpt)]
+pub struct CacheVerifyCommand {}
+
+impl CacheVerifyCommand {
+    fn execute(self, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<i32> {
+        let evicted = cached_itarbundle::verify_cache(None, status)?;
+
+        // Repair the damage to the bundles that are in use. Files of other
+        // bundles will be downloaded again if they are ever needed.
+
+        for loc in bundle_locs(None, &config) {
+            if !is_web_bundle(&loc) {
+                continue;
+            }
+
+            let names = match cached_itarbundle::cached_bundle_digest(&loc, None)? {
+                Some(d) => match evicted.get(&d.to_string()) {
+                    Some(names) => names,
+                    None => continue,
+                },
+                None => continue,
+            };
+
+            tt_note!(
+                status,
+                "downloading {} evicted files again from `{}`",
+                names.len(),
+                loc
+            );
+
+            // Don't switch to a newer bundle here, since the evicted files
+            // belong to the one that the cache currently points to.
+            let result = CachedITarBundle::new(
+                &loc,
+                false,
+                UpdatePolicy::Never,
+                None,
+                &config.network_config(),
+                status,
+            )
+            .and_then(|mut bundle| bundle.fetch_files(names, status));
+
+            if let Err(e) = result {
+                tt_warning!(status, "failed to repair the cache; the files will be downloaded when they are needed"; e);
+            }
         }
 
         Ok(0)
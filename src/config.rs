@@ -15,14 +15,18 @@ use serde::{Deserialize, Serialize};
 use std::{
     path::{Path, PathBuf},
     sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
+use tectonic_geturl::{GetUrlConfig, Mirror, UrlHeader};
 use url::Url;
 
 use crate::{
     app_dirs,
     errors::{ErrorKind, Result},
-    io::cached_itarbundle::CachedITarBundle,
+    io::cached_itarbundle::{CachedITarBundle, UpdatePolicy},
     io::dirbundle::DirBundle,
+    io::itarbundle::{is_itar_path, ITarBundle},
+    io::layeredbundle::LayeredBundle,
     io::zipbundle::ZipBundle,
     io::Bundle,
     status::StatusBackend,
@@ -49,6 +53,40 @@ pub fn is_config_test_mode_activated() -> bool {
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct PersistentConfig {
     default_bundles: Vec<BundleInfo>,
+
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "NetworkInfo::is_default")
+    )]
+    network: NetworkInfo,
+
+    /// How often to check whether web bundles have been updated, in days.
+    /// Zero disables the checks.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            default = "default_bundle_update_days",
+            skip_serializing_if = "is_default_bundle_update_days"
+        )
+    )]
+    bundle_update_days: u64,
+
+    /// Whether to check for bundle updates regardless of the schedule. This
+    /// is set from the command line rather than the configuration file.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    refresh_bundles: bool,
+}
+
+const DEFAULT_BUNDLE_UPDATE_DAYS: u64 = 7;
+
+#[cfg(feature = "serde")]
+fn default_bundle_update_days() -> u64 {
+    DEFAULT_BUNDLE_UPDATE_DAYS
+}
+
+#[cfg(feature = "serde")]
+fn is_default_bundle_update_days(days: &u64) -> bool {
+    *days == DEFAULT_BUNDLE_UPDATE_DAYS
 }
 
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
@@ -56,6 +94,55 @@ pub struct BundleInfo {
     url: String,
 }
 
+/// Settings for network access, such as fetching web bundles.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize), serde(default))]
+#[derive(Default)]
+pub struct NetworkInfo {
+    /// A proxy to send all requests through.
+    proxy: Option<String>,
+
+    /// A PEM file of extra certificate authorities to trust.
+    ca_certificates: Option<PathBuf>,
+
+    /// The connection timeout, in seconds.
+    connect_timeout: Option<u64>,
+
+    /// The timeout for whole requests, in seconds.
+    timeout: Option<u64>,
+
+    /// Extra headers to send with requests.
+    headers: Vec<HeaderInfo>,
+
+    /// Alternative locations for web resources.
+    mirrors: Vec<MirrorInfo>,
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct HeaderInfo {
+    #[cfg_attr(feature = "serde", serde(default))]
+    url_prefix: String,
+    name: String,
+    value: String,
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct MirrorInfo {
+    url_prefix: String,
+    mirrors: Vec<String>,
+}
+
+impl NetworkInfo {
+    #[cfg(feature = "serde")]
+    fn is_default(&self) -> bool {
+        self.proxy.is_none()
+            && self.ca_certificates.is_none()
+            && self.connect_timeout.is_none()
+            && self.timeout.is_none()
+            && self.headers.is_empty()
+            && self.mirrors.is_empty()
+    }
+}
+
 impl PersistentConfig {
     #[cfg(feature = "serialization")]
     /// Open the per-user configuration file.
@@ -124,7 +211,14 @@ impl PersistentConfig {
         custom_cache_root: Option<&Path>,
         status: &mut dyn StatusBackend,
     ) -> Result<Box<dyn Bundle>> {
-        let bundle = CachedITarBundle::new(url, only_cached, custom_cache_root, status)?;
+        let bundle = CachedITarBundle::new(
+            url,
+            only_cached,
+            self.bundle_update_policy(),
+            custom_cache_root,
+            &self.network_config(),
+            status,
+        )?;
 
         Ok(Box::new(bundle) as _)
     }
@@ -136,46 +230,128 @@ impl PersistentConfig {
     ) -> Result<Box<dyn Bundle>> {
         let bundle: Box<dyn Bundle> = if file_path.is_dir() {
             Box::new(DirBundle::new(file_path))
+        } else if is_itar_path(&file_path) {
+            Box::new(ITarBundle::open(file_path)?)
         } else {
             Box::new(ZipBundle::open(file_path)?)
         };
         Ok(bundle)
     }
 
+    /// Get the settings to use for network access.
+    pub fn network_config(&self) -> GetUrlConfig {
+        let net = &self.network;
+
+        GetUrlConfig {
+            proxy: net.proxy.clone(),
+            ca_certificates: net.ca_certificates.clone(),
+            headers: net
+                .headers
+                .iter()
+                .map(|h| UrlHeader {
+                    url_prefix: h.url_prefix.clone(),
+                    name: h.name.clone(),
+                    value: h.value.clone(),
+                })
+                .collect(),
+            mirrors: net
+                .mirrors
+                .iter()
+                .map(|m| Mirror {
+                    url_prefix: m.url_prefix.clone(),
+                    mirror_prefixes: m.mirrors.clone(),
+                })
+                .collect(),
+            connect_timeout: net.connect_timeout.map(Duration::from_secs),
+            timeout: net.timeout.map(Duration::from_secs),
+        }
+    }
+
+    /// Get the policy for checking whether web bundles have been updated.
+    pub fn bundle_update_policy(&self) -> UpdatePolicy {
+        if self.refresh_bundles {
+            UpdatePolicy::Always
+        } else if self.bundle_update_days == 0 {
+            UpdatePolicy::Never
+        } else {
+            UpdatePolicy::Interval(Duration::from_secs(self.bundle_update_days * 24 * 60 * 60))
+        }
+    }
+
+    /// Check whether web bundles have been updated every time that they are
+    /// opened, regardless of the configured schedule.
+    pub fn refresh_bundles(&mut self, value: bool) -> &mut Self {
+        self.refresh_bundles = value;
+        self
+    }
+
+    /// Get the location of the first default bundle.
     pub fn default_bundle_loc(&self) -> &str {
         &self.default_bundles[0].url
     }
 
+    /// Get the locations of all of the default bundles, in order.
+    pub fn default_bundle_locs(&self) -> impl Iterator<Item = &str> {
+        self.default_bundles.iter().map(|b| b.url.as_str())
+    }
+
+    /// Create the default bundle.
+    ///
+    /// If more than one default bundle is configured, they are combined into
+    /// a single bundle that searches them in the order in which they are
+    /// listed, so that files in the earlier bundles take precedence.
     pub fn default_bundle(
         &self,
         only_cached: bool,
         status: &mut dyn StatusBackend,
     ) -> Result<Box<dyn Bundle>> {
-        use std::io;
-
         if CONFIG_TEST_MODE_ACTIVATED.load(Ordering::SeqCst) {
             return Ok(Box::new(crate::test_util::TestBundle::default()));
         }
 
-        if self.default_bundles.len() != 1 {
+        if self.default_bundles.is_empty() {
             return Err(ErrorKind::Msg(
-                "exactly one default_bundle item must be specified (for now)".to_owned(),
+                "at least one default_bundle item must be specified".to_owned(),
             )
             .into());
         }
 
-        let url = Url::parse(&self.default_bundles[0].url)
+        let mut bundles = Vec::with_capacity(self.default_bundles.len());
+
+        for info in &self.default_bundles {
+            bundles.push(self.make_bundle_from_url(&info.url, only_cached, status)?);
+        }
+
+        if bundles.len() == 1 {
+            return Ok(bundles.pop().unwrap());
+        }
+
+        Ok(Box::new(LayeredBundle::new(bundles)))
+    }
+
+    /// Create a bundle from a URL in the configuration file. `file:` URLs
+    /// refer to local Zip files or directories; anything else is treated as a
+    /// web bundle.
+    fn make_bundle_from_url(
+        &self,
+        url: &str,
+        only_cached: bool,
+        status: &mut dyn StatusBackend,
+    ) -> Result<Box<dyn Bundle>> {
+        use std::io;
+
+        let parsed = Url::parse(url)
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "failed to parse url"))?;
-        if url.scheme() == "file" {
-            // load the local zip file.
-            let file_path = url.to_file_path().map_err(|_| {
+
+        if parsed.scheme() == "file" {
+            // load the local zip file or directory.
+            let file_path = parsed.to_file_path().map_err(|_| {
                 io::Error::new(io::ErrorKind::InvalidInput, "failed to parse local path")
             })?;
             return self.make_local_file_provider(file_path, status);
         }
-        let bundle =
-            self.make_cached_url_provider(&self.default_bundles[0].url, only_cached, None, status)?;
-        Ok(Box::new(bundle) as _)
+
+        self.make_cached_url_provider(url, only_cached, None, status)
     }
 
     pub fn format_cache_path(&self) -> Result<PathBuf> {
@@ -193,6 +369,9 @@ impl Default for PersistentConfig {
             default_bundles: vec![BundleInfo {
                 url: String::from("https://archive.org/services/purl/net/pkgwpub/tectonic-default"),
             }],
+            network: NetworkInfo::default(),
+            bundle_update_days: DEFAULT_BUNDLE_UPDATE_DAYS,
+            refresh_bundles: false,
         }
     }
 }
@@ -5,21 +5,32 @@
 
 use std::{
     collections::HashMap,
-    env, fs,
+    env,
+    fs::{self, File},
     io::{self, Read, Write},
     path::{Component, Path, PathBuf},
+    str::FromStr,
 };
-use tectonic_geturl::{DefaultBackend, GetUrlBackend};
+use tectonic_geturl::{DefaultBackend, GetUrlBackend, GetUrlConfig};
 use url::Url;
 
 use crate::{
     config, ctry,
+    digest::DigestData,
     driver::{OutputFormat, PassSetting, ProcessingSessionBuilder},
     errmsg,
-    errors::{ErrorKind, Result},
-    io::{cached_itarbundle::CachedITarBundle, dirbundle::DirBundle, zipbundle::ZipBundle, Bundle},
-    status::StatusBackend,
-    test_util, tt_error, tt_note,
+    errors::{ErrorKind, Result, SyncError},
+    io::{
+        cached_itarbundle::{CachedITarBundle, UpdatePolicy},
+        dirbundle::DirBundle,
+        itarbundle::{self, ITarBundle},
+        zipbundle::ZipBundle,
+        Bundle,
+    },
+    report::BuildReport,
+    status::{MessageKind, StatusBackend},
+    test_util, tt_error, tt_note, tt_warning,
+    watch::{InputSet, WatchOptions},
     workspace::WorkspaceCreator,
 };
 
@@ -42,6 +53,12 @@ pub struct Document {
     /// Either a URL or a local path.
     bundle_loc: String,
 
+    /// The expected digest of the bundle, as computed by
+    /// [`Bundle::get_digest`]. If it is set, builds fail when the bundle
+    /// doesn't match it, so that everyone who builds the document uses the
+    /// same support files.
+    bundle_digest: Option<DigestData>,
+
     /// The different outputs that are created from the document source. These
     /// may have different formats (e.g., PDF and HTML) or the same format but
     /// different settings (e.g., PDF with A4 paper and PDF with US Letter
@@ -92,11 +109,20 @@ impl Document {
             ));
         }
 
+        let bundle_digest = match doc.doc.bundle_digest {
+            Some(ref text) => Some(ctry!(
+                DigestData::from_str(text);
+                "invalid bundle digest `{}` in TOML specification", text
+            )),
+            None => None,
+        };
+
         Ok(Document {
             src_dir,
             build_dir,
             name: doc.doc.name,
             bundle_loc: doc.doc.bundle,
+            bundle_digest,
             outputs,
         })
     }
@@ -152,18 +178,46 @@ impl Document {
         let bundle_loc = if config::is_config_test_mode_activated() {
             "test-bundle".to_owned()
         } else {
-            let mut gub = DefaultBackend::default();
+            let mut gub = DefaultBackend::with_config(config.network_config());
             gub.resolve_url(config.default_bundle_loc(), status)?
         };
 
-        // All done.
-        Ok(Document {
+        let mut doc = Document {
             src_dir,
             build_dir,
             name,
             bundle_loc,
+            bundle_digest: None,
             outputs: default_outputs(),
-        })
+        };
+
+        // Pin the bundle's current contents, so that everyone who builds the
+        // document uses the same support files. Getting the digest may need
+        // the network, though, and that's no reason to fail to create the
+        // document.
+        let digest = match doc.bundle(
+            &config.network_config(),
+            config.bundle_update_policy(),
+            false,
+            status,
+        ) {
+            Ok(mut bundle) => bundle.get_digest(status),
+            Err(e) => Err(SyncError::new(e).into()),
+        };
+
+        match digest {
+            Ok(d) => doc.bundle_digest = Some(d),
+            Err(e) => {
+                tt_warning!(status, "couldn't get the bundle's digest to pin it in Tectonic.toml"; e);
+                tt_note!(
+                    status,
+                    "run `tectonic -X bundle pin` in the new workspace to pin it later"
+                );
+            }
+        }
+
+        // All done.
+        Ok(doc)
     }
 
     /// Write out this document's state as a new TOML file. This should only be
@@ -180,6 +234,7 @@ impl Document {
             doc: syntax::DocSection {
                 name: self.name.clone(),
                 bundle: self.bundle_loc.clone(),
+                bundle_digest: self.bundle_digest.map(|d| d.to_string()),
             },
             outputs,
         };
@@ -199,6 +254,87 @@ impl Document {
         toml_file.write_all(toml_text.as_bytes())?;
         Ok(())
     }
+
+    /// Record the expected digest of this document's bundle in its
+    /// `Tectonic.toml` file, so that later builds check the bundle against
+    /// it. Only the `bundle_digest` item of the file is changed, so that
+    /// comments and formatting elsewhere are preserved.
+    pub fn pin_bundle_digest(&mut self, digest: DigestData) -> Result<()> {
+        let mut toml_path = self.src_dir.clone();
+        toml_path.push("Tectonic.toml");
+
+        let toml_text = ctry!(
+            fs::read_to_string(&toml_path);
+            "couldn\'t read `{}`", toml_path.display()
+        );
+
+        let toml_text = match set_doc_item(
+            &toml_text,
+            "bundle_digest",
+            &format!("\"{}\"", digest.to_string()),
+        ) {
+            Some(t) => t,
+            None => {
+                return Err(errmsg!(
+                    "couldn\'t find the `[doc]` section of `{}`",
+                    toml_path.display()
+                ))
+            }
+        };
+
+        // Make sure that the edit didn't break anything.
+        let _: syntax::Document = toml::from_str(&toml_text)?;
+
+        ctry!(
+            fs::write(&toml_path, toml_text);
+            "couldn\'t write `{}`", toml_path.display()
+        );
+        self.bundle_digest = Some(digest);
+        Ok(())
+    }
+}
+
+/// Set an item in the `[doc]` section of the text of a `Tectonic.toml` file,
+/// replacing any existing value and otherwise adding it to the end of the
+/// section. *value* must already be in TOML syntax. Returns `None` if there
+/// is no `[doc]` section.
+fn set_doc_item(toml_text: &str, key: &str, value: &str) -> Option<String> {
+    let mut lines: Vec<String> = toml_text.lines().map(|l| l.to_owned()).collect();
+    let mut in_doc = false;
+    let mut end_of_doc = None;
+    let mut existing = None;
+
+    for (i, line) in lines.iter().enumerate() {
+        let line = line.trim();
+
+        if line.starts_with('[') {
+            in_doc = line == "[doc]";
+
+            if in_doc {
+                end_of_doc = Some(i);
+            }
+        } else if in_doc && !line.is_empty() && !line.starts_with('#') {
+            end_of_doc = Some(i);
+
+            if let Some(rest) = line.strip_prefix(key) {
+                if rest.trim_start().starts_with('=') {
+                    existing = Some(i);
+                }
+            }
+        }
+    }
+
+    let item = format!("{} = {}", key, value);
+
+    match (existing, end_of_doc) {
+        (Some(i), _) => lines[i] = item,
+        (None, Some(i)) => lines.insert(i + 1, item),
+        (None, None) => return None,
+    }
+
+    let mut result = lines.join("\n");
+    result.push('\n');
+    Some(result)
 }
 
 /// Persistent settings for a document build.
@@ -214,6 +350,19 @@ pub struct OutputProfile {
 pub enum BuildTargetType {
     /// Output to the Portable Document Format (PDF).
     Pdf,
+
+    /// Output to HTML, using the engine's "semantic pagination" mode.
+    Html,
+}
+
+impl BuildTargetType {
+    /// Get the file extension used by the main output of this target type.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            BuildTargetType::Pdf => "pdf",
+            BuildTargetType::Html => "html",
+        }
+    }
 }
 
 /// Temporary options for a document build.
@@ -223,8 +372,11 @@ pub struct BuildOptions {
     only_cached: bool,
     keep_intermediates: bool,
     keep_logs: bool,
+    incremental: bool,
     print_stdout: bool,
     open: bool,
+    network: GetUrlConfig,
+    bundle_update: UpdatePolicy,
 }
 
 impl BuildOptions {
@@ -248,6 +400,11 @@ impl BuildOptions {
         self
     }
 
+    pub fn incremental(&mut self, value: bool) -> &mut Self {
+        self.incremental = value;
+        self
+    }
+
     pub fn print_stdout(&mut self, value: bool) -> &mut Self {
         self.print_stdout = value;
         self
@@ -257,6 +414,16 @@ impl BuildOptions {
         self.open = value;
         self
     }
+
+    pub fn network_config(&mut self, value: GetUrlConfig) -> &mut Self {
+        self.network = value;
+        self
+    }
+
+    pub fn bundle_update_policy(&mut self, value: UpdatePolicy) -> &mut Self {
+        self.bundle_update = value;
+        self
+    }
 }
 
 const DEFAULT_PRIMARY_INPUT: &[u8] = br#"
@@ -282,15 +449,31 @@ impl Document {
         BuildOptions::default()
     }
 
-    /// Get the bundle used by this document.
+    /// Get the name of this document.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the location of the bundle used by this document.
+    pub fn bundle_loc(&self) -> &str {
+        &self.bundle_loc
+    }
+
+    /// Get the bundle used by this document. Web bundles are accessed with
+    /// the settings in *network*, and checked for updates according to
+    /// *update*.
     pub fn bundle(
         &self,
+        network: &GetUrlConfig,
+        update: UpdatePolicy,
         only_cached: bool,
         status: &mut dyn StatusBackend,
     ) -> Result<Box<dyn Bundle>> {
         fn bundle_from_path(p: PathBuf) -> Result<Box<dyn Bundle>> {
             if p.is_dir() {
                 Ok(Box::new(DirBundle::new(p)))
+            } else if itarbundle::is_itar_path(&p) {
+                Ok(Box::new(ITarBundle::open(p)?))
             } else {
                 Ok(Box::new(ZipBundle::open(p)?))
             }
@@ -300,7 +483,14 @@ impl Document {
             Ok(Box::new(test_util::TestBundle::default()))
         } else if let Ok(url) = Url::parse(&self.bundle_loc) {
             if url.scheme() != "file" {
-                let bundle = CachedITarBundle::new(&self.bundle_loc, only_cached, None, status)?;
+                let bundle = CachedITarBundle::new(
+                    &self.bundle_loc,
+                    only_cached,
+                    update,
+                    None,
+                    network,
+                    status,
+                )?;
                 Ok(Box::new(bundle))
             } else {
                 let file_path = url.to_file_path().map_err(|_| {
@@ -313,17 +503,90 @@ impl Document {
         }
     }
 
+    /// Check that a bundle matches the digest pinned for this document, if
+    /// there is one.
+    fn check_bundle_digest(
+        &self,
+        bundle: &mut dyn Bundle,
+        status: &mut dyn StatusBackend,
+    ) -> Result<()> {
+        let expected = match self.bundle_digest {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+
+        let actual = bundle.get_digest(status)?;
+
+        if actual != expected {
+            return Err(errmsg!(
+                "the bundle `{}` has digest {}, but `Tectonic.toml` expects {}; \
+                 if the document should use the new bundle, run `tectonic -X bundle pin` to update it",
+                self.bundle_loc,
+                actual.to_string(),
+                expected.to_string()
+            ));
+        }
+
+        Ok(())
+    }
+
     /// Build one of the document’s outputs.
     pub fn build(
         &self,
         output_profile: &str,
         options: &BuildOptions,
         status: &mut dyn StatusBackend,
+    ) -> Result<i32> {
+        self.build_inner(output_profile, options, None, None, status)
+    }
+
+    /// Build one of the document’s outputs, replacing *report* with a report
+    /// on what happened. The report is filled in even if the build fails.
+    pub fn build_with_report(
+        &self,
+        output_profile: &str,
+        options: &BuildOptions,
+        report: &mut BuildReport,
+        status: &mut dyn StatusBackend,
+    ) -> Result<i32> {
+        *report = BuildReport::default();
+        let result = self.build_inner(output_profile, options, None, Some(report), status);
+
+        // If we failed before getting as far as running the engines, the
+        // report doesn't know about it yet.
+        if result.is_err() && report.error.is_none() {
+            report.record_outcome(&result);
+        }
+
+        result
+    }
+
+    /// Build one of the document’s outputs, adding the source files that it
+    /// read to *inputs*. The files are recorded even if the build fails, so
+    /// that fixing them can be noticed.
+    pub fn build_and_record_inputs(
+        &self,
+        output_profile: &str,
+        options: &BuildOptions,
+        inputs: &mut InputSet,
+        status: &mut dyn StatusBackend,
+    ) -> Result<i32> {
+        self.build_inner(output_profile, options, Some(inputs), None, status)
+    }
+
+    fn build_inner(
+        &self,
+        output_profile: &str,
+        options: &BuildOptions,
+        inputs: Option<&mut InputSet>,
+        report: Option<&mut BuildReport>,
+        status: &mut dyn StatusBackend,
     ) -> Result<i32> {
         let profile = self.outputs.get(output_profile).unwrap();
 
         let output_format = match profile.target_type {
             BuildTargetType::Pdf => OutputFormat::Pdf,
+            BuildTargetType::Html => OutputFormat::Html,
         };
 
         let mut sess_builder = ProcessingSessionBuilder::default();
@@ -341,7 +604,14 @@ impl Document {
         if options.only_cached {
             tt_note!(status, "using only cached resource files");
         }
-        sess_builder.bundle(self.bundle(options.only_cached, status)?);
+        let mut bundle = self.bundle(
+            &options.network,
+            options.bundle_update,
+            options.only_cached,
+            status,
+        )?;
+        self.check_bundle_digest(&mut *bundle, status)?;
+        sess_builder.bundle(bundle);
 
         // keep intermed, keep logs, print stdout
 
@@ -361,12 +631,47 @@ impl Document {
         );
         sess_builder.output_dir(&output_dir);
 
+        if options.incremental {
+            sess_builder.intermediates_dir(output_dir.join(".tectonic"));
+        }
+
         let mut sess = sess_builder.create(status)?;
         let result = sess.run(status);
 
+        if let Some(report) = report {
+            *report = sess.report().clone();
+        }
+
+        if let Some(inputs) = inputs {
+            for (name, digest) in sess.events.filesystem_inputs() {
+                // Directory bundles also report their files as coming from
+                // the filesystem, but relative to the bundle directory, so
+                // skip names that aren't in the source directory.
+                let path = tex_dir.join(name);
+
+                if path.exists() {
+                    inputs.add(path, digest);
+                }
+            }
+        }
+
         if let Err(e) = &result {
             if let ErrorKind::EngineError(engine) = e.kind() {
-                if let Some(output) = sess.io.mem.files.borrow().get(sess.io.mem.stdout_key()) {
+                // If we can make sense of what TeX said, report the problems
+                // that it found rather than its whole output.
+                let diagnostics = if *engine == "TeX" {
+                    sess.tex_diagnostics()
+                } else {
+                    Vec::new()
+                };
+
+                if diagnostics.iter().any(|d| d.kind == MessageKind::Error) {
+                    for diag in &diagnostics {
+                        status.report_diagnostic(diag);
+                    }
+                } else if let Some(output) =
+                    sess.io.mem.files.borrow().get(sess.io.mem.stdout_key())
+                {
                     tt_error!(
                         status,
                         "something bad happened inside {}; its output follows:\n",
@@ -377,12 +682,9 @@ impl Document {
                 }
             }
         } else if options.open {
-            let out_file =
-                output_dir
-                    .join(&profile.name)
-                    .with_extension(match profile.target_type {
-                        BuildTargetType::Pdf => "pdf",
-                    });
+            let out_file = output_dir
+                .join(&profile.name)
+                .with_extension(profile.target_type.extension());
             tt_note!(status, "opening `{}`", out_file.display());
             if let Err(e) = open::that(&out_file) {
                 tt_error!(
@@ -396,6 +698,91 @@ impl Document {
 
         result.map(|_| 0)
     }
+
+    /// Build all of the document’s outputs, then rebuild them whenever the
+    /// source files that they read, or the document’s `Tectonic.toml` file,
+    /// change.
+    ///
+    /// Build failures are reported to *status* rather than returned, so that
+    /// the user can fix them and carry on. Changes that leave a file’s
+    /// contents the same, such as saving it without modifications, don’t
+    /// cause a rebuild. If `Tectonic.toml` changes, the document is reloaded
+    /// from it. *keep_going* is called regularly while waiting for changes;
+    /// once it returns false, this function returns.
+    pub fn watch<F: FnMut() -> bool>(
+        &mut self,
+        options: &BuildOptions,
+        watch: &WatchOptions,
+        status: &mut dyn StatusBackend,
+        mut keep_going: F,
+    ) -> Result<()> {
+        let toml_path = self.src_dir.join("Tectonic.toml");
+
+        loop {
+            let mut inputs = InputSet::default();
+            inputs.add(&toml_path, None);
+
+            let mut output_names: Vec<String> = self.output_names().map(|n| n.to_owned()).collect();
+            output_names.sort();
+
+            for name in &output_names {
+                if let Err(e) = self.build_and_record_inputs(name, options, &mut inputs, status) {
+                    status.report_error(&SyncError::new(e).into());
+                }
+            }
+
+            tt_note!(status, "watching {} files for changes", inputs.len());
+
+            let mut waited = false;
+
+            let changed = loop {
+                // Check right away too, in case a file changed while we were
+                // building.
+                let changed = inputs.changed();
+
+                if !changed.is_empty() {
+                    break changed;
+                }
+
+                if waited {
+                    tt_note!(status, "the input files are unchanged; not rebuilding");
+                }
+
+                inputs.refresh_times();
+
+                if !inputs.wait_for_change(watch, &mut keep_going) {
+                    return Ok(());
+                }
+
+                waited = true;
+            };
+
+            for path in &changed {
+                tt_note!(status, "`{}` changed", path.display());
+            }
+
+            if changed.contains(&toml_path) {
+                match self.reload(&toml_path) {
+                    Ok(doc) => *self = doc,
+                    Err(e) => {
+                        tt_error!(
+                            status,
+                            "failed to reload `{}`; keeping the previous settings",
+                            toml_path.display();
+                            SyncError::new(e).into()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Load a new copy of this document from its `Tectonic.toml` file.
+    fn reload(&self, toml_path: &Path) -> Result<Document> {
+        let mut toml_file =
+            ctry!(File::open(toml_path); "failed to open `{}`", toml_path.display());
+        Document::new_from_toml(self.src_dir.clone(), self.build_dir.clone(), &mut toml_file)
+    }
 }
 
 /// The concrete syntax for saving document state, wired up via serde.
@@ -416,6 +803,9 @@ mod syntax {
     pub struct DocSection {
         pub name: String,
         pub bundle: String,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub bundle_digest: Option<String>,
     }
 
     #[derive(Debug, Deserialize, Serialize)]
@@ -459,18 +849,21 @@ mod syntax {
     #[derive(Clone, Copy, Debug, Eq, PartialEq)]
     pub enum BuildTargetType {
         Pdf,
+        Html,
     }
 
     impl BuildTargetType {
         pub fn from_runtime(rt: &super::BuildTargetType) -> Self {
             match rt {
                 super::BuildTargetType::Pdf => BuildTargetType::Pdf,
+                super::BuildTargetType::Html => BuildTargetType::Html,
             }
         }
 
         pub fn to_runtime(&self) -> super::BuildTargetType {
             match self {
                 BuildTargetType::Pdf => super::BuildTargetType::Pdf,
+                BuildTargetType::Html => super::BuildTargetType::Html,
             }
         }
     }
@@ -482,6 +875,7 @@ mod syntax {
         {
             serializer.serialize_str(match *self {
                 BuildTargetType::Pdf => "pdf",
+                BuildTargetType::Html => "html",
             })
         }
     }
@@ -493,8 +887,45 @@ mod syntax {
             let s = String::deserialize(deserializer)?;
             Ok(match s.as_str() {
                 "pdf" => BuildTargetType::Pdf,
-                other => return Err(<D as Deserializer>::Error::unknown_variant(other, &["pdf"])),
+                "html" => BuildTargetType::Html,
+                other => {
+                    return Err(<D as Deserializer>::Error::unknown_variant(
+                        other,
+                        &["pdf", "html"],
+                    ))
+                }
             })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_doc_items() {
+        let text = "[doc]\nname = 'x'\nbundle = 'y'\n\n[[output]]\nname = 'default'\n";
+
+        let added = set_doc_item(text, "bundle_digest", "'abc'").unwrap();
+        assert_eq!(
+            added,
+            "[doc]\nname = 'x'\nbundle = 'y'\nbundle_digest = 'abc'\n\n[[output]]\nname = 'default'\n"
+        );
+
+        let replaced = set_doc_item(&added, "bundle_digest", "'def'").unwrap();
+        assert_eq!(replaced, added.replace("abc", "def"));
+
+        // Keys that merely start with the same text are left alone.
+        let text = "[doc]\nbundle = 'y'\n# a comment\n";
+        assert_eq!(
+            set_doc_item(text, "bund", "1").unwrap(),
+            "[doc]\nbundle = 'y'\nbund = 1\n# a comment\n"
+        );
+
+        assert_eq!(
+            set_doc_item("[[output]]\nname = 'x'\n", "bundle", "1"),
+            None
+        );
+    }
+}
@@ -12,6 +12,8 @@
 //! CLI program.
 
 use byte_unit::Byte;
+#[cfg(feature = "serde")]
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
@@ -19,7 +21,7 @@ use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::result::Result as StdResult;
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use crate::{
     ctry,
@@ -28,11 +30,13 @@ use crate::{
     errmsg,
     errors::{ChainErrCompatExt, ErrorKind, Result},
     io::{
-        memory::MemoryFileCollection, Bundle, InputOrigin, IoProvider, IoSetup, IoSetupBuilder,
-        OpenResult,
+        intermediates::IntermediatesStore, memory::MemoryFileCollection, Bundle, InputOrigin,
+        IoProvider, IoSetup, IoSetupBuilder, OpenResult,
     },
-    status::StatusBackend,
-    tt_error, tt_note, tt_warning,
+    page_selection::{Imposition, PageSelection},
+    report::{BuildReport, FileReport, MessageRecorder, OutputReport, PassKind, RerunReason},
+    status::{Diagnostic, StatusBackend},
+    tex_log, tt_error, tt_note, tt_warning,
     unstable_opts::UnstableOptions,
     BibtexEngine, Spx2HtmlEngine, TexEngine, TexResult, XdvipdfmxEngine,
 };
@@ -40,6 +44,7 @@ use crate::{
 /// Different patterns with which files may have been accessed by the
 /// underlying engines. Once a file is marked as ReadThenWritten or
 /// WrittenThenRead, its pattern does not evolve further.
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "snake_case"))]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum AccessPattern {
     /// This file is only ever read.
@@ -107,6 +112,19 @@ impl IoEvents {
     fn new() -> IoEvents {
         IoEvents(HashMap::new())
     }
+
+    /// Iterate over the files that were read from the filesystem, along with
+    /// the digests of their contents when they were first read. Files that
+    /// were also written to disk are skipped, since they are outputs of the
+    /// processing as well as inputs.
+    pub fn filesystem_inputs(&self) -> impl Iterator<Item = (&str, Option<DigestData>)> {
+        self.0
+            .iter()
+            .filter(|(_, info)| {
+                info.input_origin == InputOrigin::Filesystem && !info.got_written_to_disk
+            })
+            .map(|(name, info)| (name.as_ref(), info.read_digest))
+    }
 }
 
 impl IoEventBackend for IoEvents {
@@ -332,8 +350,11 @@ pub struct ProcessingSessionBuilder {
     bundle: Option<Box<dyn Bundle>>,
     keep_intermediates: bool,
     keep_logs: bool,
+    intermediates_dir: Option<PathBuf>,
     synctex: bool,
     build_date: Option<SystemTime>,
+    page_selection: Option<PageSelection>,
+    imposition: Option<Imposition>,
     unstables: UnstableOptions,
 }
 
@@ -478,6 +499,20 @@ impl ProcessingSessionBuilder {
         self
     }
 
+    /// Save the intermediate files of the processing in this directory, and
+    /// start from the ones saved by the previous session, if any.
+    ///
+    /// Starting from the previous `.aux`, `.toc`, `.bbl` files and so on
+    /// means that the TeX engine can often finish in a single pass, rather
+    /// than needing a second pass to pick up the files that the first one
+    /// wrote. The files are only saved if processing succeeds; if it fails,
+    /// they are removed, so that a bad intermediate file can't break later
+    /// sessions. See [`IntermediatesStore`] for the layout of the directory.
+    pub fn intermediates_dir<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.intermediates_dir = Some(p.as_ref().to_owned());
+        self
+    }
+
     /// If set to `true`, tex files will be compiled using synctex information.
     pub fn synctex(&mut self, s: bool) -> &mut Self {
         self.synctex = s;
@@ -491,6 +526,26 @@ impl ProcessingSessionBuilder {
         self
     }
 
+    /// Only keep the selected pages of the output.
+    ///
+    /// The XDV file produced by the TeX engine is filtered in memory after
+    /// the last TeX pass, so the selection affects PDF, HTML, and XDV output.
+    pub fn page_selection(&mut self, sel: PageSelection) -> &mut Self {
+        self.page_selection = Some(sel);
+        self
+    }
+
+    /// Arrange the pages of the output on larger sheets.
+    ///
+    /// This is done after any page selection, so the selected pages are
+    /// arranged. The size of the pages is found as `xdvipdfmx` would, using
+    /// the `paper-size` unstable option if the document does not set it. It
+    /// only affects PDF and XDV output.
+    pub fn imposition(&mut self, layout: Imposition) -> &mut Self {
+        self.imposition = Some(layout);
+        self
+    }
+
     /// Loads unstable options into the processing session
     pub fn unstables(&mut self, opts: UnstableOptions) -> &mut Self {
         self.unstables = opts;
@@ -586,19 +641,18 @@ impl ProcessingSessionBuilder {
             tex_rerun_specification: self.reruns,
             keep_intermediates: self.keep_intermediates,
             keep_logs: self.keep_logs,
+            intermediates: self.intermediates_dir.map(IntermediatesStore::new),
+            restored_intermediates: false,
+            report: BuildReport::default(),
             synctex_enabled: self.synctex,
             build_date: self.build_date.unwrap_or(SystemTime::UNIX_EPOCH),
+            page_selection: self.page_selection,
+            imposition: self.imposition,
             unstables: self.unstables,
         })
     }
 }
 
-#[derive(Debug, Clone)]
-enum RerunReason {
-    Bibtex,
-    FileChange(String),
-}
-
 /// The ProcessingSession struct runs the whole show when we're actually
 /// processing a file. It understands, for example, the need to re-run the TeX
 /// engine if the `.aux` file changed.
@@ -646,11 +700,28 @@ pub struct ProcessingSession {
     tex_rerun_specification: Option<usize>,
     keep_intermediates: bool,
     keep_logs: bool,
+
+    /// If set, intermediate files are saved here for the next session, and
+    /// restored from here at the start of this one.
+    intermediates: Option<IntermediatesStore>,
+
+    /// Whether any intermediate files were restored from a previous session.
+    restored_intermediates: bool,
+
+    /// What happened while running the session.
+    report: BuildReport,
+
     synctex_enabled: bool,
 
     /// See `TexEngine::with_date` and `XdvipdfmxEngine::with_date`.
     build_date: SystemTime,
 
+    /// If set, only these pages are kept in the output.
+    page_selection: Option<PageSelection>,
+
+    /// If set, the pages are arranged on sheets in this way.
+    imposition: Option<Imposition>,
+
     unstables: UnstableOptions,
 }
 
@@ -660,6 +731,78 @@ const ALWAYS_INTERMEDIATE_EXTENSIONS: &[&str] = &[
 ];
 
 impl ProcessingSession {
+    /// Decide whether a file in the memory layer is an intermediate file, as
+    /// opposed to a final output.
+    fn is_intermediate(name: &str, summ: &FileSummary) -> bool {
+        summ.access_pattern != AccessPattern::Written
+            || ALWAYS_INTERMEDIATE_EXTENSIONS
+                .iter()
+                .any(|ext| name.ends_with(ext))
+    }
+
+    /// Load the intermediate files saved by a previous session into the
+    /// memory layer, if we're keeping them. Problems are only worth a
+    /// warning, since we can always start from scratch.
+    fn restore_intermediates(&mut self, status: &mut dyn StatusBackend) {
+        let store = match self.intermediates {
+            Some(ref s) => s,
+            None => return,
+        };
+
+        let files = match store.load() {
+            Ok(f) => f,
+            Err(e) => {
+                tt_warning!(status, "failed to load the intermediate files of the previous build"; e);
+                return;
+            }
+        };
+
+        self.restored_intermediates = !files.is_empty();
+
+        for (name, data) in files {
+            self.io.mem.create_entry(&name, data);
+        }
+    }
+
+    /// Save the intermediate files in the memory layer for the next session,
+    /// if we're keeping them. Files left over from the previous session that
+    /// weren't used this time are dropped.
+    fn save_intermediates(&self, status: &mut dyn StatusBackend) {
+        let store = match self.intermediates {
+            Some(ref s) => s,
+            None => return,
+        };
+
+        let files = self.io.mem.files.borrow();
+
+        let to_save = files.iter().filter_map(|(name, file)| {
+            if name == self.io.mem.stdout_key() || name.ends_with(".log") || name.ends_with(".blg")
+            {
+                return None;
+            }
+
+            match self.events.0.get(name) {
+                Some(summ) if Self::is_intermediate(name, summ) => {
+                    Some((name.as_ref(), &file.data[..]))
+                }
+                _ => None,
+            }
+        });
+
+        if let Err(e) = store.save(to_save) {
+            tt_warning!(status, "failed to save the intermediate files for the next build"; e);
+        }
+    }
+
+    /// Remove the saved intermediate files, if we're keeping them.
+    fn forget_intermediates(&self, status: &mut dyn StatusBackend) {
+        if let Some(ref store) = self.intermediates {
+            if let Err(e) = store.clear() {
+                tt_warning!(status, "failed to remove the saved intermediate files"; e);
+            }
+        }
+    }
+
     /// Assess whether we need to rerun an engine. This is the case if there
     /// was a file that the engine read and then rewrote, and the rewritten
     /// version is different than the version that it read in.
@@ -727,7 +870,61 @@ impl ProcessingSession {
     /// - run BibTeX, if it seems to be required
     /// - repeat the last two steps as often as needed
     /// - write the output files to disk, including a Makefile if it was requested.
+    ///
+    /// Whether or not it succeeds, a record of what happened is available
+    /// from [`Self::report`] afterwards.
     pub fn run(&mut self, status: &mut dyn StatusBackend) -> Result<()> {
+        let start = Instant::now();
+        let result = self.run_inner(status);
+
+        let stdout_key = self.io.mem.stdout_key();
+        let mut files: Vec<FileReport> = self
+            .events
+            .0
+            .iter()
+            .filter(|(name, _)| name.as_str() != stdout_key)
+            .map(|(name, info)| FileReport {
+                name: name.clone(),
+                access_pattern: info.access_pattern,
+                origin: info.input_origin,
+                read_digest: info.read_digest,
+                write_digest: info.write_digest,
+            })
+            .collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let report = &mut self.report;
+        report.record_outcome(&result);
+        report.files = files;
+        report.seconds = start.elapsed().as_secs_f64();
+
+        result
+    }
+
+    /// Get the report on what happened while running the session.
+    pub fn report(&self) -> &BuildReport {
+        &self.report
+    }
+
+    /// Get the diagnostics from the last run of the TeX engine, as found in
+    /// its log file, or in its terminal output if it didn't write one.
+    pub fn tex_diagnostics(&self) -> Vec<Diagnostic> {
+        let log_name = Path::new(&self.tex_aux_path)
+            .with_extension("log")
+            .display()
+            .to_string();
+        let files = self.io.mem.files.borrow();
+
+        match files
+            .get(&log_name)
+            .or_else(|| files.get(self.io.mem.stdout_key()))
+        {
+            Some(file) => tex_log::parse_log(&String::from_utf8_lossy(&file.data)),
+            None => Vec::new(),
+        }
+    }
+
+    fn run_inner(&mut self, status: &mut dyn StatusBackend) -> Result<()> {
         // Do we need to generate the format file?
 
         let generate_format = if self.output_format == OutputFormat::Format {
@@ -753,15 +950,19 @@ impl ProcessingSession {
             self.make_format_pass(status)?;
         }
 
+        // This has to happen after making the format, which clears the
+        // memory layer.
+
+        self.restore_intermediates(status);
+
         // Do the meat of the work.
 
         let result = match self.pass {
             PassSetting::Tex => match self.tex_pass(None, status) {
-                Ok(Some(warnings)) => {
-                    tt_warning!(status, "{}", warnings);
+                Ok(warnings) => {
+                    self.report_tex_warnings(warnings, status);
                     Ok(0)
                 }
-                Ok(None) => Ok(0),
                 Err(e) => Err(e),
             },
             PassSetting::Default => self.default_pass(false, status),
@@ -769,10 +970,13 @@ impl ProcessingSession {
         };
 
         if let Err(e) = result {
+            self.forget_intermediates(status);
             self.write_files(None, status, true)?;
             return Err(e);
         };
 
+        self.save_intermediates(status);
+
         // Write output files and the first line of our Makefile output.
 
         let mut mf_dest_maybe = match self.makefile_output_path {
@@ -867,7 +1071,13 @@ impl ProcessingSession {
             }
 
             let sname = name;
-            let summ = self.events.0.get_mut(name).unwrap();
+
+            // Intermediate files restored from a previous session might not
+            // have been touched at all this time.
+            let summ = match self.events.0.get_mut(name) {
+                Some(s) => s,
+                None => continue,
+            };
 
             if !only_logs && (self.output_format == OutputFormat::Aux) {
                 // In this mode we're only writing the .aux file. I initially
@@ -878,12 +1088,7 @@ impl ProcessingSession {
                 if !sname.ends_with(".aux") {
                     continue;
                 }
-            } else if !self.keep_intermediates
-                && (summ.access_pattern != AccessPattern::Written
-                    || ALWAYS_INTERMEDIATE_EXTENSIONS
-                        .iter()
-                        .any(|ext| sname.ends_with(ext)))
-            {
+            } else if !self.keep_intermediates && Self::is_intermediate(sname, summ) {
                 n_skipped_intermediates += 1;
                 continue;
             }
@@ -918,6 +1123,10 @@ impl ProcessingSession {
             let mut f = File::create(&real_path)?;
             f.write_all(&file.data)?;
             summ.got_written_to_disk = true;
+            self.report.outputs.push(OutputReport {
+                path: real_path.clone(),
+                size: file.data.len() as u64,
+            });
 
             if let Some(ref mut mf_dest) = mf_dest_maybe {
                 // Maybe it'd be better to have this just be a warning? But if
@@ -951,7 +1160,17 @@ impl ProcessingSession {
 
             if self.is_bibtex_needed() {
                 self.bibtex_pass(status)?;
-                Some(RerunReason::Bibtex)
+
+                // If we restored the intermediate files of a previous
+                // session, TeX has already read the `.bbl` file from then
+                // (or found that there wasn't one), so the usual check tells
+                // us whether BibTeX changed anything. Otherwise, we always
+                // need another pass to pick up the bibliography.
+                if self.restored_intermediates {
+                    self.is_rerun_needed(status)
+                } else {
+                    Some(RerunReason::Bibtex)
+                }
             } else {
                 self.is_rerun_needed(status)
             }
@@ -965,12 +1184,11 @@ impl ProcessingSession {
         };
 
         for i in 0..pass_count {
-            let rerun_explanation = if reruns_fixed {
-                "I was told to".to_owned()
+            let rerun_reason = if reruns_fixed {
+                RerunReason::Requested
             } else {
                 match rerun_result {
-                    Some(RerunReason::Bibtex) => "bibtex was run".to_owned(),
-                    Some(RerunReason::FileChange(ref s)) => format!("\"{}\" changed", s),
+                    Some(ref r) => r.clone(),
                     None => break,
                 }
             };
@@ -985,7 +1203,7 @@ impl ProcessingSession {
                 summ.read_digest = None;
             }
 
-            warnings = self.tex_pass(Some(&rerun_explanation), status)?;
+            warnings = self.tex_pass(Some(&rerun_reason), status)?;
 
             if !reruns_fixed {
                 rerun_result = self.is_rerun_needed(status);
@@ -1001,13 +1219,19 @@ impl ProcessingSession {
             }
         }
 
-        // The last tex pass generated warnings.
-        if let Some(warnings) = warnings {
-            tt_warning!(status, "{}", warnings);
-        }
+        // Report the problems that remain after the last tex pass.
+        self.report_tex_warnings(warnings, status);
 
         // And finally, xdvipdfmx or spx2html. Maybe.
 
+        if self.page_selection.is_some() {
+            self.select_pages(status)?;
+        }
+
+        if self.imposition.is_some() {
+            self.impose_pages(status)?;
+        }
+
         if let OutputFormat::Pdf = self.output_format {
             self.xdvipdfmx_pass(status)?;
         } else if let OutputFormat::Html = self.output_format {
@@ -1060,6 +1284,7 @@ impl ProcessingSession {
         });
         let stem = r?;
 
+        let mut recorder = MessageRecorder::new(status);
         let result = {
             let mut stack = self
                 .io
@@ -1070,12 +1295,16 @@ impl ProcessingSession {
                 .process(
                     &mut stack,
                     &mut self.events,
-                    status,
+                    &mut recorder,
                     "UNUSED.fmt",
                     "texput",
                     &self.unstables,
                 )
         };
+        let tex_result = result.as_ref().ok().copied();
+        self.report
+            .passes
+            .push(recorder.finish(PassKind::Format, None, tex_result));
 
         match result {
             Ok(TexResult::Spotless) => {}
@@ -1121,16 +1350,19 @@ impl ProcessingSession {
     /// Run one pass of the TeX engine.
     fn tex_pass(
         &mut self,
-        rerun_explanation: Option<&str>,
+        rerun_reason: Option<&RerunReason>,
         status: &mut dyn StatusBackend,
     ) -> Result<Option<&'static str>> {
+        if let Some(r) = rerun_reason {
+            status.note_highlighted("Rerunning ", "TeX", &format!(" because {} ...", r));
+        } else {
+            status.note_highlighted("Running ", "TeX", " ...");
+        }
+
+        let mut recorder = MessageRecorder::new(status);
         let result = {
+            let mem_files = self.io.mem.files.clone();
             let mut stack = self.io.as_stack();
-            if let Some(s) = rerun_explanation {
-                status.note_highlighted("Rerunning ", "TeX", &format!(" because {} ...", s));
-            } else {
-                status.note_highlighted("Running ", "TeX", " ...");
-            }
 
             TexEngine::new()
                 .halt_on_error_mode(true)
@@ -1138,15 +1370,28 @@ impl ProcessingSession {
                 .synctex(self.synctex_enabled)
                 .semantic_pagination(self.output_format == OutputFormat::Html)
                 .build_date(self.build_date)
+                .shell_escape(mem_files)
                 .process(
                     &mut stack,
                     &mut self.events,
-                    status,
+                    &mut recorder,
                     &self.format_name,
                     &self.primary_input_tex_path,
                     &self.unstables,
                 )
         };
+        let tex_result = result.as_ref().ok().copied();
+
+        // Problems that a rerun may fix, such as undefined references, are
+        // only shown to the user after the last pass, but every pass keeps
+        // them in its report.
+        for diag in self.tex_diagnostics() {
+            recorder.record_diagnostic(&diag);
+        }
+
+        self.report
+            .passes
+            .push(recorder.finish(PassKind::Tex, rerun_reason, tex_result));
 
         let warnings = match result {
             Ok(TexResult::Spotless) => None,
@@ -1162,19 +1407,36 @@ impl ProcessingSession {
         Ok(warnings)
     }
 
+    /// Report the problems found in the log of the last TeX pass, followed by
+    /// the summary of its outcome returned by [`Self::tex_pass`], if any.
+    fn report_tex_warnings(&self, warnings: Option<&str>, status: &mut dyn StatusBackend) {
+        for diag in self.tex_diagnostics() {
+            status.report_diagnostic(&diag);
+        }
+
+        if let Some(warnings) = warnings {
+            tt_warning!(status, "{}", warnings);
+        }
+    }
+
     fn bibtex_pass(&mut self, status: &mut dyn StatusBackend) -> Result<i32> {
+        status.note_highlighted("Running ", "BibTeX", " ...");
+        let mut recorder = MessageRecorder::new(status);
         let result = {
             let mut stack = self.io.as_stack();
             let mut engine = BibtexEngine::new();
-            status.note_highlighted("Running ", "BibTeX", " ...");
             engine.process(
                 &mut stack,
                 &mut self.events,
-                status,
+                &mut recorder,
                 &self.tex_aux_path,
                 &self.unstables,
             )
         };
+        let tex_result = result.as_ref().ok().copied();
+        self.report
+            .passes
+            .push(recorder.finish(PassKind::Bibtex, None, tex_result));
 
         match result {
             Ok(TexResult::Spotless) => {}
@@ -1199,32 +1461,100 @@ impl ProcessingSession {
         Ok(0)
     }
 
+    /// Filter the XDV output of the TeX engine according to the page
+    /// selection.
+    fn select_pages(&mut self, status: &mut dyn StatusBackend) -> Result<()> {
+        let sel = match self.page_selection {
+            Some(ref s) => s,
+            None => return Ok(()),
+        };
+
+        let mut files = self.io.mem.files.borrow_mut();
+
+        let file = match files.get_mut(&self.tex_xdv_path) {
+            Some(f) => f,
+            None => return Ok(()), // aux or format output, or no pages at all
+        };
+
+        let (data, n_kept) = sel.apply_to_xdv(&file.data)?;
+
+        if n_kept == 0 {
+            return Err(errmsg!("the page selection does not match any pages"));
+        }
+
+        tt_note!(status, "keeping {} selected page(s)", n_kept);
+        file.data = data;
+        Ok(())
+    }
+
+    /// Arrange the pages in the XDV output of the TeX engine on sheets,
+    /// according to the imposition.
+    fn impose_pages(&mut self, status: &mut dyn StatusBackend) -> Result<()> {
+        let layout = match self.imposition {
+            Some(ref l) => l,
+            None => return Ok(()),
+        };
+
+        if let OutputFormat::Html = self.output_format {
+            tt_warning!(status, "n-up imposition is not supported for HTML output");
+            return Ok(());
+        }
+
+        let mut files = self.io.mem.files.borrow_mut();
+
+        let file = match files.get_mut(&self.tex_xdv_path) {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+
+        let paper = self.unstables.paper_size.as_deref().unwrap_or("letter");
+        let (data, n_sheets) = layout.apply_to_xdv(&file.data, paper)?;
+        tt_note!(status, "arranged the pages on {} sheet(s)", n_sheets);
+        file.data = data;
+        Ok(())
+    }
+
     fn xdvipdfmx_pass(&mut self, status: &mut dyn StatusBackend) -> Result<i32> {
-        {
+        status.note_highlighted("Running ", "xdvipdfmx", " ...");
+        let mut recorder = MessageRecorder::new(status);
+        let result = {
             let mut stack = self.io.as_stack();
             let mut engine = XdvipdfmxEngine::new().with_date(self.build_date);
-            status.note_highlighted("Running ", "xdvipdfmx", " ...");
             engine.process(
                 &mut stack,
                 &mut self.events,
-                status,
+                &mut recorder,
                 &self.tex_xdv_path,
                 &self.tex_pdf_path,
                 &self.unstables,
-            )?;
-        }
+            )
+        };
+        self.report
+            .passes
+            .push(recorder.finish(PassKind::Xdvipdfmx, None, None));
+        result?;
 
         self.io.mem.files.borrow_mut().remove(&self.tex_xdv_path);
         Ok(0)
     }
 
     fn spx2html_pass(&mut self, status: &mut dyn StatusBackend) -> Result<i32> {
-        {
+        status.note_highlighted("Running ", "spx2html", " ...");
+        let mut recorder = MessageRecorder::new(status);
+        let result = {
             let mut stack = self.io.as_stack();
             let mut engine = Spx2HtmlEngine::new();
-            status.note_highlighted("Running ", "spx2html", " ...");
-            engine.process(&mut stack, &mut self.events, status, &self.tex_xdv_path)?;
-        }
+            engine.process(
+                &mut stack,
+                &mut self.events,
+                &mut recorder,
+                &self.tex_xdv_path,
+            )
+        };
+        self.report
+            .passes
+            .push(recorder.finish(PassKind::Spx2Html, None, None));
+        result?;
 
         self.io.mem.files.borrow_mut().remove(&self.tex_xdv_path);
         Ok(0)
@@ -4,9 +4,30 @@
 //! Convert Tectonic’s SPX format to HTML
 //!
 //! Yay, an engine actually written in pure Rust!
+//!
+//! Text is emitted as UTF-8. Runs of text that appear outside of any explicit
+//! structure are gathered into paragraphs. In semantic pagination mode the
+//! engine sets each paragraph as a single line, so page boundaries and
+//! downward moves to a new line in the SPX file both act as paragraph breaks.
+//! Word spaces are inferred from horizontal moves between runs of text that
+//! are wider than a typical kern. Documents can control the output more
+//! precisely with `\special`s whose contents start with `tdux:`, followed by
+//! a command and its (optional) argument, separated by whitespace:
+//!
+//! - `tdux:asp` — start a paragraph, if one is not already open
+//! - `tdux:aep` — end the current paragraph, if one is open
+//! - `tdux:mfs <tag> [attributes]` — start an HTML element, e.g.
+//!   `tdux:mfs h1` or `tdux:mfs div class="abstract"`. Attributes must have
+//!   the form `name="value"`.
+//! - `tdux:me <tag>` — end the most recently started element with the given
+//!   tag, along with any elements nested inside of it
+//! - `tdux:dt <text>` — emit the given text directly
+//! - `tdux:setTitle <text>` — set the title of the HTML document
+//!
+//! Other specials are ignored.
 
 use std::io::Write;
-use tectonic_xdv::{FileType, XdvEvents, XdvParser};
+use tectonic_xdv::{FileType, GlyphRun, Position, XdvEvents, XdvParser};
 
 use super::IoEventBackend;
 use crate::errors::{Error, Result};
@@ -48,7 +69,7 @@ impl Spx2HtmlEngine {
         {
             let state = State::new(outname, io, events, status);
             let (state, _n_bytes) = XdvParser::process(&mut input, state)?;
-            state.finished();
+            state.finished()?;
         }
 
         let (name, digest_opt) = input.into_name_digest();
@@ -58,6 +79,59 @@ impl Spx2HtmlEngine {
     }
 }
 
+/// A downward move larger than this, in DVI units, starts a new line. This
+/// is big enough to not be triggered by subscripts.
+const NEW_LINE_THRESHOLD: i32 = 4 * 65536;
+
+/// A horizontal move larger than this, in DVI units, between two runs of
+/// text on the same line is treated as a word space. Kerns are smaller.
+const SPACE_THRESHOLD: i32 = 65536;
+
+/// Elements that can contain flow content, inside of which text is gathered
+/// into automatic paragraphs.
+const FLOW_CONTAINER_TAGS: &[&str] = &[
+    "article",
+    "aside",
+    "blockquote",
+    "div",
+    "figure",
+    "footer",
+    "header",
+    "main",
+    "nav",
+    "section",
+];
+
+/// Elements that cannot appear inside of a paragraph. Starting one of these
+/// ends any automatic paragraph that is currently open.
+const BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "div",
+    "dl",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "ul",
+];
+
 struct State<'a, 'b: 'a> {
     outname: String,
     io: &'a mut IoStack<'b>,
@@ -65,7 +139,36 @@ struct State<'a, 'b: 'a> {
     status: &'a mut dyn StatusBackend,
     cur_output: Option<OutputHandle>,
     warned_lost_chars: bool,
-    buf: Vec<u8>,
+    warned_bad_chars: bool,
+
+    /// The HTML body content. We buffer it all up so that we can emit the
+    /// document header, which may depend on specials that appear anywhere in
+    /// the input, at the very end.
+    content: String,
+    title: Option<String>,
+
+    /// The tags of the elements that have been explicitly started but not
+    /// yet ended.
+    elem_stack: Vec<String>,
+
+    /// If a paragraph is open, the depth of the element stack when it was
+    /// started.
+    para_depth: Option<usize>,
+
+    /// Whether a space should be emitted before the next run of text.
+    need_space: bool,
+
+    /// The vertical position of the baseline of the current line of text,
+    /// if any.
+    line_v: Option<i32>,
+
+    /// The horizontal position where the most recent run of text on the
+    /// current line ended, as the XDV parser reckons it. The parser doesn't
+    /// know the widths of characters in traditional TeX fonts, so it doesn't
+    /// count them. The gap between this and the start of the next run is
+    /// therefore the sum of the explicit moves in between, whatever kind of
+    /// font was used.
+    text_end_h: Option<i32>,
 }
 
 impl<'a, 'b: 'a> State<'a, 'b> {
@@ -82,26 +185,325 @@ impl<'a, 'b: 'a> State<'a, 'b> {
             status,
             cur_output: None,
             warned_lost_chars: false,
-            buf: Vec::new(),
+            warned_bad_chars: false,
+            content: String::new(),
+            title: None,
+            elem_stack: Vec::new(),
+            para_depth: None,
+            need_space: false,
+            line_v: None,
+            text_end_h: None,
         }
     }
-}
 
-#[inline(always)]
-fn as_printable_ascii(c: i32) -> Option<u8> {
-    if c > 0x20 && c < 0x7F {
-        Some(c as u8)
-    } else {
-        None
+    /// Whether text emitted in the current context should be wrapped in an
+    /// automatic paragraph.
+    fn wants_auto_para(&self) -> bool {
+        match self.elem_stack.last() {
+            None => true,
+            Some(tag) => FLOW_CONTAINER_TAGS.contains(&tag.as_str()),
+        }
     }
-}
 
-impl<'a, 'b: 'a> State<'a, 'b> {
-    pub fn finished(self) {
-        if let Some(oh) = self.cur_output {
+    fn start_para(&mut self) {
+        if self.para_depth.is_some() {
+            return;
+        }
+
+        self.content.push_str("<p>");
+        self.para_depth = Some(self.elem_stack.len());
+        self.block_break();
+    }
+
+    fn end_para(&mut self) {
+        if let Some(depth) = self.para_depth.take() {
+            self.close_elems_above(depth);
+            self.content.push_str("</p>\n");
+            self.block_break();
+        }
+    }
+
+    /// Forget about the current line of text, so that whatever comes next is
+    /// not separated from it by a word space.
+    fn block_break(&mut self) {
+        self.need_space = false;
+        self.text_end_h = None;
+    }
+
+    /// Close any explicitly started elements nested more deeply than
+    /// `depth`, warning about each one.
+    fn close_elems_above(&mut self, depth: usize) {
+        while self.elem_stack.len() > depth {
+            let tag = self.elem_stack.pop().unwrap();
+            tt_warning!(
+                self.status,
+                "SPX-to-HTML: element <{}> was not explicitly ended",
+                tag
+            );
+            self.push_end_tag(&tag);
+        }
+    }
+
+    fn push_end_tag(&mut self, tag: &str) {
+        self.content.push_str("</");
+        self.content.push_str(tag);
+        self.content.push('>');
+
+        if BLOCK_TAGS.contains(&tag) {
+            self.content.push('\n');
+            self.block_break();
+        }
+    }
+
+    /// Whether something at vertical position *v* belongs to the current
+    /// line of text.
+    fn on_current_line(&self, v: i32) -> bool {
+        match self.line_v {
+            Some(line_v) => v - line_v <= NEW_LINE_THRESHOLD,
+            None => false,
+        }
+    }
+
+    /// Update the line tracking for something that appears at position
+    /// (*h*, *v*), deciding whether a word space or a paragraph break comes
+    /// before it.
+    fn move_to(&mut self, h: i32, v: i32) {
+        match self.line_v {
+            Some(_) if self.on_current_line(v) => {
+                // Same line. A big enough move since the previous run of
+                // text is a word space.
+                if let Some(prev_end) = self.text_end_h {
+                    if h - prev_end > SPACE_THRESHOLD {
+                        self.need_space = true;
+                    }
+                }
+            }
+
+            Some(_) => {
+                // New line. Outside of automatic paragraphs, it's at least a
+                // word break.
+                if self.wants_auto_para() {
+                    self.end_para();
+                } else if self.text_end_h.is_some() {
+                    self.need_space = true;
+                }
+
+                self.line_v = Some(v);
+                self.text_end_h = None;
+            }
+
+            None => {
+                self.line_v = Some(v);
+            }
+        }
+    }
+
+    /// Emit a run of text that starts at position (*h*, *v*) and ends at
+    /// *end_h*, as the XDV parser reckons it.
+    fn push_text(&mut self, h: i32, v: i32, text: &str, end_h: i32) {
+        if text.is_empty() {
+            return;
+        }
+
+        self.move_to(h, v);
+
+        if self.wants_auto_para() {
+            self.start_para();
+        }
+
+        if self.need_space {
+            self.content.push(' ');
+            self.need_space = false;
+        }
+
+        push_escaped(&mut self.content, text);
+        self.text_end_h = Some(end_h);
+    }
+
+    fn start_elem(&mut self, h: i32, v: i32, args: &str) {
+        let mut pieces = args.splitn(2, char::is_whitespace);
+        let tag = pieces.next().unwrap_or("").to_ascii_lowercase();
+        let attrs = match parse_attributes(pieces.next().unwrap_or("")) {
+            Some(a) => a,
+            None => {
+                tt_warning!(
+                    self.status,
+                    "SPX-to-HTML: ignoring malformed attributes in `tdux:mfs` special \"{}\"",
+                    args
+                );
+                Vec::new()
+            }
+        };
+
+        if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric()) {
+            tt_warning!(
+                self.status,
+                "SPX-to-HTML: ignoring `tdux:mfs` special with invalid tag name \"{}\"",
+                tag
+            );
+            return;
+        }
+
+        if BLOCK_TAGS.contains(&tag.as_str()) {
+            self.end_para();
+            self.block_break();
+        } else {
+            // Put any word space before the element, rather than inside it.
+            self.move_to(h, v);
+
+            if self.wants_auto_para() {
+                self.start_para();
+            }
+
+            if self.need_space {
+                self.content.push(' ');
+                self.need_space = false;
+            }
+
+            if self.text_end_h.is_some() {
+                self.text_end_h = Some(h);
+            }
+        }
+
+        self.content.push('<');
+        self.content.push_str(&tag);
+
+        for (name, value) in attrs {
+            self.content.push(' ');
+            self.content.push_str(&name);
+            self.content.push_str("=\"");
+            push_escaped(&mut self.content, &value);
+            self.content.push('"');
+        }
+
+        self.content.push('>');
+        self.elem_stack.push(tag);
+    }
+
+    fn end_elem(&mut self, args: &str) {
+        let tag = args.trim().to_ascii_lowercase();
+
+        let index = match self.elem_stack.iter().rposition(|t| *t == tag) {
+            Some(i) => i,
+            None => {
+                tt_warning!(
+                    self.status,
+                    "SPX-to-HTML: ignoring `tdux:me` special for element <{}>, which is not open",
+                    tag
+                );
+                return;
+            }
+        };
+
+        if let Some(depth) = self.para_depth {
+            if depth > index {
+                self.end_para();
+            }
+        }
+
+        self.close_elems_above(index + 1);
+        self.elem_stack.pop();
+        self.push_end_tag(&tag);
+    }
+
+    fn handle_tdux(&mut self, h: i32, v: i32, text: &str) {
+        let mut pieces = text.trim().splitn(2, char::is_whitespace);
+        let command = pieces.next().unwrap_or("");
+        let rest = pieces.next().unwrap_or("").trim_start();
+
+        match command {
+            "asp" => self.start_para(),
+            "aep" => self.end_para(),
+            "mfs" => self.start_elem(h, v, rest),
+            "me" => self.end_elem(rest),
+            "dt" => self.push_text(h, v, rest, h),
+            "setTitle" => self.title = Some(rest.to_owned()),
+            other => {
+                tt_warning!(
+                    self.status,
+                    "SPX-to-HTML: ignoring unrecognized special `tdux:{}`",
+                    other
+                );
+            }
+        }
+    }
+
+    pub fn finished(mut self) -> Result<()> {
+        self.end_para();
+        self.close_elems_above(0);
+
+        if let Some(mut oh) = self.cur_output.take() {
+            let title = match self.title {
+                Some(ref t) => t.clone(),
+                None => self
+                    .outname
+                    .strip_suffix(".html")
+                    .unwrap_or(&self.outname)
+                    .to_owned(),
+            };
+
+            let mut head = String::new();
+            head.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>");
+            push_escaped(&mut head, &title);
+            head.push_str("</title>\n</head>\n<body>\n");
+
+            oh.write_all(head.as_bytes())?;
+            oh.write_all(self.content.as_bytes())?;
+            oh.write_all(b"</body>\n</html>\n")?;
+
             let (name, digest) = oh.into_name_digest();
             self.events.output_closed(name, digest);
         }
+
+        Ok(())
+    }
+}
+
+/// Parse the attributes of a `tdux:mfs` special, which must be a
+/// whitespace-separated list of `name="value"` pairs. Returns `None` if they
+/// are malformed.
+fn parse_attributes(mut text: &str) -> Option<Vec<(String, String)>> {
+    let mut attrs = Vec::new();
+
+    loop {
+        text = text.trim_start();
+
+        if text.is_empty() {
+            return Some(attrs);
+        }
+
+        let eq = text.find('=')?;
+        let name = &text[..eq];
+
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ':')
+        {
+            return None;
+        }
+
+        let rest = text[eq + 1..].strip_prefix('"')?;
+        let close = rest.find('"')?;
+        attrs.push((name.to_ascii_lowercase(), rest[..close].to_owned()));
+        text = &rest[close + 1..];
+
+        if !(text.is_empty() || text.starts_with(char::is_whitespace)) {
+            return None;
+        }
+    }
+}
+
+/// Append text to an HTML buffer, escaping special characters.
+fn push_escaped(dest: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => dest.push_str("&amp;"),
+            '<' => dest.push_str("&lt;"),
+            '>' => dest.push_str("&gt;"),
+            '"' => dest.push_str("&quot;"),
+            c => dest.push(c),
+        }
     }
 }
 
@@ -128,35 +530,249 @@ impl<'a, 'b: 'a> XdvEvents for State<'a, 'b> {
         Ok(())
     }
 
-    fn handle_char_run(&mut self, chars: &[i32]) -> Result<()> {
-        let dest = match self.cur_output {
-            Some(ref mut h) => h,
-            None => {
-                if !self.warned_lost_chars {
-                    tt_warning!(
-                        self.status,
-                        "losing characters in SPX file: no current output"
-                    );
-                    self.warned_lost_chars = true;
-                }
+    fn handle_begin_page(&mut self, _counters: &[i32], _previous_bop: i32) -> Result<()> {
+        // Page boundaries in SPX are semantic, so we treat them as
+        // paragraph breaks.
+        if self.wants_auto_para() {
+            self.end_para();
+        }
+
+        self.line_v = None;
+        self.text_end_h = None;
+        Ok(())
+    }
+
+    fn handle_glyph_run(&mut self, pos: Position, run: &GlyphRun) -> Result<()> {
+        // Glyphs without text, such as those in math, don't produce any
+        // output, but they take up space that mustn't be mistaken for a word
+        // space.
+        if self.text_end_h.is_some() && self.on_current_line(pos.v) {
+            self.text_end_h = Some(pos.h + run.width);
+        }
+
+        Ok(())
+    }
+
+    fn handle_special(&mut self, pos: Position, contents: &[u8]) -> Result<()> {
+        if let Some(rest) = contents.strip_prefix(b"tdux:") {
+            let text = String::from_utf8_lossy(rest);
+            self.handle_tdux(pos.h, pos.v, &text);
+        }
+
+        Ok(())
+    }
 
-                return Ok(());
+    fn handle_char_run(&mut self, pos: Position, chars: &[i32]) -> Result<()> {
+        if self.cur_output.is_none() {
+            if !self.warned_lost_chars {
+                tt_warning!(
+                    self.status,
+                    "losing characters in SPX file: no current output"
+                );
+                self.warned_lost_chars = true;
             }
-        };
 
-        self.buf.clear();
+            return Ok(());
+        }
+
+        let mut text = String::with_capacity(chars.len());
 
         for c in chars.iter() {
-            if let Some(b) = as_printable_ascii(*c) {
-                self.buf.push(b);
+            match std::char::from_u32(*c as u32) {
+                Some(ch) if !ch.is_control() => text.push(ch),
+                _ => {
+                    if !self.warned_bad_chars {
+                        tt_warning!(
+                            self.status,
+                            "SPX-to-HTML: dropping unrepresentable character code {}",
+                            c
+                        );
+                        self.warned_bad_chars = true;
+                    }
+                }
             }
         }
 
-        if !self.buf.is_empty() {
-            self.buf.push(0x0a); // newline
-            dest.write_all(&self.buf)?;
+        // We don't have the font metrics needed to know how wide this run is,
+        // and neither does the parser, so as far as it's concerned the run
+        // ends where it starts.
+        self.push_text(pos.h, pos.v, &text, pos.h);
+        Ok(())
+    }
+
+    fn handle_text_and_glyphs(&mut self, pos: Position, text: &str, run: &GlyphRun) -> Result<()> {
+        // Native fonts give us the text directly, so we don't need to worry
+        // about the glyphs.
+        if self.cur_output.is_some() {
+            self.push_text(pos.h, pos.v, text, pos.h + run.width);
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engines::NoopIoEventBackend;
+    use crate::io::memory::MemoryIo;
+    use crate::status::NoopStatusBackend;
+
+    const PT: i32 = 65536;
+
+    fn pos(h: i32, v: i32) -> Position {
+        Position {
+            h,
+            v,
+            h_exact: true,
+        }
+    }
+
+    fn chars(text: &str) -> Vec<i32> {
+        text.chars().map(|c| c as i32).collect()
+    }
+
+    fn glyphs(width: i32) -> GlyphRun {
+        GlyphRun {
+            font_num: 1,
+            width,
+            glyphs: Vec::new(),
+        }
+    }
+
+    /// Run some events through the converter and return the complete HTML.
+    fn convert<F>(f: F) -> String
+    where
+        F: FnOnce(&mut State) -> Result<()>,
+    {
+        let mut mem = MemoryIo::new(false);
+        let mut events = NoopIoEventBackend::default();
+        let mut status = NoopStatusBackend::default();
+
+        {
+            let mut io = IoStack::new(vec![&mut mem]);
+            let mut state = State::new("doc.html".to_owned(), &mut io, &mut events, &mut status);
+            state.handle_header(FileType::Spx, b"").unwrap();
+            state.handle_begin_page(&[0; 10], -1).unwrap();
+            f(&mut state).unwrap();
+            state.finished().unwrap();
+        }
+
+        let files = mem.files.borrow();
+        String::from_utf8(files["doc.html"].data.clone()).unwrap()
+    }
+
+    /// Like `convert`, but only return the contents of the body.
+    fn convert_body<F>(f: F) -> String
+    where
+        F: FnOnce(&mut State) -> Result<()>,
+    {
+        let html = convert(f);
+        let start = html.find("<body>\n").unwrap() + 7;
+        let end = html.find("</body>").unwrap();
+        html[start..end].to_owned()
+    }
+
+    #[test]
+    fn document_and_title() {
+        let html = convert(|s| {
+            s.handle_special(pos(0, 0), b"tdux:setTitle Fish & Chips")?;
+            s.handle_char_run(pos(0, 10 * PT), &chars("Hi"))
+        });
+
+        assert_eq!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Fish &amp; Chips</title>\n</head>\n<body>\n\
+             <p>Hi</p>\n</body>\n</html>\n"
+        );
+
+        let html = convert(|_| Ok(()));
+        assert!(html.contains("<title>doc</title>"));
+    }
+
+    #[test]
+    fn utf8_and_escaping() {
+        let body = convert_body(|s| {
+            s.handle_char_run(pos(0, 0), &[0x41, 0xF1, 0x2192, 0x1F642, 0x7, 0x3C, 0x26])?;
+            s.handle_text_and_glyphs(pos(PT / 2, 0), "\"é\">", &glyphs(PT))
+        });
+
+        assert_eq!(body, "<p>Añ→🙂&lt;&amp;&quot;é&quot;&gt;</p>\n");
+    }
+
+    #[test]
+    fn auto_paragraphs() {
+        let body = convert_body(|s| {
+            s.handle_char_run(pos(0, 10 * PT), &chars("One"))?;
+            // A subscript stays on the same line; a line further down
+            // starts a new paragraph, and so does a new page.
+            s.handle_char_run(pos(0, 12 * PT), &chars("a"))?;
+            s.handle_char_run(pos(0, 30 * PT), &chars("Two"))?;
+            s.handle_end_page()?;
+            s.handle_begin_page(&[0; 10], -1)?;
+            s.handle_char_run(pos(0, 30 * PT), &chars("Three"))?;
+            s.handle_special(pos(0, 30 * PT), b"tdux:aep")?;
+            s.handle_char_run(pos(0, 30 * PT), &chars("Four"))
+        });
+
+        assert_eq!(body, "<p>Onea</p>\n<p>Two</p>\n<p>Three</p>\n<p>Four</p>\n");
+    }
+
+    #[test]
+    fn elements() {
+        let body = convert_body(|s| {
+            s.handle_special(pos(0, 0), b"tdux:mfs h1")?;
+            s.handle_char_run(pos(0, 0), &chars("Title"))?;
+            s.handle_special(pos(0, 0), b"tdux:me h1")?;
+            s.handle_special(pos(0, 0), br#"tdux:mfs div class="abstract" id="a<b""#)?;
+            s.handle_char_run(pos(0, 20 * PT), &chars("Text"))?;
+            s.handle_special(pos(0, 20 * PT), b"tdux:mfs em")?;
+            s.handle_char_run(pos(0, 20 * PT), &chars("more"))?;
+            // Ending the div also ends the paragraph and the unended <em>.
+            s.handle_special(pos(0, 20 * PT), b"tdux:me div")?;
+            s.handle_special(pos(0, 40 * PT), b"tdux:mfs a href=\"?x=1&y=2\"")?;
+            s.handle_special(pos(0, 40 * PT), b"tdux:dt link")?;
+            s.handle_special(pos(0, 40 * PT), b"tdux:me a")?;
+            s.handle_special(pos(0, 40 * PT), b"tdux:mfs span onclick=alert(1)")?;
+            s.handle_special(pos(0, 40 * PT), b"tdux:me span")?;
+            s.handle_special(pos(0, 40 * PT), b"tdux:mfs bad<tag")?;
+            s.handle_special(pos(0, 40 * PT), b"tdux:me table")
+        });
+
+        assert_eq!(
+            body,
+            "<h1>Title</h1>\n\
+             <div class=\"abstract\" id=\"a&lt;b\"><p>Text<em>more</em></p>\n</div>\n\
+             <p><a href=\"?x=1&amp;y=2\">link</a><span></span></p>\n"
+        );
+    }
+
+    #[test]
+    fn word_spaces() {
+        let body = convert_body(|s| {
+            // Characters from TFM fonts, whose widths neither the parser nor
+            // we know: kerns and adjacent runs don't make spaces, but
+            // interword glue does.
+            s.handle_char_run(pos(0, 0), &chars("W"))?;
+            s.handle_char_run(pos(-PT / 4, 0), &chars("orld"))?;
+            s.handle_char_run(pos(-PT / 4, 0), &chars("ly"))?;
+            s.handle_char_run(pos(3 * PT, 0), &chars("wide"))?;
+
+            // An inline element gets its space before it, not inside it.
+            s.handle_special(pos(6 * PT, 0), b"tdux:mfs em")?;
+            s.handle_char_run(pos(6 * PT, 0), &chars("web"))?;
+            s.handle_special(pos(6 * PT, 0), b"tdux:me em")?;
+            s.handle_char_run(pos(6 * PT, 0), &chars("."))?;
+
+            // Native fonts tell us their widths.
+            s.handle_text_and_glyphs(pos(10 * PT, 0), "na", &glyphs(10 * PT))?;
+            s.handle_text_and_glyphs(pos(20 * PT + PT / 2, 0), "tive", &glyphs(10 * PT))?;
+            s.handle_glyph_run(pos(30 * PT, 0), &glyphs(5 * PT))?;
+            s.handle_text_and_glyphs(pos(35 * PT, 0), "s", &glyphs(5 * PT))?;
+            s.handle_text_and_glyphs(pos(43 * PT, 0), "too", &glyphs(10 * PT))
+        });
+
+        assert_eq!(body, "<p>Worldly wide <em>web</em>. natives too</p>\n");
+    }
+}
@@ -2,16 +2,30 @@
 // Copyright 2017-2020 the Tectonic Project
 // Licensed under the MIT License.
 
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use sha2::Digest;
+use std::cell::RefCell;
 use std::ffi::CString;
+use std::fs;
+use std::path::{Component, Path};
+use std::process::Command;
+use std::rc::Rc;
 use std::time::SystemTime;
-use tectonic_bridge_core::{CoreBridgeLauncher, EngineAbortedError, IoEventBackend};
-use tectonic_errors::anyhow::anyhow;
+use tectonic_bridge_core::{
+    CoreBridgeLauncher, EngineAbortedError, IoEventBackend, ShellEscapeBackend,
+};
+use tectonic_errors::{anyhow::anyhow, atry};
+use tectonic_io_base::digest::{self, DigestData};
 
 use crate::errors::{DefinitelySame, Result};
+use crate::io::memory::{MemoryFileCollection, MemoryFileInfo};
 use crate::io::IoStack;
 use crate::status::StatusBackend;
+use crate::tt_warning;
 use crate::unstable_opts::UnstableOptions;
 
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "snake_case"))]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum TexResult {
     // The Errors possibility should only occur if halt_on_error_p is false --
@@ -39,6 +53,7 @@ pub struct TexEngine {
     synctex_enabled: bool,
     semantic_pagination_enabled: bool,
     build_date: SystemTime,
+    shell_escape_files: Option<Rc<RefCell<MemoryFileCollection>>>,
 }
 
 impl Default for TexEngine {
@@ -49,6 +64,7 @@ impl Default for TexEngine {
             synctex_enabled: false,
             semantic_pagination_enabled: false,
             build_date: SystemTime::UNIX_EPOCH,
+            shell_escape_files: None,
         }
     }
 }
@@ -100,6 +116,20 @@ impl TexEngine {
         self
     }
 
+    /// Configure the engine to execute shell-escape (`\write18`) commands
+    /// against the given collection of in-memory files.
+    ///
+    /// Each command is run in a scratch directory populated with the files in
+    /// the collection. Any files that the command creates or modifies are
+    /// copied back into the collection, so that they are visible to the
+    /// engine and to subsequent passes. Shell-escape must also be enabled
+    /// with the `-Z shell-escape` unstable option; otherwise, the engine
+    /// will never attempt to run any commands.
+    pub fn shell_escape(&mut self, files: Rc<RefCell<MemoryFileCollection>>) -> &mut Self {
+        self.shell_escape_files = Some(files);
+        self
+    }
+
     // This function can't be generic across the IoProvider trait, for now,
     // since the global pointer that stashes the ExecutionState must have a
     // complete type.
@@ -116,8 +146,17 @@ impl TexEngine {
         let cformat = CString::new(format_file_name)?;
         let cinput = CString::new(input_file_name)?;
 
+        let mut shell_escape = self
+            .shell_escape_files
+            .as_ref()
+            .map(|files| MemoryShellEscape::new(files.clone()));
+
         let mut launcher = CoreBridgeLauncher::new(io, events, status);
 
+        if let Some(ref mut se) = shell_escape {
+            launcher = launcher.with_shell_escape(se);
+        }
+
         launcher
             .with_global_lock(|state| {
                 // Note that we have to do all of this setup while holding the
@@ -183,3 +222,145 @@ impl TexEngine {
             .map_err(|e| e.into())
     }
 }
+
+/// A shell-escape backend that runs commands in a temporary directory that
+/// mirrors the contents of a memory-backed I/O layer.
+struct MemoryShellEscape {
+    files: Rc<RefCell<MemoryFileCollection>>,
+}
+
+impl MemoryShellEscape {
+    fn new(files: Rc<RefCell<MemoryFileCollection>>) -> Self {
+        MemoryShellEscape { files }
+    }
+
+    /// Copy the in-memory files into the scratch directory. Files with names
+    /// that would land outside of the directory are skipped.
+    fn populate(&self, root: &Path) -> tectonic_errors::Result<()> {
+        for (name, info) in &*self.files.borrow() {
+            // The empty name is used for the captured standard output stream.
+            if name.is_empty() || !is_relative_within(name) {
+                continue;
+            }
+
+            let path = root.join(name);
+
+            if let Some(parent) = path.parent() {
+                atry!(fs::create_dir_all(parent); ["failed to create directory `{}`", parent.display()]);
+            }
+
+            atry!(fs::write(&path, &info.data); ["failed to write `{}`", path.display()]);
+        }
+
+        Ok(())
+    }
+
+    /// Copy any new or changed files from the scratch directory back into
+    /// memory, returning their names and digests.
+    fn harvest(&self, root: &Path) -> tectonic_errors::Result<Vec<(String, DigestData)>> {
+        let mut found = Vec::new();
+        collect_files(root, "", &mut found)?;
+
+        let mut files = self.files.borrow_mut();
+        let mut changed = Vec::new();
+
+        for name in found {
+            let path = root.join(&name);
+            let data = atry!(fs::read(&path); ["failed to read `{}`", path.display()]);
+
+            if let Some(info) = files.get(&name) {
+                if info.data == data {
+                    continue;
+                }
+            }
+
+            let mut dc = digest::create();
+            dc.update(&data);
+            changed.push((name.clone(), DigestData::from(dc)));
+
+            let unix_mtime = fs::metadata(&path)
+                .and_then(|md| md.modified())
+                .ok()
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as i64);
+
+            files.insert(name, MemoryFileInfo { data, unix_mtime });
+        }
+
+        Ok(changed)
+    }
+}
+
+impl ShellEscapeBackend for MemoryShellEscape {
+    fn execute_shell_command(
+        &mut self,
+        command: &str,
+        status: &mut dyn StatusBackend,
+    ) -> tectonic_errors::Result<Vec<(String, DigestData)>> {
+        let work_dir = atry!(
+            tempfile::Builder::new().prefix("tectonic_shell").tempdir();
+            ["failed to create a scratch directory for shell-escape"]
+        );
+
+        self.populate(work_dir.path())?;
+
+        let mut cmd = if cfg!(windows) {
+            let mut c = Command::new("cmd");
+            c.arg("/C");
+            c
+        } else {
+            let mut c = Command::new("sh");
+            c.arg("-c");
+            c
+        };
+
+        let output = atry!(
+            cmd.arg(command).current_dir(work_dir.path()).output();
+            ["failed to launch the shell"]
+        );
+
+        if !output.status.success() {
+            tt_warning!(
+                status,
+                "shell-escape command \"{}\" exited with {}",
+                command,
+                output.status
+            );
+
+            let stderr = String::from_utf8_lossy(&output.stderr);
+
+            for line in stderr.lines() {
+                tt_warning!(status, "  {}", line);
+            }
+        }
+
+        self.harvest(work_dir.path())
+    }
+}
+
+/// Test whether a file name is a relative path that stays within its root
+/// directory.
+fn is_relative_within(name: &str) -> bool {
+    Path::new(name)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Recursively list the files under `dir`, naming them relative to the
+/// scratch directory root with forward slashes, as the memory layer does.
+fn collect_files(dir: &Path, prefix: &str, dest: &mut Vec<String>) -> tectonic_errors::Result<()> {
+    for entry in atry!(fs::read_dir(dir); ["failed to read directory `{}`", dir.display()]) {
+        let entry = atry!(entry; ["failed to read directory `{}`", dir.display()]);
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let file_type =
+            atry!(entry.file_type(); ["failed to inspect `{}`", entry.path().display()]);
+
+        if file_type.is_dir() {
+            collect_files(&entry.path(), &format!("{}/", name), dest)?;
+        } else if file_type.is_file() {
+            dest.push(name);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,317 @@
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Creating bundles from trees of TeX support files.
+//!
+//! Bundles have a flat namespace, so the files of a `texmf` tree are
+//! collected by their base names. The bundle digest is computed from the
+//! sorted names and SHA256 digests of the files, and stored in the bundle in
+//! the `SHA256SUM` file that [`super::Bundle::get_digest`] reads. The output
+//! does not depend on file modification times or the order in which the
+//! filesystem lists directories, so the same tree always produces the same
+//! bundle.
+
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Seek, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tectonic_errors::{anyhow::bail, atry, Result};
+use zip::{write::FileOptions, CompressionMethod, DateTime, ZipWriter};
+
+use super::digest::{self, Digest, DigestData};
+use crate::{status::StatusBackend, tt_warning};
+
+/// The size of the blocks that make up a tar file.
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// The kinds of bundle file that can be created.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BundleFormat {
+    /// An indexed tar file, with its index in a separate gzipped file.
+    ITar,
+
+    /// A Zip file.
+    Zip,
+}
+
+impl FromStr for BundleFormat {
+    type Err = &'static str;
+
+    fn from_str(a_str: &str) -> std::result::Result<Self, Self::Err> {
+        match a_str {
+            "itar" => Ok(BundleFormat::ITar),
+            "zip" => Ok(BundleFormat::Zip),
+            _ => Err("unsupported or unknown bundle format"),
+        }
+    }
+}
+
+/// A set of files to be packaged up into a bundle.
+#[derive(Clone, Debug, Default)]
+pub struct BundleCreator {
+    files: BTreeMap<String, PathBuf>,
+}
+
+impl BundleCreator {
+    /// Collect the files in a directory tree.
+    ///
+    /// Files are named in the bundle by their base names. If several files
+    /// have the same name, the first one in order of their paths is used and
+    /// a warning is issued for the others. Hidden files, `ls-R` databases,
+    /// any existing `SHA256SUM` file, and files whose names contain
+    /// whitespace (which the indexed tar format can't represent) are skipped.
+    pub fn from_tree<P: AsRef<Path>>(root: P, status: &mut dyn StatusBackend) -> Result<Self> {
+        let mut paths = Vec::new();
+        let root = root.as_ref();
+        atry!(collect_files(root, &mut paths); ["failed to scan directory `{}`", root.display()]);
+        paths.sort();
+
+        let mut files: BTreeMap<String, PathBuf> = BTreeMap::new();
+
+        for path in paths {
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(n) => n.to_owned(),
+                None => {
+                    tt_warning!(
+                        status,
+                        "skipping `{}`: its name is not Unicode",
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+
+            if name.starts_with('.') || name == "ls-R" || name == digest::DIGEST_NAME {
+                continue;
+            }
+
+            if name.contains(char::is_whitespace) {
+                tt_warning!(
+                    status,
+                    "skipping `{}`: its name contains whitespace",
+                    path.display()
+                );
+                continue;
+            }
+
+            if let Some(existing) = files.get(&name) {
+                tt_warning!(
+                    status,
+                    "skipping `{}`: a file named `{}` was already found at `{}`",
+                    path.display(),
+                    name,
+                    existing.display()
+                );
+                continue;
+            }
+
+            files.insert(name, path);
+        }
+
+        Ok(BundleCreator { files })
+    }
+
+    /// Get the number of files that will go into the bundle, not counting
+    /// the digest file.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Test whether the bundle will be empty, apart from the digest file.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Write the bundle as a Zip file, returning its digest.
+    pub fn write_zip<W: Write + Seek>(&self, dest: W) -> Result<DigestData> {
+        let mut zip = ZipWriter::new(dest);
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .last_modified_time(DateTime::default());
+
+        let digest = self.for_each_file(|name, contents| {
+            zip.start_file(name, options)?;
+            zip.write_all(contents)?;
+            Ok(())
+        })?;
+
+        zip.finish()?;
+        Ok(digest)
+    }
+
+    /// Write the bundle as an indexed tar file, returning its digest. The
+    /// index is written to *index* in gzipped form; it should normally be
+    /// saved next to the tar file with `.index.gz` appended to its name.
+    pub fn write_itar<W: Write, I: Write>(&self, mut tar: W, index: I) -> Result<DigestData> {
+        let mut index = GzEncoder::new(index, Compression::default());
+        let mut offset = 0;
+
+        let digest = self.for_each_file(|name, contents| {
+            tar.write_all(&tar_header(name, contents.len() as u64)?)?;
+            offset += TAR_BLOCK_SIZE;
+            writeln!(index, "{} {} {}", name, offset, contents.len())?;
+
+            let n_padding = (TAR_BLOCK_SIZE - contents.len() % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+            tar.write_all(contents)?;
+            tar.write_all(&vec![0; n_padding])?;
+            offset += contents.len() + n_padding;
+            Ok(())
+        })?;
+
+        // A tar file ends with two empty blocks.
+        tar.write_all(&[0; 2 * TAR_BLOCK_SIZE])?;
+        index.finish()?;
+        Ok(digest)
+    }
+
+    /// Call a function with the name and contents of each file, in order,
+    /// and then with the digest file. Returns the bundle digest.
+    fn for_each_file<F>(&self, mut f: F) -> Result<DigestData>
+    where
+        F: FnMut(&str, &[u8]) -> Result<()>,
+    {
+        let mut bundle_dc = digest::create();
+
+        for (name, path) in &self.files {
+            let contents = atry!(fs::read(path); ["failed to read `{}`", path.display()]);
+
+            let mut dc = digest::create();
+            dc.update(&contents);
+
+            bundle_dc.update(name.as_bytes());
+            bundle_dc.update(b"\0");
+            bundle_dc.update(dc.finalize());
+
+            f(name, &contents)?;
+        }
+
+        let digest = DigestData::from(bundle_dc);
+        f(digest::DIGEST_NAME, digest.to_string().as_bytes())?;
+        Ok(digest)
+    }
+}
+
+/// Recursively collect the paths of the files below a directory.
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            collect_files(&path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Create a POSIX tar header for a regular file. The metadata are fixed so
+/// that the output is reproducible.
+fn tar_header(name: &str, size: u64) -> Result<[u8; TAR_BLOCK_SIZE]> {
+    fn octal(field: &mut [u8], value: u64) {
+        let text = format!("{:0width$o}\0", value, width = field.len() - 1);
+        field.copy_from_slice(text.as_bytes());
+    }
+
+    if name.len() > 100 {
+        bail!("the file name `{}` is too long for a tar file", name);
+    }
+
+    if size >= 1 << 33 {
+        bail!("the file `{}` is too large for a tar file", name);
+    }
+
+    let mut header = [0; TAR_BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644); // mode
+    octal(&mut header[108..116], 0); // uid
+    octal(&mut header[116..124], 0); // gid
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], 0); // mtime
+    header[156] = b'0'; // regular file
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with the checksum field filled with spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{itarbundle::ITarBundle, zipbundle::ZipBundle, Bundle, OpenResult};
+    use crate::status::NoopStatusBackend;
+    use std::{fs::File, io::Read};
+
+    fn read(bundle: &mut dyn Bundle, name: &str) -> Option<String> {
+        let mut sb = NoopStatusBackend::default();
+
+        match bundle.input_open_name(name, &mut sb) {
+            OpenResult::Ok(mut h) => {
+                let mut s = String::new();
+                h.read_to_string(&mut s).unwrap();
+                Some(s)
+            }
+            OpenResult::NotAvailable => None,
+            OpenResult::Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn create_bundles() {
+        let mut sb = NoopStatusBackend::default();
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("texmf");
+
+        for (path, contents) in &[
+            ("tex/a/a.sty", "a"),
+            ("tex/b/a.sty", "not a"),
+            ("tex/b/b.cls", "b"),
+            ("fonts/c.tfm", "c"),
+            ("ls-R", "database"),
+        ] {
+            let path = tree.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        let creator = BundleCreator::from_tree(&tree, &mut sb).unwrap();
+        assert_eq!(creator.len(), 3);
+
+        let zip_path = dir.path().join("bundle.zip");
+        let zip_digest = creator.write_zip(File::create(&zip_path).unwrap()).unwrap();
+
+        let tar_path = dir.path().join("bundle.tar");
+        let tar_digest = creator
+            .write_itar(
+                File::create(&tar_path).unwrap(),
+                File::create(dir.path().join("bundle.tar.index.gz")).unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(zip_digest, tar_digest);
+        assert_eq!(fs::metadata(&tar_path).unwrap().len() % 512, 0);
+
+        let bundles: Vec<Box<dyn Bundle>> = vec![
+            Box::new(ZipBundle::open(&zip_path).unwrap()),
+            Box::new(ITarBundle::open(&tar_path).unwrap()),
+        ];
+
+        for mut bundle in bundles {
+            assert_eq!(read(&mut *bundle, "a.sty").as_deref(), Some("a"));
+            assert_eq!(read(&mut *bundle, "b.cls").as_deref(), Some("b"));
+            assert_eq!(read(&mut *bundle, "c.tfm").as_deref(), Some("c"));
+            assert_eq!(read(&mut *bundle, "ls-R"), None);
+            assert_eq!(bundle.get_digest(&mut sb).unwrap(), zip_digest);
+        }
+    }
+}
@@ -4,16 +4,30 @@
 use flate2::read::GzDecoder;
 use fs2::FileExt;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{self, File},
-    io::{BufRead, BufReader, Error as IoError, ErrorKind as IoErrorKind, Read, Write},
+    io::{
+        BufRead, BufReader, Error as IoError, ErrorKind as IoErrorKind, Read, Seek, SeekFrom, Write,
+    },
     path::{Path, PathBuf},
     str::FromStr,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tectonic_errors::{
+    anyhow::{anyhow, bail},
+    atry, Result,
+};
+use tectonic_geturl::{
+    DefaultBackend, DefaultRangeReader, GetUrlBackend, GetUrlConfig, RangeReader,
 };
-use tectonic_errors::{anyhow::bail, atry, Result};
-use tectonic_geturl::{DefaultBackend, DefaultRangeReader, GetUrlBackend, RangeReader};
 
-use super::{try_open_file, Bundle, InputHandle, InputOrigin, IoProvider, OpenResult};
+use super::{
+    format_cache,
+    itarbundle::{parse_index_line, FileInfo},
+    try_open_file, Bundle, InputHandle, InputOrigin, IoProvider, OpenResult,
+};
 use crate::app_dirs;
 use crate::digest::{self, Digest, DigestData};
 use crate::errors::SyncError;
@@ -22,10 +36,63 @@ use crate::{tt_note, tt_warning};
 
 const MAX_HTTP_ATTEMPTS: usize = 4;
 
-#[derive(Clone, Copy, Debug)]
-struct FileInfo {
-    offset: u64,
-    length: u64,
+/// When fetching many files, they are downloaded in batches of this size,
+/// with progress reported after each one.
+const FETCH_BATCH_SIZE: usize = 1000;
+
+/// Files that are at most this many bytes apart in the bundle are retrieved
+/// with a single range request.
+const MAX_COALESCE_GAP: u64 = 64 * 1024;
+
+/// The maximum size of a single range request that covers several files.
+const MAX_COALESCED_LENGTH: u64 = 8 * 1024 * 1024;
+
+/// The maximum number of range requests to make at once.
+const MAX_PARALLEL_REQUESTS: usize = 8;
+
+/// The maximum number of files to prefetch when a package or class is loaded.
+const MAX_PREFETCH_FILES: usize = 500;
+
+/// How often to check whether the bundle at a URL has been updated.
+///
+/// The first time that a URL is used, the digest of the bundle that it points
+/// to is recorded in the local cache, and that bundle is used from then on.
+/// When a check finds that the URL points to a different bundle, its index is
+/// downloaded and the cache switches over to it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UpdatePolicy {
+    /// Never check for updates.
+    Never,
+
+    /// Check for updates every time that the bundle is opened.
+    Always,
+
+    /// Check for updates if the last check was at least this long ago.
+    Interval(Duration),
+}
+
+impl Default for UpdatePolicy {
+    fn default() -> Self {
+        UpdatePolicy::Never
+    }
+}
+
+impl UpdatePolicy {
+    /// Decide whether a check is due. The digest file of a URL is rewritten
+    /// whenever it is checked, so its modification time records when the
+    /// last check happened.
+    fn check_due(&self, digest_path: &Path) -> bool {
+        match *self {
+            UpdatePolicy::Never => false,
+            UpdatePolicy::Always => true,
+            UpdatePolicy::Interval(interval) => fs::metadata(digest_path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.elapsed().ok())
+                .map(|age| age >= interval)
+                .unwrap_or(true),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -69,12 +136,27 @@ fn get_file(
             }
         };
 
+        // Discard anything left over from a failed attempt.
+        buf.clear();
+
         if let Err(e) = stream.read_to_end(&mut buf) {
             tt_warning!(status, "failure downloading \"{}\" from network", name; e.into());
             any_failed = true;
             continue;
         }
 
+        if buf.len() != length {
+            tt_warning!(
+                status,
+                "failure downloading \"{}\" from network: expected {} bytes but got {}",
+                name,
+                length,
+                buf.len()
+            );
+            any_failed = true;
+            continue;
+        }
+
         overall_failed = false;
         break;
     }
@@ -93,21 +175,193 @@ fn get_file(
     Ok(buf)
 }
 
-fn parse_index_line(line: &str) -> Result<Option<(String, FileInfo)>> {
-    let mut bits = line.split_whitespace();
+/// Read a byte range, retrying a few times in case of transient network
+/// problems.
+fn read_range_with_retries(data: &mut DefaultRangeReader, range: FileInfo) -> Result<Vec<u8>> {
+    let mut last_error = None;
 
-    if let (Some(name), Some(offset), Some(length)) = (bits.next(), bits.next(), bits.next()) {
-        Ok(Some((
-            name.to_owned(),
-            FileInfo {
-                offset: offset.parse::<u64>()?,
-                length: length.parse::<u64>()?,
-            },
-        )))
-    } else {
-        // TODO: preserve the warning info or something!
-        Ok(None)
+    for _ in 0..MAX_HTTP_ATTEMPTS {
+        let mut buf = Vec::with_capacity(range.length as usize);
+
+        let result = data
+            .read_range(range.offset, range.length as usize)
+            .and_then(|mut stream| Ok(stream.read_to_end(&mut buf)?));
+
+        match result {
+            Ok(n) if n as u64 == range.length => return Ok(buf),
+            Ok(n) => {
+                last_error = Some(anyhow!(
+                    "expected {} bytes from the network but got {}",
+                    range.length,
+                    n
+                ))
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap())
+}
+
+/// Download several byte ranges of a URL, making up to
+/// `MAX_PARALLEL_REQUESTS` requests at once. The results are returned in the
+/// same order as the ranges.
+fn get_ranges_parallel(
+    url: &str,
+    network: &GetUrlConfig,
+    ranges: &[FileInfo],
+) -> Vec<Result<Vec<u8>>> {
+    let queue = Arc::new(Mutex::new(
+        ranges.iter().copied().enumerate().collect::<Vec<_>>(),
+    ));
+    let (tx, rx) = mpsc::channel();
+
+    for _ in 0..MAX_PARALLEL_REQUESTS.min(ranges.len()) {
+        let queue = queue.clone();
+        let tx = tx.clone();
+        let url = url.to_owned();
+        let network = network.clone();
+
+        thread::spawn(move || {
+            let mut data = DefaultBackend::with_config(network).open_range_reader(&url);
+
+            loop {
+                let (i, range) = match queue.lock().unwrap().pop() {
+                    Some(item) => item,
+                    None => break,
+                };
+
+                if tx
+                    .send((i, read_range_with_retries(&mut data, range)))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Make sure that the loop below ends once all of the workers are done.
+    drop(tx);
+
+    let mut results: Vec<Option<Result<Vec<u8>>>> = ranges.iter().map(|_| None).collect();
+
+    for (i, result) in rx {
+        results[i] = Some(result);
+    }
+
+    results
+        .into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(anyhow!("download thread failed"))))
+        .collect()
+}
+
+/// Group files into byte ranges of the bundle that can each be retrieved
+/// with a single request. Each range is returned along with the files that
+/// it contains.
+fn coalesce_ranges(mut files: Vec<(String, FileInfo)>) -> Vec<(FileInfo, Vec<(String, FileInfo)>)> {
+    files.sort_by_key(|(_, info)| info.offset);
+
+    let mut groups: Vec<(FileInfo, Vec<(String, FileInfo)>)> = Vec::new();
+
+    for (name, info) in files {
+        if let Some((range, members)) = groups.last_mut() {
+            let range_end = range.offset + range.length;
+            let new_end = range_end.max(info.offset + info.length);
+
+            if info.offset <= range_end + MAX_COALESCE_GAP
+                && new_end - range.offset <= MAX_COALESCED_LENGTH
+            {
+                range.length = new_end - range.offset;
+                members.push((name, info));
+                continue;
+            }
+        }
+
+        groups.push((info, vec![(name, info)]));
+    }
+
+    groups
+}
+
+/// Test whether a file is a LaTeX package or class.
+fn is_package_or_class(name: &str) -> bool {
+    name.ends_with(".sty") || name.ends_with(".cls")
+}
+
+/// Find the packages and classes that a LaTeX package or class loads, as the
+/// names of the files that provide them.
+///
+/// This is a rough scan of the source code that is only used to decide what
+/// to prefetch, so it doesn't need to be exact.
+fn scan_dependencies(content: &[u8]) -> Vec<String> {
+    const COMMANDS: &[(&str, &str)] = &[
+        ("\\RequirePackage", "sty"),
+        ("\\RequirePackageWithOptions", "sty"),
+        ("\\usepackage", "sty"),
+        ("\\LoadClass", "cls"),
+        ("\\LoadClassWithOptions", "cls"),
+    ];
+
+    // Strip comments, so that we don't prefetch things that are commented
+    // out, but keep the line structure out of the way of arguments that span
+    // several lines.
+
+    let text: String = String::from_utf8_lossy(content)
+        .lines()
+        .map(|l| l.split('%').next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut deps = Vec::new();
+    let mut rest = text.as_str();
+
+    while let Some(pos) = rest.find('\\') {
+        rest = &rest[pos..];
+
+        let found = COMMANDS.iter().find(|(cmd, _)| {
+            rest.starts_with(cmd)
+                && !rest[cmd.len()..]
+                    .chars()
+                    .next()
+                    .map(|c| c.is_ascii_alphabetic() || c == '@')
+                    .unwrap_or(false)
+        });
+
+        let (cmd, ext) = match found {
+            Some(f) => f,
+            None => {
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        rest = &rest[cmd.len()..];
+        let mut args = rest.trim_start();
+
+        if args.starts_with('[') {
+            match args.find(']') {
+                Some(i) => args = args[i + 1..].trim_start(),
+                None => continue,
+            }
+        }
+
+        if let Some(args) = args.strip_prefix('{') {
+            if let Some(i) = args.find('}') {
+                for name in args[..i].split(',') {
+                    let name = name.trim();
+
+                    if !name.is_empty()
+                        && !name.contains(|c: char| c == '\\' || c == '#' || c.is_whitespace())
+                    {
+                        deps.push(format!("{}.{}", name, ext));
+                    }
+                }
+            }
+        }
     }
+
+    deps
 }
 
 /// Attempts to find the redirected url, download the index and digest.
@@ -221,10 +475,69 @@ fn load_cache_inner(
     })
 }
 
+/// Save freshly downloaded data, making the URL whose digest is stored at
+/// *digest_path* point to the bundle with digest *digest_text*.
+fn save_cache(
+    digest_path: &Path,
+    redirect_base: &Path,
+    index_base: &Path,
+    digest_text: &str,
+    index: &str,
+    redirect_url: &str,
+) -> Result<()> {
+    file_create_write(make_txt_path(redirect_base, digest_text), |f| {
+        f.write_all(redirect_url.as_bytes())
+    })?;
+    file_create_write(make_txt_path(index_base, digest_text), |f| {
+        f.write_all(index.as_bytes())
+    })?;
+    file_create_write(digest_path, |f| writeln!(f, "{}", digest_text))
+}
+
+/// Remove the cached formats generated from an old version of a bundle, once
+/// no URL in *url_base* points to it any more.
+fn forget_bundle_formats(
+    url_base: &Path,
+    formats_base: &Path,
+    old_digest: &DigestData,
+    status: &mut dyn StatusBackend,
+) -> Result<()> {
+    let old_text = old_digest.to_string();
+
+    for entry in fs::read_dir(url_base)? {
+        if fs::read_to_string(entry?.path())?.trim() == old_text {
+            return Ok(());
+        }
+    }
+
+    let (n_removed, _) = format_cache::remove_bundle_formats(formats_base, old_digest)?;
+
+    if n_removed > 0 {
+        tt_note!(
+            status,
+            "removed {} cached formats built from the old bundle",
+            n_removed
+        );
+    }
+
+    Ok(())
+}
+
 fn make_txt_path(base: &Path, digest_text: &str) -> PathBuf {
     base.join(&digest_text).with_extension("txt")
 }
 
+/// Split a line of a manifest file into the file name, length, and digest.
+/// The name may contain spaces, so we split from the right.
+fn parse_manifest_line(line: &str) -> Option<(&str, &str, &str)> {
+    let mut bits = line.rsplitn(3, ' ');
+
+    match (bits.next(), bits.next(), bits.next(), bits.next()) {
+        (Some(s), Some(t), Some(r), None) => Some((r, t, s)),
+        _ => None,
+    }
+}
+
 /// Bundle provided by an indexed tar file over http with a local cache.
 #[derive(Debug)]
 pub struct CachedITarBundle {
@@ -234,50 +547,114 @@ pub struct CachedITarBundle {
     cached_digest: DigestData,
     checked_digest: bool,
     redirect_base: PathBuf,
+    formats_base: PathBuf,
     manifest_path: PathBuf,
     data_base: PathBuf,
     contents: HashMap<String, LocalCacheItem>,
     only_cached: bool,
+    network: GetUrlConfig,
 
     tar_data: DefaultRangeReader,
     index: HashMap<String, FileInfo>,
 }
 
 impl CachedITarBundle {
+    /// Open the bundle at *url*, using the local cache as much as possible.
+    ///
+    /// The bundle that the URL pointed to when it was first used is used
+    /// until *update* says that it's time to check for a newer one. Checks
+    /// are never made if *only_cached* is true, and if a check fails, the
+    /// cached bundle is used with a warning.
     pub fn new(
         url: &str,
         only_cached: bool,
+        update: UpdatePolicy,
         custom_cache_root: Option<&Path>,
+        network: &GetUrlConfig,
         status: &mut dyn StatusBackend,
     ) -> Result<CachedITarBundle> {
-        let mut backend = DefaultBackend::default();
-        let digest_path = cache_dir("urls", custom_cache_root)?.join(app_dirs::sanitized(url));
+        let mut backend = DefaultBackend::with_config(network.clone());
+        let url_base = &cache_dir("urls", custom_cache_root)?;
+        let digest_path = url_base.join(app_dirs::sanitized(url));
 
         let redirect_base = &cache_dir("redirects", custom_cache_root)?;
         let index_base = &cache_dir("indexes", custom_cache_root)?;
         let manifest_base = &cache_dir("manifests", custom_cache_root)?;
         let data_base = &cache_dir("files", custom_cache_root)?;
+        let formats_base = &cache_dir("formats", custom_cache_root)?;
 
+        // Try loading the cached files.
+        let mut content = load_cache(&digest_path, redirect_base, index_base)?;
         let mut checked_digest = false;
-        let CacheContent {digest_text, redirect_url, index} =
-            // Try loading the cached files.
-            match load_cache(&digest_path, &redirect_base, &index_base)? {
-                Some(c) => c,
-                None => {
-                    // At least one of the cached files does not exists. We fetch everything from
-                    // scratch and save the files.
-                    let (digest_text, index, redirect_url) = get_everything(&mut backend, url, status)?;
-                    let _ = DigestData::from_str(&digest_text)?;
+
+        if content.is_some() && !only_cached && update.check_due(&digest_path) {
+            tt_note!(status, "checking for updates to the bundle at {}", url);
+
+            match get_everything(&mut backend, url, status) {
+                Ok((digest_text, index, redirect_url)) => {
+                    let new_digest = atry!(
+                        DigestData::from_str(&digest_text);
+                        ["bad SHA256 digest from bundle"]
+                    );
+                    let old_digest = DigestData::from_str(&content.take().unwrap().digest_text)?;
+
+                    save_cache(
+                        &digest_path,
+                        redirect_base,
+                        index_base,
+                        &digest_text,
+                        &index,
+                        &redirect_url,
+                    )?;
                     checked_digest = true;
 
-                    file_create_write(&digest_path, |f| writeln!(f, "{}", digest_text))?;
-                    file_create_write(make_txt_path(&redirect_base, &digest_text), |f| f.write_all(redirect_url.as_bytes()))?;
-                    file_create_write(make_txt_path(&index_base, &digest_text), |f| f.write_all(index.as_bytes()))?;
+                    if new_digest != old_digest {
+                        tt_note!(
+                            status,
+                            "the bundle at {} has been updated; switching from {} to {}",
+                            url,
+                            old_digest.to_string(),
+                            digest_text
+                        );
+                        forget_bundle_formats(url_base, formats_base, &old_digest, status)?;
+                    }
+                }
 
-                    // Reload the cached files now when they were saved.
-                    atry!(load_cache(&digest_path, &redirect_base, &index_base)?; ["cache files missing even after they were created"])
+                Err(e) => {
+                    tt_warning!(status, "failed to check for updates to the bundle at {}; using the cached version", url; e);
                 }
-            };
+            }
+        }
+
+        let CacheContent {
+            digest_text,
+            redirect_url,
+            index,
+        } = match content {
+            Some(c) => c,
+            None => {
+                if !checked_digest {
+                    // At least one of the cached files does not exists. We
+                    // fetch everything from scratch and save the files.
+                    let (digest_text, index, redirect_url) =
+                        get_everything(&mut backend, url, status)?;
+                    let _ = DigestData::from_str(&digest_text)?;
+                    checked_digest = true;
+
+                    save_cache(
+                        &digest_path,
+                        redirect_base,
+                        index_base,
+                        &digest_text,
+                        &index,
+                        &redirect_url,
+                    )?;
+                }
+
+                // Reload the cached files now when they were saved.
+                atry!(load_cache(&digest_path, redirect_base, index_base)?; ["cache files missing even after they were created"])
+            }
+        };
 
         let cached_digest = DigestData::from_str(&digest_text)?;
 
@@ -306,13 +683,11 @@ impl CachedITarBundle {
 
                 for res in f.lines() {
                     let line = res?;
-                    let mut bits = line.rsplitn(3, ' ');
 
-                    let (original_name, length, digest) =
-                        match (bits.next(), bits.next(), bits.next(), bits.next()) {
-                            (Some(s), Some(t), Some(r), None) => (r, t, s),
-                            _ => continue,
-                        };
+                    let (original_name, length, digest) = match parse_manifest_line(&line) {
+                        Some(t) => t,
+                        None => continue,
+                    };
 
                     let name = original_name.to_owned();
 
@@ -358,8 +733,10 @@ impl CachedITarBundle {
             manifest_path,
             data_base: data_base.to_owned(),
             redirect_base: redirect_base.to_owned(),
+            formats_base: formats_base.to_owned(),
             contents,
             only_cached,
+            network: network.clone(),
             tar_data,
             index,
         })
@@ -428,7 +805,7 @@ impl CachedITarBundle {
 
         // The quick check failed. Try to pull all data to make sure that it wasn't a network
         // error or that the redirect url hasn't been updated.
-        let mut backend = DefaultBackend::default();
+        let mut backend = DefaultBackend::with_config(self.network.clone());
         let (digest_text, _index, redirect_url) = get_everything(&mut backend, &self.url, status)?;
 
         let current_digest =
@@ -441,6 +818,15 @@ impl CachedITarBundle {
             file_create_write(&self.digest_path, |f| {
                 writeln!(f, "{}", current_digest.to_string())
             })?;
+
+            if let Some(url_base) = self.digest_path.parent() {
+                if let Err(e) =
+                    forget_bundle_formats(url_base, &self.formats_base, &self.cached_digest, status)
+                {
+                    tt_warning!(status, "failed to remove the cached formats of the old bundle"; e);
+                }
+            }
+
             bail!("backend digest changed; rerun tectonic to use updated information");
         }
 
@@ -459,6 +845,110 @@ impl CachedITarBundle {
         Ok(())
     }
 
+    /// Test whether a file is stored in the local cache.
+    pub fn is_cached(&self, name: &str) -> bool {
+        self.contents.contains_key(name)
+    }
+
+    /// Expand a list of file and package names into the names of files in
+    /// the bundle.
+    ///
+    /// A name that exactly matches a file in the bundle selects that file.
+    /// Otherwise, it is treated as the name of a package and selects every
+    /// file whose name has the form `NAME.EXT`, so that `hyperref` selects
+    /// `hyperref.sty`, `hyperref.cfg`, and so on. It is an error if a name
+    /// selects nothing.
+    pub fn select_files(&self, names: &[String]) -> Result<Vec<String>> {
+        let mut selected = Vec::new();
+
+        for name in names {
+            if self.index.contains_key(name) {
+                selected.push(name.clone());
+                continue;
+            }
+
+            let mut files = self.package_files(&format!("{}.", name));
+
+            if files.is_empty() {
+                bail!("no file or package named \"{}\" in the bundle", name);
+            }
+
+            selected.append(&mut files);
+        }
+
+        selected.sort_unstable();
+        selected.dedup();
+        Ok(selected)
+    }
+
+    /// Download files from the bundle into the local cache.
+    ///
+    /// Files that are already cached are skipped, and each file is recorded
+    /// in the cache as soon as it has been downloaded, so an interrupted
+    /// fetch can be resumed by running it again. Failures to download
+    /// individual files are reported as warnings, and the remaining files are
+    /// still fetched, but an error is returned at the end.
+    pub fn fetch_files(&mut self, names: &[String], status: &mut dyn StatusBackend) -> Result<()> {
+        let n_total = names.len();
+
+        // Fetch the files in the order that they appear in the tar file, so
+        // that the batches can be coalesced into as few requests as possible.
+
+        let mut to_fetch: Vec<&String> = names.iter().filter(|n| !self.is_cached(n)).collect();
+        to_fetch.sort_by_key(|n| self.index.get(*n).map(|info| info.offset));
+        let n_cached = n_total - to_fetch.len();
+
+        tt_note!(
+            status,
+            "fetching {} files ({} already cached)",
+            to_fetch.len(),
+            n_cached
+        );
+
+        let mut n_done = 0;
+        let mut n_failed = 0;
+
+        for batch in to_fetch.chunks(FETCH_BATCH_SIZE) {
+            let batch: Vec<String> = batch.iter().map(|n| (*n).clone()).collect();
+            self.fetch_batch(&batch, status);
+
+            // Anything that the batch missed gets another chance, with the
+            // usual retries and error reporting.
+
+            for name in &batch {
+                if self.is_cached(name) {
+                    continue;
+                }
+
+                match self.path_for_name(name, status) {
+                    OpenResult::Ok(_) => {}
+                    OpenResult::NotAvailable => {
+                        tt_warning!(status, "file \"{}\" is not in the bundle", name);
+                        n_failed += 1;
+                    }
+                    OpenResult::Err(e) => {
+                        tt_warning!(status, "failed to fetch \"{}\"", name; e);
+                        n_failed += 1;
+                    }
+                }
+            }
+
+            n_done += batch.len();
+            tt_note!(status, "fetched {} of {} files", n_done, to_fetch.len());
+        }
+
+        if n_failed > 0 {
+            bail!(
+                "failed to fetch {} of {} files; run the command again to retry",
+                n_failed,
+                to_fetch.len()
+            );
+        }
+
+        tt_note!(status, "all {} files are now cached", n_total);
+        Ok(())
+    }
+
     /// Find the path in the local cache for the provided file. Download the file first if it is
     /// not in the local cache already.
     fn path_for_name(&mut self, name: &str, status: &mut dyn StatusBackend) -> OpenResult<PathBuf> {
@@ -505,20 +995,35 @@ impl CachedITarBundle {
             Err(e) => return OpenResult::Err(e),
         };
 
+        let final_path = match self.store_file(name, &content) {
+            Ok(p) => p,
+            Err(e) => return OpenResult::Err(e),
+        };
+
+        // Packages and classes usually load more packages. Fetch those now,
+        // in bulk, rather than one at a time as TeX asks for them.
+
+        if is_package_or_class(name) {
+            self.prefetch_dependencies(&content, status);
+        }
+
+        OpenResult::Ok(final_path)
+    }
+
+    /// Save a file downloaded from the bundle into the local cache, returning
+    /// its path.
+    fn store_file(&mut self, name: &str, content: &[u8]) -> Result<PathBuf> {
         // OK, we can stream the file to a temporary location on disk,
         // computing its SHA256 as we go.
 
         let length = content.len();
 
         let mut digest_builder = digest::create();
-        digest_builder.update(&content);
+        digest_builder.update(content);
 
         let digest = DigestData::from(digest_builder);
 
-        let final_path = match digest.create_two_part_path(&self.data_base) {
-            Ok(p) => p,
-            Err(e) => return OpenResult::Err(e),
-        };
+        let final_path = digest.create_two_part_path(&self.data_base)?;
 
         // Perform a racy check for the destination existing, because this
         // matters on Windows: if the destination is already there, we'll get
@@ -527,36 +1032,137 @@ impl CachedITarBundle {
         // subject to the race once.
 
         if !final_path.exists() {
-            if let Err(e) = file_create_write(&final_path, |f| f.write_all(&content)) {
-                return OpenResult::Err(e);
-            }
+            file_create_write(&final_path, |f| f.write_all(content))?;
 
             // Now we can make the file readonly. It would be nice to set the
             // permissions using the already-open file handle owned by the
             // tempfile, but mkstemp doesn't give us access.
-            let mut perms = match fs::metadata(&final_path) {
-                Ok(p) => p,
-                Err(e) => {
-                    return OpenResult::Err(e.into());
-                }
-            }
-            .permissions();
+            let mut perms = fs::metadata(&final_path)?.permissions();
             perms.set_readonly(true);
-
-            if let Err(e) = fs::set_permissions(&final_path, perms) {
-                return OpenResult::Err(e.into());
-            }
+            fs::set_permissions(&final_path, perms)?;
         }
 
         // And finally add a record of this file to our manifest. Note that
         // we're opening and closing this file every time we load a new file;
         // not so efficient, but whatever.
 
-        if let Err(e) = self.record_cache_result(name, length as u64, digest) {
-            return OpenResult::Err(e);
+        self.record_cache_result(name, length as u64, digest)?;
+        Ok(final_path)
+    }
+
+    /// Download several files from the bundle and save them into the local
+    /// cache.
+    ///
+    /// Files that are close together in the tar file are retrieved with a
+    /// single range request, and several requests are made at once. Files
+    /// that cannot be retrieved are skipped, so that they can be fetched (or
+    /// fail) individually later. Returns the names and contents of the files
+    /// that were saved.
+    fn fetch_batch(
+        &mut self,
+        names: &[String],
+        status: &mut dyn StatusBackend,
+    ) -> Vec<(String, Vec<u8>)> {
+        let files: Vec<(String, FileInfo)> = names
+            .iter()
+            .filter_map(|n| self.index.get(n).map(|info| (n.clone(), *info)))
+            .collect();
+
+        if files.is_empty() || self.check_digest(status).is_err() {
+            return Vec::new();
         }
 
-        OpenResult::Ok(final_path)
+        let n_files = files.len();
+        let groups = coalesce_ranges(files);
+        let ranges: Vec<FileInfo> = groups.iter().map(|(range, _)| *range).collect();
+        let n_bytes: u64 = ranges.iter().map(|r| r.length).sum();
+
+        tt_note!(
+            status,
+            "downloading {} files ({:.1} MiB in {} requests)",
+            n_files,
+            n_bytes as f64 / 1048576.,
+            ranges.len()
+        );
+
+        let results = get_ranges_parallel(&self.redirect_url, &self.network, &ranges);
+        let mut fetched = Vec::new();
+
+        for ((range, members), result) in groups.into_iter().zip(results) {
+            let data = match result {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+
+            for (name, info) in members {
+                let start = (info.offset - range.offset) as usize;
+                let content = data[start..start + info.length as usize].to_vec();
+
+                if self.store_file(&name, &content).is_ok() {
+                    fetched.push((name, content));
+                }
+            }
+        }
+
+        fetched
+    }
+
+    /// Get the files of a package, given the name of its main file: the
+    /// other files in the bundle with the same stem and a single extension.
+    fn package_files(&self, main_name: &str) -> Vec<String> {
+        let stem = match main_name.rfind('.') {
+            Some(i) => &main_name[..i],
+            None => main_name,
+        };
+
+        let prefix = format!("{}.", stem);
+
+        self.index
+            .keys()
+            .filter(|f| f.starts_with(&prefix) && !f[prefix.len()..].contains('.'))
+            .cloned()
+            .collect()
+    }
+
+    /// Fetch the packages and classes loaded by a package or class, and the
+    /// ones that they load in turn, and so on.
+    ///
+    /// This is only an optimization, so failures are ignored: anything that
+    /// isn't fetched here will be fetched when TeX asks for it.
+    fn prefetch_dependencies(&mut self, content: &[u8], status: &mut dyn StatusBackend) {
+        let mut seen = HashSet::new();
+        let mut wanted = scan_dependencies(content);
+        let mut n_fetched = 0;
+
+        while !wanted.is_empty() && n_fetched < MAX_PREFETCH_FILES {
+            let mut wave = Vec::new();
+
+            for dep in wanted.drain(..) {
+                if !seen.insert(dep.clone()) || !self.index.contains_key(&dep) {
+                    continue;
+                }
+
+                for name in self.package_files(&dep) {
+                    if !self.is_cached(&name) && !wave.contains(&name) {
+                        wave.push(name);
+                    }
+                }
+            }
+
+            wave.truncate(MAX_PREFETCH_FILES - n_fetched);
+
+            if wave.is_empty() {
+                break;
+            }
+
+            for (name, content) in self.fetch_batch(&wave, status) {
+                n_fetched += 1;
+
+                if is_package_or_class(&name) {
+                    wanted.append(&mut scan_dependencies(&content));
+                }
+            }
+        }
     }
 }
 
@@ -589,6 +1195,286 @@ impl Bundle for CachedITarBundle {
     fn get_digest(&mut self, _status: &mut dyn StatusBackend) -> Result<DigestData> {
         Ok(self.cached_digest)
     }
+
+    fn all_files(&mut self, _status: &mut dyn StatusBackend) -> Result<Vec<String>> {
+        Ok(self.index.keys().cloned().collect())
+    }
+}
+
+/// Get the digest of the bundle at a URL as recorded in the local cache,
+/// without touching the network. Returns `None` if the URL has never been
+/// used.
+pub fn cached_bundle_digest(
+    url: &str,
+    custom_cache_root: Option<&Path>,
+) -> Result<Option<DigestData>> {
+    let digest_path = cache_dir("urls", custom_cache_root)?.join(app_dirs::sanitized(url));
+
+    let text = match fs::read_to_string(&digest_path) {
+        Ok(t) => t,
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(
+        atry!(DigestData::from_str(text.trim()); ["bad digest in cache file {}", digest_path.display()]),
+    ))
+}
+
+/// Check every file in the local cache against the digest recorded for it.
+///
+/// Files that are missing or whose contents do not match their digests are
+/// deleted and their entries are removed from the manifests, as are manifest
+/// entries that cannot be parsed, so that the files will be downloaded again
+/// the next time that they are needed. Returns the names of the evicted
+/// files, keyed by the digest of the bundle they belong to.
+pub fn verify_cache(
+    custom_cache_root: Option<&Path>,
+    status: &mut dyn StatusBackend,
+) -> Result<HashMap<String, Vec<String>>> {
+    let manifest_base = cache_dir("manifests", custom_cache_root)?;
+    let data_base = cache_dir("files", custom_cache_root)?;
+
+    // Several bundles may share a file, so only check each one once.
+    let mut file_ok: HashMap<String, bool> = HashMap::new();
+    let mut evicted = HashMap::new();
+    let mut n_bad_entries = 0;
+
+    for (bundle_digest, manifest_path) in list_txt_files(&manifest_base)? {
+        let mut mfile = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&manifest_path)?;
+
+        // Lock will be released when file is closed at the end of this iteration.
+        atry!(mfile.lock_exclusive(); ["failed to lock manifest file \"{}\" for writing", manifest_path.display()]);
+
+        let mut kept = Vec::new();
+        let mut bad_names = Vec::new();
+        let mut changed = false;
+
+        for res in BufReader::new(&mfile).lines() {
+            let line = res?;
+
+            let parsed = parse_manifest_line(&line).and_then(|(name, length, digest)| {
+                length.parse::<u64>().ok()?;
+                Some((name.to_owned(), DigestData::from_str(digest).ok()?))
+            });
+
+            let (name, digest) = match parsed {
+                Some(p) => p,
+                None => {
+                    n_bad_entries += 1;
+                    changed = true;
+                    continue;
+                }
+            };
+
+            let digest_text = digest.to_string();
+
+            let ok = match file_ok.get(&digest_text) {
+                Some(ok) => *ok,
+                None => {
+                    let path = cached_file_path(&data_base, &digest_text);
+                    let ok = cached_file_matches(&path, &digest)?;
+
+                    if !ok {
+                        tt_warning!(status, "cached file \"{}\" is missing or corrupt", name);
+                        remove_cached_file(&path)?;
+                    }
+
+                    file_ok.insert(digest_text, ok);
+                    ok
+                }
+            };
+
+            if ok {
+                kept.push(line);
+            } else {
+                bad_names.push(name);
+                changed = true;
+            }
+        }
+
+        if changed {
+            mfile.set_len(0)?;
+            mfile.seek(SeekFrom::Start(0))?;
+
+            for line in &kept {
+                writeln!(mfile, "{}", line)?;
+            }
+        }
+
+        if !bad_names.is_empty() {
+            evicted.insert(bundle_digest, bad_names);
+        }
+    }
+
+    let n_evicted = file_ok.values().filter(|ok| !**ok).count();
+
+    tt_note!(
+        status,
+        "checked {} cached files: {} evicted, {} bad manifest entries removed",
+        file_ok.len(),
+        n_evicted,
+        n_bad_entries
+    );
+
+    Ok(evicted)
+}
+
+/// Remove everything from the local cache that is not needed by the bundles
+/// at *live_urls*.
+///
+/// This removes the records of all other URLs, the indexes and manifests of
+/// bundles that none of the live URLs currently point to, and any cached
+/// files that are not listed in a remaining manifest. The network is not
+/// consulted, so a live URL whose bundle has changed upstream keeps the
+/// files of the version that was last used.
+pub fn gc_cache(
+    live_urls: &[&str],
+    custom_cache_root: Option<&Path>,
+    status: &mut dyn StatusBackend,
+) -> Result<()> {
+    let url_base = cache_dir("urls", custom_cache_root)?;
+    let redirect_base = cache_dir("redirects", custom_cache_root)?;
+    let index_base = cache_dir("indexes", custom_cache_root)?;
+    let manifest_base = cache_dir("manifests", custom_cache_root)?;
+    let data_base = cache_dir("files", custom_cache_root)?;
+
+    let live_names: HashSet<String> = live_urls.iter().map(|u| app_dirs::sanitized(u)).collect();
+    let mut live_digests = HashSet::new();
+    let mut n_bundles = 0;
+
+    for entry in fs::read_dir(&url_base)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if live_names.contains(&name) {
+            let text = fs::read_to_string(entry.path())?;
+            live_digests.insert(text.trim().to_owned());
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    for base in &[&redirect_base, &index_base] {
+        for (digest_text, path) in list_txt_files(base)? {
+            if !live_digests.contains(&digest_text) {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+
+    let mut live_files = HashSet::new();
+
+    for (digest_text, path) in list_txt_files(&manifest_base)? {
+        if !live_digests.contains(&digest_text) {
+            fs::remove_file(path)?;
+            n_bundles += 1;
+            continue;
+        }
+
+        for res in BufReader::new(File::open(&path)?).lines() {
+            if let Some((_, _, digest)) = parse_manifest_line(&res?) {
+                live_files.insert(digest.to_owned());
+            }
+        }
+    }
+
+    let mut n_files = 0;
+    let mut n_bytes = 0;
+
+    for dir_entry in fs::read_dir(&data_base)? {
+        let dir_entry = dir_entry?;
+
+        if !dir_entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let prefix = dir_entry.file_name().to_string_lossy().into_owned();
+
+        for entry in fs::read_dir(dir_entry.path())? {
+            let entry = entry?;
+            let digest_text = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+
+            if !live_files.contains(&digest_text) {
+                n_bytes += entry.metadata()?.len();
+                remove_cached_file(&entry.path())?;
+                n_files += 1;
+            }
+        }
+
+        // This fails harmlessly if the directory isn't empty.
+        let _ = fs::remove_dir(dir_entry.path());
+    }
+
+    tt_note!(
+        status,
+        "removed data for {} unused bundles and {} cached files ({:.1} MiB)",
+        n_bundles,
+        n_files,
+        n_bytes as f64 / 1048576.
+    );
+    Ok(())
+}
+
+/// List the `{digest}.txt` files in one of the cache directories, along with
+/// their digest text.
+fn list_txt_files(base: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(base)? {
+        let path = entry?.path();
+
+        if path.extension().map(|e| e == "txt").unwrap_or(false) {
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                files.push((stem.to_owned(), path.clone()));
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Get the path of a file in the data directory, given its digest. Unlike
+/// `DigestData::create_two_part_path`, this does not create any directories.
+fn cached_file_path(data_base: &Path, digest_text: &str) -> PathBuf {
+    data_base.join(&digest_text[..2]).join(&digest_text[2..])
+}
+
+/// Test whether a cached file exists and has the expected digest.
+fn cached_file_matches(path: &Path, expected: &DigestData) -> Result<bool> {
+    let content = match fs::read(path) {
+        Ok(c) => c,
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut digest_builder = digest::create();
+    digest_builder.update(&content);
+    Ok(DigestData::from(digest_builder) == *expected)
+}
+
+/// Delete a file from the data directory, if it exists.
+fn remove_cached_file(path: &Path) -> Result<()> {
+    // Cached files are made read-only, and on Windows that prevents them from
+    // being deleted.
+    #[cfg(windows)]
+    {
+        if let Ok(md) = fs::metadata(path) {
+            let mut perms = md.permissions();
+            #[allow(clippy::permissions_set_readonly_false)]
+            perms.set_readonly(false);
+            fs::set_permissions(path, perms)?;
+        }
+    }
+
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
 }
 
 /// A convenience method to provide a better error message when writing to a created file.
@@ -617,3 +1503,168 @@ fn cache_dir(path: &str, custom_cache_root: Option<&Path>) -> Result<PathBuf> {
         Ok(app_dirs::user_cache_dir(path).map_err(SyncError::new)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::NoopStatusBackend;
+
+    const DIGEST_A: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+    const DIGEST_B: &str = "0000000000000000000000000000000000000000000000000000000000000002";
+
+    /// Put a file into the cache as part of a bundle, recording it in the
+    /// manifest with the digest of *recorded* rather than its actual
+    /// contents, so that corrupt files can be simulated.
+    fn add_file(root: &Path, bundle: &str, name: &str, contents: &[u8], recorded: &[u8]) {
+        let mut dc = digest::create();
+        dc.update(contents);
+        let path = DigestData::from(dc)
+            .create_two_part_path(&cache_dir("files", Some(root)).unwrap())
+            .unwrap();
+        fs::write(path, contents).unwrap();
+
+        let mut dc = digest::create();
+        dc.update(recorded);
+        let manifest_path = make_txt_path(&cache_dir("manifests", Some(root)).unwrap(), bundle);
+        let mut man = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(manifest_path)
+            .unwrap();
+        writeln!(
+            man,
+            "{} {} {}",
+            name,
+            recorded.len(),
+            DigestData::from(dc).to_string()
+        )
+        .unwrap();
+    }
+
+    fn add_bundle(root: &Path, url: &str, bundle: &str) {
+        let urls = cache_dir("urls", Some(root)).unwrap();
+        fs::write(urls.join(app_dirs::sanitized(url)), format!("{}\n", bundle)).unwrap();
+
+        for dir in &["redirects", "indexes"] {
+            let base = cache_dir(dir, Some(root)).unwrap();
+            fs::write(make_txt_path(&base, bundle), url).unwrap();
+        }
+    }
+
+    #[test]
+    fn coalesce() {
+        let file =
+            |name: &str, offset: u64, length: u64| (name.to_owned(), FileInfo { offset, length });
+
+        let groups = coalesce_ranges(vec![
+            file("c", 2000, 100),
+            file("a", 0, 1000),
+            file("b", 1000, 500),
+            file("d", 1_000_000, 10),
+            file("e", 1_000_010, MAX_COALESCED_LENGTH),
+        ]);
+
+        let summary: Vec<(u64, u64, Vec<&str>)> = groups
+            .iter()
+            .map(|(range, members)| {
+                (
+                    range.offset,
+                    range.length,
+                    members.iter().map(|(n, _)| n.as_str()).collect(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                (0, 2100, vec!["a", "b", "c"]),
+                (1_000_000, 10, vec!["d"]),
+                (1_000_010, MAX_COALESCED_LENGTH, vec!["e"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn dependencies() {
+        let sty = br#"
+\NeedsTeXFormat{LaTeX2e}
+\RequirePackage{ltxcmds}
+\RequirePackage[hyphens]{url}
+\RequirePackageWithOptions{ kvoptions ,
+  pdftexcmds}
+% \RequirePackage{commented}
+\usepackage{\@currname-extra}
+\RequirePackageFoo{notapackage}
+\LoadClass{article}
+"#;
+
+        assert_eq!(
+            scan_dependencies(sty),
+            vec![
+                "ltxcmds.sty",
+                "url.sty",
+                "kvoptions.sty",
+                "pdftexcmds.sty",
+                "article.cls"
+            ]
+        );
+    }
+
+    #[test]
+    fn verify_and_gc() {
+        let mut sb = NoopStatusBackend::default();
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+
+        add_bundle(root, "https://example.com/a", DIGEST_A);
+        add_bundle(root, "https://example.com/b", DIGEST_B);
+        add_file(root, DIGEST_A, "good.tex", b"good", b"good");
+        add_file(root, DIGEST_A, "bad.tex", b"corrupted", b"bad");
+        add_file(root, DIGEST_B, "old.tex", b"old", b"old");
+
+        let manifest_a = make_txt_path(&root.join("manifests"), DIGEST_A);
+        let mut man = fs::OpenOptions::new()
+            .append(true)
+            .open(&manifest_a)
+            .unwrap();
+        writeln!(man, "garbage").unwrap();
+        drop(man);
+
+        let evicted = verify_cache(Some(root), &mut sb).unwrap();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[DIGEST_A], vec!["bad.tex".to_owned()]);
+
+        let manifest = fs::read_to_string(&manifest_a).unwrap();
+        assert_eq!(manifest.lines().count(), 1);
+        assert!(manifest.starts_with("good.tex 4 "));
+
+        gc_cache(&["https://example.com/a"], Some(root), &mut sb).unwrap();
+
+        assert_eq!(
+            cached_bundle_digest("https://example.com/a", Some(root))
+                .unwrap()
+                .map(|d| d.to_string())
+                .as_deref(),
+            Some(DIGEST_A)
+        );
+        assert!(cached_bundle_digest("https://example.com/b", Some(root))
+            .unwrap()
+            .is_none());
+
+        for dir in &["redirects", "indexes", "manifests"] {
+            let names: Vec<String> = list_txt_files(&root.join(dir))
+                .unwrap()
+                .into_iter()
+                .map(|(d, _)| d)
+                .collect();
+            assert_eq!(names, vec![DIGEST_A.to_owned()]);
+        }
+
+        let n_files: usize = fs::read_dir(root.join("files"))
+            .unwrap()
+            .map(|e| fs::read_dir(e.unwrap().path()).unwrap().count())
+            .sum();
+        assert_eq!(n_files, 1);
+    }
+}
@@ -1,4 +1,9 @@
-use std::{fs::File, io::BufReader, path::PathBuf};
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::PathBuf,
+};
+use tectonic_errors::Result;
 
 use super::{Bundle, InputHandle, InputOrigin, IoProvider, OpenResult};
 use crate::status::StatusBackend;
@@ -37,4 +42,20 @@ impl IoProvider for DirBundle {
     }
 }
 
-impl Bundle for DirBundle {}
+impl Bundle for DirBundle {
+    fn all_files(&mut self, _status: &mut dyn StatusBackend) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+
+            if entry.file_type()?.is_file() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+
+        Ok(names)
+    }
+}
@@ -6,13 +6,17 @@
 //! Code for locally caching compiled format files.
 
 use std::{
+    fs,
     io::{BufReader, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 use tectonic_errors::{anyhow::bail, Result};
 
 use super::{InputHandle, InputOrigin, IoProvider, OpenResult};
-use crate::{digest::DigestData, status::StatusBackend};
+use crate::{
+    digest::{self, DigestData},
+    status::StatusBackend,
+};
 
 /// A local cache for compiled format files.
 ///
@@ -62,6 +66,78 @@ impl FormatCache {
     }
 }
 
+/// Remove cached format files that are of no further use.
+///
+/// Format files are removed if they were generated from a bundle whose
+/// digest is not among *live_digests*, or if they were generated by a version
+/// of the engine with a different format serial number, since those can
+/// never be loaded again. Files in *formats_base* that do not look like
+/// cached formats are left alone. Returns the number of files removed and
+/// their total size in bytes.
+pub fn remove_unused_formats(
+    formats_base: &Path,
+    live_digests: &[DigestData],
+) -> Result<(usize, u64)> {
+    let live: Vec<String> = live_digests.iter().map(|d| d.to_string()).collect();
+    let serial_suffix = format!("-{}.fmt", crate::FORMAT_SERIAL);
+
+    remove_formats_where(formats_base, |name| {
+        let is_live = live.iter().any(|d| name.starts_with(d.as_str()));
+        !(is_live && name.ends_with(&serial_suffix))
+    })
+}
+
+/// Remove the cached format files generated from the bundle with digest
+/// *bundle_digest*, whatever their format serial number. This is used when a
+/// bundle has been replaced by a newer version. Returns the number of files
+/// removed and their total size in bytes.
+pub fn remove_bundle_formats(
+    formats_base: &Path,
+    bundle_digest: &DigestData,
+) -> Result<(usize, u64)> {
+    let digest_text = bundle_digest.to_string();
+    remove_formats_where(formats_base, |name| name.starts_with(&digest_text))
+}
+
+/// Remove the cached format files in *formats_base* whose names satisfy
+/// *predicate*, ignoring files that do not look like cached formats.
+fn remove_formats_where<F: Fn(&str) -> bool>(
+    formats_base: &Path,
+    predicate: F,
+) -> Result<(usize, u64)> {
+    let mut n_removed = 0;
+    let mut n_bytes = 0;
+
+    for entry in fs::read_dir(formats_base)? {
+        let entry = entry?;
+        let name = entry.file_name();
+
+        let name = match name.to_str() {
+            Some(n) => n,
+            None => continue,
+        };
+
+        // Names have the form `{digest}-{stem}-{serial}.fmt`.
+
+        if !name.ends_with(".fmt")
+            || name.len() <= digest::DIGEST_LEN
+            || name.as_bytes()[digest::DIGEST_LEN] != b'-'
+        {
+            continue;
+        }
+
+        if !predicate(name) {
+            continue;
+        }
+
+        n_bytes += entry.metadata()?.len();
+        fs::remove_file(entry.path())?;
+        n_removed += 1;
+    }
+
+    Ok((n_removed, n_bytes))
+}
+
 impl IoProvider for FormatCache {
     fn input_open_format(
         &mut self,
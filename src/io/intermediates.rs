@@ -0,0 +1,243 @@
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Keeping the intermediate files of a processing session between runs.
+//!
+//! Files such as `.aux`, `.toc` and `.bbl` files carry information from one
+//! TeX pass to the next. A processing session normally starts without them,
+//! so it always needs at least two passes. An [`IntermediatesStore`] saves
+//! them after a session, so that the next one can start from where the last
+//! one left off and finish in a single pass if nothing changed.
+//!
+//! A store is a directory containing a manifest file, `manifest.txt`, with
+//! one line for each saved file giving the digest of its contents and its
+//! name. The contents are saved in the `data` subdirectory, in files named
+//! after their digests.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tectonic_errors::{atry, Result};
+
+use crate::digest::{self, Digest, DigestData};
+
+const MANIFEST_NAME: &str = "manifest.txt";
+const DATA_DIR_NAME: &str = "data";
+
+/// A directory where the intermediate files of a processing session are
+/// saved for the next one.
+#[derive(Clone, Debug)]
+pub struct IntermediatesStore {
+    dir: PathBuf,
+}
+
+impl IntermediatesStore {
+    /// Create a store in the given directory. The directory is created when
+    /// files are first saved.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> IntermediatesStore {
+        IntermediatesStore { dir: dir.into() }
+    }
+
+    /// Get the directory of the store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn data_path(&self, digest: &DigestData) -> PathBuf {
+        self.dir.join(DATA_DIR_NAME).join(digest.to_string())
+    }
+
+    /// Load the saved files, as pairs of names and contents. If nothing has
+    /// been saved, the list is empty. Files whose contents are missing or
+    /// don’t match their recorded digests are skipped.
+    pub fn load(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let manifest_path = self.dir.join(MANIFEST_NAME);
+
+        if !manifest_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let manifest = atry!(
+            fs::read_to_string(&manifest_path);
+            ["failed to read `{}`", manifest_path.display()]
+        );
+
+        let mut files = Vec::new();
+
+        for line in manifest.lines() {
+            let (digest_text, name) = match line.find(' ') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => continue,
+            };
+
+            let digest = atry!(
+                DigestData::from_str(digest_text);
+                ["corrupt line in `{}`: {}", manifest_path.display(), line]
+            );
+
+            let data = match fs::read(self.data_path(&digest)) {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+
+            let mut dc = digest::create();
+            dc.update(&data);
+
+            if DigestData::from(dc) == digest {
+                files.push((name.to_owned(), data));
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Replace the saved files with the given ones, passed as pairs of names
+    /// and contents.
+    pub fn save<'a, I>(&self, files: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (&'a str, &'a [u8])>,
+    {
+        let data_dir = self.dir.join(DATA_DIR_NAME);
+        atry!(fs::create_dir_all(&data_dir); ["failed to create directory `{}`", data_dir.display()]);
+
+        let mut manifest = String::new();
+        let mut used = HashSet::new();
+
+        for (name, data) in files {
+            // The manifest can't represent these.
+            if name.contains('\n') || name.contains('\r') {
+                continue;
+            }
+
+            let mut dc = digest::create();
+            dc.update(data);
+            let digest = DigestData::from(dc);
+            let digest_text = digest.to_string();
+            let path = self.data_path(&digest);
+
+            if used.insert(digest_text.clone()) && !path.exists() {
+                let mut temp_dest = tempfile::Builder::new()
+                    .prefix("data_")
+                    .rand_bytes(6)
+                    .tempfile_in(&data_dir)?;
+                temp_dest.write_all(data)?;
+                temp_dest.persist(&path)?;
+            }
+
+            manifest.push_str(&digest_text);
+            manifest.push(' ');
+            manifest.push_str(name);
+            manifest.push('\n');
+        }
+
+        let mut temp_dest = tempfile::Builder::new()
+            .prefix("manifest_")
+            .rand_bytes(6)
+            .tempfile_in(&self.dir)?;
+        temp_dest.write_all(manifest.as_bytes())?;
+        temp_dest.persist(self.dir.join(MANIFEST_NAME))?;
+
+        // Now that the new manifest is in place, remove the contents of files
+        // that are no longer saved.
+        for entry in fs::read_dir(&data_dir)? {
+            let entry = entry?;
+
+            if !used.contains(&*entry.file_name().to_string_lossy()) {
+                let path = entry.path();
+                atry!(fs::remove_file(&path); ["failed to remove `{}`", path.display()]);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forget all of the saved files, removing the directory of the store.
+    pub fn clear(&self) -> Result<()> {
+        if self.dir.exists() {
+            atry!(fs::remove_dir_all(&self.dir); ["failed to remove directory `{}`", self.dir.display()]);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut files: Vec<(String, Vec<u8>)>) -> Vec<(String, Vec<u8>)> {
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn save_and_load() {
+        let root = tempfile::tempdir().unwrap();
+        let store = IntermediatesStore::new(root.path().join(".tectonic"));
+        assert!(store.load().unwrap().is_empty());
+
+        store
+            .save(vec![
+                ("doc.aux", &b"aux"[..]),
+                ("doc.toc", &b"toc"[..]),
+                ("chapters/one.aux", &b"aux"[..]),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            sorted(store.load().unwrap()),
+            vec![
+                ("chapters/one.aux".to_owned(), b"aux".to_vec()),
+                ("doc.aux".to_owned(), b"aux".to_vec()),
+                ("doc.toc".to_owned(), b"toc".to_vec()),
+            ]
+        );
+
+        // Saving again replaces everything, and cleans up the old contents.
+        store.save(vec![("doc.aux", &b"new aux"[..])]).unwrap();
+        assert_eq!(
+            store.load().unwrap(),
+            vec![("doc.aux".to_owned(), b"new aux".to_vec())]
+        );
+        assert_eq!(
+            fs::read_dir(store.dir().join(DATA_DIR_NAME))
+                .unwrap()
+                .count(),
+            1
+        );
+
+        store.clear().unwrap();
+        assert!(!store.dir().exists());
+        assert!(store.load().unwrap().is_empty());
+        store.clear().unwrap();
+    }
+
+    #[test]
+    fn skip_corrupt_files() {
+        let root = tempfile::tempdir().unwrap();
+        let store = IntermediatesStore::new(root.path());
+        store
+            .save(vec![("a.aux", &b"a"[..]), ("b.aux", &b"b"[..])])
+            .unwrap();
+
+        for entry in fs::read_dir(root.path().join(DATA_DIR_NAME)).unwrap() {
+            let path = entry.unwrap().path();
+
+            if fs::read(&path).unwrap() == b"b" {
+                fs::write(&path, "not b").unwrap();
+            }
+        }
+
+        assert_eq!(
+            store.load().unwrap(),
+            vec![("a.aux".to_owned(), b"a".to_vec())]
+        );
+
+        fs::write(root.path().join(MANIFEST_NAME), "nonsense a.aux\n").unwrap();
+        assert!(store.load().is_err());
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! A bundle stored as a local indexed tar file.
+//!
+//! An indexed tar bundle consists of an ordinary tar file along with an
+//! index of the files that it contains, so that they can be read without
+//! scanning the archive. The index is gzipped text with one line for each
+//! file, giving its name, the offset of its data in the tar file, and its
+//! length. Web bundles use the same format; the index lives next to the tar
+//! file, with `.index.gz` appended to its name.
+
+use flate2::read::GzDecoder;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom},
+    path::Path,
+};
+use tectonic_errors::{atry, Result};
+
+use super::{Bundle, InputHandle, InputOrigin, IoProvider, OpenResult};
+use crate::status::StatusBackend;
+
+/// The location of a file in an indexed tar file.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FileInfo {
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+}
+
+/// Parse a line of an indexed tar file's index. Returns `None` if the line
+/// does not have enough fields.
+pub(crate) fn parse_index_line(line: &str) -> Result<Option<(String, FileInfo)>> {
+    let mut bits = line.split_whitespace();
+
+    if let (Some(name), Some(offset), Some(length)) = (bits.next(), bits.next(), bits.next()) {
+        Ok(Some((
+            name.to_owned(),
+            FileInfo {
+                offset: offset.parse::<u64>()?,
+                length: length.parse::<u64>()?,
+            },
+        )))
+    } else {
+        // TODO: preserve the warning info or something!
+        Ok(None)
+    }
+}
+
+/// A bundle backed by an indexed tar file on the local filesystem.
+pub struct ITarBundle<R: Read + Seek> {
+    data: R,
+    index: HashMap<String, FileInfo>,
+}
+
+impl<R: Read + Seek> ITarBundle<R> {
+    /// Create a new bundle from a reader for the tar data and one for the
+    /// decompressed text of the index.
+    pub fn new<I: Read>(data: R, index: I) -> Result<ITarBundle<R>> {
+        let mut parsed = HashMap::new();
+
+        for line in BufReader::new(index).lines() {
+            if let Some((name, info)) = parse_index_line(&line?)? {
+                parsed.insert(name, info);
+            }
+        }
+
+        Ok(ITarBundle {
+            data,
+            index: parsed,
+        })
+    }
+}
+
+impl ITarBundle<File> {
+    /// Open an indexed tar file. Its index is expected to be in the same
+    /// directory, with `.index.gz` appended to its name.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ITarBundle<File>> {
+        let path = path.as_ref();
+        let mut index_path = OsString::from(path);
+        index_path.push(".index.gz");
+        let index_path = Path::new(&index_path);
+
+        let data = atry!(File::open(path); ["failed to open bundle file `{}`", path.display()]);
+        let index = atry!(File::open(index_path); ["failed to open bundle index `{}`", index_path.display()]);
+        Self::new(data, GzDecoder::new(index))
+    }
+}
+
+impl<R: Read + Seek> IoProvider for ITarBundle<R> {
+    fn input_open_name(
+        &mut self,
+        name: &str,
+        _status: &mut dyn StatusBackend,
+    ) -> OpenResult<InputHandle> {
+        let info = match self.index.get(name) {
+            Some(i) => *i,
+            None => return OpenResult::NotAvailable,
+        };
+
+        let mut buf = vec![0; info.length as usize];
+
+        if let Err(e) = self
+            .data
+            .seek(SeekFrom::Start(info.offset))
+            .and_then(|_| self.data.read_exact(&mut buf))
+        {
+            return OpenResult::Err(e.into());
+        }
+
+        OpenResult::Ok(InputHandle::new_read_only(
+            name,
+            Cursor::new(buf),
+            InputOrigin::Other,
+        ))
+    }
+}
+
+impl<R: Read + Seek> Bundle for ITarBundle<R> {
+    fn all_files(&mut self, _status: &mut dyn StatusBackend) -> Result<Vec<String>> {
+        Ok(self.index.keys().cloned().collect())
+    }
+}
+
+/// Test whether a local bundle path refers to an indexed tar file, based on
+/// its extension.
+pub fn is_itar_path(path: &Path) -> bool {
+    path.extension().map(|e| e == "tar").unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::NoopStatusBackend;
+    use flate2::{write::GzEncoder, Compression};
+    use std::{fs, io::Write};
+
+    #[test]
+    fn read_files() {
+        let mut sb = NoopStatusBackend::default();
+        let dir = tempfile::tempdir().unwrap();
+        let tar_path = dir.path().join("bundle.tar");
+        fs::write(&tar_path, b"....hello....world").unwrap();
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(b"a.tex 4 5\nb.tex 13 5\n\n").unwrap();
+        fs::write(dir.path().join("bundle.tar.index.gz"), gz.finish().unwrap()).unwrap();
+
+        let mut bundle = ITarBundle::open(&tar_path).unwrap();
+
+        for (name, expected) in &[("b.tex", "world"), ("a.tex", "hello")] {
+            let mut text = String::new();
+
+            match bundle.input_open_name(name, &mut sb) {
+                OpenResult::Ok(mut h) => h.read_to_string(&mut text).unwrap(),
+                _ => panic!("failed to open {}", name),
+            };
+
+            assert_eq!(&text, expected);
+        }
+
+        assert!(matches!(
+            bundle.input_open_name("c.tex", &mut sb),
+            OpenResult::NotAvailable
+        ));
+
+        let mut names = bundle.all_files(&mut sb).unwrap();
+        names.sort();
+        assert_eq!(names, &["a.tex", "b.tex"]);
+
+        assert!(ITarBundle::open(dir.path().join("missing.tar")).is_err());
+    }
+}
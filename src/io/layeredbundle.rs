@@ -0,0 +1,158 @@
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! A bundle that combines several other bundles.
+
+use sha2::Digest;
+use tectonic_errors::{anyhow::bail, atry, Result};
+
+use super::{digest, Bundle, DigestData, InputHandle, IoProvider, OpenResult};
+use crate::status::StatusBackend;
+
+/// A LayeredBundle searches an ordered list of bundles in turn, so that
+/// files in earlier bundles take precedence over files in later ones. This
+/// makes it possible to layer a small bundle of extra files over a standard
+/// one.
+pub struct LayeredBundle {
+    bundles: Vec<Box<dyn Bundle>>,
+}
+
+impl LayeredBundle {
+    /// Create a new layered bundle. The first bundle in the list is searched
+    /// first.
+    pub fn new(bundles: Vec<Box<dyn Bundle>>) -> LayeredBundle {
+        LayeredBundle { bundles }
+    }
+}
+
+impl IoProvider for LayeredBundle {
+    fn input_open_name(
+        &mut self,
+        name: &str,
+        status: &mut dyn StatusBackend,
+    ) -> OpenResult<InputHandle> {
+        for bundle in &mut self.bundles {
+            let r = bundle.input_open_name(name, status);
+
+            match r {
+                OpenResult::NotAvailable => continue,
+                _ => return r,
+            };
+        }
+
+        OpenResult::NotAvailable
+    }
+}
+
+impl Bundle for LayeredBundle {
+    /// The digest of a layered bundle is derived from the digests of its
+    /// components, in order, so that formats generated from different
+    /// combinations of bundles are cached separately. A layered bundle with
+    /// just one component has the same digest as that component.
+    fn get_digest(&mut self, status: &mut dyn StatusBackend) -> Result<DigestData> {
+        if self.bundles.is_empty() {
+            bail!("cannot compute the digest of an empty list of bundles");
+        }
+
+        if self.bundles.len() == 1 {
+            return self.bundles[0].get_digest(status);
+        }
+
+        let mut dc = digest::create();
+
+        for (i, bundle) in self.bundles.iter_mut().enumerate() {
+            let d =
+                atry!(bundle.get_digest(status); ["cannot get the digest of bundle #{}", i + 1]);
+            dc.update(d.to_string().as_bytes());
+            dc.update(b"\n");
+        }
+
+        Ok(DigestData::from(dc))
+    }
+
+    /// The files of a layered bundle are those of all of its components.
+    fn all_files(&mut self, status: &mut dyn StatusBackend) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        for bundle in &mut self.bundles {
+            names.append(&mut bundle.all_files(status)?);
+        }
+
+        names.sort_unstable();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::dirbundle::DirBundle;
+    use crate::status::NoopStatusBackend;
+    use std::{fs, io::Read, path::Path};
+
+    fn make_dir_bundle(root: &Path, name: &str, files: &[(&str, &str)]) -> Box<dyn Bundle> {
+        let dir = root.join(name);
+        fs::create_dir(&dir).unwrap();
+
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        Box::new(DirBundle::new(dir))
+    }
+
+    fn read(bundle: &mut LayeredBundle, name: &str) -> Option<String> {
+        let mut sb = NoopStatusBackend::default();
+
+        match bundle.input_open_name(name, &mut sb) {
+            OpenResult::Ok(mut h) => {
+                let mut s = String::new();
+                h.read_to_string(&mut s).unwrap();
+                Some(s)
+            }
+            OpenResult::NotAvailable => None,
+            OpenResult::Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    const DIGEST_A: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+    const DIGEST_B: &str = "0000000000000000000000000000000000000000000000000000000000000002";
+
+    #[test]
+    fn search_order() {
+        let root = tempfile::tempdir().unwrap();
+        let a = make_dir_bundle(root.path(), "a", &[("both.tex", "a"), ("a.tex", "a")]);
+        let b = make_dir_bundle(root.path(), "b", &[("both.tex", "b"), ("b.tex", "b")]);
+        let mut bundle = LayeredBundle::new(vec![a, b]);
+
+        assert_eq!(read(&mut bundle, "both.tex").as_deref(), Some("a"));
+        assert_eq!(read(&mut bundle, "a.tex").as_deref(), Some("a"));
+        assert_eq!(read(&mut bundle, "b.tex").as_deref(), Some("b"));
+        assert_eq!(read(&mut bundle, "c.tex"), None);
+    }
+
+    #[test]
+    fn combined_digest() {
+        let mut sb = NoopStatusBackend::default();
+        let root = tempfile::tempdir().unwrap();
+        let a = make_dir_bundle(root.path(), "a", &[(digest::DIGEST_NAME, DIGEST_A)]);
+        let b = make_dir_bundle(root.path(), "b", &[(digest::DIGEST_NAME, DIGEST_B)]);
+        let c = make_dir_bundle(root.path(), "c", &[]);
+
+        let mut single = LayeredBundle::new(vec![a]);
+        assert_eq!(single.get_digest(&mut sb).unwrap().to_string(), DIGEST_A);
+
+        let a = single.bundles.pop().unwrap();
+        let mut ab = LayeredBundle::new(vec![a, b]);
+        let d_ab = ab.get_digest(&mut sb).unwrap();
+        assert_ne!(d_ab.to_string(), DIGEST_A);
+        assert_ne!(d_ab.to_string(), DIGEST_B);
+
+        ab.bundles.reverse();
+        assert_ne!(ab.get_digest(&mut sb).unwrap(), d_ab);
+
+        ab.bundles.push(c);
+        assert!(ab.get_digest(&mut sb).is_err());
+    }
+}
@@ -7,9 +7,13 @@ use std::{io::Read, str::FromStr};
 use tectonic_errors::{anyhow::bail, atry, Result};
 use tectonic_status_base::StatusBackend;
 
+pub mod bundle_creator;
 pub mod cached_itarbundle;
 pub mod dirbundle;
 pub mod format_cache;
+pub mod intermediates;
+pub mod itarbundle;
+pub mod layeredbundle;
 pub mod memory;
 pub mod setup;
 pub mod zipbundle;
@@ -71,12 +75,26 @@ pub trait Bundle: IoProvider {
 
         Ok(atry!(DigestData::from_str(&digest_text); ["corrupted SHA256 digest data"]))
     }
+
+    /// List the names of all of the files in this bundle, in no particular
+    /// order.
+    ///
+    /// This is used to inspect bundles rather than to process documents, so
+    /// not every kind of bundle needs to support it. The default
+    /// implementation returns an error.
+    fn all_files(&mut self, _status: &mut dyn StatusBackend) -> Result<Vec<String>> {
+        bail!("this kind of bundle cannot list its contents");
+    }
 }
 
 impl<B: Bundle + ?Sized> Bundle for Box<B> {
     fn get_digest(&mut self, status: &mut dyn StatusBackend) -> Result<DigestData> {
         (**self).get_digest(status)
     }
+
+    fn all_files(&mut self, status: &mut dyn StatusBackend) -> Result<Vec<String>> {
+        (**self).all_files(status)
+    }
 }
 
 // Helper for testing. FIXME: I want this to be conditionally compiled with
@@ -66,4 +66,16 @@ impl<R: Read + Seek> IoProvider for ZipBundle<R> {
     }
 }
 
-impl<R: Read + Seek> Bundle for ZipBundle<R> {}
+impl<R: Read + Seek> Bundle for ZipBundle<R> {
+    fn all_files(
+        &mut self,
+        _status: &mut dyn StatusBackend,
+    ) -> tectonic_errors::Result<Vec<String>> {
+        Ok(self
+            .zip
+            .file_names()
+            .filter(|n| !n.ends_with('/'))
+            .map(|n| n.to_owned())
+            .collect())
+    }
+}
@@ -57,8 +57,12 @@ pub mod driver;
 pub mod engines;
 pub mod errors;
 pub mod io;
+pub mod page_selection;
+pub mod report;
 pub mod status;
+pub mod tex_log;
 pub mod unstable_opts;
+pub mod watch;
 #[cfg(feature = "serialization")]
 pub mod workspace;
 
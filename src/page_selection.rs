@@ -0,0 +1,936 @@
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Selecting and arranging the pages of an XDV file.
+//!
+//! A [`PageSelection`] picks out pages by their physical position in the
+//! document or by the value of TeX's `\count0` register when they were
+//! shipped out, which is what LaTeX uses for the page number. The driver uses
+//! it to filter the XDV output of the TeX engine in memory, before it is
+//! converted to PDF, so that parts of a document can be rendered without
+//! rerunning TeX.
+//!
+//! Pages are copied verbatim, so the selected pages come out exactly as TeX
+//! wrote them. Specials on the other pages are dropped along with them,
+//! except for the ones that set up the whole document rather than draw on
+//! the page, such as the paper size and the PDF metadata that LaTeX and
+//! `hyperref` put on the first page. These are moved to the next page that
+//! is kept, or to the last one if there is none.
+//!
+//! An [`Imposition`] arranges pages in a grid on larger sheets, which is
+//! what is wanted for proof sheets. The pages are placed on the sheets at
+//! their full size, so each sheet is as many pages wide and high as there
+//! are columns and rows in the grid.
+
+use std::str::FromStr;
+use tectonic_xdv::{
+    FileType, FontDefinition, GlyphRun, NativeFontDefinition, Position, XdvEvents, XdvParser,
+    XdvWriter,
+};
+
+use crate::{
+    errmsg,
+    errors::{Error, Result},
+};
+
+/// How the pages in a [`PageSelection`] are numbered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PageNumbering {
+    /// Pages are numbered by their position in the document, starting at 1.
+    Physical,
+
+    /// Pages are numbered by the value of `\count0` at shipout.
+    Count0,
+}
+
+/// A set of pages to keep from a document.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PageSelection {
+    numbering: PageNumbering,
+    ranges: Vec<(Option<i32>, Option<i32>)>,
+}
+
+impl PageSelection {
+    /// Create a new page selection from a textual list of ranges.
+    ///
+    /// The list is separated by commas. Each item is either a single page
+    /// number or a range of the form `first-last`, inclusive, in which
+    /// either end may be omitted to leave it open. For instance, `1,4-6,10-`
+    /// selects the first page, the fourth through sixth, and everything from
+    /// the tenth onwards.
+    pub fn new(numbering: PageNumbering, ranges: &str) -> Result<Self> {
+        let mut parsed = Vec::new();
+
+        for item in ranges.split(',') {
+            let item = item.trim();
+
+            let range = if let Some(dash) = item.find('-') {
+                let first = parse_page_number(&item[..dash], ranges)?;
+                let last = parse_page_number(&item[dash + 1..], ranges)?;
+                (first, last)
+            } else {
+                match parse_page_number(item, ranges)? {
+                    Some(n) => (Some(n), Some(n)),
+                    None => return Err(errmsg!("empty item in page selection \"{}\"", ranges)),
+                }
+            };
+
+            parsed.push(range);
+        }
+
+        Ok(PageSelection {
+            numbering,
+            ranges: parsed,
+        })
+    }
+
+    /// Test whether a page is selected.
+    ///
+    /// *index* is the physical page number, starting at 1, and *counters*
+    /// are the TeX counters recorded for the page.
+    pub fn includes(&self, index: usize, counters: &[i32]) -> bool {
+        let n = match self.numbering {
+            PageNumbering::Physical => index as i32,
+            PageNumbering::Count0 => counters[0],
+        };
+
+        self.ranges.iter().any(|&(first, last)| {
+            first.map(|f| n >= f).unwrap_or(true) && last.map(|l| n <= l).unwrap_or(true)
+        })
+    }
+
+    /// Filter the pages of an XDV or SPX file.
+    ///
+    /// Returns the filtered file data and the number of pages that were
+    /// kept.
+    pub fn apply_to_xdv(&self, data: &[u8]) -> Result<(Vec<u8>, usize)> {
+        let malformed = || errmsg!("cannot select pages: the XDV data are malformed");
+
+        // Find the post-postamble, which is followed by at least four padding
+        // bytes, and the postamble that it points to.
+
+        let mut end = data.len();
+
+        while end > 0 && data[end - 1] == PADDING_BYTE {
+            end -= 1;
+        }
+
+        if end < 6 || data.len() - end < 4 || data[end - 6] != POST_POST {
+            return Err(malformed());
+        }
+
+        let post_post = end - 6;
+        let id_byte = data[end - 1];
+        let post = read_u32(data, post_post + 1).ok_or_else(malformed)? as usize;
+
+        if data.get(post) != Some(&POST) {
+            return Err(malformed());
+        }
+
+        // Walk the chain of beginning-of-page pointers backwards from the
+        // postamble to find all of the pages.
+
+        let mut bops = Vec::new();
+        let mut bop = read_i32(data, post + 1).ok_or_else(malformed)?;
+
+        while bop >= 0 {
+            let offset = bop as usize;
+
+            if offset >= post || data[offset] != BOP || bops.last() == Some(&offset) {
+                return Err(malformed());
+            }
+
+            bops.push(offset);
+            bop = read_i32(data, offset + BOP_PREVIOUS_OFFSET).ok_or_else(malformed)?;
+        }
+
+        bops.reverse();
+
+        // Decide which pages to keep, and which setup specials each of them
+        // takes over from the pages that are dropped.
+
+        let mut kept = Vec::new();
+
+        for (i, &start) in bops.iter().enumerate() {
+            let mut counters = [0; 10];
+
+            for (j, counter) in counters.iter_mut().enumerate() {
+                *counter = read_i32(data, start + 1 + 4 * j).ok_or_else(malformed)?;
+            }
+
+            if self.includes(i + 1, &counters) {
+                kept.push(i);
+            }
+        }
+
+        let mut carried: Vec<Vec<Vec<u8>>> = vec![Vec::new(); kept.len()];
+
+        if !kept.is_empty() && kept.len() < bops.len() {
+            let pages = XdvParser::process(data, SetupSpecials::default())
+                .map_err(|_| malformed())?
+                .0
+                 .0;
+
+            for (i, specials) in pages.into_iter().enumerate() {
+                if kept.binary_search(&i).is_ok() {
+                    continue;
+                }
+
+                let next = kept.iter().position(|&k| k > i).unwrap_or(kept.len() - 1);
+                carried[next].extend(specials);
+            }
+        }
+
+        // Now we can assemble the output. Anything between the end of one
+        // page and the beginning of the next (namely, font definitions) is
+        // carried along with the former.
+
+        let preamble_end = bops.first().copied().unwrap_or(post);
+        let mut output = data[..preamble_end].to_vec();
+        let mut last_bop: i32 = -1;
+        let n_kept = kept.len();
+
+        for (&i, specials) in kept.iter().zip(&carried) {
+            let start = bops[i];
+            let end = bops.get(i + 1).copied().unwrap_or(post);
+            let new_start = output.len();
+            output.extend_from_slice(&data[start..start + BOP_LENGTH]);
+            output[new_start + BOP_PREVIOUS_OFFSET..new_start + BOP_PREVIOUS_OFFSET + 4]
+                .copy_from_slice(&last_bop.to_be_bytes());
+
+            for contents in specials {
+                push_special(&mut output, contents);
+            }
+
+            output.extend_from_slice(&data[start + BOP_LENGTH..end]);
+            last_bop = new_start as i32;
+        }
+
+        let new_post = output.len();
+        output.extend_from_slice(&data[post..post_post]);
+        output[new_post + 1..new_post + 5].copy_from_slice(&last_bop.to_be_bytes());
+        output[new_post + POST_N_PAGES_OFFSET..new_post + POST_N_PAGES_OFFSET + 2]
+            .copy_from_slice(&(n_kept as u16).to_be_bytes());
+
+        output.push(POST_POST);
+        output.extend_from_slice(&(new_post as u32).to_be_bytes());
+        output.push(id_byte);
+
+        let n_padding = 4 + (4 - (output.len() + 4) % 4) % 4;
+        output.resize(output.len() + n_padding, PADDING_BYTE);
+
+        Ok((output, n_kept))
+    }
+}
+
+/// A grid in which to arrange pages on sheets, for n-up imposition.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Imposition {
+    columns: u32,
+    rows: u32,
+}
+
+impl Imposition {
+    /// Create a new imposition from a textual description of its grid.
+    ///
+    /// This is either the number of columns and rows, separated by an `x`,
+    /// or just the number of columns, to put that many pages side by side.
+    /// For instance, `2x2` puts four pages on each sheet, and `2` puts two.
+    /// Pages are placed from left to right, then from top to bottom.
+    pub fn new(spec: &str) -> Result<Self> {
+        let invalid = || errmsg!("invalid n-up layout \"{}\"", spec);
+
+        let (columns, rows) = match spec.find('x') {
+            Some(i) => (&spec[..i], &spec[i + 1..]),
+            None => (spec, "1"),
+        };
+
+        let columns: u32 = columns.trim().parse().map_err(|_| invalid())?;
+        let rows: u32 = rows.trim().parse().map_err(|_| invalid())?;
+
+        if columns == 0 || rows == 0 || columns.saturating_mul(rows) > MAX_PAGES_PER_SHEET {
+            return Err(invalid());
+        }
+
+        Ok(Imposition { columns, rows })
+    }
+
+    /// Arrange the pages of an XDV file on sheets.
+    ///
+    /// The size of the pages is taken from the paper size specials on the
+    /// first page, as `xdvipdfmx` does, or from *default_paper* if there
+    /// aren't any. That is a paper name such as `a4`, or a width and height
+    /// separated by a comma, as for the `-Z paper-size` option.
+    ///
+    /// Returns the new file data and the number of sheets.
+    pub fn apply_to_xdv(&self, data: &[u8], default_paper: &str) -> Result<(Vec<u8>, usize)> {
+        let malformed = || -> Error { errmsg!("cannot arrange pages: the XDV data are malformed") };
+
+        let (scan, _) =
+            XdvParser::process(data, PageSizeScanner::default()).map_err(|_| malformed())?;
+
+        let (width, height) = match scan.size.or_else(|| paper_size(default_paper)) {
+            Some(size) => size,
+            None => {
+                return Err(errmsg!(
+                    "cannot arrange pages: unknown paper size \"{}\"",
+                    default_paper
+                ))
+            }
+        };
+
+        let imposer = Imposer {
+            writer: XdvWriter::new(Vec::new()),
+            grid: *self,
+            page_width: width,
+            page_height: height,
+            n_pages: 0,
+            n_sheets: 0,
+            offset: (0, 0),
+        };
+
+        let (mut imposer, _) = XdvParser::process(data, imposer).map_err(|_| malformed())?;
+
+        if imposer.n_sheets > 0 {
+            imposer.writer.end_page().map_err(|_| malformed())?;
+        }
+
+        let output = imposer.writer.finish().map_err(|_| malformed())?;
+        Ok((output, imposer.n_sheets))
+    }
+}
+
+/// Finds the size of the pages of a document from the specials on its first
+/// page.
+#[derive(Default)]
+struct PageSizeScanner {
+    n_pages: usize,
+    size: Option<(i32, i32)>,
+}
+
+impl XdvEvents for PageSizeScanner {
+    type Error = std::io::Error;
+
+    fn handle_begin_page(&mut self, _counters: &[i32], _previous_bop: i32) -> std::io::Result<()> {
+        self.n_pages += 1;
+        Ok(())
+    }
+
+    fn handle_special(&mut self, _pos: Position, contents: &[u8]) -> std::io::Result<()> {
+        if self.n_pages == 1 {
+            if let Some(size) = page_size_special(contents) {
+                self.size = Some(size);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Forwards the events of an XDV file to a writer, putting its pages on
+/// sheets.
+///
+/// Each page is bracketed by a `push` and a `pop`, and everything on it is
+/// moved to its place on the sheet. The paper size specials of the pages are
+/// replaced with one giving the size of the sheets on the first sheet.
+struct Imposer {
+    writer: XdvWriter<Vec<u8>>,
+    grid: Imposition,
+    page_width: i32,
+    page_height: i32,
+    n_pages: usize,
+    n_sheets: usize,
+    offset: (i32, i32),
+}
+
+impl Imposer {
+    fn pages_per_sheet(&self) -> usize {
+        (self.grid.columns * self.grid.rows) as usize
+    }
+}
+
+impl XdvEvents for Imposer {
+    type Error = std::io::Error;
+
+    fn handle_header(&mut self, filetype: FileType, comment: &[u8]) -> std::io::Result<()> {
+        self.writer.header(filetype, comment)
+    }
+
+    fn handle_begin_page(&mut self, counters: &[i32], _previous_bop: i32) -> std::io::Result<()> {
+        let slot = self.n_pages % self.pages_per_sheet();
+
+        if slot == 0 {
+            if self.n_sheets > 0 {
+                self.writer.end_page()?;
+            }
+
+            self.writer.begin_page(counters)?;
+            self.n_sheets += 1;
+
+            if self.n_sheets == 1 {
+                let size = format!(
+                    "papersize={},{}",
+                    format_length(self.page_width.saturating_mul(self.grid.columns as i32)),
+                    format_length(self.page_height.saturating_mul(self.grid.rows as i32))
+                );
+                self.writer.special(0, 0, size.as_bytes())?;
+            }
+        }
+
+        let column = slot as i32 % self.grid.columns as i32;
+        let row = slot as i32 / self.grid.columns as i32;
+        self.offset = (
+            column.saturating_mul(self.page_width),
+            row.saturating_mul(self.page_height),
+        );
+        self.n_pages += 1;
+        self.writer.push()
+    }
+
+    fn handle_end_page(&mut self) -> std::io::Result<()> {
+        self.writer.pop()
+    }
+
+    fn handle_postamble(
+        &mut self,
+        max_height: i32,
+        max_width: i32,
+        _n_pages: u16,
+    ) -> std::io::Result<()> {
+        let (columns, rows) = (self.grid.columns as i32, self.grid.rows as i32);
+        self.writer.max_page_dimensions(
+            max_height.saturating_add((rows - 1).saturating_mul(self.page_height)),
+            max_width.saturating_add((columns - 1).saturating_mul(self.page_width)),
+        );
+        Ok(())
+    }
+
+    fn handle_special(&mut self, pos: Position, contents: &[u8]) -> std::io::Result<()> {
+        if page_size_special(contents).is_some() {
+            return Ok(());
+        }
+
+        let (h, v) = self.place(pos);
+        self.writer.special(h, v, contents)
+    }
+
+    fn handle_select_font(&mut self, font_num: i32) -> std::io::Result<()> {
+        self.writer.select_font(font_num)
+    }
+
+    fn handle_char_run(&mut self, pos: Position, chars: &[i32]) -> std::io::Result<()> {
+        let (h, v) = self.place(pos);
+        self.writer.char_run(h, v, chars)
+    }
+
+    fn handle_rule(&mut self, pos: Position, height: i32, width: i32) -> std::io::Result<()> {
+        let (h, v) = self.place(pos);
+        self.writer.rule(h, v, height, width)
+    }
+
+    fn handle_define_font(&mut self, font: &FontDefinition) -> std::io::Result<()> {
+        self.writer.define_font(font)
+    }
+
+    fn handle_define_native_font(&mut self, font: &NativeFontDefinition) -> std::io::Result<()> {
+        self.writer.define_native_font(font)
+    }
+
+    fn handle_glyph_run(&mut self, pos: Position, run: &GlyphRun) -> std::io::Result<()> {
+        let (h, v) = self.place(pos);
+        self.writer.glyph_run(h, v, run)
+    }
+
+    fn handle_text_and_glyphs(
+        &mut self,
+        pos: Position,
+        text: &str,
+        run: &GlyphRun,
+    ) -> std::io::Result<()> {
+        let (h, v) = self.place(pos);
+        self.writer.text_and_glyphs(h, v, text, run)
+    }
+}
+
+impl Imposer {
+    /// Get the position on the sheet of a position on the current page.
+    fn place(&self, pos: Position) -> (i32, i32) {
+        (
+            pos.h.saturating_add(self.offset.0),
+            pos.v.saturating_add(self.offset.1),
+        )
+    }
+}
+
+impl FromStr for PageNumbering {
+    type Err = &'static str;
+
+    fn from_str(a_str: &str) -> std::result::Result<Self, Self::Err> {
+        match a_str {
+            "physical" => Ok(PageNumbering::Physical),
+            "count0" => Ok(PageNumbering::Count0),
+            _ => Err("unsupported or unknown page numbering"),
+        }
+    }
+}
+
+const BOP: u8 = 139;
+const XXX1: u8 = 239;
+const XXX4: u8 = 242;
+const POST: u8 = 248;
+const POST_POST: u8 = 249;
+const PADDING_BYTE: u8 = 223;
+
+/// The offset of the previous-page pointer within a `bop` command.
+const BOP_PREVIOUS_OFFSET: usize = 41;
+
+/// The length of a `bop` command.
+const BOP_LENGTH: usize = 45;
+
+/// The most pages that an [`Imposition`] can put on a sheet.
+const MAX_PAGES_PER_SHEET: u32 = 64;
+
+/// The number of DVI units in a TeX point.
+const DVI_UNITS_PER_POINT: f64 = 65536.;
+
+/// Paper sizes known by name, as width and height in big points.
+const PAPER_SIZES: &[(&str, f64, f64)] = &[
+    ("letter", 612., 792.),
+    ("legal", 612., 1008.),
+    ("ledger", 1224., 792.),
+    ("tabloid", 792., 1224.),
+    ("a3", 841.89, 1190.55),
+    ("a4", 595.28, 841.89),
+    ("a5", 419.53, 595.28),
+    ("b5", 498.9, 708.66),
+];
+
+/// The offset of the page count within a `post` command.
+const POST_N_PAGES_OFFSET: usize = 27;
+
+/// Collects the specials on each page that set up the document rather than
+/// draw on the page, which have to be kept even if the page is dropped.
+#[derive(Default)]
+struct SetupSpecials(Vec<Vec<Vec<u8>>>);
+
+impl XdvEvents for SetupSpecials {
+    type Error = std::io::Error;
+
+    fn handle_begin_page(&mut self, _counters: &[i32], _previous_bop: i32) -> std::io::Result<()> {
+        self.0.push(Vec::new());
+        Ok(())
+    }
+
+    fn handle_special(&mut self, _pos: Position, contents: &[u8]) -> std::io::Result<()> {
+        if let Some(page) = self.0.last_mut() {
+            if is_setup_special(contents) {
+                page.push(contents.to_owned());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Test whether a special sets up the document, as opposed to drawing on the
+/// page that it is on. These are the specials that `xdvipdfmx` looks for on
+/// the first page to set the paper size, along with the ones that set
+/// document-wide PDF options and metadata.
+fn is_setup_special(contents: &[u8]) -> bool {
+    const PDF_SETUP: &[&[u8]] = &[
+        b"pagesize",
+        b"docinfo",
+        b"docview",
+        b"mapfile",
+        b"mapline",
+        b"majorversion",
+        b"minorversion",
+    ];
+
+    let contents = trim_start(contents);
+
+    if contents.starts_with(b"papersize") {
+        return true;
+    }
+
+    match contents.strip_prefix(b"pdf:") {
+        Some(rest) => {
+            let rest = trim_start(rest);
+            PDF_SETUP.iter().any(|keyword| {
+                rest.starts_with(keyword)
+                    && !rest
+                        .get(keyword.len())
+                        .map(|c| c.is_ascii_alphanumeric())
+                        .unwrap_or(false)
+            })
+        }
+        None => false,
+    }
+}
+
+/// If a special sets the paper size, get the width and height that it sets,
+/// in DVI units. This understands the `papersize` special and `xdvipdfmx`'s
+/// `pdf:pagesize`.
+fn page_size_special(contents: &[u8]) -> Option<(i32, i32)> {
+    let text = std::str::from_utf8(trim_start(contents)).ok()?;
+
+    if let Some(rest) = text.strip_prefix("papersize") {
+        return paper_size(rest.trim_start().strip_prefix('=')?);
+    }
+
+    let rest = text.strip_prefix("pdf:")?.trim_start();
+    let rest = rest.strip_prefix("pagesize")?;
+    let mut width = None;
+    let mut height = None;
+    let mut words = rest.split_whitespace();
+
+    while let Some(word) = words.next() {
+        match word {
+            "width" => width = words.next().and_then(parse_length),
+            "height" => height = words.next().and_then(parse_length),
+            _ => {}
+        }
+    }
+
+    Some((width?, height?))
+}
+
+/// Get the width and height, in DVI units, of a paper size given by name or
+/// as a width and a height separated by a comma.
+fn paper_size(spec: &str) -> Option<(i32, i32)> {
+    let spec = spec.trim();
+
+    if let Some(i) = spec.find(',') {
+        return Some((parse_length(&spec[..i])?, parse_length(&spec[i + 1..])?));
+    }
+
+    PAPER_SIZES
+        .iter()
+        .find(|(name, _, _)| spec.eq_ignore_ascii_case(name))
+        .map(|&(_, w, h)| {
+            let scale = DVI_UNITS_PER_POINT * 72.27 / 72.;
+            ((w * scale).round() as i32, (h * scale).round() as i32)
+        })
+}
+
+/// Parse a length with TeX units, such as `8.5in` or `210truemm`, into DVI
+/// units.
+fn parse_length(text: &str) -> Option<i32> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(text.len());
+    let value: f64 = text[..split].parse().ok()?;
+    let unit = text[split..].trim();
+    let unit = unit.strip_prefix("true").unwrap_or(unit);
+
+    let points = match unit {
+        "pt" => 1.,
+        "bp" => 72.27 / 72.,
+        "in" => 72.27,
+        "cm" => 72.27 / 2.54,
+        "mm" => 72.27 / 25.4,
+        "pc" => 12.,
+        "dd" => 1238. / 1157.,
+        "cc" => 12. * 1238. / 1157.,
+        "sp" => 1. / DVI_UNITS_PER_POINT,
+        _ => return None,
+    };
+
+    let units = (value * points * DVI_UNITS_PER_POINT).round();
+
+    if units.abs() > i32::MAX as f64 {
+        return None;
+    }
+
+    Some(units as i32)
+}
+
+/// Format a length in DVI units as TeX points.
+fn format_length(units: i32) -> String {
+    format!("{:.5}pt", units as f64 / DVI_UNITS_PER_POINT)
+}
+
+fn trim_start(text: &[u8]) -> &[u8] {
+    let n = text
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(text.len());
+    &text[n..]
+}
+
+/// Append an `xxx` command carrying a special to some XDV data.
+fn push_special(output: &mut Vec<u8>, contents: &[u8]) {
+    if contents.len() < 256 {
+        output.push(XXX1);
+        output.push(contents.len() as u8);
+    } else {
+        output.push(XXX4);
+        output.extend_from_slice(&(contents.len() as u32).to_be_bytes());
+    }
+
+    output.extend_from_slice(contents);
+}
+
+fn parse_page_number(text: &str, ranges: &str) -> Result<Option<i32>> {
+    let text = text.trim();
+
+    if text.is_empty() {
+        return Ok(None);
+    }
+
+    text.parse().map(Some).map_err(|_| {
+        errmsg!(
+            "invalid page number \"{}\" in page selection \"{}\"",
+            text,
+            ranges
+        )
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(data.get(offset..offset + 4)?);
+    Some(u32::from_be_bytes(buf))
+}
+
+fn read_i32(data: &[u8], offset: usize) -> Option<i32> {
+    read_u32(data, offset).map(|n| n as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tectonic_xdv::{FileType, Position, XdvEvents, XdvParser, XdvWriter};
+
+    /// Make an XDV file with pages numbered by `\count0` as given.
+    fn make_xdv(count0s: &[i32]) -> Vec<u8> {
+        let mut w = XdvWriter::new(Vec::new());
+        w.header(FileType::Xdv, b"test").unwrap();
+
+        for (i, &n) in count0s.iter().enumerate() {
+            w.begin_page(&[n]).unwrap();
+            w.special(0, 0, format!("page {}", i + 1).as_bytes())
+                .unwrap();
+            w.end_page().unwrap();
+        }
+
+        w.finish().unwrap()
+    }
+
+    /// Records the specials in a file, and counts its pages.
+    #[derive(Default)]
+    struct Pages(Vec<String>, usize);
+
+    impl XdvEvents for Pages {
+        type Error = std::io::Error;
+
+        fn handle_begin_page(&mut self, _counters: &[i32], _prev: i32) -> std::io::Result<()> {
+            self.1 += 1;
+            Ok(())
+        }
+
+        fn handle_special(&mut self, _pos: Position, contents: &[u8]) -> std::io::Result<()> {
+            self.0.push(String::from_utf8_lossy(contents).into_owned());
+            Ok(())
+        }
+    }
+
+    /// Get the specials on the pages that are kept, checking that there are
+    /// as many pages as reported.
+    fn kept_pages(sel: &PageSelection, data: &[u8]) -> Vec<String> {
+        let (filtered, n_kept) = sel.apply_to_xdv(data).unwrap();
+        assert_eq!(filtered.len() % 4, 0);
+        let pages = XdvParser::process(&filtered[..], Pages::default())
+            .unwrap()
+            .0;
+        assert_eq!(pages.1, n_kept);
+        pages.0
+    }
+
+    #[test]
+    fn parse_ranges() {
+        let sel = PageSelection::new(PageNumbering::Physical, "1, 4-6,10-").unwrap();
+        assert_eq!(
+            sel.ranges,
+            vec![(Some(1), Some(1)), (Some(4), Some(6)), (Some(10), None)]
+        );
+
+        assert!(PageSelection::new(PageNumbering::Physical, "1,,2").is_err());
+        assert!(PageSelection::new(PageNumbering::Physical, "a-3").is_err());
+    }
+
+    #[test]
+    fn select_physical() {
+        let data = make_xdv(&[1, 2, 3, 4, 5]);
+        let sel = PageSelection::new(PageNumbering::Physical, "-2,4").unwrap();
+        assert_eq!(kept_pages(&sel, &data), vec!["page 1", "page 2", "page 4"]);
+    }
+
+    #[test]
+    fn select_count0() {
+        let data = make_xdv(&[1, 2, 1, 2, 3]);
+        let sel = PageSelection::new(PageNumbering::Count0, "2-").unwrap();
+        assert_eq!(kept_pages(&sel, &data), vec!["page 2", "page 4", "page 5"]);
+    }
+
+    #[test]
+    fn select_nothing() {
+        let data = make_xdv(&[1, 2]);
+        let sel = PageSelection::new(PageNumbering::Physical, "7").unwrap();
+        assert_eq!(kept_pages(&sel, &data), Vec::<String>::new());
+    }
+
+    #[test]
+    fn keep_setup_specials() {
+        let mut w = XdvWriter::new(Vec::new());
+        w.header(FileType::Xdv, b"test").unwrap();
+        let pages: &[&[&str]] = &[
+            &["papersize=5in,7in", "pdf:docinfo<</Title(x)>>", "page 1"],
+            &["pdf: pagesize width 5in height 7in", "page 2"],
+            &["page 3", "pdf:dest (x) [@thispage /XYZ @xpos @ypos null]"],
+            &["page 4", "pdf:docview<</PageMode/UseOutlines>>"],
+        ];
+
+        for (i, specials) in pages.iter().enumerate() {
+            w.begin_page(&[i as i32 + 1]).unwrap();
+
+            for special in specials.iter() {
+                w.special(0, 0, special.as_bytes()).unwrap();
+            }
+
+            w.end_page().unwrap();
+        }
+
+        let data = w.finish().unwrap();
+
+        let sel = PageSelection::new(PageNumbering::Physical, "2-3").unwrap();
+        assert_eq!(
+            kept_pages(&sel, &data),
+            vec![
+                "papersize=5in,7in",
+                "pdf:docinfo<</Title(x)>>",
+                "pdf: pagesize width 5in height 7in",
+                "page 2",
+                "pdf:docview<</PageMode/UseOutlines>>",
+                "page 3",
+                "pdf:dest (x) [@thispage /XYZ @xpos @ypos null]",
+            ]
+        );
+    }
+
+    /// Records the specials in a file with their positions and the sheets
+    /// they are on.
+    #[derive(Default)]
+    struct Placed(Vec<(usize, i32, i32, String)>, usize);
+
+    impl XdvEvents for Placed {
+        type Error = std::io::Error;
+
+        fn handle_begin_page(&mut self, _counters: &[i32], _prev: i32) -> std::io::Result<()> {
+            self.1 += 1;
+            Ok(())
+        }
+
+        fn handle_special(&mut self, pos: Position, contents: &[u8]) -> std::io::Result<()> {
+            let text = String::from_utf8_lossy(contents).into_owned();
+            self.0.push((self.1, pos.h, pos.v, text));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parse_imposition() {
+        assert_eq!(
+            Imposition::new("2x3").unwrap(),
+            Imposition {
+                columns: 2,
+                rows: 3
+            }
+        );
+        assert_eq!(
+            Imposition::new("4").unwrap(),
+            Imposition {
+                columns: 4,
+                rows: 1
+            }
+        );
+
+        assert!(Imposition::new("0x2").is_err());
+        assert!(Imposition::new("2x").is_err());
+        assert!(Imposition::new("100x100").is_err());
+    }
+
+    #[test]
+    fn parse_paper_sizes() {
+        assert_eq!(paper_size("10pt,1in"), Some((655360, 4736287)));
+        assert_eq!(paper_size("A4"), paper_size("595.28bp,841.89bp"));
+        assert_eq!(parse_length("2truecm"), parse_length("20mm"));
+        assert_eq!(
+            page_size_special(b"pdf:pagesize width 10pt height 20pt"),
+            Some((655360, 1310720))
+        );
+        assert_eq!(paper_size("folio"), None);
+        assert_eq!(parse_length("10"), None);
+    }
+
+    #[test]
+    fn impose_pages() {
+        const PT: i32 = 65536;
+        let data = make_xdv(&[1, 2, 3, 4, 5]);
+        let (imposed, n_sheets) = Imposition::new("2x2")
+            .unwrap()
+            .apply_to_xdv(&data, "10pt,20pt")
+            .unwrap();
+        assert_eq!(n_sheets, 2);
+
+        let placed = XdvParser::process(&imposed[..], Placed::default())
+            .unwrap()
+            .0;
+        assert_eq!(placed.1, n_sheets);
+        assert_eq!(
+            placed.0,
+            vec![
+                (1, 0, 0, "papersize=20.00000pt,40.00000pt".to_owned()),
+                (1, 0, 0, "page 1".to_owned()),
+                (1, 10 * PT, 0, "page 2".to_owned()),
+                (1, 0, 20 * PT, "page 3".to_owned()),
+                (1, 10 * PT, 20 * PT, "page 4".to_owned()),
+                (2, 0, 0, "page 5".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn impose_sized_pages() {
+        let mut w = XdvWriter::new(Vec::new());
+        w.header(FileType::Xdv, b"test").unwrap();
+
+        for i in 0..2 {
+            w.begin_page(&[i + 1]).unwrap();
+            w.special(0, 0, b"papersize=10pt,20pt").unwrap();
+            w.end_page().unwrap();
+        }
+
+        let data = w.finish().unwrap();
+        let (imposed, n_sheets) = Imposition::new("2")
+            .unwrap()
+            .apply_to_xdv(&data, "letter")
+            .unwrap();
+        assert_eq!(n_sheets, 1);
+
+        let placed = XdvParser::process(&imposed[..], Placed::default())
+            .unwrap()
+            .0;
+        assert_eq!(
+            placed.0,
+            vec![(1, 0, 0, "papersize=20.00000pt,20.00000pt".to_owned())]
+        );
+    }
+
+    #[test]
+    fn reject_garbage() {
+        let sel = PageSelection::new(PageNumbering::Physical, "1").unwrap();
+        assert!(sel.apply_to_xdv(b"not an XDV file").is_err());
+    }
+}
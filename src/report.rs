@@ -0,0 +1,384 @@
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Structured reports on what happened during a processing session.
+//!
+//! A [`ProcessingSession`](crate::driver::ProcessingSession) records a
+//! [`BuildReport`] as it runs: which engine passes it ran and why, what they
+//! reported, which files were read and written, and how long everything
+//! took. With the `serialization` feature, reports can be saved as JSON, so
+//! that other tools can keep track of builds without parsing Tectonic’s
+//! human-readable output.
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::{fmt::Arguments, path::PathBuf, time::Instant};
+use tectonic_errors::Error;
+
+use crate::{
+    digest::DigestData,
+    driver::AccessPattern,
+    io::InputOrigin,
+    status::{Diagnostic, MessageKind, StatusBackend},
+    TexResult,
+};
+
+#[cfg(feature = "serialization")]
+use crate::ctry;
+use crate::errors::Result;
+
+/// A report on a processing session.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Debug, Default)]
+pub struct BuildReport {
+    /// Whether the session completed successfully.
+    pub succeeded: bool,
+
+    /// If the session failed, the error that stopped it, along with its
+    /// causes, separated by colons.
+    pub error: Option<String>,
+
+    /// The passes of the different engines that were run, in order.
+    pub passes: Vec<PassReport>,
+
+    /// Every file that the engines accessed, sorted by name.
+    pub files: Vec<FileReport>,
+
+    /// The files that were written to disk.
+    pub outputs: Vec<OutputReport>,
+
+    /// How long the whole session took, in seconds.
+    pub seconds: f64,
+}
+
+/// The kinds of engine passes run by a processing session.
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "snake_case"))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PassKind {
+    /// A run of the TeX engine to generate a format file.
+    Format,
+
+    /// A run of the TeX engine.
+    Tex,
+
+    /// A run of BibTeX.
+    Bibtex,
+
+    /// A run of `xdvipdfmx`, to convert XDV to PDF.
+    Xdvipdfmx,
+
+    /// A run of `spx2html`, to convert SPX to HTML.
+    Spx2Html,
+}
+
+/// Why the TeX engine was run again.
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "snake_case"))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RerunReason {
+    /// BibTeX was run, so its output needs to be read.
+    Bibtex,
+
+    /// The named file was changed by the previous pass.
+    FileChange(String),
+
+    /// A fixed number of reruns was requested.
+    Requested,
+}
+
+impl std::fmt::Display for RerunReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RerunReason::Bibtex => write!(f, "bibtex was run"),
+            RerunReason::FileChange(name) => write!(f, "\"{}\" changed", name),
+            RerunReason::Requested => write!(f, "I was told to"),
+        }
+    }
+}
+
+/// A report on one pass of an engine.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Debug)]
+pub struct PassReport {
+    /// The kind of pass.
+    pub kind: PassKind,
+
+    /// If this was a rerun of the TeX engine, why it happened.
+    pub reason: Option<RerunReason>,
+
+    /// The outcome reported by the TeX engine or BibTeX. This is `None` for
+    /// other engines, and for passes that failed outright.
+    pub result: Option<TexResult>,
+
+    /// The warnings and errors that were issued during the pass.
+    pub messages: Vec<Message>,
+
+    /// How long the pass took, in seconds.
+    pub seconds: f64,
+}
+
+/// A warning or error issued during a pass.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Message {
+    /// Whether this is a warning or an error.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::message_kind"))]
+    pub kind: MessageKind,
+
+    /// The text of the message.
+    pub text: String,
+}
+
+/// A report on a file that was accessed during the session.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Debug)]
+pub struct FileReport {
+    /// The name of the file, as the engines know it.
+    pub name: String,
+
+    /// How the file was accessed.
+    pub access_pattern: AccessPattern,
+
+    /// Where the file came from, if it was read.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::input_origin"))]
+    pub origin: InputOrigin,
+
+    /// The digest of the file when it was first read, if known.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::digest"))]
+    pub read_digest: Option<DigestData>,
+
+    /// The digest of the file when it was last written, if known.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::digest"))]
+    pub write_digest: Option<DigestData>,
+}
+
+/// A report on a file that was written to disk.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutputReport {
+    /// The path of the file.
+    pub path: PathBuf,
+
+    /// The size of the file, in bytes.
+    pub size: u64,
+}
+
+impl BuildReport {
+    /// Record whether the session succeeded, and if not, why.
+    pub(crate) fn record_outcome<T>(&mut self, result: &Result<T>) {
+        self.succeeded = result.is_ok();
+        self.error = result.as_ref().err().map(|e| {
+            e.iter()
+                .map(|cause| cause.to_string())
+                .collect::<Vec<_>>()
+                .join(": ")
+        });
+    }
+
+    /// Save the report to a file as JSON.
+    #[cfg(feature = "serialization")]
+    pub fn write_json<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let file = ctry!(std::fs::File::create(path); "failed to create `{}`", path.display());
+        let mut writer = std::io::BufWriter::new(file);
+        ctry!(serde_json::to_writer_pretty(&mut writer, self); "failed to write `{}`", path.display());
+        ctry!(std::io::Write::flush(&mut writer); "failed to write `{}`", path.display());
+        Ok(())
+    }
+}
+
+/// A status backend that passes everything on to another one, while keeping
+/// the warnings and errors for a [`PassReport`]. The pass is timed from when
+/// the recorder is created.
+pub(crate) struct MessageRecorder<'a> {
+    inner: &'a mut dyn StatusBackend,
+    messages: Vec<Message>,
+    start: Instant,
+}
+
+impl<'a> MessageRecorder<'a> {
+    pub(crate) fn new(inner: &'a mut dyn StatusBackend) -> Self {
+        MessageRecorder {
+            inner,
+            messages: Vec::new(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Finish recording, and create the report on the pass.
+    pub(crate) fn finish(
+        self,
+        kind: PassKind,
+        reason: Option<&RerunReason>,
+        result: Option<TexResult>,
+    ) -> PassReport {
+        PassReport {
+            kind,
+            reason: reason.cloned(),
+            result,
+            messages: self.messages,
+            seconds: self.start.elapsed().as_secs_f64(),
+        }
+    }
+
+    /// Keep a diagnostic for the report without passing it on, for problems
+    /// that are only shown to the user later, if at all.
+    pub(crate) fn record_diagnostic(&mut self, diag: &Diagnostic) {
+        self.messages.push(Message {
+            kind: diag.kind,
+            text: diag.to_string(),
+        });
+    }
+}
+
+impl<'a> StatusBackend for MessageRecorder<'a> {
+    fn report(&mut self, kind: MessageKind, args: Arguments, err: Option<&Error>) {
+        if kind != MessageKind::Note {
+            self.messages.push(Message {
+                kind,
+                text: args.to_string(),
+            });
+        }
+
+        self.inner.report(kind, args, err);
+    }
+
+    fn report_error(&mut self, err: &Error) {
+        self.messages.push(Message {
+            kind: MessageKind::Error,
+            text: format!("{:#}", err),
+        });
+
+        self.inner.report_error(err);
+    }
+
+    fn note_highlighted(&mut self, before: &str, highlighted: &str, after: &str) {
+        self.inner.note_highlighted(before, highlighted, after);
+    }
+
+    fn dump_error_logs(&mut self, output: &[u8]) {
+        self.inner.dump_error_logs(output);
+    }
+
+    fn report_diagnostic(&mut self, diag: &Diagnostic) {
+        self.record_diagnostic(diag);
+        self.inner.report_diagnostic(diag);
+    }
+}
+
+/// Serialization of the types from other crates that appear in reports.
+#[cfg(feature = "serde")]
+mod ser {
+    use serde::Serializer;
+
+    use crate::{digest::DigestData, io::InputOrigin, status::MessageKind};
+
+    pub fn message_kind<S: Serializer>(kind: &MessageKind, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(match kind {
+            MessageKind::Note => "note",
+            MessageKind::Warning => "warning",
+            MessageKind::Error => "error",
+        })
+    }
+
+    pub fn input_origin<S: Serializer>(origin: &InputOrigin, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(match origin {
+            InputOrigin::Filesystem => "filesystem",
+            InputOrigin::NotInput => "not_input",
+            InputOrigin::Other => "other",
+        })
+    }
+
+    pub fn digest<S: Serializer>(digest: &Option<DigestData>, s: S) -> Result<S::Ok, S::Error> {
+        match digest {
+            Some(d) => s.serialize_some(&d.to_string()),
+            None => s.serialize_none(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "serialization"))]
+mod tests {
+    use super::*;
+    use crate::status::NoopStatusBackend;
+
+    #[test]
+    fn record_messages() {
+        let mut inner = NoopStatusBackend::default();
+        let mut recorder = MessageRecorder::new(&mut inner);
+        recorder.report(MessageKind::Note, format_args!("hello"), None);
+        recorder.report(MessageKind::Warning, format_args!("careful {}", 1), None);
+        recorder.report(MessageKind::Error, format_args!("oops"), None);
+
+        let pass = recorder.finish(PassKind::Bibtex, None, Some(TexResult::Errors));
+        assert_eq!(pass.kind, PassKind::Bibtex);
+        assert_eq!(pass.result, Some(TexResult::Errors));
+        assert_eq!(
+            pass.messages,
+            vec![
+                Message {
+                    kind: MessageKind::Warning,
+                    text: "careful 1".to_owned(),
+                },
+                Message {
+                    kind: MessageKind::Error,
+                    text: "oops".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let report = BuildReport {
+            succeeded: true,
+            error: None,
+            passes: vec![PassReport {
+                kind: PassKind::Tex,
+                reason: Some(RerunReason::FileChange("doc.aux".to_owned())),
+                result: Some(TexResult::Warnings),
+                messages: vec![Message {
+                    kind: MessageKind::Warning,
+                    text: "overfull box".to_owned(),
+                }],
+                seconds: 0.5,
+            }],
+            files: vec![FileReport {
+                name: "doc.aux".to_owned(),
+                access_pattern: AccessPattern::ReadThenWritten,
+                origin: InputOrigin::Other,
+                read_digest: None,
+                write_digest: Some(DigestData::zeros()),
+            }],
+            outputs: vec![OutputReport {
+                path: PathBuf::from("doc.pdf"),
+                size: 1234,
+            }],
+            seconds: 1.0,
+        };
+
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "succeeded": true,
+                "error": null,
+                "passes": [{
+                    "kind": "tex",
+                    "reason": {"file_change": "doc.aux"},
+                    "result": "warnings",
+                    "messages": [{"kind": "warning", "text": "overfull box"}],
+                    "seconds": 0.5,
+                }],
+                "files": [{
+                    "name": "doc.aux",
+                    "access_pattern": "read_then_written",
+                    "origin": "other",
+                    "read_digest": null,
+                    "write_digest": DigestData::zeros().to_string(),
+                }],
+                "outputs": [{"path": "doc.pdf", "size": 1234}],
+                "seconds": 1.0,
+            })
+        );
+    }
+}
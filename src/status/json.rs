@@ -0,0 +1,146 @@
+// src/status/json.rs -- status backend emitting JSON records
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! A status backend for programs that run Tectonic and want to understand its
+//! output, such as editors and CI systems.
+//!
+//! Each message is printed to standard error as a JSON object on a line of
+//! its own, so that it doesn't get mixed up with anything that a command
+//! prints to standard output. Messages look like this:
+//!
+//! ```json
+//! {"type":"message","kind":"error","message":"...","causes":["..."]}
+//! ```
+//!
+//! where `kind` is `note`, `warning` or `error`, and `causes` lists the chain
+//! of errors that led to the message, if any. When an engine fails, its
+//! terminal output is printed as a separate record:
+//!
+//! ```json
+//! {"type":"error_logs","output":"..."}
+//! ```
+//!
+//! Problems found in the files being processed, such as TeX errors, are
+//! printed with their locations, where `file` and `line` may be `null`:
+//!
+//! ```json
+//! {"type":"diagnostic","kind":"error","file":"paper.tex","line":42,"message":"..."}
+//! ```
+
+use serde::Serialize;
+use std::{
+    fmt::Arguments,
+    io::{self, Write},
+};
+use tectonic_errors::Error;
+
+use super::{ChatterLevel, Diagnostic, MessageKind, StatusBackend};
+
+/// A record printed by [`JsonStatusBackend`].
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    Message {
+        kind: &'static str,
+        message: String,
+        causes: Vec<String>,
+    },
+
+    ErrorLogs {
+        output: &'a str,
+    },
+
+    Diagnostic {
+        kind: &'static str,
+        file: Option<&'a str>,
+        line: Option<u32>,
+        message: &'a str,
+    },
+}
+
+/// A status backend that prints messages to standard error as JSON objects,
+/// one per line.
+#[derive(Clone, Debug, Default)]
+pub struct JsonStatusBackend {
+    chatter: ChatterLevel,
+}
+
+impl JsonStatusBackend {
+    /// Create a new backend with the specified chatter level.
+    pub fn new(chatter: ChatterLevel) -> Self {
+        JsonStatusBackend { chatter }
+    }
+
+    fn emit(&mut self, record: &Record) {
+        let stderr = io::stderr();
+        let mut out = stderr.lock();
+        serde_json::to_writer(&mut out, record).expect("write to stderr failed");
+        writeln!(out).expect("write to stderr failed");
+        out.flush().expect("write to stderr failed");
+    }
+}
+
+fn kind_name(kind: MessageKind) -> &'static str {
+    match kind {
+        MessageKind::Note => "note",
+        MessageKind::Warning => "warning",
+        MessageKind::Error => "error",
+    }
+}
+
+impl StatusBackend for JsonStatusBackend {
+    fn report(&mut self, kind: MessageKind, args: Arguments, err: Option<&Error>) {
+        if self.chatter.suppress_message(kind) {
+            return;
+        }
+
+        let causes = match err {
+            Some(e) => e.chain().map(|item| item.to_string()).collect(),
+            None => Vec::new(),
+        };
+
+        self.emit(&Record::Message {
+            kind: kind_name(kind),
+            message: args.to_string(),
+            causes,
+        });
+    }
+
+    fn report_error(&mut self, err: &Error) {
+        let mut chain = err.chain().map(|item| item.to_string());
+
+        self.emit(&Record::Message {
+            kind: kind_name(MessageKind::Error),
+            message: chain.next().unwrap_or_default(),
+            causes: chain.collect(),
+        });
+    }
+
+    fn note_highlighted(&mut self, before: &str, highlighted: &str, after: &str) {
+        self.report(
+            MessageKind::Note,
+            format_args!("{}{}{}", before, highlighted, after),
+            None,
+        );
+    }
+
+    fn dump_error_logs(&mut self, output: &[u8]) {
+        self.emit(&Record::ErrorLogs {
+            output: &String::from_utf8_lossy(output),
+        });
+    }
+
+    fn report_diagnostic(&mut self, diag: &Diagnostic) {
+        if self.chatter.suppress_message(diag.kind) {
+            return;
+        }
+
+        self.emit(&Record::Diagnostic {
+            kind: kind_name(diag.kind),
+            file: diag.file.as_deref(),
+            line: diag.line,
+            message: &diag.message,
+        });
+    }
+}
@@ -3,8 +3,10 @@
 
 //! Compatibility reexports of tectonic_status_base types
 
+#[cfg(feature = "serialization")]
+pub mod json;
 pub mod termcolor;
 
 pub use tectonic_status_base::{
-    plain, ChatterLevel, MessageKind, NoopStatusBackend, StatusBackend,
+    plain, ChatterLevel, Diagnostic, MessageKind, NoopStatusBackend, StatusBackend,
 };
@@ -35,7 +35,7 @@
 //! That call simultaneously tells this module where to find the test assets,
 //! and also activates the test mode.
 
-use std::{collections::HashSet, env, ffi::OsStr, path::PathBuf};
+use std::{collections::HashSet, env, ffi::OsStr, fs, path::PathBuf};
 use tectonic_errors::Result;
 
 use crate::{
@@ -129,4 +129,20 @@ impl Bundle for TestBundle {
     fn get_digest(&mut self, _status: &mut dyn StatusBackend) -> Result<DigestData> {
         Ok(DigestData::zeros())
     }
+
+    fn all_files(&mut self, _status: &mut dyn StatusBackend) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        for entry in fs::read_dir(test_path(&["assets"]))? {
+            let entry = entry?;
+
+            if entry.file_type()?.is_file() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+
+        Ok(names)
+    }
 }
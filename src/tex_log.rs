@@ -0,0 +1,489 @@
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Extracting diagnostics from the logs of the TeX engine.
+//!
+//! TeX reports problems in free-form text, mixed in with everything else
+//! that it has to say. This module picks out the most useful messages —
+//! errors, overfull and underfull boxes, LaTeX and package warnings such as
+//! undefined references and citations, and missing files — and turns them
+//! into [`Diagnostic`]s that say which file and line they are about.
+//!
+//! This is necessarily heuristic. TeX announces that it starts reading a file
+//! by printing an opening parenthesis followed by the file name, and that it
+//! is done with it by printing a closing parenthesis; we follow these to know
+//! which file is being processed. TeX also wraps long lines in its logs,
+//! which we undo before looking at them.
+
+use crate::status::{Diagnostic, MessageKind};
+
+/// The length at which TeX wraps the lines of its logs.
+const MAX_PRINT_LINE: usize = 79;
+
+/// How many lines after a message we search for more information about it,
+/// such as the line number of an error.
+const MAX_CONTEXT_LINES: usize = 12;
+
+/// What separates the source of a LaTeX warning from its text.
+const WARNING_MARKER: &str = " Warning: ";
+
+/// Extract diagnostics from a log of the TeX engine. This works with both the
+/// `.log` file and the terminal output of the engine.
+pub fn parse_log(log: &str) -> Vec<Diagnostic> {
+    let lines = unwrap_lines(log);
+    let mut parser = LogParser::default();
+    let mut i = 0;
+
+    while i < lines.len() {
+        i += parser.parse_at(&lines[i..]);
+    }
+
+    parser.diagnostics
+}
+
+/// Join up lines that TeX wrapped because they were too long.
+fn unwrap_lines(log: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+
+    for line in log.lines() {
+        current.push_str(line);
+
+        if line.chars().count() != MAX_PRINT_LINE {
+            lines.push(std::mem::take(&mut current));
+        }
+    }
+
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    lines
+}
+
+/// If *line* gives the line number of an error, as in `l.42 \foo`, get the
+/// number.
+fn error_line_number(line: &str) -> Option<u32> {
+    let rest = line.strip_prefix("l.")?;
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+/// Find a line number following *marker* in *text*, returning the number
+/// and the text with the marker and the number removed.
+fn take_line_number(text: &str, marker: &str) -> Option<(u32, String)> {
+    let start = text.find(marker)?;
+    let digits = &text[start + marker.len()..];
+    let end = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    let number = digits[..end].parse().ok()?;
+    Some((number, text[..start].to_owned()))
+}
+
+/// Decide whether the text following an opening parenthesis is the name of a
+/// file, rather than a parenthetical remark: it must have an extension that
+/// starts with a letter.
+fn looks_like_file_name(name: &str) -> bool {
+    match name.rfind('.') {
+        Some(i) if i > 0 => {
+            let ext = &name[i + 1..];
+            ext.starts_with(|c: char| c.is_ascii_alphabetic())
+                && ext.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Default)]
+struct LogParser {
+    /// The files that TeX is reading, innermost last. Parentheses that don't
+    /// introduce file names are recorded as `None`, so that they can be
+    /// matched up with their closing parentheses.
+    files: Vec<Option<String>>,
+
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl LogParser {
+    fn current_file(&self) -> Option<String> {
+        self.files.iter().rev().find_map(|f| f.clone())
+    }
+
+    fn push(&mut self, kind: MessageKind, line: Option<u32>, message: &str) {
+        let message = message.trim().trim_end_matches('.').to_owned();
+
+        self.diagnostics.push(Diagnostic {
+            kind,
+            file: self.current_file(),
+            line,
+            message,
+        });
+    }
+
+    /// Parse the log starting at the first of *lines*, returning how many
+    /// lines were consumed.
+    fn parse_at(&mut self, lines: &[String]) -> usize {
+        let line = lines[0].as_str();
+
+        if let Some(message) = line.strip_prefix("! ") {
+            return self.parse_error(message, lines);
+        }
+
+        if line.starts_with("Overfull \\") || line.starts_with("Underfull \\") {
+            return self.parse_bad_box(lines);
+        }
+
+        if let Some(i) = line.find(WARNING_MARKER) {
+            let source = &line[..i];
+
+            if source.starts_with("LaTeX")
+                || source.starts_with("Package ")
+                || source.starts_with("Class ")
+            {
+                return self.parse_warning(source, &line[i + WARNING_MARKER.len()..], lines);
+            }
+        }
+
+        if let Some(name) = line.strip_prefix("No file ") {
+            self.push(MessageKind::Warning, None, &format!("No file {}", name));
+            return 1;
+        }
+
+        self.track_files(line);
+        1
+    }
+
+    /// Parse a TeX error, along with the context lines that tell us where it
+    /// happened.
+    fn parse_error(&mut self, message: &str, lines: &[String]) -> usize {
+        let (line, consumed) = find_error_line(lines);
+
+        if message.trim_end_matches('.') == "Emergency stop" {
+            // This may follow another error, in which case it isn't
+            // interesting on its own, but may come with the line number that
+            // the first one lacked.
+            let previous = self
+                .diagnostics
+                .iter_mut()
+                .rev()
+                .find(|d| d.kind == MessageKind::Error);
+
+            if let Some(previous) = previous {
+                if previous.line.is_none() {
+                    previous.line = line;
+                }
+
+                return consumed;
+            }
+
+            // Otherwise, the reason for stopping is on the next line, as in
+            // `*** (job aborted, no legal \end found)`.
+            let reason = lines
+                .get(1)
+                .and_then(|l| l.strip_prefix("*** ("))
+                .map(|l| l.trim_end_matches(')'));
+
+            if let Some(reason) = reason {
+                self.push(MessageKind::Error, line, reason);
+                return consumed.max(2);
+            }
+        }
+
+        self.push(MessageKind::Error, line, message);
+        consumed
+    }
+
+    /// Parse a report of an overfull or underfull box, along with the
+    /// contents of the box that follow it.
+    fn parse_bad_box(&mut self, lines: &[String]) -> usize {
+        let line = lines[0].as_str();
+
+        match take_line_number(line, " at lines ").or_else(|| take_line_number(line, " at line ")) {
+            Some((n, message)) => self.push(MessageKind::Warning, Some(n), &message),
+            None => self.push(MessageKind::Warning, None, line),
+        }
+
+        // The contents of the box are usually shown up to the next empty
+        // line. They may contain parentheses that have nothing to do with
+        // files.
+        match lines
+            .iter()
+            .take(MAX_CONTEXT_LINES)
+            .position(|l| l.is_empty())
+        {
+            Some(n) => n + 1,
+            None => 1,
+        }
+    }
+
+    /// Parse a warning issued by LaTeX, a package or a class, which may be
+    /// continued over several lines.
+    fn parse_warning(&mut self, source: &str, first: &str, lines: &[String]) -> usize {
+        // Continuation lines start with the name of the package in
+        // parentheses, or with spaces.
+        let name = source.split(' ').nth(1).unwrap_or(source);
+        let prefix = format!("({})", name);
+
+        let mut text = first.trim().to_owned();
+        let mut consumed = 1;
+
+        for line in &lines[1..] {
+            let rest = match line.strip_prefix(&prefix) {
+                Some(rest) => rest,
+                None if line.starts_with(' ') && !line.trim().is_empty() => line,
+                None => break,
+            };
+
+            text.push(' ');
+            text.push_str(rest.trim());
+            consumed += 1;
+        }
+
+        let (line, text) = match take_line_number(&text, " on input line ") {
+            Some((n, rest)) => (Some(n), rest),
+            None => (None, text),
+        };
+
+        let message = if source == "LaTeX" {
+            text
+        } else {
+            format!("{} Warning: {}", source, text)
+        };
+
+        self.push(MessageKind::Warning, line, &message);
+        consumed
+    }
+
+    /// Follow the files that TeX opens and closes in a line of the log.
+    fn track_files(&mut self, line: &str) {
+        let mut rest = line;
+
+        while let Some(i) = rest.find(&['(', ')'][..]) {
+            if rest[i..].starts_with(')') {
+                self.files.pop();
+                rest = &rest[i + 1..];
+                continue;
+            }
+
+            let after = &rest[i + 1..];
+            let end = after
+                .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                .unwrap_or(after.len());
+            let name = &after[..end];
+
+            if looks_like_file_name(name) {
+                let name = name.strip_prefix("./").unwrap_or(name);
+                self.files.push(Some(name.to_owned()));
+            } else {
+                self.files.push(None);
+            }
+
+            rest = &after[end..];
+        }
+    }
+}
+
+/// Find the line number of an error in the context lines that follow it,
+/// returning the number, if found, and how many lines to consume.
+fn find_error_line(lines: &[String]) -> (Option<u32>, usize) {
+    for (i, context) in lines.iter().enumerate().skip(1).take(MAX_CONTEXT_LINES) {
+        if context.starts_with("! ") {
+            break;
+        }
+
+        if let Some(n) = error_line_number(context) {
+            // The line after the line number shows the rest of the offending
+            // input line, which isn't TeX talking about files.
+            return (Some(n), (i + 2).min(lines.len()));
+        }
+    }
+
+    (None, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diag(kind: MessageKind, file: Option<&str>, line: Option<u32>, message: &str) -> Diagnostic {
+        Diagnostic {
+            kind,
+            file: file.map(|s| s.to_owned()),
+            line,
+            message: message.to_owned(),
+        }
+    }
+
+    #[test]
+    fn undefined_control_sequence() {
+        let log = "This is XeTeX, Version 3.14159265-2.6-0.999992 (Tectonic)
+entering extended mode
+(./paper.tex (/bundle/article.cls
+Document Class: article 2019/12/20 v1.4l Standard LaTeX document class
+(size10.clo))
+! Undefined control sequence.
+l.42 \\foo
+          (bar
+No pages of output.
+";
+
+        let diags = parse_log(log);
+        assert_eq!(
+            diags,
+            vec![diag(
+                MessageKind::Error,
+                Some("paper.tex"),
+                Some(42),
+                "Undefined control sequence"
+            )]
+        );
+        assert_eq!(
+            diags[0].to_string(),
+            "paper.tex:42: Undefined control sequence"
+        );
+    }
+
+    #[test]
+    fn missing_file() {
+        let log = "(paper.tex
+! LaTeX Error: File `missing.sty' not found.
+
+Type X to quit or <RETURN> to proceed,
+or enter new name. (Default extension: sty)
+
+! Emergency stop.
+<read *>
+
+l.3 \\usepackage
+               {missing}^^M
+No file paper.bbl.
+";
+
+        assert_eq!(
+            parse_log(log),
+            vec![
+                diag(
+                    MessageKind::Error,
+                    Some("paper.tex"),
+                    Some(3),
+                    "LaTeX Error: File `missing.sty' not found"
+                ),
+                diag(
+                    MessageKind::Warning,
+                    Some("paper.tex"),
+                    None,
+                    "No file paper.bbl"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn job_aborted() {
+        let log = "(texput.tex
+! Emergency stop
+*** (job aborted, no legal \\end found)
+
+";
+
+        assert_eq!(
+            parse_log(log),
+            vec![diag(
+                MessageKind::Error,
+                Some("texput.tex"),
+                None,
+                "job aborted, no legal \\end found"
+            )]
+        );
+    }
+
+    #[test]
+    fn warnings() {
+        let log = "(./paper.tex (./chapter.tex
+Overfull \\hbox (12.3pt too wide) in paragraph at lines 7--9
+[]\\TU/lmr/m/n/10 Some (unbalanced text
+
+LaTeX Warning: Reference `fig:x' on page 1 undefined on input line 12.
+
+)
+Underfull \\vbox (badness 10000) has occurred while \\output is active []
+
+Package natbib Warning: Citation `knuth' on page 2 undefined on input line 30.
+
+
+LaTeX Font Warning: Font shape `OT1/cmr/bx/sc' undefined
+(Font)              using `OT1/cmr/bx/n' instead on input line 31.
+
+LaTeX Warning: There were undefined references.
+
+)
+";
+
+        assert_eq!(
+            parse_log(log),
+            vec![
+                diag(
+                    MessageKind::Warning,
+                    Some("chapter.tex"),
+                    Some(7),
+                    "Overfull \\hbox (12.3pt too wide) in paragraph"
+                ),
+                diag(
+                    MessageKind::Warning,
+                    Some("chapter.tex"),
+                    Some(12),
+                    "Reference `fig:x' on page 1 undefined"
+                ),
+                diag(
+                    MessageKind::Warning,
+                    Some("paper.tex"),
+                    None,
+                    "Underfull \\vbox (badness 10000) has occurred while \\output is active []"
+                ),
+                diag(
+                    MessageKind::Warning,
+                    Some("paper.tex"),
+                    Some(30),
+                    "Package natbib Warning: Citation `knuth' on page 2 undefined"
+                ),
+                diag(
+                    MessageKind::Warning,
+                    Some("paper.tex"),
+                    Some(31),
+                    "LaTeX Font Warning: Font shape `OT1/cmr/bx/sc' undefined \
+                     using `OT1/cmr/bx/n' instead"
+                ),
+                diag(
+                    MessageKind::Warning,
+                    Some("paper.tex"),
+                    None,
+                    "There were undefined references"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn wrapped_lines() {
+        let name = format!("{}.tex", "a".repeat(80));
+        let log = format!("({}\n! Undefined control sequence.\nl.5 \\foo\n", name);
+        let wrapped = format!(
+            "{}\n{}\n! Undefined control sequence.\nl.5 \\foo\n",
+            &log[..MAX_PRINT_LINE],
+            &log[MAX_PRINT_LINE..log.find('\n').unwrap()]
+        );
+
+        assert_eq!(
+            parse_log(&wrapped),
+            vec![diag(
+                MessageKind::Error,
+                Some(&name),
+                Some(5),
+                "Undefined control sequence"
+            )]
+        );
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright 2021 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Watching the input files of a build for changes.
+//!
+//! An [`InputSet`] records the files that a build read, along with the
+//! digests of their contents. Waiting for a change polls the modification
+//! times of the files, which is cheap, and then waits for them to settle
+//! down, since editors often save a file in several steps. The digests are
+//! then used to decide whether anything actually changed, so that touching a
+//! file or saving it without modifications doesn't cause a rebuild.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::digest::{self, Digest, DigestData};
+
+/// Settings for waiting for changes to a set of input files.
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    poll_interval: Duration,
+    debounce: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            poll_interval: Duration::from_millis(200),
+            debounce: Duration::from_millis(300),
+        }
+    }
+}
+
+impl WatchOptions {
+    /// Set how often the input files are checked for changes.
+    pub fn poll_interval(&mut self, value: Duration) -> &mut Self {
+        self.poll_interval = value;
+        self
+    }
+
+    /// Set how long the input files must go without being modified before a
+    /// change is reported.
+    pub fn debounce(&mut self, value: Duration) -> &mut Self {
+        self.debounce = value;
+        self
+    }
+}
+
+/// What we know about one input file.
+#[derive(Clone, Debug)]
+struct InputState {
+    /// The digest of the file's contents when it was read, or `None` if it
+    /// didn't exist.
+    digest: Option<DigestData>,
+
+    /// The file's modification time when it was last checked, or `None` if
+    /// it didn't exist.
+    modified: Option<SystemTime>,
+}
+
+/// A set of input files to watch for changes.
+#[derive(Clone, Debug, Default)]
+pub struct InputSet {
+    files: HashMap<PathBuf, InputState>,
+}
+
+/// Get the modification time of a file, or `None` if it doesn't exist.
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|md| md.modified()).ok()
+}
+
+/// Compute the digest of a file's contents, or `None` if it can't be read.
+fn file_digest(path: &Path) -> Option<DigestData> {
+    let data = fs::read(path).ok()?;
+    let mut dc = digest::create();
+    dc.update(&data);
+    Some(DigestData::from(dc))
+}
+
+impl InputSet {
+    /// Add a file to the set. If *digest* is `None`, the digest of the file's
+    /// current contents is used.
+    pub fn add<P: Into<PathBuf>>(&mut self, path: P, digest: Option<DigestData>) {
+        let path = path.into();
+        let modified = modified_time(&path);
+        let digest = digest.or_else(|| file_digest(&path));
+        self.files.insert(path, InputState { digest, modified });
+    }
+
+    /// Get the number of files in the set.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Test whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Iterate over the paths of the files in the set.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(|p| p.as_ref())
+    }
+
+    /// Test whether any of the files has been modified, created or deleted
+    /// since it was last checked, judging by its modification time.
+    fn touched(&self) -> bool {
+        self.files
+            .iter()
+            .any(|(path, state)| modified_time(path) != state.modified)
+    }
+
+    /// Get the current modification times of all of the files.
+    fn current_times(&self) -> Vec<Option<SystemTime>> {
+        self.files.keys().map(|p| modified_time(p)).collect()
+    }
+
+    /// Get the files whose contents differ from the ones that were recorded,
+    /// sorted by path.
+    pub fn changed(&self) -> Vec<PathBuf> {
+        let mut changed: Vec<PathBuf> = self
+            .files
+            .iter()
+            .filter(|(path, state)| file_digest(path) != state.digest)
+            .map(|(path, _)| path.clone())
+            .collect();
+        changed.sort();
+        changed
+    }
+
+    /// Record the current modification times of the files, so that only
+    /// later modifications count as touching them.
+    pub fn refresh_times(&mut self) {
+        for (path, state) in &mut self.files {
+            state.modified = modified_time(path);
+        }
+    }
+
+    /// Wait until any of the files is modified, created or deleted, and then
+    /// until the files have stopped being modified for the debounce period.
+    ///
+    /// *keep_going* is called before each check; if it returns false, the
+    /// wait is abandoned and this function returns false.
+    pub fn wait_for_change<F: FnMut() -> bool>(
+        &self,
+        options: &WatchOptions,
+        mut keep_going: F,
+    ) -> bool {
+        loop {
+            if !keep_going() {
+                return false;
+            }
+
+            if self.touched() {
+                break;
+            }
+
+            thread::sleep(options.poll_interval);
+        }
+
+        let mut times = self.current_times();
+
+        loop {
+            thread::sleep(options.debounce);
+
+            if !keep_going() {
+                return false;
+            }
+
+            let new_times = self.current_times();
+
+            if new_times == times {
+                return true;
+            }
+
+            times = new_times;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fast_options() -> WatchOptions {
+        let mut options = WatchOptions::default();
+        options
+            .poll_interval(Duration::from_millis(5))
+            .debounce(Duration::from_millis(5));
+        options
+    }
+
+    /// Make sure that a rewritten file gets a different modification time,
+    /// even on filesystems with coarse timestamps.
+    fn rewrite(path: &Path, contents: &str) {
+        let before = modified_time(path);
+
+        loop {
+            fs::write(path, contents).unwrap();
+
+            if modified_time(path) != before {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn changed_by_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.tex");
+        let b = dir.path().join("b.tex");
+        let missing = dir.path().join("missing.tex");
+        fs::write(&a, "a").unwrap();
+        fs::write(&b, "b").unwrap();
+
+        let mut inputs = InputSet::default();
+        inputs.add(&a, None);
+        inputs.add(&b, file_digest(&b));
+        inputs.add(&missing, None);
+        assert_eq!(inputs.len(), 3);
+        assert!(inputs.changed().is_empty());
+
+        // Rewriting a file with the same contents touches it, but doesn't
+        // change it.
+        rewrite(&a, "a");
+        assert!(inputs.touched());
+        assert!(inputs.changed().is_empty());
+        inputs.refresh_times();
+        assert!(!inputs.touched());
+
+        rewrite(&b, "B");
+        fs::write(&missing, "").unwrap();
+        assert_eq!(inputs.changed(), vec![b.clone(), missing.clone()]);
+
+        fs::remove_file(&a).unwrap();
+        assert_eq!(inputs.changed(), vec![a, b, missing]);
+    }
+
+    #[test]
+    fn wait_for_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.tex");
+        fs::write(&a, "a").unwrap();
+
+        let mut inputs = InputSet::default();
+        inputs.add(&a, None);
+        let options = fast_options();
+
+        // Nothing happens, so we give up after a few checks.
+        let mut checks = 0;
+        assert!(!inputs.wait_for_change(&options, || {
+            checks += 1;
+            checks < 5
+        }));
+
+        // Modify the file partway through waiting.
+        let mut checks = 0;
+        assert!(inputs.wait_for_change(&options, || {
+            checks += 1;
+
+            if checks == 3 {
+                rewrite(&a, "A");
+            }
+
+            checks < 100
+        }));
+        assert_eq!(inputs.changed(), vec![a]);
+    }
+}
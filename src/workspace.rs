@@ -3,13 +3,15 @@
 
 //! A Tectonic document-build workspace.
 //!
-//! For the time being, this is just a thin wrapper to provide access to a
-//! `Document` instance. This API exists to future-proof a bit for a potential
-//! world where one workspace can contain multiple documents.
+//! A workspace is a directory tree containing one or more documents. In the
+//! simplest case, the `Tectonic.toml` file at its root defines a single
+//! document. Alternatively, that file can contain a `[workspace]` section
+//! listing member directories, each with its own `Tectonic.toml` file
+//! defining a document.
 
 use std::{
     env, fs,
-    io::{self, Write},
+    io::{self, Read, Write},
     path::{Path, PathBuf},
 };
 
@@ -24,28 +26,48 @@ pub struct Workspace {
     /// The root directory of the workspace.
     root_dir: PathBuf,
 
-    /// This workspace's document. In the future, there might be more than one.
-    doc: Document,
+    /// This workspace's documents, in the order in which they are listed.
+    docs: Vec<Document>,
 }
 
 impl Workspace {
     /// Get the first document in the workspace.
-    ///
-    /// Right now, workspaces in fact only include one document. That may change
-    /// in the future.
     pub fn first_document(&self) -> &Document {
-        &self.doc
+        &self.docs[0]
     }
 
     /// Get the first document in the workspace, mutably.
-    ///
-    /// Right now, workspaces in fact only include one document. That may change
-    /// in the future.
     pub fn first_document_mut(&mut self) -> &mut Document {
-        &mut self.doc
+        &mut self.docs[0]
+    }
+
+    /// Get all of the documents in the workspace, in the order in which
+    /// they are listed.
+    pub fn documents(&self) -> &[Document] {
+        &self.docs
+    }
+
+    /// Get all of the documents in the workspace, mutably.
+    pub fn documents_mut(&mut self) -> &mut [Document] {
+        &mut self.docs
+    }
+
+    /// Get the document in the workspace with the given name, if there is
+    /// one.
+    pub fn document(&self, name: &str) -> Option<&Document> {
+        self.docs.iter().find(|d| d.name() == name)
+    }
+
+    /// Get the document in the workspace with the given name, mutably.
+    pub fn document_mut(&mut self, name: &str) -> Option<&mut Document> {
+        self.docs.iter_mut().find(|d| d.name() == name)
     }
 
     /// Open up a workspace baced on the current process environment.
+    ///
+    /// The workspace is defined by the nearest `Tectonic.toml` file in the
+    /// current directory or one of its parents. So, running a command in the
+    /// directory of a member document acts on just that document.
     pub fn open_from_environment() -> Result<Self> {
         let mut root_dir = env::current_dir()?;
         root_dir.push("tmp"); // simplifies loop logic
@@ -53,8 +75,8 @@ impl Workspace {
         while root_dir.pop() {
             root_dir.push("Tectonic.toml");
 
-            let mut doc_file = match fs::File::open(&root_dir) {
-                Ok(f) => f,
+            let toml_text = match fs::read_to_string(&root_dir) {
+                Ok(t) => t,
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
                     root_dir.pop(); // remove "Tectonic.toml"
                     continue; // this will pop up one directory and try again
@@ -63,17 +85,68 @@ impl Workspace {
             };
 
             root_dir.pop();
-            let mut doc_build_dir = root_dir.clone();
-            doc_build_dir.push("build");
-            let doc = Document::new_from_toml(root_dir.clone(), doc_build_dir, &mut doc_file)?;
-
-            return Ok(Workspace { root_dir, doc });
+            return Self::open_from_toml(root_dir, &toml_text);
         }
 
         Err(errmsg!(
             "No `Tectonic.toml` found in current directory or any of its parents"
         ))
     }
+
+    /// Open a workspace given the contents of the `Tectonic.toml` file in
+    /// its root directory.
+    fn open_from_toml(root_dir: PathBuf, toml_text: &str) -> Result<Self> {
+        let members = match syntax::members(toml_text)? {
+            Some(m) => m,
+            None => {
+                let doc = open_document(&root_dir, &mut toml_text.as_bytes())?;
+                return Ok(Workspace {
+                    root_dir,
+                    docs: vec![doc],
+                });
+            }
+        };
+
+        if members.is_empty() {
+            return Err(errmsg!(
+                "the workspace in `{}` must list at least one member",
+                root_dir.display()
+            ));
+        }
+
+        let mut docs: Vec<Document> = Vec::with_capacity(members.len());
+
+        for member in &members {
+            let src_dir = root_dir.join(member);
+            let toml_path = src_dir.join("Tectonic.toml");
+            let mut toml_file = ctry!(
+                fs::File::open(&toml_path);
+                "couldn\'t open `{}` for workspace member `{}`", toml_path.display(), member
+            );
+            let doc = ctry!(
+                open_document(&src_dir, &mut toml_file);
+                "couldn\'t load workspace member `{}`", member
+            );
+
+            if docs.iter().any(|d| d.name() == doc.name()) {
+                return Err(errmsg!(
+                    "more than one workspace member has the document name `{}`",
+                    doc.name()
+                ));
+            }
+
+            docs.push(doc);
+        }
+
+        Ok(Workspace { root_dir, docs })
+    }
+}
+
+/// Load the document rooted in *src_dir*, given its `Tectonic.toml` file.
+fn open_document<R: Read>(src_dir: &Path, toml_data: &mut R) -> Result<Document> {
+    let mut build_dir = src_dir.to_owned();
+    build_dir.push("build");
+    Document::new_from_toml(src_dir.to_owned(), build_dir, toml_data)
 }
 
 /// A type for creating a new workspace.
@@ -147,7 +220,7 @@ impl WorkspaceCreator {
 
         Ok(Workspace {
             root_dir: self.root_dir,
-            doc,
+            docs: vec![doc],
         })
     }
 
@@ -156,3 +229,36 @@ impl WorkspaceCreator {
         &self.root_dir
     }
 }
+
+/// The concrete syntax of workspace-level `Tectonic.toml` files, wired up via
+/// serde.
+mod syntax {
+    use serde::Deserialize;
+
+    use crate::errors::Result;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Workspace {
+        pub workspace: WorkspaceSection,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct WorkspaceSection {
+        pub members: Vec<String>,
+    }
+
+    /// Get the member directories listed in a `Tectonic.toml` file, or
+    /// `None` if the file defines a document rather than a workspace.
+    pub fn members(toml_text: &str) -> Result<Option<Vec<String>>> {
+        let value: toml::Value = toml::from_str(toml_text)?;
+
+        if value.get("workspace").is_none() {
+            return Ok(None);
+        }
+
+        let ws: Workspace = toml::from_str(toml_text)?;
+        Ok(Some(ws.workspace.members))
+    }
+}
@@ -2188,11 +2188,13 @@ write_out(int32_t p)
             print_cstr("disabled");
             print_char('.');
         } else {
-            // Currently, -Z shell-escape is implemented but hidden (see
-            // src/unstable_opts.rs). When this gets actually implemented,
-            // uncomment the relevant parts in that file.
-
-            print_cstr("enabled but not implemented yet!");
+            // The command text lives at the end of the string pool. The
+            // backend runs it and imports any files that it creates.
+            if (ttstub_shell_escape(&str_pool[str_start[str_ptr - TOO_BIG_CHAR]], cur_length()) == 0)
+                print_cstr("executed");
+            else
+                print_cstr("failed");
+            print_char('.');
         }
 
         capture_to_diagnostic(NULL);
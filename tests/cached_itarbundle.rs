@@ -1,24 +1,19 @@
 use flate2::{write::GzEncoder, GzBuilder};
-use futures::future;
-use headers::HeaderMapExt;
-use hyper::header::{self, HeaderValue};
-use hyper::rt::Future;
-use hyper::service::service_fn;
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use std::collections::HashMap;
+use std::fmt::Arguments;
 use std::fs;
-use std::io::{self, Write};
-use std::net::SocketAddr;
-use std::ops::Bound;
+use std::io::{self, Read, Write};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::time::Duration;
 use tectonic::config::PersistentConfig;
 use tectonic::driver::ProcessingSessionBuilder;
-use tectonic::io::OpenResult;
+use tectonic::io::cached_itarbundle::{CachedITarBundle, UpdatePolicy};
+use tectonic::io::{Bundle, OpenResult};
 use tectonic::status::termcolor::TermcolorStatusBackend;
-use tectonic::status::ChatterLevel;
-use tokio::runtime::current_thread;
+use tectonic::status::{ChatterLevel, MessageKind, StatusBackend};
+use tectonic_errors::Error;
+use tectonic_geturl::test_server::{Fault, TestServer};
+use tectonic_geturl::GetUrlConfig;
 
 mod util;
 
@@ -86,376 +81,547 @@ impl TarIndex {
 
         Ok(builder.finish())
     }
-}
 
-#[derive(Clone, Debug, PartialEq)]
-enum TectonicRequest {
-    Head(String),
-    Index,
-    File(String),
+    /// Build a tar index from a list of files, with the given digest.
+    fn from_files(files: &[(&str, &[u8])], digest: &str) -> TarIndex {
+        let mut builder = TarIndexBuilder::new();
+
+        for (name, content) in files {
+            builder.push(name, content);
+        }
+
+        builder.push(tectonic::digest::DIGEST_NAME, digest.as_bytes());
+        builder.finish()
+    }
 }
 
-struct TarIndexService {
-    tar_index: Mutex<TarIndex>,
-    requests: Mutex<Vec<TectonicRequest>>,
-    local_addr: Mutex<Option<SocketAddr>>,
+const ZERO_DIGEST: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+const ONES_DIGEST: &str = "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
+
+/// A test server hosting a web bundle. The bundle URL redirects to the data
+/// file, as the default bundle URL does.
+struct BundleServer {
+    server: TestServer,
+    tar_index: TarIndex,
 }
 
-type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = io::Error> + Send>;
+impl BundleServer {
+    fn new(tar_index: TarIndex) -> BundleServer {
+        let server = TestServer::start();
+        server.add_redirect("/tectonic-default", "/bundle.tar");
 
-impl TarIndexService {
-    fn new(tar_index: TarIndex) -> TarIndexService {
-        TarIndexService {
-            tar_index: Mutex::new(tar_index),
-            requests: Mutex::new(Vec::new()),
-            local_addr: Mutex::new(None),
-        }
+        let mut bs = BundleServer {
+            server,
+            tar_index: TarIndex::from_files(&[], ZERO_DIGEST),
+        };
+        bs.set_tar_index(tar_index);
+        bs
     }
 
-    fn set_local_addr(&self, local_addr: SocketAddr) {
-        *self.local_addr.lock().unwrap() = Some(local_addr);
+    /// Serve the default test bundle, made from the test assets.
+    fn with_assets() -> BundleServer {
+        let root = Path::new(&env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("assets");
+        BundleServer::new(TarIndex::from_dir(root).unwrap())
     }
 
-    fn set_tar_index(&self, tar_index: TarIndex) {
-        *self.tar_index.lock().unwrap() = tar_index;
+    fn set_tar_index(&mut self, tar_index: TarIndex) {
+        self.server.add_file("/bundle.tar", tar_index.tar.clone());
+        self.server
+            .add_file("/bundle.tar.index.gz", tar_index.index.clone());
+        self.tar_index = tar_index;
     }
 
-    fn response(&self, req: Request<Body>) -> ResponseFuture {
-        match (
-            req.method(),
-            req.uri().path(),
-            req.headers().typed_get::<headers::Range>(),
-        ) {
-            (&Method::HEAD, "/tectonic-default", None) => {
-                self.log_request(TectonicRequest::Head(req.uri().path().to_owned()));
-                let mut resp = Response::builder();
-                resp.status(StatusCode::FOUND);
-                resp.headers_mut().unwrap().insert(
-                    header::LOCATION,
-                    HeaderValue::from_str(&format!(
-                        "http://{}/bundle.tar",
-                        self.local_addr.lock().unwrap().unwrap()
-                    ))
-                    .unwrap(),
-                );
-                Box::new(future::ok(resp.body(Body::empty()).unwrap()))
-            }
-            (&Method::HEAD, "/bundle.tar", None) => {
-                self.log_request(TectonicRequest::Head(req.uri().path().to_owned()));
-                Box::new(future::ok(Response::new(Body::empty())))
-            }
-            (&Method::GET, "/bundle.tar", Some(range)) => {
-                if let Some((Bound::Included(l), Bound::Included(h))) = range.iter().next() {
-                    let tar_index = self.tar_index.lock().unwrap();
-                    let name = tar_index
-                        .map
-                        .get(&(l, h - l + 1))
-                        .expect("unknown file data requested");
-                    self.log_request(TectonicRequest::File(name.to_owned()));
-                    let mut resp = Response::builder();
-                    resp.status(StatusCode::PARTIAL_CONTENT);
-                    resp.headers_mut()
-                        .unwrap()
-                        .typed_insert(headers::ContentRange::bytes(l..=h, None).unwrap());
-                    Box::new(future::ok(
-                        resp.body((&tar_index.tar[l as usize..=h as usize]).to_vec().into())
-                            .unwrap(),
-                    ))
-                } else {
-                    panic!("unexpected");
-                }
-            }
-            (&Method::GET, "/bundle.tar.index.gz", None) => {
-                self.log_request(TectonicRequest::Index);
-                Box::new(future::ok(Response::new(
-                    self.tar_index.lock().unwrap().index.to_vec().into(),
-                )))
-            }
-            _ => Box::new(future::ok(
-                Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(Body::empty())
-                    .unwrap(),
-            )),
-        }
+    fn url(&self) -> String {
+        self.server.url("/tectonic-default")
     }
 
-    fn log_request(&self, request: TectonicRequest) {
-        self.requests.lock().unwrap().push(request);
+    /// Count the requests for the bundle index.
+    fn index_requests(&self) -> usize {
+        self.server.count_requests("GET", "/bundle.tar.index.gz")
     }
 
-    fn url(&self) -> String {
-        format!(
-            "http://{}/tectonic-default",
-            self.local_addr.lock().unwrap().unwrap()
-        )
+    /// Count the range requests for a file in the bundle.
+    fn file_requests(&self, name: &str) -> usize {
+        self.server
+            .requests()
+            .iter()
+            .filter_map(|r| r.range)
+            .filter(|(l, h)| {
+                self.tar_index.map.get(&(*l, h - l + 1)).map(|n| n.as_str()) == Some(name)
+            })
+            .count()
     }
-}
 
-/// Run the provided closure while http service is running. Use the tar index given as
-/// the first variable, or a default on if None.
-fn run_test<R>(tar_index: Option<TarIndex>, run: R) -> Vec<TectonicRequest>
-where
-    R: FnOnce(Arc<TarIndexService>, &str),
-{
-    // Automatically select a port
-    let addr = ([127, 0, 0, 1], 0).into();
+    /// Count the range requests that retrieved a file in the bundle, on its
+    /// own or together with others.
+    fn requests_covering(&self, name: &str) -> usize {
+        let (offset, len) = self
+            .tar_index
+            .map
+            .iter()
+            .find(|(_, n)| n.as_str() == name)
+            .map(|(k, _)| *k)
+            .unwrap();
+
+        self.server
+            .requests()
+            .iter()
+            .filter(|r| r.path == "/bundle.tar")
+            .filter_map(|r| r.range)
+            .filter(|(l, h)| *l <= offset && offset + len <= h + 1)
+            .count()
+    }
+}
 
-    let tar_service = Arc::new(TarIndexService::new(tar_index.unwrap_or_else(|| {
-        let root = Path::new(&env!("CARGO_MANIFEST_DIR"))
-            .join("tests")
-            .join("assets");
-        TarIndex::from_dir(root).unwrap()
-    })));
-    let tar_service_clone = Arc::clone(&tar_service);
+/// A status backend that keeps the notes that it is given.
+#[derive(Default)]
+struct NoteRecorder {
+    notes: Vec<String>,
+}
 
-    let server = Server::bind(&addr).serve(move || {
-        let tar_service = Arc::clone(&tar_service_clone);
-        service_fn(move |req| tar_service.response(req))
-    });
+impl StatusBackend for NoteRecorder {
+    fn report(&mut self, kind: MessageKind, args: Arguments, _err: Option<&Error>) {
+        if kind == MessageKind::Note {
+            self.notes.push(args.to_string());
+        }
+    }
 
-    // server is listening now
-    tar_service.set_local_addr(server.local_addr());
-    let url = tar_service.url();
+    fn dump_error_logs(&mut self, _output: &[u8]) {}
+}
 
-    let (server_shutdown_tx, server_shutdown_rx) = futures::sync::oneshot::channel::<()>();
+fn open_cached(
+    url: &str,
+    cache_root: &Path,
+    status: &mut TermcolorStatusBackend,
+) -> Box<dyn Bundle> {
+    PersistentConfig::default()
+        .make_cached_url_provider(url, false, Some(cache_root), status)
+        .unwrap()
+}
 
-    let graceful = server.with_graceful_shutdown(server_shutdown_rx);
+/// Read a file from a bundle, returning `None` if it is not available and
+/// `Some(Err)` if there was an error.
+fn read(bundle: &mut dyn Bundle, name: &str) -> Option<Result<Vec<u8>, String>> {
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
 
-    let server_thread = thread::spawn(|| {
-        // Run the server on a single thread (current thread)
-        current_thread::run(graceful.map_err(|_| ()));
-    });
+    match bundle.input_open_name(name, &mut status) {
+        OpenResult::Ok(mut h) => {
+            let mut buf = Vec::new();
+            h.read_to_end(&mut buf).unwrap();
+            Some(Ok(buf))
+        }
+        OpenResult::NotAvailable => None,
+        OpenResult::Err(e) => Some(Err(e.to_string())),
+    }
+}
 
-    // Server running, run the provided test
-    run(Arc::clone(&tar_service), &url);
+#[test]
+fn test_full_session() {
+    let server = BundleServer::with_assets();
+    let url = server.url();
+    let tempdir = tempfile::tempdir().unwrap();
 
-    println!("Shutting down");
+    let config = PersistentConfig::default();
 
-    // Shut down server
-    let _ = server_shutdown_tx.send(());
-    server_thread.join().unwrap();
+    let run = |path| {
+        let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+        let mut sess_builder = ProcessingSessionBuilder::default();
+        sess_builder.bundle(Box::new(
+            config
+                .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
+                .unwrap(),
+        ));
+        let input_path = Path::new(path);
+        sess_builder.primary_input_path(input_path);
+        sess_builder.tex_input_name(&input_path.file_name().unwrap().to_string_lossy());
+        sess_builder.output_dir(tempdir.path());
+        sess_builder.format_name("plain");
+        sess_builder.format_cache_path(tempdir.path());
+
+        let mut sess = sess_builder.create(&mut status).unwrap();
+        sess.run(&mut status).unwrap();
+    };
 
-    // Check tectonic's requests.
-    let requests = tar_service.requests.lock().unwrap();
+    // Run tectonic twice
+    run("tests/tex-outputs/the_letter_a.tex");
+    // On this run everything should be cached.
+    run("tests/tex-outputs/the_letter_a.tex");
+    // Run tectonic with a file that needs a new resource
+    run("tests/tex-outputs/redbox_png.tex");
 
-    requests.clone()
+    assert_eq!(server.index_requests(), 1);
+    assert_eq!(server.file_requests(tectonic::digest::DIGEST_NAME), 2);
+    // This file should be cached.
+    assert_eq!(server.file_requests("plain.tex"), 1);
 }
 
-fn check_req_count(requests: &[TectonicRequest], request: TectonicRequest, expected_number: usize) {
-    let number = requests.iter().filter(|r| **r == request).count();
-    assert_eq!(
-        number, expected_number,
-        "Expected {} requests of {:?}, got {}",
-        expected_number, request, number
-    );
+#[test]
+fn test_cached_url_provider() {
+    let server = BundleServer::new(TarIndex::from_files(
+        &[("plain.tex", b"test"), ("other.tex", b"other content")],
+        ZERO_DIGEST,
+    ));
+    let url = server.url();
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+
+    {
+        let mut cache = open_cached(&url, tempdir.path(), &mut status);
+        assert_eq!(read(&mut *cache, "plain.tex"), Some(Ok(b"test".to_vec())));
+        assert_eq!(read(&mut *cache, "plain.tex"), Some(Ok(b"test".to_vec())));
+    }
+    {
+        // should be cached
+        let mut cache = open_cached(&url, tempdir.path(), &mut status);
+        assert_eq!(read(&mut *cache, "plain.tex"), Some(Ok(b"test".to_vec())));
+    }
+    {
+        let mut cache = open_cached(&url, tempdir.path(), &mut status);
+
+        // should be cached
+        assert_eq!(read(&mut *cache, "plain.tex"), Some(Ok(b"test".to_vec())));
+        // in index, should check digest and download the file
+        assert_eq!(
+            read(&mut *cache, "other.tex"),
+            Some(Ok(b"other content".to_vec()))
+        );
+    }
+    {
+        // not in index
+        let mut cache = open_cached(&url, tempdir.path(), &mut status);
+        assert_eq!(read(&mut *cache, "my-favourite-file.tex"), None);
+    }
+
+    assert_eq!(server.index_requests(), 1);
+    assert_eq!(server.file_requests(tectonic::digest::DIGEST_NAME), 2);
+    // This files should be cached.
+    assert_eq!(server.file_requests("plain.tex"), 1);
+    assert_eq!(server.file_requests("other.tex"), 1);
 }
+
 #[test]
-fn test_full_session() {
-    let requests = run_test(None, |_, url| {
-        let tempdir = tempfile::tempdir().unwrap();
-
-        let config = PersistentConfig::default();
-
-        let run = |path| {
-            let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
-            let mut sess_builder = ProcessingSessionBuilder::default();
-            sess_builder.bundle(Box::new(
-                config
-                    .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
-                    .unwrap(),
-            ));
-            let input_path = Path::new(path);
-            sess_builder.primary_input_path(input_path);
-            sess_builder.tex_input_name(&input_path.file_name().unwrap().to_string_lossy());
-            sess_builder.output_dir(tempdir.path());
-            sess_builder.format_name("plain");
-            sess_builder.format_cache_path(tempdir.path());
-
-            let mut sess = sess_builder.create(&mut status).unwrap();
-            sess.run(&mut status).unwrap();
-        };
+fn test_bundle_update() {
+    let mut server = BundleServer::new(TarIndex::from_files(
+        &[
+            ("only-first.tex", b"test"),
+            ("file-in-both.tex", b"in both"),
+        ],
+        ZERO_DIGEST,
+    ));
+    let url = server.url();
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
 
-        // Run tectonic twice
-        run("tests/tex-outputs/the_letter_a.tex");
-        // On this run everything should be cached.
-        run("tests/tex-outputs/the_letter_a.tex");
-        // Run tectonic with a file that needs a new resource
-        run("tests/tex-outputs/redbox_png.tex");
-    });
-
-    check_req_count(&requests, TectonicRequest::Index, 1);
-    check_req_count(
-        &requests,
-        TectonicRequest::File(tectonic::digest::DIGEST_NAME.into()),
-        2,
-    );
-    // This file should be cached.
-    check_req_count(&requests, TectonicRequest::File("plain.tex".into()), 1);
+    // Run with first tar index.
+    {
+        let mut cache = open_cached(&url, tempdir.path(), &mut status);
+        assert!(matches!(read(&mut *cache, "only-first.tex"), Some(Ok(_))));
+    }
+
+    // Set a tar index with a different digest.
+    server.set_tar_index(TarIndex::from_files(
+        &[
+            ("only-second.tex", b"test"),
+            ("file-in-both.tex", b"in both"),
+        ],
+        ONES_DIGEST,
+    ));
+
+    // Run with the new tar index.
+    {
+        let mut cache = open_cached(&url, tempdir.path(), &mut status);
+
+        // This should be cached even thought the bundle does not contain it.
+        assert!(matches!(read(&mut *cache, "only-first.tex"), Some(Ok(_))));
+
+        // Not in index of the first bundle and therefore no digest check.
+        assert_eq!(read(&mut *cache, "only-second.tex"), None);
+
+        // File in the first bundle and the second bundle, but not cached yet. Should
+        // trigger a digest check.
+        assert!(
+            matches!(read(&mut *cache, "file-in-both.tex"), Some(Err(_))),
+            "Bundle digest changed but no error"
+        );
+    }
+
+    // The failed check invalidated the cache, so the next run picks up the
+    // new bundle.
+    {
+        let mut cache = open_cached(&url, tempdir.path(), &mut status);
+        assert!(matches!(read(&mut *cache, "only-second.tex"), Some(Ok(_))));
+        assert_eq!(read(&mut *cache, "only-first.tex"), None);
+    }
+
+    // Once for each bundle, plus once by the failed digest check.
+    assert_eq!(server.index_requests(), 3);
 }
 
 #[test]
-fn test_cached_url_provider() {
-    let tar_index = {
-        let mut builder = TarIndexBuilder::new();
-        builder
-            .push("plain.tex", b"test")
-            .push("other.tex", b"other content")
-            .push(
-                tectonic::digest::DIGEST_NAME,
-                b"0000000000000000000000000000000000000000000000000000000000000000",
-            );
-        builder.finish()
+fn test_update_policy() {
+    let mut server = BundleServer::new(TarIndex::from_files(
+        &[("only-first.tex", b"test")],
+        ZERO_DIGEST,
+    ));
+    let url = server.url();
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+
+    let open = |update, status: &mut TermcolorStatusBackend| {
+        CachedITarBundle::new(
+            &url,
+            false,
+            update,
+            Some(tempdir.path()),
+            &GetUrlConfig::default(),
+            status,
+        )
+        .unwrap()
     };
 
-    let requests = run_test(Some(tar_index), |_, url| {
-        let tempdir = tempfile::tempdir().unwrap();
-        let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+    open(UpdatePolicy::Never, &mut status);
+
+    server.set_tar_index(TarIndex::from_files(
+        &[("only-second.tex", b"test")],
+        ONES_DIGEST,
+    ));
+
+    let formats = tempdir.path().join("formats");
+    let old_format = formats.join(format!("{}-plain-1.fmt", ZERO_DIGEST));
+    let new_format = formats.join(format!("{}-plain-1.fmt", ONES_DIGEST));
+    fs::write(&old_format, b"old").unwrap();
+    fs::write(&new_format, b"new").unwrap();
+
+    // No check is due, so the old bundle is still used.
+    for update in &[
+        UpdatePolicy::Never,
+        UpdatePolicy::Interval(Duration::from_secs(3600)),
+    ] {
+        let mut cache = open(*update, &mut status);
+        assert_eq!(
+            cache.get_digest(&mut status).unwrap().to_string(),
+            ZERO_DIGEST
+        );
+    }
 
-        let config = PersistentConfig::default();
+    assert_eq!(server.index_requests(), 1);
 
-        {
-            let mut cache = config
-                .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
-                .unwrap();
-
-            match cache.input_open_name("plain.tex", &mut status) {
-                OpenResult::Ok(_) => {}
-                _ => panic!("Failed to open plain.tex"),
-            }
-            match cache.input_open_name("plain.tex", &mut status) {
-                OpenResult::Ok(_) => {}
-                _ => panic!("Failed to open plain.tex"),
-            }
-        }
-        {
-            let mut cache = config
-                .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
-                .unwrap();
+    // A check that fails leaves things as they were.
+    server
+        .server
+        .add_faults("/bundle.tar.index.gz", &[Fault::ServerError]);
 
-            // should be cached
-            match cache.input_open_name("plain.tex", &mut status) {
-                OpenResult::Ok(_) => {}
-                _ => panic!("Failed to open plain.tex"),
-            }
-        }
-        {
-            let mut cache = config
-                .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
-                .unwrap();
-
-            // should be cached
-            match cache.input_open_name("plain.tex", &mut status) {
-                OpenResult::Ok(_) => {}
-                _ => panic!("Failed to open plain.tex"),
-            }
-            // in index, should check digest and download the file
-            match cache.input_open_name("other.tex", &mut status) {
-                OpenResult::Ok(_) => {}
-                _ => panic!("Failed to open other.tex"),
-            }
-        }
-        {
-            let mut cache = config
-                .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
-                .unwrap();
+    {
+        let mut cache = open(UpdatePolicy::Interval(Duration::ZERO), &mut status);
+        assert_eq!(
+            cache.get_digest(&mut status).unwrap().to_string(),
+            ZERO_DIGEST
+        );
+    }
 
-            // not in index
-            match cache.input_open_name("my-favourite-file.tex", &mut status) {
-                OpenResult::NotAvailable => {}
-                _ => panic!("'my-favourite-file.tex' file exists?"),
-            }
-        }
-    });
+    assert_eq!(server.index_requests(), 2);
+
+    // A successful check switches to the new bundle and removes the formats
+    // built from the old one.
+    {
+        let mut config = PersistentConfig::default();
+        config.refresh_bundles(true);
+        let mut cache = config
+            .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
+            .unwrap();
+        assert_eq!(
+            cache.get_digest(&mut status).unwrap().to_string(),
+            ONES_DIGEST
+        );
+        assert!(matches!(read(&mut *cache, "only-second.tex"), Some(Ok(_))));
+        assert_eq!(read(&mut *cache, "only-first.tex"), None);
+    }
 
-    check_req_count(&requests, TectonicRequest::Index, 1);
-    check_req_count(
-        &requests,
-        TectonicRequest::File(tectonic::digest::DIGEST_NAME.into()),
-        2,
+    assert_eq!(server.index_requests(), 3);
+    assert!(!old_format.exists());
+    assert!(new_format.exists());
+
+    // The check was recorded, so another one isn't due yet.
+    open(
+        UpdatePolicy::Interval(Duration::from_secs(3600)),
+        &mut status,
     );
-    // This files should be cached.
-    check_req_count(&requests, TectonicRequest::File("plain.tex".into()), 1);
-    check_req_count(&requests, TectonicRequest::File("other.tex".into()), 1);
+    assert_eq!(server.index_requests(), 3);
 }
 
 #[test]
-fn test_bundle_update() {
+fn test_transient_failures_are_retried() {
+    let server = BundleServer::new(TarIndex::from_files(
+        &[("a.tex", b"the first file"), ("b.tex", b"the second file")],
+        ZERO_DIGEST,
+    ));
     let tempdir = tempfile::tempdir().unwrap();
-    let tar_index = {
-        let mut builder = TarIndexBuilder::new();
-        builder
-            .push("only-first.tex", b"test")
-            .push("file-in-both.tex", b"in both")
-            .push(
-                tectonic::digest::DIGEST_NAME,
-                b"0000000000000000000000000000000000000000000000000000000000000000",
-            );
-        builder.finish()
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+    let mut cache = open_cached(&server.url(), tempdir.path(), &mut status);
+
+    // Three failures are tolerated, and a partially downloaded file isn't
+    // mistaken for the real thing.
+    server.server.add_faults(
+        "/bundle.tar",
+        &[
+            Fault::DropConnection,
+            Fault::TruncateBody,
+            Fault::ServerError,
+        ],
+    );
+    assert_eq!(
+        read(&mut *cache, "a.tex"),
+        Some(Ok(b"the first file".to_vec()))
+    );
+    assert_eq!(server.file_requests("a.tex"), 4);
+
+    // Four are not.
+    server
+        .server
+        .add_faults("/bundle.tar", &[Fault::ServerError; 4]);
+    assert!(matches!(read(&mut *cache, "b.tex"), Some(Err(_))));
+
+    // Nothing bad was cached, so the file can be fetched once the server
+    // recovers.
+    assert_eq!(
+        read(&mut *cache, "b.tex"),
+        Some(Ok(b"the second file".to_vec()))
+    );
+}
+
+#[test]
+fn test_unhonored_range_requests() {
+    let server = BundleServer::new(TarIndex::from_files(
+        &[("a.tex", b"the first file"), ("b.tex", b"the second file")],
+        ZERO_DIGEST,
+    ));
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+    let mut cache = open_cached(&server.url(), tempdir.path(), &mut status);
+
+    // A server that sends the whole bundle instead of the requested range
+    // must not be trusted.
+    server
+        .server
+        .add_faults("/bundle.tar", &[Fault::IgnoreRange; 4]);
+    assert!(matches!(read(&mut *cache, "b.tex"), Some(Err(_))));
+    assert_eq!(
+        read(&mut *cache, "b.tex"),
+        Some(Ok(b"the second file".to_vec()))
+    );
+}
+
+#[test]
+fn test_fetch_files() {
+    // The files are bigger than the gap that range requests are allowed to
+    // bridge, so a cached file is never downloaded again as part of a
+    // coalesced request for its neighbours.
+    let big = |c: u8| vec![c; 70 * 1024];
+    let (a, cfg, sty, b, c) = (big(b'a'), big(b'f'), big(b's'), big(b'b'), big(b'c'));
+    let server = BundleServer::new(TarIndex::from_files(
+        &[
+            ("a.tex", &a),
+            ("pkg.cfg", &cfg),
+            ("b.tex", &b),
+            ("pkg.sty", &sty),
+            ("c.tex", &c),
+        ],
+        ZERO_DIGEST,
+    ));
+    let url = server.url();
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+
+    let open = |status: &mut TermcolorStatusBackend| {
+        CachedITarBundle::new(
+            &url,
+            false,
+            UpdatePolicy::Never,
+            Some(tempdir.path()),
+            &GetUrlConfig::default(),
+            status,
+        )
+        .unwrap()
     };
 
-    run_test(Some(tar_index), |service, url| {
-        let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+    // One file gets cached the usual way.
+    {
+        let mut cache = open_cached(&url, tempdir.path(), &mut status);
+        assert_eq!(read(&mut *cache, "b.tex"), Some(Ok(b.clone())));
+    }
 
-        let config = PersistentConfig::default();
-
-        {
-            // Run with first tar index.
-            {
-                let mut cache = config
-                    .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
-                    .unwrap();
-
-                match cache.input_open_name("only-first.tex", &mut status) {
-                    OpenResult::Ok(_) => {}
-                    _ => panic!("Failed to open only-first.tex"),
-                }
-            }
-
-            // Set a tar index with a different digest.
-            let tar_index = {
-                let mut builder = TarIndexBuilder::new();
-                builder
-                    .push("only-second.tex", b"test")
-                    .push("file-in-both.tex", b"in both")
-                    .push(
-                        tectonic::digest::DIGEST_NAME,
-                        b"ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
-                    );
-                builder.finish()
-            };
-            service.set_tar_index(tar_index);
-
-            // Run with the new tar index.
-            {
-                let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
-
-                let config = PersistentConfig::default();
-
-                {
-                    let mut cache = config
-                        .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
-                        .unwrap();
-
-                    // This should be cached even thought the bundle does not contain it.
-                    match cache.input_open_name("only-first.tex", &mut status) {
-                        OpenResult::Ok(_) => {}
-                        _ => panic!("Failed to open only-first.tex"),
-                    }
-
-                    // Not in index of the first bundle and therefore no digest check.
-                    match cache.input_open_name("only-second.tex", &mut status) {
-                        OpenResult::NotAvailable => {}
-                        _ => panic!("File should not be in the first bundle"),
-                    }
-                    // File in the first bundle and the second bundle, but not cached yet. Should
-                    // trigger a digest check.
-                    match cache.input_open_name("file-in-both.tex", &mut status) {
-                        OpenResult::Err(_) => {}
-                        _ => panic!("Bundle digest changed but no error"),
-                    }
-                }
-            }
-        }
-    });
+    // Fetch a package and a file.
+    {
+        let mut cache = open(&mut status);
+        let names = cache
+            .select_files(&["pkg".to_owned(), "a.tex".to_owned()])
+            .unwrap();
+        assert_eq!(names, vec!["a.tex", "pkg.cfg", "pkg.sty"]);
+
+        let mut recorder = NoteRecorder::default();
+        cache.fetch_files(&names, &mut recorder).unwrap();
+        assert!(recorder
+            .notes
+            .contains(&"fetching 3 files (0 already cached)".to_owned()));
+        assert!(recorder.notes.contains(&"fetched 3 of 3 files".to_owned()));
+        assert!(recorder
+            .notes
+            .contains(&"all 3 files are now cached".to_owned()));
+    }
+
+    // Fetching everything picks up where that left off.
+    {
+        let mut cache = open(&mut status);
+        let names = cache.all_files(&mut status).unwrap();
+        let n_total = names.len();
+
+        let mut recorder = NoteRecorder::default();
+        cache.fetch_files(&names, &mut recorder).unwrap();
+        assert!(recorder.notes.contains(&format!(
+            "fetching {} files (4 already cached)",
+            n_total - 4
+        )));
+        assert!(recorder
+            .notes
+            .contains(&format!("all {} files are now cached", n_total)));
+    }
+
+    // Everything is cached now, and was only downloaded once.
+    {
+        let mut cache = open_cached(&url, tempdir.path(), &mut status);
+        assert_eq!(read(&mut *cache, "a.tex"), Some(Ok(a)));
+        assert_eq!(read(&mut *cache, "pkg.sty"), Some(Ok(sty)));
+        assert_eq!(read(&mut *cache, "c.tex"), Some(Ok(c)));
+    }
+
+    for name in &["a.tex", "pkg.cfg", "b.tex", "pkg.sty", "c.tex"] {
+        assert_eq!(server.requests_covering(name), 1, "{}", name);
+    }
+}
+
+#[cfg(feature = "geturl-reqwest")]
+#[test]
+fn test_redirect_into_cdn() {
+    // A bundle URL that redirects to a versioned file, which is in turn
+    // served from a CDN under a name that the index can't be found next to.
+    let tar_index = TarIndex::from_files(&[("a.tex", b"the file")], ZERO_DIGEST);
+    let server = TestServer::start();
+    server.add_redirect("/tectonic-default", "/bundles/v1.tar");
+    server.add_redirect("/bundles/v1.tar", "/cdn/0123abcd");
+    server.add_file("/bundles/v1.tar.index.gz", tar_index.index.clone());
+    server.add_file("/cdn/0123abcd", tar_index.tar.clone());
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+    let mut cache = open_cached(
+        &server.url("/tectonic-default"),
+        tempdir.path(),
+        &mut status,
+    );
+
+    assert_eq!(read(&mut *cache, "a.tex"), Some(Ok(b"the file".to_vec())));
+    assert_eq!(server.count_requests("HEAD", "/cdn/0123abcd"), 0);
+    assert_eq!(server.count_requests("GET", "/bundles/v1.tar.index.gz"), 1);
 }
@@ -256,6 +256,42 @@ fn keep_logs_on_error() {
     assert!(log.contains(r"job aborted, no legal \end found"));
 }
 
+#[cfg(feature = "serialization")]
+#[test]
+fn message_format_json() {
+    let fmt_arg = get_plain_format_arg();
+    let tempdir = setup_and_copy_files(&[]);
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[&fmt_arg, "-", "--message-format=json"],
+        "no end to this file",
+    );
+
+    // Every line of the output is a JSON object.
+    let records: Vec<serde_json::Value> = str::from_utf8(&output.stderr)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("output line is not valid JSON"))
+        .collect();
+    error_or_panic(output);
+
+    assert!(records
+        .iter()
+        .any(|r| r["type"] == "message" && r["kind"] == "note"));
+
+    let diag = records
+        .iter()
+        .find(|r| r["type"] == "diagnostic")
+        .expect("no diagnostic record");
+    assert_eq!(diag["kind"], "error");
+    assert_eq!(diag["message"], r"job aborted, no legal \end found");
+
+    let last = records.last().unwrap();
+    assert_eq!(last["type"], "message");
+    assert_eq!(last["kind"], "error");
+    assert!(!last["causes"].as_array().unwrap().is_empty());
+}
+
 #[test]
 fn no_color_option() {
     // No input files here, but output files are created.
@@ -353,6 +389,123 @@ fn relative_include() {
     check_file(&tempdir, "subdirectory/relative_include.pdf");
 }
 
+#[cfg(feature = "serialization")]
+#[test]
+fn report_option() {
+    let fmt_arg = get_plain_format_arg();
+    let tempdir = setup_and_copy_files(&[]);
+    let read_report = || -> serde_json::Value {
+        let data = fs::read(tempdir.path().join("report.json")).expect("`report.json` not found");
+        serde_json::from_slice(&data).expect("`report.json` is not valid JSON")
+    };
+
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[&fmt_arg, "-", "--outfmt=xdv", "--report=report.json"],
+        "Report content.\\bye",
+    );
+    success_or_panic(output);
+
+    let report = read_report();
+    assert_eq!(report["succeeded"], true);
+    assert_eq!(report["error"], serde_json::Value::Null);
+    assert_eq!(report["passes"][0]["kind"], "tex");
+    assert_eq!(report["passes"][0]["result"], "spotless");
+    assert_eq!(report["outputs"][0]["path"], "texput.xdv");
+    assert!(report["outputs"][0]["size"].as_u64().unwrap() > 0);
+
+    // The report is written even if processing fails.
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[&fmt_arg, "-", "--report=report.json"],
+        "no end to this file",
+    );
+    error_or_panic(output);
+
+    let report = read_report();
+    assert_eq!(report["succeeded"], false);
+    assert!(report["error"].as_str().unwrap().contains("TeX"));
+}
+
+#[test]
+fn select_pages() {
+    use tectonic_xdv::{XdvEvents, XdvParser};
+
+    struct Count0s(Vec<i32>);
+
+    impl XdvEvents for Count0s {
+        type Error = std::io::Error;
+
+        fn handle_begin_page(&mut self, counters: &[i32], _: i32) -> std::io::Result<()> {
+            self.0.push(counters[0]);
+            Ok(())
+        }
+    }
+
+    let fmt_arg = get_plain_format_arg();
+    let tempdir = setup_and_copy_files(&[]);
+    let input = "A\\vfill\\eject B\\vfill\\eject C\\vfill\\eject D\\bye";
+
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[&fmt_arg, "-", "--outfmt=xdv", "--pages=2-3"],
+        input,
+    );
+    success_or_panic(output);
+
+    let xdv = File::open(tempdir.path().join("texput.xdv")).unwrap();
+    let (pages, _) = XdvParser::process(xdv, Count0s(Vec::new())).unwrap();
+    assert_eq!(pages.0, vec![2, 3]);
+
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[&fmt_arg, "-", "--outfmt=xdv", "--pages=7"],
+        input,
+    );
+    error_or_panic(output);
+
+    // The selected pages are the ones arranged on sheets, which begin with
+    // the counters of their first page.
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[
+            &fmt_arg,
+            "-",
+            "--outfmt=xdv",
+            "--pages=2-4",
+            "--n-up=2",
+            "-Z",
+            "paper-size=a4",
+        ],
+        input,
+    );
+    success_or_panic(output);
+
+    let xdv = File::open(tempdir.path().join("texput.xdv")).unwrap();
+    let (pages, _) = XdvParser::process(xdv, Count0s(Vec::new())).unwrap();
+    assert_eq!(pages.0, vec![2, 4]);
+
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[&fmt_arg, "-", "--outfmt=xdv", "--n-up=0x2"],
+        input,
+    );
+    error_or_panic(output);
+}
+
+#[test]
+fn shell_escape() {
+    let fmt_arg = get_plain_format_arg();
+    let tempdir = setup_and_copy_files(&[]);
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[&fmt_arg, "-", "-Z", "shell-escape", "--keep-intermediates"],
+        "\\immediate\\write18{echo Generated > generated.tex}\\input generated \\bye",
+    );
+    success_or_panic(output);
+    check_file(&tempdir, "generated.tex");
+}
+
 // Regression #36
 #[test]
 fn space_in_filename() {
@@ -376,6 +529,86 @@ fn stdin_content() {
     success_or_panic(output);
 }
 
+#[cfg(feature = "serialization")]
+#[test]
+fn tex_error_diagnostics() {
+    let fmt_arg = get_plain_format_arg();
+    let tempdir = setup_and_copy_files(&[]);
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[&fmt_arg, "-", "--message-format=json"],
+        "Hello.\n\\undefinedmacro\n\\bye\n",
+    );
+
+    let records: Vec<serde_json::Value> = str::from_utf8(&output.stderr)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("output line is not valid JSON"))
+        .collect();
+    error_or_panic(output);
+
+    // The problem is reported with its location, rather than by dumping the
+    // output of the engine.
+    let diag = records
+        .iter()
+        .find(|r| r["type"] == "diagnostic")
+        .expect("no diagnostic record");
+    assert_eq!(diag["kind"], "error");
+    assert_eq!(diag["line"], 2);
+    assert_eq!(diag["message"], "Undefined control sequence");
+    assert!(!records.iter().any(|r| r["type"] == "error_logs"));
+}
+
+#[cfg(feature = "serialization")]
+#[test]
+fn tex_warning_diagnostics() {
+    let fmt_arg = get_plain_format_arg();
+    let tempdir = setup_and_copy_files(&[]);
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[
+            &fmt_arg,
+            "-",
+            "--outfmt=xdv",
+            "--message-format=json",
+            "--report=report.json",
+        ],
+        "Hello.\n\\hbox to 1pt{This box is much too narrow.}\n\\bye\n",
+    );
+
+    let records: Vec<serde_json::Value> = str::from_utf8(&output.stderr)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("output line is not valid JSON"))
+        .collect();
+    success_or_panic(output);
+
+    // Problems are reported even if processing succeeds.
+    let diag = records
+        .iter()
+        .find(|r| r["type"] == "diagnostic")
+        .expect("no diagnostic record");
+    assert_eq!(diag["kind"], "warning");
+    assert_eq!(diag["line"], 2);
+    assert!(diag["message"]
+        .as_str()
+        .unwrap()
+        .starts_with(r"Overfull \hbox"));
+
+    let data = fs::read(tempdir.path().join("report.json")).expect("`report.json` not found");
+    let report: serde_json::Value =
+        serde_json::from_slice(&data).expect("`report.json` is not valid JSON");
+    assert_eq!(report["succeeded"], true);
+    assert!(
+        report["passes"][0]["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|m| m["kind"] == "warning"
+                && m["text"].as_str().unwrap().contains(r"Overfull \hbox"))
+    );
+}
+
 #[cfg(feature = "serialization")]
 #[test]
 fn v2_new_build() {
@@ -429,6 +662,62 @@ fn v2_new_build() {
     success_or_panic(output);
 }
 
+#[cfg(feature = "serialization")]
+#[test]
+fn v2_new_build_html() {
+    util::set_test_root();
+
+    let tempdir = setup_and_copy_files(&[]);
+    let mut temppath = tempdir.path().to_owned();
+    let output = run_tectonic(&temppath, &["-X", "new", "doc"]);
+    success_or_panic(output);
+
+    temppath.push("doc");
+
+    // Use plain TeX, as above, and add an HTML output alongside the default
+    // PDF one.
+
+    {
+        let mut toml_path = temppath.clone();
+        toml_path.push("Tectonic.toml");
+        let mut file = OpenOptions::new().append(true).open(toml_path).unwrap();
+        writeln!(file, "tex_format = 'plain'").unwrap();
+        writeln!(file).unwrap();
+        writeln!(file, "[[output]]").unwrap();
+        writeln!(file, "name = 'web'").unwrap();
+        writeln!(file, "type = 'html'").unwrap();
+        writeln!(file, "tex_format = 'plain'").unwrap();
+    }
+
+    {
+        let mut path = temppath.clone();
+        path.push("src");
+
+        {
+            path.push("_preamble.tex");
+            let mut file = File::create(&path).unwrap();
+            writeln!(file).unwrap();
+            path.pop();
+        }
+
+        {
+            path.push("_postamble.tex");
+            let mut file = File::create(&path).unwrap();
+            writeln!(file, "\\end").unwrap();
+            path.pop();
+        }
+    }
+
+    let output = run_tectonic(&temppath, &["-X", "build"]);
+    success_or_panic(output);
+
+    let mut html_path = temppath;
+    html_path.push("build");
+    html_path.push("web");
+    html_path.push("web.html");
+    assert!(html_path.is_file(), "HTML output should have been created");
+}
+
 #[test]
 #[cfg(all(feature = "serialization", not(windows)))] // `echo` may not be available
 fn v2_new_build_open() {
@@ -481,3 +770,217 @@ fn v2_new_build_open() {
     let output = run_tectonic(&temppath, &["-X", "build", "--open"]);
     success_or_panic(output);
 }
+
+#[cfg(feature = "serialization")]
+#[test]
+fn v2_build_incremental() {
+    util::set_test_root();
+
+    let tempdir = setup_and_copy_files(&[]);
+    let temppath = tempdir.path().join("doc");
+    let output = run_tectonic(tempdir.path(), &["-X", "new", "doc"]);
+    success_or_panic(output);
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(temppath.join("Tectonic.toml"))
+        .unwrap();
+    writeln!(file, "tex_format = 'plain'").unwrap();
+
+    // A document that reads its `.aux` file and then rewrites it, so that a
+    // build from scratch needs a second pass.
+    let src = temppath.join("src");
+    fs::write(
+        src.join("_preamble.tex"),
+        "\\newread\\r \\openin\\r=\\jobname.aux\n\
+         \\ifeof\\r \\else \\closein\\r \\input \\jobname.aux \\fi\n\
+         \\newwrite\\w \\immediate\\openout\\w=\\jobname.aux\n\
+         \\immediate\\write\\w{\\relax}\\immediate\\closeout\\w\n",
+    )
+    .unwrap();
+    fs::write(src.join("_postamble.tex"), "\\end\n").unwrap();
+
+    let n_passes = |output: &Output| {
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|l| l.contains("Running TeX") || l.contains("Rerunning TeX"))
+            .count()
+    };
+
+    let state_dir = temppath.join("build").join("default").join(".tectonic");
+
+    // Without `--incremental`, every build starts from scratch.
+
+    let output = run_tectonic(&temppath, &["-X", "build"]);
+    assert_eq!(n_passes(&output), 2);
+    success_or_panic(output);
+    assert!(!state_dir.exists());
+
+    // With it, the first build saves its intermediate files ...
+
+    let output = run_tectonic(&temppath, &["-X", "build", "--incremental"]);
+    assert_eq!(n_passes(&output), 2);
+    success_or_panic(output);
+    assert!(state_dir.join("manifest.txt").is_file());
+
+    // ... so that the next one only needs a single pass.
+
+    let output = run_tectonic(&temppath, &["-X", "build", "--incremental"]);
+    assert_eq!(n_passes(&output), 1);
+    success_or_panic(output);
+
+    // A failed build forgets them.
+
+    fs::write(src.join("index.tex"), "\\undefinedcontrolsequence\n").unwrap();
+    let output = run_tectonic(&temppath, &["-X", "build", "--incremental"]);
+    error_or_panic(output);
+    assert!(!state_dir.exists());
+}
+
+#[cfg(feature = "serialization")]
+#[test]
+fn v2_bundle_inspection() {
+    util::set_test_root();
+
+    let tempdir = setup_and_copy_files(&[]);
+    let temppath = tempdir.path().to_owned();
+
+    let output = run_tectonic(&temppath, &["-X", "bundle", "ls"]);
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    success_or_panic(output);
+    assert!(stdout.lines().any(|l| l == "plain.tex"));
+
+    let output = run_tectonic(&temppath, &["-X", "bundle", "search", "cmbx?.tfm"]);
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    success_or_panic(output);
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        &[
+            "cmbx5.tfm",
+            "cmbx6.tfm",
+            "cmbx7.tfm",
+            "cmbx8.tfm",
+            "cmbx9.tfm"
+        ]
+    );
+
+    let output = run_tectonic(&temppath, &["-X", "bundle", "cat", "plain.tex"]);
+    let expected = fs::read(util::test_path(&["assets", "plain.tex"])).unwrap();
+    assert_eq!(output.stdout, expected);
+    success_or_panic(output);
+
+    // JSON messages don't get mixed up with the file.
+    let output = run_tectonic(
+        &temppath,
+        &["-X", "--message-format=json", "bundle", "cat", "plain.tex"],
+    );
+    assert_eq!(output.stdout, expected);
+    success_or_panic(output);
+
+    let output = run_tectonic(&temppath, &["-X", "bundle", "cat", "nonexistent.sty"]);
+    error_or_panic(output);
+}
+
+#[cfg(feature = "serialization")]
+#[test]
+fn v2_bundle_pin() {
+    util::set_test_root();
+
+    let tempdir = setup_and_copy_files(&[]);
+    let mut temppath = tempdir.path().to_owned();
+    let output = run_tectonic(&temppath, &["-X", "new", "doc"]);
+    success_or_panic(output);
+
+    temppath.push("doc");
+    let toml_path = temppath.join("Tectonic.toml");
+    let pinned = format!("bundle_digest = \"{}\"", "0".repeat(64));
+    let other = format!("bundle_digest = \"{}\"", "f".repeat(64));
+
+    // New documents are pinned to the current bundle.
+    let toml_text = fs::read_to_string(&toml_path).unwrap();
+    assert!(toml_text.contains(&pinned));
+
+    // A build against a different bundle fails ...
+    fs::write(&toml_path, toml_text.replace(&pinned, &other)).unwrap();
+    let output = run_tectonic(&temppath, &["-X", "build"]);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    error_or_panic(output);
+    assert!(stderr.contains("bundle pin"));
+
+    // ... until the document is pinned to it.
+    let output = run_tectonic(&temppath, &["-X", "bundle", "pin"]);
+    success_or_panic(output);
+    assert_eq!(fs::read_to_string(&toml_path).unwrap(), toml_text);
+}
+
+#[cfg(feature = "serialization")]
+#[test]
+fn v2_workspace_members() {
+    util::set_test_root();
+
+    let tempdir = setup_and_copy_files(&[]);
+    let temppath = tempdir.path().to_owned();
+
+    fs::write(
+        temppath.join("Tectonic.toml"),
+        "[workspace]\nmembers = [\"paper\", \"slides\"]\n",
+    )
+    .unwrap();
+
+    // Create two documents that use plain TeX, as in `v2_new_build`.
+    for name in &["paper", "slides"] {
+        let output = run_tectonic(&temppath, &["-X", "new", *name]);
+        success_or_panic(output);
+
+        let docpath = temppath.join(name);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(docpath.join("Tectonic.toml"))
+            .unwrap();
+        writeln!(file, "tex_format = 'plain'").unwrap();
+        fs::write(docpath.join("src").join("_preamble.tex"), "\n").unwrap();
+        fs::write(docpath.join("src").join("_postamble.tex"), "\\end\n").unwrap();
+    }
+
+    let pdf_path = |name: &str| {
+        temppath
+            .join(name)
+            .join("build")
+            .join("default")
+            .join("default.pdf")
+    };
+
+    // By default, every document is built ...
+
+    let output = run_tectonic(&temppath, &["-X", "build"]);
+    success_or_panic(output);
+    assert!(pdf_path("paper").is_file());
+    assert!(pdf_path("slides").is_file());
+
+    // ... but one can be chosen by name.
+
+    fs::remove_file(pdf_path("paper")).unwrap();
+    fs::remove_file(pdf_path("slides")).unwrap();
+
+    let output = run_tectonic(&temppath, &["-X", "build", "--doc", "slides"]);
+    success_or_panic(output);
+    assert!(!pdf_path("paper").is_file());
+    assert!(pdf_path("slides").is_file());
+
+    let output = run_tectonic(&temppath, &["-X", "build", "--doc", "poster"]);
+    error_or_panic(output);
+
+    // Watching needs a single document to be chosen.
+
+    let output = run_tectonic(&temppath, &["-X", "watch"]);
+    error_or_panic(output);
+
+    let output = run_tectonic(&temppath, &["-X", "watch", "--doc", "poster"]);
+    error_or_panic(output);
+
+    // Inside a member, only that document is built.
+
+    let output = run_tectonic(&temppath.join("paper"), &["-X", "build"]);
+    success_or_panic(output);
+    assert!(pdf_path("paper").is_file());
+}